@@ -0,0 +1,58 @@
+//! Emulation speed, in frames per second.
+//!
+//! Besides the built-in scenes, the ROMs listed in `TOMBOY_BENCH_ROMS` (separated like `PATH`) are measured:
+//! ```text
+//! TOMBOY_BENCH_ROMS=tetris.gb:zelda.gb cargo bench
+//! ```
+
+use std::{env, fs, hint::black_box, path::Path};
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use tomboy_emulator::{cart::LoadOptions, gb::Gameboy, testing};
+
+const TCYCLES_PER_FRAME: usize = 70224;
+/// Frames run before measuring, so games are past their boot screens.
+const WARMUP_FRAMES: usize = 120;
+
+fn boot(rom: &[u8]) -> Gameboy {
+  let mut gb = Gameboy::boot_with_options(rom, &LoadOptions::tolerant()).unwrap();
+  for _ in 0..WARMUP_FRAMES {
+    gb.run_tcycles(TCYCLES_PER_FRAME);
+  }
+  gb
+}
+
+fn bench_rom(c: &mut Criterion, name: &str, rom: &[u8]) {
+  let mut group = c.benchmark_group("frame");
+  group.throughput(Throughput::Elements(1));
+  let mut gb = boot(rom);
+  // a frame's worth of cycles, so ROMs turning the LCD off are measured the same way
+  group.bench_function(name, |b| b.iter(|| black_box(gb.run_tcycles(TCYCLES_PER_FRAME))));
+  group.finish();
+}
+
+fn scenes(c: &mut Criterion) {
+  bench_rom(c, "cpu_loop", &testing::cpu_bound_rom());
+  bench_rom(c, "ppu_scene", &testing::ppu_bound_rom());
+}
+
+fn roms(c: &mut Criterion) {
+  let Some(paths) = env::var_os("TOMBOY_BENCH_ROMS") else { return };
+  for path in env::split_paths(&paths) {
+    let rom = fs::read(&path).unwrap();
+    let name = Path::new(&path).file_stem().unwrap().to_string_lossy().into_owned();
+    bench_rom(c, &name, &rom);
+  }
+}
+
+fn boot_time(c: &mut Criterion) {
+  let rom = testing::ppu_bound_rom();
+  c.bench_function("boot", |b| b.iter_batched(
+    || rom.clone(),
+    |rom| Gameboy::boot_with_options(&rom, &LoadOptions::tolerant()).unwrap(),
+    BatchSize::SmallInput,
+  ));
+}
+
+criterion_group!(benches, scenes, roms, boot_time);
+criterion_main!(benches);
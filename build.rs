@@ -0,0 +1,66 @@
+//! Generates the instructions table of `src/instr.rs` from `utils/instr.json`, which stays its source of truth.
+
+use std::{env, fmt::Write, fs, path::Path};
+
+use serde_json::Value;
+
+const TABLE: &str = "utils/instr.json";
+
+/// The `TargetKind` variant of an operand name of the table.
+fn target_kind(name: &str) -> String {
+  match name {
+    "n8" => "Immediate8".into(),
+    "n16" => "Immediate16".into(),
+    "a8" => "Address8".into(),
+    "a16" => "Address16".into(),
+    "e8" => "Signed8".into(),
+    rst if rst.starts_with('$') => format!("RST{}", &rst[1..]),
+    bit if bit.len() == 1 && bit.as_bytes()[0].is_ascii_digit() => format!("Bit{bit}"),
+    reg => reg.into(),
+  }
+}
+
+fn flag(value: &Value, key: &str) -> bool {
+  value.get(key).and_then(Value::as_bool).unwrap_or(false)
+}
+
+fn instruction(out: &mut String, opcode: u8, prefix: bool, instr: &Value) {
+  let cycles = instr["cycles"].as_array().unwrap().iter()
+    .map(|c| c.as_u64().unwrap().to_string())
+    .collect::<Vec<_>>()
+    .join(", ");
+
+  let mut operands = String::new();
+  for op in instr["operands"].as_array().unwrap() {
+    write!(operands,
+      "InstrTarget {{ kind: TargetKind::{}, immediate: {}, increment: {}, decrement: {} }}, ",
+      target_kind(op["name"].as_str().unwrap()), flag(op, "immediate"), flag(op, "increment"), flag(op, "decrement"),
+    ).unwrap();
+  }
+
+  writeln!(out,
+    "  Instruction {{ opcode: 0x{opcode:02X}, name: {:?}, bytes: {}, cycles: &[{cycles}], immediate: {}, prefix: {prefix}, operands: &[{operands}] }},",
+    instr["mnemonic"].as_str().unwrap(), instr["bytes"].as_u64().unwrap(), flag(instr, "immediate"),
+  ).unwrap();
+}
+
+fn main() {
+  println!("cargo:rerun-if-changed={TABLE}");
+  println!("cargo:rerun-if-changed=build.rs");
+
+  let json = fs::read_to_string(TABLE).expect("the instructions table is readable");
+  let table: Value = serde_json::from_str(&json).expect("the instructions table is valid json");
+
+  let mut out = String::from("pub static INSTRUCTIONS: [Instruction; 256 * 2] = [\n");
+  for (group, prefix) in [("unprefixed", false), ("cbprefixed", true)] {
+    for opcode in 0..=255u8 {
+      let instr = &table[group][format!("0x{opcode:02X}")];
+      assert!(instr.is_object(), "{group} opcode {opcode:02X} is missing from {TABLE}");
+      instruction(&mut out, opcode, prefix, instr);
+    }
+  }
+  out.push_str("];\n");
+
+  let dest = Path::new(&env::var("OUT_DIR").unwrap()).join("instructions.rs");
+  fs::write(dest, out).unwrap();
+}
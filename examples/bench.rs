@@ -0,0 +1,49 @@
+//! Headless runner reporting the emulation speed, as emulated seconds per wall second.
+//! ```text
+//! cargo run --release --example bench -- [--seconds N] [ROM...]
+//! ```
+//! Without ROMs, it runs the built-in CPU and PPU scenes.
+
+use std::{env, error::Error, fs, time::Instant};
+
+use tomboy_emulator::{cart::LoadOptions, gb::Gameboy, testing};
+
+const CPU_FREQ: usize = 4194304;
+
+fn run(name: &str, rom: &[u8], seconds: usize) -> Result<(), Box<dyn Error>> {
+  let mut gb = Gameboy::boot_with_options(rom, &LoadOptions::tolerant())?;
+
+  let start = Instant::now();
+  gb.run_tcycles(seconds * CPU_FREQ);
+  let wall = start.elapsed().as_secs_f64();
+
+  let emulated = gb.total_tcycles() as f64 / CPU_FREQ as f64;
+  println!(
+    "{name}: {emulated:.1}s emulated in {wall:.2}s, {:.1}x realtime, {:.0} frames/s",
+    emulated / wall,
+    gb.total_frames() as f64 / wall,
+  );
+  Ok(())
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+  let mut seconds = 60;
+  let mut roms = Vec::new();
+
+  let mut args = env::args().skip(1);
+  while let Some(arg) = args.next() {
+    match arg.as_str() {
+      "--seconds" => seconds = args.next().ok_or("--seconds needs a value")?.parse()?,
+      _ => roms.push(arg),
+    }
+  }
+
+  if roms.is_empty() {
+    run("cpu_loop", &testing::cpu_bound_rom(), seconds)?;
+    run("ppu_scene", &testing::ppu_bound_rom(), seconds)?;
+  }
+  for path in roms {
+    run(&path, &fs::read(&path)?, seconds)?;
+  }
+  Ok(())
+}
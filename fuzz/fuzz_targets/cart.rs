@@ -0,0 +1,37 @@
+//! Loads arbitrary bytes as a ROM, then pokes the mapper registers and the cartridge RAM.
+
+#![no_main]
+
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+use tomboy_emulator::{cart::{CartHeader, LoadOptions}, mbc::Cart};
+
+#[derive(Arbitrary, Debug)]
+enum Op {
+  RomWrite(u16, u8),
+  RamWrite(u16, u8),
+  RamRead(u16),
+  RomRead(u16),
+}
+
+#[derive(Arbitrary, Debug)]
+struct Input {
+  rom: Vec<u8>,
+  ops: Vec<Op>,
+}
+
+fuzz_target!(|input: Input| {
+  let _ = CartHeader::new(&input.rom);
+  let _ = Cart::new(&input.rom);
+  let Ok(mut cart) = Cart::with_options(&input.rom, &LoadOptions::tolerant()) else { return };
+
+  // the mapper takes offsets: 0x0000-0x7FFF for the ROM, 0x0000-0x1FFF into the 0xA000 RAM window
+  for op in input.ops {
+    match op {
+      Op::RomWrite(addr, val) => cart.rom_write(addr & 0x7FFF, val),
+      Op::RamWrite(addr, val) => cart.ram_write(addr & 0x1FFF, val),
+      Op::RamRead(addr) => { cart.ram_read(addr & 0x1FFF); }
+      Op::RomRead(addr) => { cart.rom_read(addr & 0x7FFF); }
+    }
+  }
+});
@@ -0,0 +1,19 @@
+//! Runs arbitrary bytes as code on the Ram64kb CPU, for a bounded number of M-cycles.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use tomboy_emulator::{cpu::Cpu, mem::Memory};
+
+const MAX_MCYCLES: usize = 100_000;
+
+fuzz_target!(|code: &[u8]| {
+  let mut cpu = Cpu::with_ram64kb();
+  for (addr, byte) in code.iter().take(0x10000).enumerate() {
+    cpu.bus.write(addr as u16, *byte);
+  }
+
+  while cpu.mcycles < MAX_MCYCLES {
+    cpu.step();
+  }
+});
@@ -0,0 +1,122 @@
+#ifndef TOMBOY_H
+#define TOMBOY_H
+
+/* Generated with cbindgen from src/ffi.rs, don't edit by hand. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+#define TOMBOY_BUTTON_UP 0
+
+#define TOMBOY_BUTTON_DOWN 1
+
+#define TOMBOY_BUTTON_LEFT 2
+
+#define TOMBOY_BUTTON_RIGHT 3
+
+#define TOMBOY_BUTTON_A 4
+
+#define TOMBOY_BUTTON_B 5
+
+#define TOMBOY_BUTTON_START 6
+
+#define TOMBOY_BUTTON_SELECT 7
+
+typedef enum TomboyStatus {
+  TOMBOY_STATUS_OK = 0,
+  TOMBOY_STATUS_NULL_POINTER,
+  // No ROM was loaded with `tomboy_load_rom`
+  TOMBOY_STATUS_NO_ROM,
+  // The ROM couldn't be loaded
+  TOMBOY_STATUS_INVALID_ROM,
+  TOMBOY_STATUS_INVALID_BUTTON,
+  TOMBOY_STATUS_BUFFER_TOO_SMALL,
+  // The save state is corrupted, or was saved with another ROM or version
+  TOMBOY_STATUS_INVALID_STATE,
+} TomboyStatus;
+
+// An emulator instance, opaque to C.
+typedef struct Tomboy Tomboy;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// Creates an emulator without a ROM. Free it with [`tomboy_destroy`].
+struct Tomboy *tomboy_create(void);
+
+// # Safety
+// `tomboy` must be null or come from [`tomboy_create`], and not be used afterwards.
+void tomboy_destroy(struct Tomboy *tomboy);
+
+// Boots a ROM, with relaxed header checks. The bytes are copied.
+//
+// # Safety
+// `rom` must point to `len` readable bytes.
+enum TomboyStatus tomboy_load_rom(struct Tomboy *tomboy, const uint8_t *rom, size_t len);
+
+// Runs until the next frame is completed.
+//
+// # Safety
+// `tomboy` must come from [`tomboy_create`].
+enum TomboyStatus tomboy_run_frame(struct Tomboy *tomboy);
+
+// # Safety
+// `tomboy` must come from [`tomboy_create`].
+enum TomboyStatus tomboy_reset(struct Tomboy *tomboy);
+
+// Returns the RGBA screen, `width * height * 4` bytes, and writes its size to `width` and `height` if they aren't null.
+// The pointer is valid until the next call taking `tomboy`. Returns null if no ROM is loaded.
+//
+// # Safety
+// `tomboy` must come from [`tomboy_create`], `width` and `height` must be null or writable.
+const uint8_t *tomboy_get_framebuffer(struct Tomboy *tomboy,
+                                      size_t *width,
+                                      size_t *height);
+
+// Presses or releases a button, one of the `TOMBOY_BUTTON_` constants.
+//
+// # Safety
+// `tomboy` must come from [`tomboy_create`].
+enum TomboyStatus tomboy_set_button(struct Tomboy *tomboy, uint32_t button, bool pressed);
+
+// Drains up to `len` audio samples into `out`, interleaved stereo at 44100 Hz,
+// and writes how many were written to `written`. Samples that don't fit are kept for the next call.
+//
+// # Safety
+// `tomboy` must come from [`tomboy_create`], `out` must point to `len` writable floats, `written` must be writable.
+enum TomboyStatus tomboy_get_samples(struct Tomboy *tomboy,
+                                     float *out,
+                                     size_t len,
+                                     size_t *written);
+
+// Size of the buffer needed by [`tomboy_save_state`]. It changes as the emulation runs, ask again before saving.
+//
+// # Safety
+// `tomboy` must come from [`tomboy_create`], `size` must be writable.
+enum TomboyStatus tomboy_save_state_size(struct Tomboy *tomboy,
+                                         size_t *size);
+
+// Writes the emulator state to `buf`, the state size is written to `written`.
+//
+// # Safety
+// `tomboy` must come from [`tomboy_create`], `buf` must point to `len` writable bytes, `written` must be writable.
+enum TomboyStatus tomboy_save_state(struct Tomboy *tomboy,
+                                    uint8_t *buf,
+                                    size_t len,
+                                    size_t *written);
+
+// Restores a state written by [`tomboy_save_state`].
+//
+// # Safety
+// `tomboy` must come from [`tomboy_create`], `buf` must point to `len` readable bytes.
+enum TomboyStatus tomboy_load_state(struct Tomboy *tomboy, const uint8_t *buf, size_t len);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* TOMBOY_H */
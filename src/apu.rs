@@ -1,21 +1,694 @@
-#[derive(Default)]
+mod square;
+mod wave;
+mod noise;
+mod resampler;
+mod filter;
+pub(crate) mod wav;
+
+use std::{fs::File, io::{self, BufWriter}, path::Path};
+
+use square::Square;
+use wave::Wave;
+use noise::Noise;
+use resampler::Resampler;
+use filter::Filter;
+use wav::WavWriter;
+pub use filter::HighPass;
+use serde::{Deserialize, Serialize};
+
+const CPU_FREQ: usize = 4194304;
+
+/// Bits always read back as 1 for each register in 0xFF10..=0xFF2F,
+/// either because they are unused or write-only.
+const READ_MASKS: [u8; 0x20] = [
+  0x80, 0x3F, 0x00, 0xFF, 0xBF, // NR10-NR14
+  0xFF, 0x3F, 0x00, 0xFF, 0xBF, // NR20-NR24
+  0x7F, 0xFF, 0x9F, 0xFF, 0xBF, // NR30-NR34
+  0xFF, 0xFF, 0x00, 0x00, 0xBF, // NR40-NR44
+  0x00, 0x00, 0x70,             // NR50-NR52
+  0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, // unused
+];
+
+#[derive(Default, Serialize, Deserialize)]
+pub(super) struct Length {
+  pub enabled: bool,
+  counter: u16,
+  max: u16,
+}
+
+impl Length {
+  pub fn new(max: u16) -> Self {
+    Self { enabled: false, counter: 0, max }
+  }
+
+  pub fn load(&mut self, val: u8) {
+    self.counter = self.max - (val as u16 & (self.max - 1));
+  }
+
+  /// Handles the length enable and trigger bits of a NRx4 write.
+  /// When the next frame sequencer step doesn't clock the length, enabling it
+  /// clocks it once more, and so does a trigger reloading an empty counter.
+  /// Returns true when the extra clock expires the counter, and the channel should be disabled.
+  pub fn write_control(&mut self, val: u8, extra_clock: bool) -> bool {
+    let was_enabled = self.enabled;
+    self.enabled = val & 0x40 != 0;
+    let trigger = val & 0x80 != 0;
+
+    let mut expired = false;
+    if extra_clock && !was_enabled && self.enabled && self.counter > 0 {
+      self.counter -= 1;
+      expired = self.counter == 0 && !trigger;
+    }
+
+    if trigger && self.counter == 0 {
+      self.counter = self.max;
+      if extra_clock && self.enabled {
+        self.counter -= 1;
+      }
+    }
+
+    expired
+  }
+
+  pub fn fill_state(&self, state: &mut ChannelState) {
+    state.length_enabled = self.enabled;
+    state.length_counter = self.counter;
+  }
+
+  /// Returns true when the counter expires, and the channel should be disabled
+  pub fn clock(&mut self) -> bool {
+    if self.enabled && self.counter > 0 {
+      self.counter -= 1;
+      return self.counter == 0;
+    }
+
+    false
+  }
+}
+
+#[derive(Default, Serialize, Deserialize)]
+pub(super) struct Envelope {
+  pub volume: u8,
+  initial_volume: u8,
+  increase: bool,
+  period: u8,
+  timer: u8,
+}
+
+impl Envelope {
+  pub fn read(&self) -> u8 {
+    (self.initial_volume << 4) | ((self.increase as u8) << 3) | self.period
+  }
+
+  /// Writing NRx2 while the channel is on changes the volume in odd ways ("zombie mode").
+  pub fn write(&mut self, val: u8, channel_enabled: bool) {
+    let increase = val & 0b1000 != 0;
+
+    if channel_enabled {
+      if self.period == 0 {
+        self.volume += 1;
+      } else if !self.increase {
+        self.volume += 2;
+      }
+
+      if self.increase != increase {
+        self.volume = 16u8.wrapping_sub(self.volume);
+      }
+      self.volume &= 0xF;
+    }
+
+    self.initial_volume = val >> 4;
+    self.increase = increase;
+    self.period = val & 0b111;
+  }
+
+  pub fn fill_state(&self, state: &mut ChannelState) {
+    state.volume = self.volume;
+    state.envelope_timer = self.timer;
+  }
+
+  pub fn trigger(&mut self) {
+    self.volume = self.initial_volume;
+    self.timer = self.period;
+  }
+
+  pub fn clock(&mut self) {
+    if self.period == 0 { return; }
+
+    self.timer = self.timer.saturating_sub(1);
+    if self.timer == 0 {
+      self.timer = self.period;
+
+      if self.increase && self.volume < 15 {
+        self.volume += 1;
+      } else if !self.increase && self.volume > 0 {
+        self.volume -= 1;
+      }
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AudioConfig {
+  /// Output sample rate in Hz
+  pub sample_rate: u32,
+  /// Max stereo samples kept buffered, older ones are dropped when it is exceeded
+  pub buffer_size: usize,
+  /// High-pass filter applied on the stereo mix
+  pub high_pass: HighPass,
+}
+
+impl Default for AudioConfig {
+  fn default() -> Self {
+    Self { sample_rate: 44100, buffer_size: 8192, high_pass: HighPass::default() }
+  }
+}
+
+/// Converts a 4 bit digital channel output to an analog value in the -1..1 range.
+/// A disabled DAC outputs nothing, while an enabled one outputs -1 for a digital 0,
+/// which is the DC offset the high-pass filter removes.
+fn dac(enabled: bool, digital: u8) -> f32 {
+  if enabled {
+    digital as f32 / 7.5 - 1.0
+  } else { 0.0 }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Channel { Square1, Square2, Wave, Noise }
+
+/// Snapshot of a channel internal counters, for debugging.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ChannelState {
+  pub enabled: bool,
+  pub dac_enabled: bool,
+  pub period: u32,
+  pub timer: u32,
+  pub length_enabled: bool,
+  pub length_counter: u16,
+  pub volume: u8,
+  pub envelope_timer: u8,
+  pub sweep_timer: u8,
+}
+
+/// Snapshot of the APU internal counters, for debugging.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ApuState {
+  pub enabled: bool,
+  pub frame_step: u8,
+  pub square1: ChannelState,
+  pub square2: ChannelState,
+  pub wave: ChannelState,
+  pub noise: ChannelState,
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct Apu {
+  square1: Square,
+  square2: Square,
+  wave: Wave,
+  noise: Noise,
+
+  enabled: bool,
+  nr50: u8,
+  nr51: u8,
+
+  frame_step: u8,
+
+  config: AudioConfig,
+  resampler: Resampler,
+  filter: Filter,
+  #[serde(skip)]
+  muted: [bool; 4],
+  #[serde(skip)]
+  capture: Option<WavWriter<BufWriter<File>>>,
+  #[serde(skip)]
+  samples: Vec<f32>,
+  samples_generated: usize,
+}
 
+impl Default for Apu {
+  fn default() -> Self {
+    Self {
+      square1: Square::new(true),
+      square2: Square::new(false),
+      wave: Wave::default(),
+      noise: Noise::default(),
+      enabled: true,
+      nr50: 0x77,
+      nr51: 0xF3,
+      frame_step: 0,
+      config: AudioConfig::default(),
+      resampler: Resampler::new(AudioConfig::default().sample_rate),
+      filter: Filter::new(HighPass::default(), AudioConfig::default().sample_rate),
+      muted: [false; 4],
+      capture: None,
+      samples: Vec::new(),
+      samples_generated: 0,
+    }
+  }
 }
 
+impl Drop for Apu {
+  fn drop(&mut self) {
+    let _ = self.stop_capture();
+  }
+}
 
 impl Apu {
-  pub fn tick(&mut self) {}
+  pub fn tick(&mut self) {
+    if self.enabled {
+      self.square1.tick();
+      self.square2.tick();
+      self.wave.tick();
+      self.noise.tick();
+    }
+
+    let (left, right) = self.mix();
+    if let Some((left, right)) = self.resampler.push(left, right) {
+      let (left, right) = self.filter.apply(left, right);
+      // nobody is consuming, drop the oldest half
+      if self.samples.len() >= self.config.buffer_size * 2 {
+        let half = (self.samples.len() / 4 * 2).max(2);
+        self.samples.drain(..half);
+      }
+
+      self.samples.push(left);
+      self.samples.push(right);
+      self.samples_generated += 1;
+
+      if let Some(wav) = &mut self.capture {
+        if wav.write(left, right).is_err() {
+          self.capture = None;
+        }
+      }
+    }
+  }
+
+  pub fn debug_state(&self) -> ApuState {
+    ApuState {
+      enabled: self.enabled,
+      frame_step: self.frame_step,
+      square1: self.square1.debug_state(),
+      square2: self.square2.debug_state(),
+      wave: self.wave.debug_state(),
+      noise: self.noise.debug_state(),
+    }
+  }
+
+  /// Mutes or unmutes a channel in the output mix only, the channel keeps running.
+  pub fn set_channel_enabled(&mut self, ch: Channel, enabled: bool) {
+    self.muted[ch as usize] = !enabled;
+  }
+
+  pub fn is_channel_enabled(&self, ch: Channel) -> bool {
+    !self.muted[ch as usize]
+  }
+
+  /// Mutes every channel but the given one.
+  pub fn solo_channel(&mut self, ch: Channel) {
+    self.muted = [true; 4];
+    self.muted[ch as usize] = false;
+  }
+
+  /// Starts recording the output to a 16 bit stereo WAV file.
+  /// A capture already in progress is finished first.
+  pub fn start_capture(&mut self, path: impl AsRef<Path>) -> io::Result<()> {
+    self.stop_capture()?;
+    let file = BufWriter::new(File::create(path)?);
+    self.capture = Some(WavWriter::new(file, self.config.sample_rate)?);
+    Ok(())
+  }
+
+  /// Stops recording, and finalizes the WAV file.
+  pub fn stop_capture(&mut self) -> io::Result<()> {
+    match self.capture.take() {
+      Some(wav) => wav.finish().map(|_| ()),
+      None => Ok(()),
+    }
+  }
+
+  pub fn is_capturing(&self) -> bool {
+    self.capture.is_some()
+  }
+
+  pub fn config(&self) -> AudioConfig {
+    self.config
+  }
+
+  /// Takes the output settings and the capture from `old`, after `self` was loaded from a save state or rebuilt by a reset.
+  pub(crate) fn keep_from(&mut self, old: &mut Apu) {
+    self.muted = old.muted;
+    self.capture = old.capture.take();
+    // the saved resampler and filter phases are kept when they can be, for bit exact audio
+    if self.config != old.config {
+      self.config = old.config;
+      self.resampler = Resampler::new(old.config.sample_rate);
+      self.filter = Filter::new(old.config.high_pass, old.config.sample_rate);
+    }
+  }
+
+  pub fn set_config(&mut self, config: AudioConfig) {
+    self.config = config;
+    self.resampler = Resampler::new(config.sample_rate);
+    self.filter = Filter::new(config.high_pass, config.sample_rate);
+    // the capture was started with the old sample rate
+    let _ = self.stop_capture();
+    self.samples.clear();
+  }
+
+  /// Clocks the frame sequencer, on the falling edges of DIV bit 12 (512 Hz).
+  /// Resetting DIV while the bit is set clocks it early.
+  pub fn div_apu_tick(&mut self) {
+    if self.enabled { self.frame_sequencer_step(); }
+  }
+
+  fn frame_sequencer_step(&mut self) {
+    if self.frame_step.is_multiple_of(2) {
+      self.square1.clock_length();
+      self.square2.clock_length();
+      self.wave.clock_length();
+      self.noise.clock_length();
+    }
+
+    if self.frame_step == 2 || self.frame_step == 6 {
+      self.square1.clock_sweep();
+    }
+
+    if self.frame_step == 7 {
+      self.square1.clock_envelope();
+      self.square2.clock_envelope();
+      self.noise.clock_envelope();
+    }
+
+    self.frame_step = (self.frame_step + 1) % 8;
+  }
+
+  fn mix(&self) -> (f32, f32) {
+    let outputs = [
+      dac(self.square1.dac_enabled(), self.square1.output()),
+      dac(self.square2.dac_enabled(), self.square2.output()),
+      dac(self.wave.dac_enabled(), self.wave.output()),
+      dac(self.noise.dac_enabled(), self.noise.output()),
+    ];
+
+    let mut left = 0.0;
+    let mut right = 0.0;
+    for (ch, out) in outputs.iter().enumerate() {
+      if self.muted[ch] { continue; }
+      if self.nr51 & (1 << (ch + 4)) != 0 { left  += out; }
+      if self.nr51 & (1 << ch) != 0       { right += out; }
+    }
+
+    let left_vol  = ((self.nr50 >> 4) & 0b111) as f32 + 1.0;
+    let right_vol = (self.nr50 & 0b111) as f32 + 1.0;
+    (left / 4.0 * left_vol / 8.0, right / 4.0 * right_vol / 8.0)
+  }
 
   pub fn read(&self, addr: u16) -> u8 {
-    0xff
+    let val = match addr {
+      0xFF10..=0xFF14 => self.square1.read(addr - 0xFF10),
+      0xFF15..=0xFF19 => self.square2.read(addr - 0xFF15),
+      0xFF1A..=0xFF1E => self.wave.read(addr - 0xFF1A),
+      0xFF1F..=0xFF23 => self.noise.read(addr - 0xFF1F),
+      0xFF24 => self.nr50,
+      0xFF25 => self.nr51,
+      0xFF26 => {
+        (self.enabled as u8) << 7
+        | (self.noise.enabled as u8) << 3
+        | (self.wave.enabled as u8) << 2
+        | (self.square2.enabled as u8) << 1
+        | self.square1.enabled as u8
+      }
+      0xFF30..=0xFF3F => return self.wave.wave_read(addr - 0xFF30),
+      _ => 0,
+    };
+
+    match addr {
+      0xFF10..=0xFF2F => val | READ_MASKS[addr as usize - 0xFF10],
+      _ => 0xFF,
+    }
   }
 
-  pub fn write(&self, addr: u16, val: u8) {
+  pub fn write(&mut self, addr: u16, val: u8) {
+    // while powered off, only NR52, wave RAM and the length counters (on DMG) are writable
+    if !self.enabled {
+      match addr {
+        0xFF11 => self.square1.write(1, val & 0x3F),
+        0xFF16 => self.square2.write(1, val & 0x3F),
+        0xFF1B => self.wave.write(1, val),
+        0xFF20 => self.noise.write(1, val),
+        0xFF26 => self.set_power(val & 0x80 != 0),
+        0xFF30..=0xFF3F => self.wave.wave_write(addr - 0xFF30, val),
+        _ => {}
+      }
+      return;
+    }
+
+    // the next frame sequencer step doesn't clock the length counters
+    let extra_clock = self.frame_step % 2 == 1;
+    match addr {
+      0xFF14 => self.square1.write_control(val, extra_clock),
+      0xFF19 => self.square2.write_control(val, extra_clock),
+      0xFF1E => self.wave.write_control(val, extra_clock),
+      0xFF23 => self.noise.write_control(val, extra_clock),
+      0xFF10..=0xFF14 => self.square1.write(addr - 0xFF10, val),
+      0xFF15..=0xFF19 => self.square2.write(addr - 0xFF15, val),
+      0xFF1A..=0xFF1E => self.wave.write(addr - 0xFF1A, val),
+      0xFF1F..=0xFF23 => self.noise.write(addr - 0xFF1F, val),
+      0xFF24 => self.nr50 = val,
+      0xFF25 => self.nr51 = val,
+      0xFF26 => self.set_power(val & 0x80 != 0),
+      0xFF30..=0xFF3F => self.wave.wave_write(addr - 0xFF30, val),
+      _ => {}
+    }
+  }
+
+  /// Reads the CGB-only PCM12 (0xFF76) and PCM34 (0xFF77) registers,
+  /// holding the current digital output of two channels each.
+  pub fn read_pcm(&self, addr: u16) -> u8 {
+    match addr {
+      0xFF76 => (self.square2.output() << 4) | self.square1.output(),
+      0xFF77 => (self.noise.output() << 4) | self.wave.output(),
+      _ => 0xFF,
+    }
+  }
+
+  fn set_power(&mut self, on: bool) {
+    if self.enabled == on { return; }
+
+    if on {
+      // the frame sequencer restarts, so the next step clocks the length counters
+      self.frame_step = 0;
+    } else {
+      self.square1.power_off();
+      self.square2.power_off();
+      self.wave.power_off();
+      self.noise.power_off();
+      self.nr50 = 0;
+      self.nr51 = 0;
+    }
 
+    self.enabled = on;
   }
 
+  /// Drains the generated samples, as interleaved stereo (left, right) pairs in the -1..1 range.
   pub fn consume_samples(&mut self) -> Vec<f32> {
-    Vec::new()
+    std::mem::take(&mut self.samples)
   }
-}
\ No newline at end of file
+
+  /// Like [`Apu::consume_samples`], but appends to an existing buffer, to avoid allocating every frame.
+  pub fn drain_samples_into(&mut self, out: &mut Vec<f32>) {
+    out.append(&mut self.samples);
+  }
+
+  /// Drains as many whole stereo pairs as fit in `out`, and returns how many floats were written.
+  pub fn drain_samples_to_slice(&mut self, out: &mut [f32]) -> usize {
+    let count = self.samples.len().min(out.len()) & !1;
+    out[..count].copy_from_slice(&self.samples[..count]);
+    self.samples.drain(..count);
+    count
+  }
+
+  /// Total samples generated since boot, including already consumed ones.
+  pub fn samples_generated(&self) -> usize {
+    self.samples_generated
+  }
+
+  /// T-cycles elapsed since the last sample was generated.
+  pub fn sample_phase(&self) -> usize {
+    self.resampler.phase()
+  }
+}
+
+#[cfg(test)]
+mod apu_tests {
+  use super::*;
+
+  // CPU freq / Frame sequencer freq = 4194304 Hz / 512 Hz = 8192
+  const CYCLES_PER_FRAME_STEP: usize = 8192;
+
+  /// Runs with the frame sequencer clocked as by a DIV starting at 0.
+  fn run(apu: &mut Apu, tcycles: usize) {
+    for i in 1..=tcycles {
+      apu.tick();
+      if i % CYCLES_PER_FRAME_STEP == 0 { apu.div_apu_tick(); }
+    }
+  }
+
+  #[test]
+  fn state_round_trip() {
+    let mut apu = Apu::default();
+    // square1 with sweep, length and decreasing envelope
+    apu.write(0xFF10, 0x16);
+    apu.write(0xFF11, 0x90);
+    apu.write(0xFF12, 0xF3);
+    apu.write(0xFF13, 0x40);
+    apu.write(0xFF14, 0xC6);
+    // noise with length and increasing envelope
+    apu.write(0xFF21, 0x1A);
+    apu.write(0xFF22, 0x31);
+    apu.write(0xFF20, 0x10);
+    apu.write(0xFF23, 0xC0);
+
+    // stop mid-note, between two frame sequencer steps
+    run(&mut apu, 3 * CYCLES_PER_FRAME_STEP + 1234);
+    apu.consume_samples();
+
+    let state = serde_json::to_string(&apu).unwrap();
+    let mut loaded: Apu = serde_json::from_str(&state).unwrap();
+    assert_eq!(apu.debug_state(), loaded.debug_state());
+
+    run(&mut apu, 8 * CYCLES_PER_FRAME_STEP);
+    run(&mut loaded, 8 * CYCLES_PER_FRAME_STEP);
+    assert_eq!(apu.debug_state(), loaded.debug_state());
+
+    let expected = apu.consume_samples();
+    let samples = loaded.consume_samples();
+    assert!(expected.iter().any(|s| *s != 0.0));
+    assert!(expected.iter().map(|s| s.to_bits()).eq(samples.iter().map(|s| s.to_bits())));
+  }
+
+  #[test]
+  fn high_pass_removes_dc() {
+    let mut apu = Apu::default();
+    // DAC on, but volume 0: the channel outputs a constant -1
+    apu.write(0xFF12, 0x08);
+    apu.write(0xFF14, 0x80);
+
+    run(&mut apu, 1000);
+    assert!(apu.consume_samples()[0] < -0.01);
+    run(&mut apu, CPU_FREQ / 8);
+    assert!(apu.consume_samples().last().unwrap().abs() < 0.001);
+
+    apu.set_config(AudioConfig { high_pass: HighPass::Off, ..Default::default() });
+    run(&mut apu, CPU_FREQ / 8);
+    assert!(apu.consume_samples().last().unwrap().abs() > 0.01);
+  }
+
+  #[test]
+  fn read_masks() {
+    let mut apu = Apu::default();
+    for addr in 0xFF10..=0xFF25 { apu.write(addr, 0); }
+    for addr in 0xFF10..=0xFF25 {
+      assert_eq!(apu.read(addr), READ_MASKS[addr as usize - 0xFF10], "{addr:04X}");
+    }
+    for addr in 0xFF27..=0xFF2F { assert_eq!(apu.read(addr), 0xFF); }
+
+    for addr in 0xFF10..=0xFF25 { apu.write(addr, 0xFF); }
+    for addr in 0xFF10..=0xFF25 { assert_eq!(apu.read(addr), 0xFF, "{addr:04X}"); }
+  }
+
+  #[test]
+  fn power_off() {
+    let mut apu = Apu::default();
+    apu.write(0xFF30, 0x12);
+    apu.write(0xFF11, 0xBE);
+    apu.write(0xFF12, 0xF0);
+    apu.write(0xFF14, 0xC0);
+    let length = apu.debug_state().square1.length_counter;
+
+    apu.write(0xFF26, 0);
+    assert_eq!(apu.read(0xFF26), 0x70);
+    for addr in 0xFF10..=0xFF25 {
+      assert_eq!(apu.read(addr), READ_MASKS[addr as usize - 0xFF10], "{addr:04X}");
+    }
+    assert_eq!(apu.debug_state().square1.length_counter, length);
+    assert_eq!(apu.read(0xFF30), 0x12);
+
+    // writes are ignored, but length counters can still be loaded
+    apu.write(0xFF12, 0xF0);
+    apu.write(0xFF24, 0x77);
+    apu.write(0xFF20, 0x3F);
+    assert_eq!(apu.read(0xFF12), 0x00);
+    assert_eq!(apu.read(0xFF24), 0x00);
+    assert_eq!(apu.debug_state().noise.length_counter, 1);
+
+    apu.write(0xFF26, 0x80);
+    assert_eq!(apu.read(0xFF26), 0xF0);
+    assert_eq!(apu.debug_state().frame_step, 0);
+    apu.write(0xFF24, 0x77);
+    assert_eq!(apu.read(0xFF24), 0x77);
+  }
+
+  #[test]
+  fn length_extra_clock() {
+    let mut apu = Apu::default();
+    apu.write(0xFF12, 0xF0);
+    // after the first step the next one doesn't clock length
+    run(&mut apu, CYCLES_PER_FRAME_STEP);
+    assert_eq!(apu.debug_state().frame_step, 1);
+
+    // enabling length clocks it once more, expiring a counter of 1
+    apu.write(0xFF11, 0x3F);
+    apu.write(0xFF14, 0x80);
+    apu.write(0xFF14, 0x40);
+    assert!(!apu.debug_state().square1.enabled);
+
+    // triggering with an empty counter reloads it one short
+    apu.write(0xFF14, 0xC0);
+    assert!(apu.debug_state().square1.enabled);
+    assert_eq!(apu.debug_state().square1.length_counter, 63);
+  }
+
+  #[test]
+  fn sweep_negate_quirk() {
+    let mut apu = Apu::default();
+    apu.write(0xFF12, 0xF0);
+    apu.write(0xFF10, 0x19);
+    apu.write(0xFF13, 0x00);
+    apu.write(0xFF14, 0x84);
+    assert!(apu.debug_state().square1.enabled);
+
+    apu.write(0xFF10, 0x11);
+    assert!(!apu.debug_state().square1.enabled);
+  }
+
+  #[test]
+  fn zombie_mode() {
+    let mut apu = Apu::default();
+    apu.write(0xFF12, 0x50);
+    apu.write(0xFF14, 0x80);
+    assert_eq!(apu.debug_state().square1.volume, 5);
+
+    // period 0: volume goes up by one
+    apu.write(0xFF12, 0x50);
+    assert_eq!(apu.debug_state().square1.volume, 6);
+    // switching direction: volume becomes 16 - volume
+    apu.write(0xFF12, 0x58);
+    assert_eq!(apu.debug_state().square1.volume, 9);
+  }
+
+  #[test]
+  fn pcm_registers() {
+    let mut apu = Apu::default();
+    assert_eq!(apu.read_pcm(0xFF76), 0);
+
+    // square2 at full volume, the 75% duty goes high after the first step
+    apu.write(0xFF16, 0xC0);
+    apu.write(0xFF17, 0xF0);
+    apu.write(0xFF19, 0x80);
+    run(&mut apu, 8200);
+    assert_eq!(apu.read_pcm(0xFF76), 0xF0);
+    assert_eq!(apu.read_pcm(0xFF77), 0);
+  }
+}
@@ -0,0 +1,50 @@
+use serde::{Deserialize, Serialize};
+
+use super::CPU_FREQ;
+
+/// Capacitor charge factor per t-cycle of the output high-pass filter.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum HighPass {
+  /// Raw mix, with the DC offset of enabled DACs left in
+  Off,
+  #[default]
+  Dmg,
+  Cgb,
+}
+
+impl HighPass {
+  fn charge_factor(&self) -> Option<f64> {
+    match self {
+      HighPass::Off => None,
+      HighPass::Dmg => Some(0.999958),
+      HighPass::Cgb => Some(0.998943),
+    }
+  }
+}
+
+/// Models the capacitors on the console audio output, which remove the DC offset
+/// of the DACs, so that the output doesn't pop when channels are toggled.
+#[derive(Serialize, Deserialize)]
+pub struct Filter {
+  charge: Option<f32>,
+  capacitor_left: f32,
+  capacitor_right: f32,
+}
+
+impl Filter {
+  pub fn new(mode: HighPass, sample_rate: u32) -> Self {
+    let charge = mode.charge_factor()
+      .map(|f| f.powf(CPU_FREQ as f64 / sample_rate as f64) as f32);
+    Self { charge, capacitor_left: 0.0, capacitor_right: 0.0 }
+  }
+
+  pub fn apply(&mut self, left: f32, right: f32) -> (f32, f32) {
+    let Some(charge) = self.charge else { return (left, right) };
+
+    let out_left = left - self.capacitor_left;
+    let out_right = right - self.capacitor_right;
+    self.capacitor_left = left - out_left * charge;
+    self.capacitor_right = right - out_right * charge;
+    (out_left, out_right)
+  }
+}
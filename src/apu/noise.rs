@@ -0,0 +1,175 @@
+use serde::{Deserialize, Serialize};
+
+use super::{ChannelState, Envelope, Length};
+
+const DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+#[derive(Serialize, Deserialize)]
+pub struct Noise {
+  pub enabled: bool,
+  dac_enabled: bool,
+
+  nr43: u8,
+  timer: u32,
+  lfsr: u16,
+
+  length: Length,
+  envelope: Envelope,
+}
+
+impl Default for Noise {
+  fn default() -> Self {
+    Self {
+      enabled: false,
+      dac_enabled: false,
+      nr43: 0,
+      timer: 0,
+      lfsr: 0x7FFF,
+      length: Length::new(64),
+      envelope: Envelope::default(),
+    }
+  }
+}
+
+impl Noise {
+  fn clock_shift(&self) -> u8 {
+    self.nr43 >> 4
+  }
+
+  fn short_mode(&self) -> bool {
+    self.nr43 & 0b1000 != 0
+  }
+
+  fn period(&self) -> u32 {
+    DIVISORS[self.nr43 as usize & 0b111] << self.clock_shift()
+  }
+
+  pub fn tick(&mut self) {
+    if self.timer > 1 {
+      self.timer -= 1;
+      return;
+    }
+
+    self.timer = self.period();
+    // clock shifts 14 and 15 leave the LFSR without clocks
+    if self.clock_shift() >= 14 { return; }
+
+    let xor = (self.lfsr & 1) ^ ((self.lfsr >> 1) & 1);
+    self.lfsr = (self.lfsr >> 1) | (xor << 14);
+    if self.short_mode() {
+      self.lfsr = (self.lfsr & !(1 << 6)) | (xor << 6);
+    }
+  }
+
+  pub fn output(&self) -> u8 {
+    if !self.enabled { return 0; }
+    (!self.lfsr & 1) as u8 * self.envelope.volume
+  }
+
+  pub fn dac_enabled(&self) -> bool {
+    self.dac_enabled
+  }
+
+  pub fn debug_state(&self) -> ChannelState {
+    let mut state = ChannelState {
+      enabled: self.enabled,
+      dac_enabled: self.dac_enabled,
+      period: self.period(),
+      timer: self.timer,
+      ..Default::default()
+    };
+    self.length.fill_state(&mut state);
+    self.envelope.fill_state(&mut state);
+    state
+  }
+
+  pub fn read(&self, reg: u16) -> u8 {
+    match reg {
+      2 => self.envelope.read(),
+      3 => self.nr43,
+      4 => (self.length.enabled as u8) << 6,
+      _ => 0,
+    }
+  }
+
+  pub fn write(&mut self, reg: u16, val: u8) {
+    match reg {
+      1 => self.length.load(val),
+      2 => {
+        self.envelope.write(val, self.enabled);
+        self.dac_enabled = val & 0xF8 != 0;
+        if !self.dac_enabled { self.enabled = false; }
+      }
+      3 => self.nr43 = val,
+      _ => {}
+    }
+  }
+
+  pub fn write_control(&mut self, val: u8, extra_length_clock: bool) {
+    if self.length.write_control(val, extra_length_clock) {
+      self.enabled = false;
+    }
+    if val & 0x80 != 0 { self.trigger(); }
+  }
+
+  /// Clears every register but the length counter, which is kept on DMG.
+  pub fn power_off(&mut self) {
+    let mut length = std::mem::take(&mut self.length);
+    length.enabled = false;
+    *self = Self { length, ..Self::default() };
+  }
+
+  fn trigger(&mut self) {
+    self.enabled = self.dac_enabled;
+    self.timer = self.period();
+    self.lfsr = 0x7FFF;
+    self.envelope.trigger();
+  }
+
+  pub fn clock_length(&mut self) {
+    if self.length.clock() {
+      self.enabled = false;
+    }
+  }
+
+  pub fn clock_envelope(&mut self) {
+    self.envelope.clock();
+  }
+}
+
+#[cfg(test)]
+mod noise_tests {
+  use super::*;
+
+  fn lfsr_period(nr43: u8) -> usize {
+    let mut noise = Noise::default();
+    noise.write(3, nr43);
+    // the bits above bit 6 need to shift out before the short mode loops
+    for _ in 0..16 * noise.period() { noise.tick(); }
+    let start = noise.lfsr;
+    for steps in 1..=0x8000 {
+      for _ in 0..noise.period() { noise.tick(); }
+      if noise.lfsr == start { return steps; }
+    }
+    0
+  }
+
+  #[test]
+  fn lfsr_width() {
+    assert_eq!(lfsr_period(0x00), 32767);
+    assert_eq!(lfsr_period(0x08), 127);
+  }
+
+  #[test]
+  fn nr43_read_back() {
+    let mut noise = Noise::default();
+    noise.write(3, 0xE9);
+    assert_eq!(noise.read(3), 0xE9);
+    assert_eq!(noise.period(), 16 << 14);
+
+    // shift 14 and 15 freeze the LFSR
+    noise.timer = 1;
+    noise.tick();
+    assert_eq!(noise.lfsr, 0x7FFF);
+  }
+}
@@ -0,0 +1,51 @@
+use serde::{Deserialize, Serialize};
+
+use super::CPU_FREQ;
+
+/// Downsamples the per t-cycle APU output to the output sample rate.
+/// Every output sample is the average of all the t-cycles it spans (a box filter),
+/// which removes most of the aliasing a nearest-sample decimation produces.
+/// The sample period is kept fractional, so the output rate doesn't drift.
+#[derive(Serialize, Deserialize)]
+pub struct Resampler {
+  cycles_per_sample: f64,
+  cycles: f64,
+  acc_left: f32,
+  acc_right: f32,
+  count: usize,
+}
+
+impl Resampler {
+  pub fn new(sample_rate: u32) -> Self {
+    Self {
+      cycles_per_sample: CPU_FREQ as f64 / sample_rate as f64,
+      cycles: 0.0,
+      acc_left: 0.0,
+      acc_right: 0.0,
+      count: 0,
+    }
+  }
+
+  /// Feeds one t-cycle of output, and returns a sample once one is complete.
+  pub fn push(&mut self, left: f32, right: f32) -> Option<(f32, f32)> {
+    self.acc_left += left;
+    self.acc_right += right;
+    self.count += 1;
+    self.cycles += 1.0;
+
+    if self.cycles < self.cycles_per_sample { return None; }
+
+    self.cycles -= self.cycles_per_sample;
+    let count = self.count as f32;
+    let res = (self.acc_left / count, self.acc_right / count);
+    self.acc_left = 0.0;
+    self.acc_right = 0.0;
+    self.count = 0;
+    Some(res)
+  }
+
+  /// T-cycles fed since the last sample was completed.
+  pub fn phase(&self) -> usize {
+    self.count
+  }
+}
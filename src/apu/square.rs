@@ -0,0 +1,208 @@
+use serde::{Deserialize, Serialize};
+
+use super::{ChannelState, Envelope, Length};
+
+const DUTY_TABLE: [[u8; 8]; 4] = [
+  [0, 0, 0, 0, 0, 0, 0, 1], // 12.5%
+  [1, 0, 0, 0, 0, 0, 0, 1], // 25%
+  [1, 0, 0, 0, 0, 1, 1, 1], // 50%
+  [0, 1, 1, 1, 1, 1, 1, 0], // 75%
+];
+
+#[derive(Default, Serialize, Deserialize)]
+struct Sweep {
+  enabled: bool,
+  period: u8,
+  negate: bool,
+  shift: u8,
+  timer: u8,
+  shadow: u16,
+  /// A period was calculated in negate mode since the last trigger
+  negated: bool,
+}
+
+impl Sweep {
+  fn read(&self) -> u8 {
+    (self.period << 4) | ((self.negate as u8) << 3) | self.shift
+  }
+
+  fn write(&mut self, val: u8) {
+    self.period = (val >> 4) & 0b111;
+    self.negate = val & 0b1000 != 0;
+    self.shift = val & 0b111;
+  }
+
+  fn reload_timer(&mut self) {
+    self.timer = if self.period == 0 { 8 } else { self.period };
+  }
+
+  fn next_period(&mut self) -> u16 {
+    let delta = self.shadow >> self.shift;
+    if self.negate {
+      self.negated = true;
+      self.shadow.wrapping_sub(delta)
+    } else {
+      self.shadow + delta
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Square {
+  pub enabled: bool,
+  dac_enabled: bool,
+  has_sweep: bool,
+
+  duty: u8,
+  duty_step: u8,
+  period: u16,
+  timer: u16,
+
+  length: Length,
+  envelope: Envelope,
+  sweep: Sweep,
+}
+
+impl Square {
+  pub fn new(has_sweep: bool) -> Self {
+    Self {
+      enabled: false,
+      dac_enabled: false,
+      has_sweep,
+      duty: 0,
+      duty_step: 0,
+      period: 0,
+      timer: 0,
+      length: Length::new(64),
+      envelope: Envelope::default(),
+      sweep: Sweep::default(),
+    }
+  }
+
+  pub fn tick(&mut self) {
+    if self.timer > 0 {
+      self.timer -= 1;
+    } else {
+      self.timer = (2048 - self.period) * 4;
+      self.duty_step = (self.duty_step + 1) % 8;
+    }
+  }
+
+  pub fn output(&self) -> u8 {
+    if !self.enabled { return 0; }
+    DUTY_TABLE[self.duty as usize][self.duty_step as usize] * self.envelope.volume
+  }
+
+  pub fn dac_enabled(&self) -> bool {
+    self.dac_enabled
+  }
+
+  pub fn debug_state(&self) -> ChannelState {
+    let mut state = ChannelState {
+      enabled: self.enabled,
+      dac_enabled: self.dac_enabled,
+      period: self.period.into(),
+      timer: self.timer.into(),
+      sweep_timer: self.sweep.timer,
+      ..Default::default()
+    };
+    self.length.fill_state(&mut state);
+    self.envelope.fill_state(&mut state);
+    state
+  }
+
+  pub fn read(&self, reg: u16) -> u8 {
+    match reg {
+      0 if self.has_sweep => self.sweep.read(),
+      1 => self.duty << 6,
+      2 => self.envelope.read(),
+      4 => (self.length.enabled as u8) << 6,
+      _ => 0,
+    }
+  }
+
+  pub fn write(&mut self, reg: u16, val: u8) {
+    match reg {
+      0 if self.has_sweep => {
+        self.sweep.write(val);
+        // leaving negate mode after it was used disables the channel
+        if self.sweep.negated && !self.sweep.negate {
+          self.enabled = false;
+        }
+      }
+      1 => {
+        self.duty = val >> 6;
+        self.length.load(val);
+      }
+      2 => {
+        self.envelope.write(val, self.enabled);
+        self.dac_enabled = val & 0xF8 != 0;
+        if !self.dac_enabled { self.enabled = false; }
+      }
+      3 => self.period = (self.period & 0x700) | val as u16,
+      _ => {}
+    }
+  }
+
+  pub fn write_control(&mut self, val: u8, extra_length_clock: bool) {
+    self.period = (self.period & 0xFF) | ((val as u16 & 0b111) << 8);
+    if self.length.write_control(val, extra_length_clock) {
+      self.enabled = false;
+    }
+    if val & 0x80 != 0 { self.trigger(); }
+  }
+
+  /// Clears every register but the length counter, which is kept on DMG.
+  pub fn power_off(&mut self) {
+    let mut length = std::mem::take(&mut self.length);
+    length.enabled = false;
+    *self = Self { length, ..Self::new(self.has_sweep) };
+  }
+
+  fn trigger(&mut self) {
+    self.enabled = self.dac_enabled;
+    self.timer = (2048 - self.period) * 4;
+    self.envelope.trigger();
+
+    if self.has_sweep {
+      self.sweep.shadow = self.period;
+      self.sweep.negated = false;
+      self.sweep.reload_timer();
+      self.sweep.enabled = self.sweep.period != 0 || self.sweep.shift != 0;
+      if self.sweep.shift != 0 && self.sweep.next_period() > 2047 {
+        self.enabled = false;
+      }
+    }
+  }
+
+  pub fn clock_length(&mut self) {
+    if self.length.clock() {
+      self.enabled = false;
+    }
+  }
+
+  pub fn clock_envelope(&mut self) {
+    self.envelope.clock();
+  }
+
+  pub fn clock_sweep(&mut self) {
+    self.sweep.timer = self.sweep.timer.saturating_sub(1);
+    if self.sweep.timer > 0 { return; }
+
+    self.sweep.reload_timer();
+    if !self.sweep.enabled || self.sweep.period == 0 { return; }
+
+    let period = self.sweep.next_period();
+    if period > 2047 {
+      self.enabled = false;
+    } else if self.sweep.shift != 0 {
+      self.sweep.shadow = period;
+      self.period = period;
+
+      // overflow check is done again with the new period
+      if self.sweep.next_period() > 2047 {
+        self.enabled = false;
+      }
+    }
+  }
+}
@@ -0,0 +1,74 @@
+use std::io::{self, Seek, SeekFrom, Write};
+
+/// Streams stereo samples to a 16 bit PCM WAV.
+/// The header sizes are only known at the end, and are fixed up in [`WavWriter::finish`].
+pub struct WavWriter<W: Write + Seek> {
+  out: W,
+  data_len: u32,
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+  pub fn new(mut out: W, sample_rate: u32) -> io::Result<Self> {
+    const CHANNELS: u16 = 2;
+    const BITS: u16 = 16;
+    let block_align = CHANNELS * BITS / 8;
+
+    out.write_all(b"RIFF")?;
+    out.write_all(&0u32.to_le_bytes())?;
+    out.write_all(b"WAVE")?;
+
+    out.write_all(b"fmt ")?;
+    out.write_all(&16u32.to_le_bytes())?;
+    out.write_all(&1u16.to_le_bytes())?; // PCM
+    out.write_all(&CHANNELS.to_le_bytes())?;
+    out.write_all(&sample_rate.to_le_bytes())?;
+    out.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+    out.write_all(&block_align.to_le_bytes())?;
+    out.write_all(&BITS.to_le_bytes())?;
+
+    out.write_all(b"data")?;
+    out.write_all(&0u32.to_le_bytes())?;
+
+    Ok(Self { out, data_len: 0 })
+  }
+
+  pub fn write(&mut self, left: f32, right: f32) -> io::Result<()> {
+    for sample in [left, right] {
+      let sample = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+      self.out.write_all(&sample.to_le_bytes())?;
+    }
+
+    self.data_len += 4;
+    Ok(())
+  }
+
+  pub fn finish(mut self) -> io::Result<W> {
+    self.out.seek(SeekFrom::Start(4))?;
+    self.out.write_all(&(36 + self.data_len).to_le_bytes())?;
+    self.out.seek(SeekFrom::Start(40))?;
+    self.out.write_all(&self.data_len.to_le_bytes())?;
+    self.out.flush()?;
+    Ok(self.out)
+  }
+}
+
+#[cfg(test)]
+mod wav_tests {
+  use std::io::Cursor;
+  use super::WavWriter;
+
+  #[test]
+  fn header_fixup() {
+    let mut wav = WavWriter::new(Cursor::new(Vec::new()), 48000).unwrap();
+    wav.write(1.0, -1.0).unwrap();
+    wav.write(0.0, 2.0).unwrap();
+    let bytes = wav.finish().unwrap().into_inner();
+
+    assert_eq!(bytes.len(), 44 + 8);
+    assert_eq!(&bytes[0..4], b"RIFF");
+    assert_eq!(u32::from_le_bytes(bytes[4..8].try_into().unwrap()), 36 + 8);
+    assert_eq!(u32::from_le_bytes(bytes[24..28].try_into().unwrap()), 48000);
+    assert_eq!(u32::from_le_bytes(bytes[40..44].try_into().unwrap()), 8);
+    assert_eq!(&bytes[44..], [0xFF, 0x7F, 0x01, 0x80, 0x00, 0x00, 0xFF, 0x7F]);
+  }
+}
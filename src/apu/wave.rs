@@ -0,0 +1,132 @@
+use serde::{Deserialize, Serialize};
+
+use super::{ChannelState, Length};
+
+#[derive(Serialize, Deserialize)]
+pub struct Wave {
+  pub enabled: bool,
+  dac_enabled: bool,
+
+  volume_code: u8,
+  period: u16,
+  timer: u16,
+  position: u8,
+  sample: u8,
+
+  length: Length,
+  wave_ram: [u8; 16],
+}
+
+impl Default for Wave {
+  fn default() -> Self {
+    Self {
+      enabled: false,
+      dac_enabled: false,
+      volume_code: 0,
+      period: 0,
+      timer: 0,
+      position: 0,
+      sample: 0,
+      length: Length::new(256),
+      wave_ram: [0; 16],
+    }
+  }
+}
+
+impl Wave {
+  pub fn tick(&mut self) {
+    if self.timer > 0 {
+      self.timer -= 1;
+    } else {
+      self.timer = (2048 - self.period) * 2;
+      self.position = (self.position + 1) % 32;
+
+      let byte = self.wave_ram[self.position as usize / 2];
+      self.sample = if self.position.is_multiple_of(2) { byte >> 4 } else { byte & 0xF };
+    }
+  }
+
+  pub fn output(&self) -> u8 {
+    if !self.enabled { return 0; }
+
+    match self.volume_code {
+      0 => 0,
+      1 => self.sample,
+      2 => self.sample >> 1,
+      _ => self.sample >> 2,
+    }
+  }
+
+  pub fn dac_enabled(&self) -> bool {
+    self.dac_enabled
+  }
+
+  pub fn debug_state(&self) -> ChannelState {
+    let mut state = ChannelState {
+      enabled: self.enabled,
+      dac_enabled: self.dac_enabled,
+      period: self.period.into(),
+      timer: self.timer.into(),
+      ..Default::default()
+    };
+    self.length.fill_state(&mut state);
+    state
+  }
+
+  pub fn read(&self, reg: u16) -> u8 {
+    match reg {
+      0 => (self.dac_enabled as u8) << 7,
+      2 => self.volume_code << 5,
+      4 => (self.length.enabled as u8) << 6,
+      _ => 0,
+    }
+  }
+
+  pub fn write(&mut self, reg: u16, val: u8) {
+    match reg {
+      0 => {
+        self.dac_enabled = val & 0x80 != 0;
+        if !self.dac_enabled { self.enabled = false; }
+      }
+      1 => self.length.load(val),
+      2 => self.volume_code = (val >> 5) & 0b11,
+      3 => self.period = (self.period & 0x700) | val as u16,
+      _ => {}
+    }
+  }
+
+  pub fn wave_read(&self, addr: u16) -> u8 {
+    self.wave_ram[addr as usize]
+  }
+
+  pub fn wave_write(&mut self, addr: u16, val: u8) {
+    self.wave_ram[addr as usize] = val;
+  }
+
+  pub fn write_control(&mut self, val: u8, extra_length_clock: bool) {
+    self.period = (self.period & 0xFF) | ((val as u16 & 0b111) << 8);
+    if self.length.write_control(val, extra_length_clock) {
+      self.enabled = false;
+    }
+    if val & 0x80 != 0 { self.trigger(); }
+  }
+
+  /// Clears every register but the length counter and wave RAM.
+  pub fn power_off(&mut self) {
+    let mut length = std::mem::take(&mut self.length);
+    length.enabled = false;
+    *self = Self { length, wave_ram: self.wave_ram, ..Self::default() };
+  }
+
+  fn trigger(&mut self) {
+    self.enabled = self.dac_enabled;
+    self.timer = (2048 - self.period) * 2;
+    self.position = 0;
+  }
+
+  pub fn clock_length(&mut self) {
+    if self.length.clock() {
+      self.enabled = false;
+    }
+  }
+}
@@ -1,10 +1,11 @@
-use std::{cell::Cell, rc::Rc};
+use std::ops::RangeInclusive;
 
-use crate::{apu::Apu, joypad::Joypad, mbc::Cart, mem::Memory, ppu::Ppu, serial::Serial, timer::Timer};
+use crate::{state, apu::Apu, cart::CgbMode, debugger::{Access, Debugger, Violation}, infrared::Transceiver, joypad::Joypad, mbc::Cart, mem::{Memory, PowerOnState}, ppu::Ppu, serial::Serial, sgb::Sgb, timer::Timer};
 use bitflags::bitflags;
+use serde::{Deserialize, Serialize};
 
 bitflags! {
-  #[derive(PartialEq, Clone, Copy, Debug)]
+  #[derive(PartialEq, Clone, Copy, Debug, Default, Serialize, Deserialize)]
   pub struct IFlags: u8 {
     const unused = 0b1110_0000;
     const joypad = 0b0001_0000;
@@ -15,7 +16,7 @@ bitflags! {
   }
 }
 
-#[derive(Default)]
+#[derive(Default, Serialize, Deserialize)]
 struct Dma {
 	start: u16,
 	offset: u16,
@@ -45,28 +46,62 @@ impl Dma {
   }
 }
 
-pub type InterruptFlags = Rc<Cell<IFlags>>;
+/// Audio/video alignment data, latched at every vblank.
+#[derive(Default, Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct FrameTiming {
+  /// Frames completed since boot
+  pub frame: usize,
+  /// Audio samples generated between the previous vblank and this one
+  pub samples: usize,
+  /// Total audio samples generated when vblank fired
+  pub vblank_sample: usize,
+  /// T-cycles elapsed since the last generated sample when vblank fired
+  pub vblank_sample_offset: usize,
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct Bus {
+  #[serde(with = "state::array")]
   ram: [u8; 8*1024],
+  #[serde(with = "state::array")]
   hram: [u8; 0x7F],
   dma: Dma,
 
-  bootrom: Option<Vec<u8>>,
+  /// Mapped over the cartridge ROM until 0xFF50 is written, see [`Gameboy::set_boot_rom`](crate::gb::Gameboy::set_boot_rom)
+  #[serde(default)]
+  pub(crate) bootrom: Option<Vec<u8>>,
   pub cart: Cart,
   pub ppu: Ppu,
   pub timer: Timer,
   pub serial: Serial,
   pub joypad: Joypad,
   pub apu: Apu,
+  /// Set when running as on a Super Game Boy, see [`Gameboy::set_sgb`](crate::gb::Gameboy::set_sgb)
+  pub sgb: Option<Sgb>,
+
+  /// RP, the CGB infrared port: its LED and read enable bits
+  #[serde(default)]
+  infrared: u8,
+  /// What faces the infrared port, see [`Gameboy::set_infrared`](crate::gb::Gameboy::set_infrared)
+  #[serde(skip)]
+  pub(crate) transceiver: Option<Box<dyn Transceiver>>,
+  /// The undocumented CGB registers 0xFF72 to 0xFF75, which only store what is written.
+  #[serde(default)]
+  undocumented: [u8; 4],
 
   pub inte: IFlags,
-  pub intf: InterruptFlags,
-  tcycles: usize,
+  /// IF as last written, the components keep the interrupts they raise since then
+  #[serde(default)]
+  intf: IFlags,
+  pub frame_timing: FrameTiming,
+  #[serde(skip)]
+  pub debugger: Debugger,
+  mcycles: usize,
 }
 
 enum BusTarget {
   Rom, VRam, OamDma, ExRam, WRam, Oam, Unusable, Boot,
-  Joypad, Serial, Ppu, Apu, Timer, NoImpl, HRam, IF, IE,
+  Joypad, Serial, Ppu, Opri, Infrared, Undocumented, Apu, Pcm, Timer, NoImpl, HRam, IF, IE,
 }
 
 #[allow(unused)]
@@ -88,41 +123,179 @@ fn map_addr(addr: u16) -> (BusTarget, u16) {
     0xFF46 => (OamDma, addr),
     0xFF40..=0xFF4B | 0xFF4F => (Ppu, addr),
     0xFF50 => (Boot, addr),
+    0xFF56 => (Infrared, addr),
+    0xFF6C => (Opri, addr),
+    0xFF72..=0xFF75 => (Undocumented, addr - 0xFF72),
+    0xFF76..=0xFF77 => (Pcm, addr),
     0xFF80..=0xFFFE => (HRam, addr - 0xFF80),
     0xFFFF => (IE, addr),
+    // unused, like 0xFF03 and 0xFF08-0xFF0E, or locked after boot, like KEY0 (0xFF4C)
     _ => (NoImpl, addr),
   }
 }
 
-pub fn send_interrupt(intf: &Cell<IFlags>, int: IFlags) {
-  let mut flags = intf.get();
-  flags.insert(int);
-  intf.set(flags);
-}
-
 impl Memory for Bus {
   fn read(&mut self, addr: u16) -> u8 {
+    let val = if self.is_oam_blocked(addr) { 0xFF } else { self.peek(addr) };
+    self.debugger.on_access(addr, Access::Read, val);
+    self.check_strict(addr, Access::Read);
+    val
+  }
+
+  /// Never ticks, mutates, or triggers watchpoints.
+  fn peek(&self, addr: u16) -> u8 {
     let (target, addr) = map_addr(addr);
     use BusTarget::*;
     match &target {
-      Rom => self.cart.rom_read(addr),
+      Rom => match &self.bootrom {
+        // the CGB boot ROM skips the cartridge header at 0x100-0x1FF
+        Some(boot) if !(0x100..0x200).contains(&addr) && (addr as usize) < boot.len() => boot[addr as usize],
+        _ => self.cart.rom_read(addr),
+      }
       VRam => self.ppu.vram[addr as usize],
       ExRam => self.cart.ram_read(addr),
       WRam => self.ram[addr as usize],
       Oam => self.ppu.oam[addr as usize],
-      Joypad => self.joypad.read(),
+      Joypad => match &self.sgb {
+        Some(sgb) => sgb.read_joypad(&self.joypad),
+        None => self.joypad.read(),
+      },
       Serial => self.serial.read(addr),
-      // Apu => self.apu.read(addr),
+      Apu => self.apu.read(addr),
+      Pcm if self.is_cgb() => self.apu.read_pcm(addr),
+      Pcm => 0xFF,
       Ppu => self.ppu.read(addr),
+      Opri if self.is_cgb() => self.ppu.read(addr),
+      Opri => 0xFF,
+      // bit 1 is 0 while receiving a signal, with reading enabled. Bits 2-5 are unused
+      Infrared if self.is_cgb() => {
+        let receiving = self.infrared & 0b1100_0000 == 0b1100_0000
+          && self.transceiver.as_ref().is_some_and(|ir| ir.receiving());
+        self.infrared | 0b0011_1100 | if receiving { 0 } else { 0b10 }
+      }
+      // 0xFF75 only has bits 4-6
+      Undocumented if self.is_cgb() && addr == 3 => self.undocumented[3] | 0b1000_1111,
+      Undocumented if self.is_cgb() => self.undocumented[addr as usize],
+      Infrared | Undocumented => 0xFF,
       Timer => self.timer.read(addr),
-      IF => (self.intf.get() | IFlags::unused).bits(),
+      IF => (self.intf() | IFlags::unused).bits(),
       HRam => self.hram[addr as usize],
       IE => self.inte.bits(),
-      _ => 0,
+      OamDma => (self.dma.start >> 8) as u8,
+      // the CGB (revision E) repeats the high nibble of the address, the DMG reads 0
+      Unusable if self.is_cgb() => (addr as u8 & 0xF0) | (addr as u8 >> 4),
+      Unusable => 0,
+      // open bus, unused registers and bits read as 1
+      Boot | NoImpl => 0xFF,
     }
   }
 
   fn write(&mut self, addr: u16, val: u8) {
+    self.debugger.on_access(addr, Access::Write, val);
+    self.check_strict(addr, Access::Write);
+    if self.is_oam_blocked(addr) { return; }
+    self.write_mapped(addr, val);
+  }
+
+  fn tick(&mut self) {
+    self.mcycles += 1;
+    for _ in 0..4 {
+      let frames = self.ppu.frame_count();
+      self.ppu.tick();
+      self.apu.tick();
+      if self.ppu.frame_count() != frames {
+        self.latch_frame_timing();
+        if let Some(sgb) = &mut self.sgb {
+          sgb.on_frame(&self.ppu.lcd, !self.ppu.frame_skip);
+          self.ppu.force_output = sgb.is_transferring();
+        }
+      }
+    }
+    for _ in 0..4 { self.timer.tick(); }
+    self.clock_div_apu();
+    self.serial.tick();
+    self.cart.mbc.tick();
+    self.handle_dma();
+  }
+
+  fn pending_interrupt(&self) -> Option<IFlags> {
+    // the lower bits have priority, from vblank to joypad
+    let pending = (self.inte & self.intf()).difference(IFlags::unused).bits();
+    (pending != 0).then(|| IFlags::from_bits_truncate(1 << pending.trailing_zeros()))
+  }
+
+  fn ack_interrupt(&mut self, int: IFlags) {
+    let mut intf = self.intf();
+    intf.remove(int);
+    self.set_intf(intf);
+  }
+}
+
+impl Bus {
+  pub fn new(cart: Cart) -> Bus {
+    let mut bus = Self {
+      ram: [0; 8*1024],
+      hram: [0; 0x7F],
+      dma: Dma::default(),
+
+      bootrom: None,
+      cart,
+      ppu: Ppu::new(),
+      apu: Apu::default(),
+      sgb: None,
+      timer: Timer::new(),
+      serial: Serial::new(),
+      joypad: Joypad::new(),
+      infrared: 0,
+      transceiver: None,
+      undocumented: [0; 4],
+      inte: IFlags::empty(), 
+      intf: IFlags::empty(),
+      frame_timing: FrameTiming::default(),
+      debugger: Debugger::default(),
+      mcycles: 0,
+    };
+
+    // the CGB boot ROM leaves OPRI in OAM index mode for CGB games
+    if bus.is_cgb() { bus.ppu.write(0xFF6C, 0); }
+    bus
+  }
+
+  /// M-cycles elapsed since power on.
+  pub fn mcycles(&self) -> usize {
+    self.mcycles
+  }
+
+  /// The work RAM, mapped at 0xC000-0xDFFF, and mirrored by echo RAM.
+  pub fn wram(&self) -> &[u8] {
+    &self.ram
+  }
+
+  /// The high RAM, mapped at 0xFF80-0xFFFE.
+  pub fn hram(&self) -> &[u8] {
+    &self.hram
+  }
+
+  /// Plugs `transceiver` in the infrared port, telling it whether the LED is on.
+  pub(crate) fn set_transceiver(&mut self, mut transceiver: Option<Box<dyn Transceiver>>) {
+    if let Some(ir) = &mut transceiver { ir.set_led(self.infrared & 1 != 0); }
+    self.transceiver = transceiver;
+  }
+
+  /// Fills the work RAM, high RAM, VRAM and OAM as they are at power on.
+  pub(crate) fn fill_ram(&mut self, state: PowerOnState) {
+    state.fill(&mut self.ram, 0xC000);
+    state.fill(&mut self.hram, 0xFF80);
+    state.fill(&mut self.ppu.vram, 0x8000);
+    state.fill(&mut self.ppu.oam, 0xFE00);
+  }
+
+  /// Side-effect free read of a memory range, see [`Memory::peek`].
+  pub fn peek_range(&self, range: RangeInclusive<u16>) -> Vec<u8> {
+    range.map(|addr| self.peek(addr)).collect()
+  }
+
+  pub(crate) fn write_mapped(&mut self, addr: u16, val: u8) {
     let (target, addr) = map_addr(addr);
     use BusTarget::*;
     match &target {
@@ -131,10 +304,22 @@ impl Memory for Bus {
       ExRam => self.cart.ram_write(addr, val),
       WRam => self.ram[addr as usize] = val,
       Oam => self.ppu.oam[addr as usize] = val,
-      Unusable => {}
-      Joypad => self.joypad.write(val),
+      Opri if self.is_cgb() => self.ppu.write(addr, val),
+      Infrared if self.is_cgb() => {
+        self.infrared = val & 0b1100_0001;
+        if let Some(ir) = &mut self.transceiver { ir.set_led(val & 1 != 0); }
+      }
+      Undocumented if self.is_cgb() => self.undocumented[addr as usize] = val,
+      Unusable | Pcm | Opri | Infrared | Undocumented => {}
+      Joypad => {
+        self.joypad.write(val);
+        if let Some(sgb) = &mut self.sgb {
+          sgb.write_joypad(val);
+          self.ppu.force_output = sgb.is_transferring();
+        }
+      }
       Serial => self.serial.write(addr, val),
-      // Apu =>  self.apu.write(addr, val),
+      Apu => self.apu.write(addr, val),
       Ppu => self.ppu.write(addr, val),
       OamDma => {
         self.dma.init(val);
@@ -142,64 +327,53 @@ impl Memory for Bus {
       }
       Timer => {
         self.timer.write(addr, val);
-        if self.timer.div == 0 {
-          // self.apu.tcycles = 0;
-        }
+        self.clock_div_apu();
       }
-      Boot => {
-        if let Some(data) = self.bootrom.take() {
-          self.cart.rom[..256].copy_from_slice(&data);
-        }
-      }
-      IF => self.intf.set(IFlags::from_bits_truncate(val)),
+      Boot => self.bootrom = None,
+      IF => self.set_intf(IFlags::from_bits_truncate(val)),
       HRam => self.hram[addr as usize] = val,
       IE => self.inte = IFlags::from_bits_truncate(val),
-      Apu | NoImpl => {},
+      NoImpl => {},
     }
   }
 
-  fn tick(&mut self) {
-    self.tcycles += 1;
-    for _ in 0..4 { self.ppu.tick(); }
-    for _ in 0..4 { self.timer.tick(); }
-    for _ in 0..4 { self.apu.tick(); }
+  // The CPU can't access OAM, and the unusable area after it, during mode 2 and 3.
+  // Debuggers and cheats still can, through peek and write_mapped.
+  fn is_oam_blocked(&self, addr: u16) -> bool {
+    (0xFE00..=0xFEFF).contains(&addr) && !self.ppu.is_oam_accessible()
   }
 
-  fn halt_tick(&mut self) {
-    self.tick();
-    self.handle_dma();
+  fn clock_div_apu(&mut self) {
+    for _ in 0..std::mem::take(&mut self.timer.div_apu_edges) {
+      self.apu.div_apu_tick();
+    }
   }
 
-  fn has_pending_interrupts(&self) -> bool {
-    !(self.inte & self.intf()).is_empty()
+  fn check_strict(&mut self, addr: u16, access: Access) {
+    if self.debugger.strict().is_none() { return; }
+
+    let violation = match addr {
+      0x8000..=0x9FFF if !self.ppu.is_vram_accessible() => Violation::LockedVram,
+      0xE000..=0xFDFF => Violation::EchoRam,
+      0xFE00..=0xFE9F if !self.ppu.is_oam_accessible() => Violation::LockedOam,
+      0xFEA0..=0xFEFF => Violation::Unusable,
+      _ => return,
+    };
+    self.debugger.on_violation(addr, access, violation);
   }
-}
 
-impl Bus {
-  pub fn new(mut cart: Cart) -> Bus {
-    let intf = Rc::new(Cell::new(IFlags::empty()));
-    let bootrom = Some(cart.rom[..256].to_vec());
-    
-    // TODO: remove this hardcoding
-    // cart.rom[..256]
-    //   .copy_from_slice(include_bytes!("../bootroms/dmg_boot.bin"));
-
-    Self {
-      ram: [0; 8*1024],
-      hram: [0; 0x7F],
-      dma: Dma::default(),
+  fn is_cgb(&self) -> bool {
+    !matches!(self.cart.header.cgb_mode, CgbMode::Monochrome)
+  }
 
-      bootrom,
-      cart,
-      ppu: Ppu::new(intf.clone()),
-      apu: Apu::default(),
-      timer: Timer::new(intf.clone()),
-      serial: Serial::new(intf.clone()),
-      joypad: Joypad::new(intf.clone()),
-      inte: IFlags::empty(), 
-      intf,
-      tcycles: 0,
-    }
+  fn latch_frame_timing(&mut self) {
+    let generated = self.apu.samples_generated();
+    self.frame_timing = FrameTiming {
+      frame: self.ppu.frame_count(),
+      samples: generated - self.frame_timing.vblank_sample,
+      vblank_sample: generated,
+      vblank_sample_offset: self.apu.sample_phase(),
+    };
   }
 
   pub fn handle_dma(&mut self) {
@@ -207,7 +381,8 @@ impl Bus {
       self.dma.delay = false;
     } else if self.dma.is_transferring() {
       let addr = self.dma.current();
-      let val = self.read(addr);
+      // not a CPU access, it doesn't trigger the debugger
+      let val = self.peek(addr);
       // self.write(0xFE00 + self.dma.offset(), val);
       self.ppu.oam[self.dma.offset() as usize] = val;
 
@@ -215,11 +390,48 @@ impl Bus {
     }
   }
 
+  /// Takes what isn't part of a save state from `old`, after `self` was loaded from one:
+  /// the ROM, the cheats, the debugger, the infrared transceiver, the screen, and the output settings.
+  pub(crate) fn keep_from(&mut self, old: Bus) {
+    let Bus { cart, ppu, mut apu, joypad, debugger, sgb, transceiver, .. } = old;
+    self.cart.header = cart.header;
+    self.cart.rom = cart.rom;
+    self.cart.cheats = cart.cheats;
+    self.cart.keep_mapper(cart.mbc);
+
+    // the screen isn't saved, it shows the old frame until the next one is drawn
+    if let Some(new) = &mut self.sgb {
+      match sgb {
+        Some(old) => {
+          new.screen = old.screen;
+          for (joypad, old) in new.joypads.iter_mut().zip(old.joypads) { joypad.turbo = old.turbo; }
+        }
+        None => new.screen = Sgb::screen_with(ppu.lcd.format()),
+      }
+    }
+    self.ppu.lcd = ppu.lcd;
+    self.ppu.frame_skip = ppu.frame_skip;
+    self.ppu.dmg_quirks = ppu.dmg_quirks;
+    self.ppu.hidden_layers = ppu.hidden_layers;
+    self.apu.keep_from(&mut apu);
+    self.joypad.turbo = joypad.turbo;
+    self.debugger = debugger;
+    // the peer sees the LED of the loaded state
+    self.set_transceiver(transceiver);
+  }
+
+  /// IF: the interrupts requested, as last written, with the ones the components raised since.
   pub fn intf(&self) -> IFlags {
-    self.intf.get()
+    let joypads = std::iter::once(&self.joypad).chain(self.sgb.iter().flat_map(|sgb| &sgb.joypads));
+    joypads.fold(self.intf | self.ppu.intf | self.timer.intf | self.serial.intf, |flags, joypad| flags | joypad.intf)
   }
 
-  pub fn set_intf(&self, val: IFlags) {
-    self.intf.set(val);
+  pub fn set_intf(&mut self, val: IFlags) {
+    self.intf = val;
+    self.ppu.intf = IFlags::empty();
+    self.timer.intf = IFlags::empty();
+    self.serial.intf = IFlags::empty();
+    let joypads = std::iter::once(&mut self.joypad).chain(self.sgb.iter_mut().flat_map(|sgb| &mut sgb.joypads));
+    joypads.for_each(|joypad| joypad.intf = IFlags::empty());
   }
 }
\ No newline at end of file
@@ -1,6 +1,11 @@
-use core::{cmp, hash, str};
+use core::{cmp, fmt, hash, str};
+#[cfg(feature = "game-db")]
+use std::sync::Arc;
+
+use crate::Error;
+#[cfg(feature = "game-db")]
+use crate::game_db::GameDb;
 
-#[allow(unused)]
 #[derive(Debug, Default, Clone)]
 pub struct CartHeader {
     pub cart_type: &'static str,
@@ -16,34 +21,138 @@ pub struct CartHeader {
     pub ram_banks: usize,
     pub ram_size: usize,
     pub has_battery: bool,
+    /// Set for unlicensed carts, whose mapper isn't told by the cart type
+    pub unlicensed: Option<Unlicensed>,
+    /// MBC1M, the MBC1 of multicarts, with bit 4 of the ROM bank unwired.
+    /// Detected from the header of the second game, at bank 0x10, or set by the [game database](crate::game_db).
+    pub multicart: bool,
     version: u8,
-    checksum: u8,
+    header_checksum: Checksum<u8>,
+    global_checksum: Checksum<u16>,
+}
+
+/// A checksum stored in the header, and the one computed from the ROM.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Checksum<T> {
+    pub expected: T,
+    pub computed: T,
+}
+
+impl<T: PartialEq> Checksum<T> {
+    pub fn is_valid(&self) -> bool {
+        self.expected == self.computed
+    }
 }
 
-const NINTENDO_LOGO: [u8; 48] = [
+pub(crate) const NINTENDO_LOGO: [u8; 48] = [
     0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0C, 0x00, 0x0D,
     0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E, 0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99,
     0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
 ];
 
-#[derive(Debug, Default, Clone)]
+/// Header checks done when loading a ROM, and the game overrides.
+/// Homebrew often has a bad checksum or no logo, but runs fine on hardware.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoadOptions {
+    /// Reject ROMs without the Nintendo logo
+    pub require_logo: bool,
+    /// Reject ROMs with a wrong header checksum
+    pub require_checksum: bool,
+    /// Reject ROMs smaller than their header size, instead of padding them with 0xFF
+    pub require_full_rom: bool,
+    /// Overrides looked up before the embedded [game database](crate::game_db), like the user's
+    #[cfg(feature = "game-db")]
+    pub game_db: Option<Arc<GameDb>>,
+}
+
+impl Default for LoadOptions {
+    fn default() -> Self {
+        Self {
+            require_logo: true, require_checksum: true, require_full_rom: true,
+            #[cfg(feature = "game-db")]
+            game_db: None,
+        }
+    }
+}
+
+impl LoadOptions {
+    /// Accepts anything with a parsable header.
+    pub fn tolerant() -> Self {
+        Self {
+            require_logo: false, require_checksum: false, require_full_rom: false,
+            #[cfg(feature = "game-db")]
+            game_db: None,
+        }
+    }
+
+    /// Looks up `db` before the embedded game database.
+    #[cfg(feature = "game-db")]
+    pub fn with_game_db(self, db: Arc<GameDb>) -> Self {
+        Self { game_db: Some(db), ..self }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
 pub enum CgbMode { #[default] Monochrome, CgbEnhanced, ColorOnly }
-#[derive(Debug, Default, Clone)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
 pub enum Region { Japan, #[default] Overseas } 
 
+/// Unlicensed mappers, found with header heuristics.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Unlicensed {
+    /// Banks the whole ROM in 32kb banks, selected by the address written to, not the value.
+    /// The header says cart type 0x00 or 0xC0 and 32kb of ROM, with "WISDOM" in the title.
+    WisdomTree,
+    /// MBC5 which ignores writes to 0x2101-0x2FFF.
+    /// The header has a MBC5 cart type, and its own logo instead of Nintendo's, which the cart swaps at boot.
+    /// As a corrupt or truncated MBC5 image has a wrong logo too, both checksums must match, and the logo can't be blank.
+    LiCheng,
+}
+
+fn detect_unlicensed(bytes: &[u8]) -> Option<Unlicensed> {
+    let mapper_code = bytes[0x147];
+    let title = &bytes[0x134..0x143];
+    if matches!(mapper_code, 0x00 | 0xC0) && title.starts_with(b"WISDOM") {
+        Some(Unlicensed::WisdomTree)
+    } else if (0x19..=0x1E).contains(&mapper_code) && is_li_cheng_logo(&bytes[0x104..=0x133])
+        && bytes[0x14d] == compute_header_checksum(bytes)
+        && u16::from_be_bytes([bytes[0x14e], bytes[0x14f]]) == compute_global_checksum(bytes) {
+        Some(Unlicensed::LiCheng)
+    } else {
+        None
+    }
+}
+
+/// MBC1M carts are 1MB, with the header of a game every 256kb: the Nintendo logo is found at bank 0x10 too.
+fn is_multicart(bytes: &[u8], mapper_code: u8) -> bool {
+    const SECOND_GAME: usize = 0x10 * 0x4000;
+    (0x01..=0x03).contains(&mapper_code)
+        && bytes.len() == 64 * 0x4000
+        && bytes[SECOND_GAME + 0x104..=SECOND_GAME + 0x133] == NINTENDO_LOGO
+}
+
+/// Another logo than Nintendo's, not filled with a single byte like erased or missing data.
+fn is_li_cheng_logo(logo: &[u8]) -> bool {
+    logo != NINTENDO_LOGO && logo.iter().any(|byte| *byte != logo[0])
+}
+
 fn parse_info<Info: cmp::Eq + hash::Hash, Parsed: Copy>(
     code: Info, 
     // map: &HashMap<Info, Parsed>,
     map: &[(Info, Parsed)],
-    err: &'static str
-) -> Result<Parsed, &'static str> {
+    field: &'static str
+) -> Result<Parsed, Error> {
     map.iter().find(|i| i.0 == code)
     .map(|o| o.1)
-    .ok_or(err)
+    .ok_or(Error::InvalidHeader(field))
 }
 
+const HEADER_END: usize = 0x150;
+/// The biggest ROM a header can declare, 512 banks of 16 KiB
+pub const MAX_ROM_SIZE: usize = 8*1024*1024;
+
 pub fn is_gb_rom(bytes: &[u8]) -> bool {
-    if bytes.len() < 0x104 + (0x14F - 0x104) {
+    if bytes.len() < HEADER_END {
         return false;
     }
 
@@ -51,19 +160,24 @@ pub fn is_gb_rom(bytes: &[u8]) -> bool {
 }
 
 impl CartHeader {
-    pub fn new(bytes: &[u8]) -> Result<Self, &str> {
-        if bytes.len() < 0x104 + (0x14F - 0x104) {
-            return Err("Rom file is too small")
+    pub fn new(bytes: &[u8]) -> Result<Self, Error> {
+        Self::with_options(bytes, &LoadOptions::default())
+    }
+
+    pub fn with_options(bytes: &[u8], opts: &LoadOptions) -> Result<Self, Error> {
+        if bytes.len() < HEADER_END {
+            return Err(Error::RomTooSmall { len: bytes.len(), expected: HEADER_END });
         }
 
-        if bytes[0x104..=0x133] != NINTENDO_LOGO {
-            return Err("Nintendo logo not found");
+        let unlicensed = detect_unlicensed(bytes);
+        if opts.require_logo && unlicensed.is_none() && bytes[0x104..=0x133] != NINTENDO_LOGO {
+            return Err(Error::MissingLogo);
         }
 
         let title = str
             ::from_utf8(&bytes[0x134..0x143])
-            .map(|s| String::from(s))
-            .map_err(|_| "Invalid title")?
+            .map(String::from)
+            .map_err(|_| Error::InvalidHeader("title"))?
             .chars()
             .filter(|c| !c.is_control())
             .collect();
@@ -77,21 +191,26 @@ impl CartHeader {
         let sgb_support = bytes[0x146] != 0;
 
         let mapper_code = bytes[0x147];
-        let cart_type = 
-            parse_info(mapper_code, &CART_TYPE_MAP, "Invalid cart type")?;
+        let cart_type = match unlicensed {
+            Some(Unlicensed::WisdomTree) => "WISDOM TREE",
+            _ => parse_info(mapper_code, &CART_TYPE_MAP, "cart type")?,
+        };
         let has_battery = cart_type.contains("BATTERY");
 
         let rom_size_id = bytes[0x148];
         let rom_banks = 
-            parse_info(rom_size_id, &ROM_SIZE_MAP, "Invalid ROM size")?;
-        let rom_size = 16*1024*rom_banks;
+            parse_info(rom_size_id, &ROM_SIZE_MAP, "ROM size")?;
+        let mut rom_size = 16*1024*rom_banks;
+        // Wisdom Tree carts declare 32kb whatever their size
+        if unlicensed == Some(Unlicensed::WisdomTree) {
+            rom_size = rom_size.max(bytes.len().next_multiple_of(32*1024));
+        }
+        let rom_banks = rom_size / (16*1024);
 
         let ram_size_id = bytes[0x149];
         let ram_banks = 
-            parse_info(ram_size_id, &RAM_SIZE_MAP, "Invalid RAM size")?;
+            parse_info(ram_size_id, &RAM_SIZE_MAP, "RAM size")?;
         let ram_size = 8*1024*ram_banks;
-        // default ram to 8kb
-        let ram_size = if ram_size > 0 { ram_size } else { 8*1024 };
 
         let region = match bytes[0x14a] != 0 {
             false => Region::Japan,
@@ -100,16 +219,14 @@ impl CartHeader {
 
         let licensee_id = bytes[0x14b];
         let licensee = 
-            parse_info(licensee_id, &LICENSEE_MAP, "Invalid old licensee")?;
+            parse_info(licensee_id, &LICENSEE_MAP, "old licensee")?;
 
         let licensee_new = if licensee_id == 0x33 {
             let licensee_new_str = str
                 ::from_utf8(&bytes[0x144..=0x145])
                 .unwrap_or("00");
-            let licensee_new = 
-                parse_info(licensee_new_str, &NEW_LICESEE_MAP, "Invalid new licensee")
-                .unwrap_or("None");
-            licensee_new
+            parse_info(licensee_new_str, &NEW_LICESEE_MAP, "new licensee")
+                .unwrap_or("None")
         } else {
             NEW_LICESEE_MAP.iter().find(|i| i.0 == "00").unwrap().1
         };
@@ -117,14 +234,16 @@ impl CartHeader {
         let version = bytes[0x14c];
         let checksum = bytes[0x14d];
 
-        let mut check = 0u8;
-        for addr in 0x0134..=0x14C {
-            check = check.wrapping_sub(bytes[addr]).wrapping_sub(1);
+        let check = compute_header_checksum(bytes);
+        if opts.require_checksum && check != checksum {
+            return Err(Error::ChecksumMismatch { expected: checksum, computed: check });
         }
 
-        if check != checksum {
-            return Err("Invalid checksum");
-        }
+        let header_checksum = Checksum { expected: checksum, computed: check };
+        let global_checksum = Checksum {
+            expected: u16::from_be_bytes([bytes[0x14e], bytes[0x14f]]),
+            computed: compute_global_checksum(bytes),
+        };
 
         Ok(Self {
             title,
@@ -140,19 +259,107 @@ impl CartHeader {
             rom_size,
             ram_size,
             has_battery,
+            unlicensed,
+            multicart: is_multicart(bytes, mapper_code),
             version,
-            checksum,
+            header_checksum,
+            global_checksum,
         })
     }
+
+    /// The title, without the padding.
+    pub fn title(&self) -> &str {
+        &self.title
+    }
+
+    /// The publisher, from the new licensee code if the old one says to use it.
+    pub fn licensee(&self) -> &'static str {
+        if self.licensee.starts_with("Indicates") { self.licensee_new } else { self.licensee }
+    }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    /// The version of the game, usually 0.
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    /// The checksum of the header bytes at 0x134-0x14C, stored at 0x14D. The boot ROM locks up if it is wrong.
+    pub fn header_checksum(&self) -> Checksum<u8> {
+        self.header_checksum
+    }
+
+    /// The sum of all the ROM bytes, stored at 0x14E-0x14F. Nothing checks it on hardware, so it is often wrong in homebrew.
+    /// It is computed over the bytes the header was parsed from, it is only meaningful if that was the whole ROM.
+    pub fn global_checksum(&self) -> Checksum<u16> {
+        self.global_checksum
+    }
+}
+
+/// The checksum the boot ROM computes over the header bytes 0x134-0x14C, stored at 0x14D.
+fn compute_header_checksum(bytes: &[u8]) -> u8 {
+    bytes[0x134..=0x14C].iter().fold(0u8, |check, byte| check.wrapping_sub(*byte).wrapping_sub(1))
+}
+
+/// The sum of every byte of the ROM, but the checksum itself.
+fn compute_global_checksum(rom: &[u8]) -> u16 {
+    rom.iter()
+        .enumerate()
+        .filter(|(i, _)| !(0x14e..=0x14f).contains(i))
+        .fold(0u16, |sum, (_, byte)| sum.wrapping_add(*byte as u16))
+}
+
+impl fmt::Display for CartHeader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let cgb_mode = match self.cgb_mode {
+            CgbMode::Monochrome => "no",
+            CgbMode::CgbEnhanced => "enhanced",
+            CgbMode::ColorOnly => "only",
+        };
+        let region = match self.region {
+            Region::Japan => "Japan",
+            Region::Overseas => "Overseas",
+        };
+
+        writeln!(f, "Title:     {}", self.title)?;
+        writeln!(f, "Cart type: {} ({:#04X})", self.cart_type, self.mapper_code)?;
+        writeln!(f, "ROM:       {} KiB, {} banks", self.rom_size / 1024, self.rom_banks)?;
+        writeln!(f, "RAM:       {} KiB, {} banks", self.ram_size / 1024, self.ram_banks)?;
+        writeln!(f, "Color:     {cgb_mode}")?;
+        writeln!(f, "SGB:       {}", if self.sgb_support { "yes" } else { "no" })?;
+        writeln!(f, "Region:    {region}")?;
+        writeln!(f, "Licensee:  {}", self.licensee())?;
+        writeln!(f, "Version:   {}", self.version)?;
+
+        let Checksum { expected, computed } = self.header_checksum;
+        write!(f, "Header checksum: {expected:#04X}, ")?;
+        if self.header_checksum.is_valid() {
+            writeln!(f, "ok")?;
+        } else {
+            writeln!(f, "bad, computed {computed:#04X}")?;
+        }
+        let Checksum { expected, computed } = self.global_checksum;
+        write!(f, "Global checksum: {expected:#06X}, ")?;
+        if self.global_checksum.is_valid() {
+            write!(f, "ok")
+        } else {
+            write!(f, "bad, computed {computed:#06X} (the Gameboy doesn't check it)")
+        }
+    }
 }
 
 #[cfg(test)]
 mod cart_tests {
-    use super::CartHeader;
+    use super::{CartHeader, Checksum, Region};
+    use super::LoadOptions;
+    use crate::{mbc::Cart, testing::RomBuilder, Error};
 
     #[test]
     fn read_rom() {
-        let rom = std::fs::read_dir("roms/").unwrap();
+        // local ROM collection, not part of the repo
+        let Ok(rom) = std::fs::read_dir("roms/") else { return };
         for file in rom {
             let file = std::fs::read(file.unwrap().path()).unwrap();
             match CartHeader::new(&file) {
@@ -161,6 +368,78 @@ mod cart_tests {
             }
         }
     }
+
+    #[test]
+    fn load_errors() {
+        let rom = RomBuilder::new().build();
+        assert!(Cart::new(&rom).is_ok());
+
+        assert!(matches!(CartHeader::new(&rom[..0x100]), Err(Error::RomTooSmall { .. })));
+        assert!(matches!(Cart::new(&rom[..0x4000]), Err(Error::RomTooSmall { len: 0x4000, expected: 0x8000 })));
+
+        let mut bad = rom.clone();
+        bad[0x104] = 0;
+        assert_eq!(CartHeader::new(&bad).unwrap_err(), Error::MissingLogo);
+
+        let mut bad = rom.clone();
+        bad[0x14D] ^= 0xFF;
+        assert!(matches!(CartHeader::new(&bad), Err(Error::ChecksumMismatch { .. })));
+
+        let rom = RomBuilder::new().cart_type(0x20).build();
+        assert_eq!(Cart::new(&rom).err(), Some(Error::UnsupportedMapper(0x20)));
+    }
+
+    #[test]
+    fn checksums() {
+        let mut rom = RomBuilder::new().build();
+        let header = CartHeader::new(&rom).unwrap();
+        assert!(header.header_checksum().is_valid());
+        assert!(!header.global_checksum().is_valid());
+        let report = header.to_string();
+        assert!(report.contains("Header checksum: "), "{report}");
+        assert!(report.contains("Global checksum: 0x0000, bad, computed"), "{report}");
+
+        let sum = header.global_checksum().computed;
+        rom[0x14E..=0x14F].copy_from_slice(&sum.to_be_bytes());
+        // the checksum bytes aren't part of the sum
+        assert_eq!(CartHeader::new(&rom).unwrap().global_checksum(), Checksum { expected: sum, computed: sum });
+        assert!(CartHeader::new(&rom).unwrap().to_string().ends_with(&format!("{sum:#06X}, ok")));
+
+        rom[0x7FFF] = rom[0x7FFF].wrapping_add(1);
+        let header = CartHeader::new(&rom).unwrap();
+        assert_eq!(header.global_checksum().computed, sum.wrapping_add(1));
+    }
+
+    #[test]
+    fn getters() {
+        let rom = RomBuilder::new()
+            .at(0x134, b"POKEMON RED")
+            .at(0x14A, &[1])
+            .at(0x14B, &[0x01])
+            .at(0x14C, &[2])
+            .build();
+        let header = CartHeader::new(&rom).unwrap();
+        assert_eq!(header.title(), "POKEMON RED");
+        assert_eq!(header.region(), Region::Overseas);
+        assert_eq!(header.licensee(), "Nintendo");
+        assert_eq!(header.version(), 2);
+
+        let rom = RomBuilder::new().at(0x144, b"01").at(0x14B, &[0x33]).build();
+        assert_eq!(CartHeader::new(&rom).unwrap().licensee(), "Nintendo Research & Development 1");
+    }
+
+    #[test]
+    fn tolerant_load() {
+        let mut rom = RomBuilder::new().build();
+        rom[0x104] = 0;
+        rom[0x14D] ^= 0xFF;
+        let rom = &rom[..0x4000];
+        assert!(Cart::new(rom).is_err());
+
+        let cart = Cart::with_options(rom, &LoadOptions::tolerant()).unwrap();
+        assert_eq!(cart.rom.len(), 0x8000);
+        assert_eq!(cart.rom_read(0x7FFF), 0xFF);
+    }
 }
 
 const NEW_LICESEE_MAP: [(&str, &str); 64] = [
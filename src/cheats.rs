@@ -0,0 +1,282 @@
+//! GameShark and Game Genie codes.
+//!
+//! GameShark codes (`TTVVLLHH`) write a value to cartridge RAM, WRAM or HRAM at every frame.
+//! Game Genie codes (`VVA-AAA` or `VVA-AAA-CCC`) patch a ROM byte, only when it holds the compare value if given.
+//! [`CheatSearch`] finds the addresses to write with GameShark codes.
+
+use std::ops::RangeInclusive;
+
+use crate::{bus::Bus, mem::Memory, Error};
+
+/// The GameShark types: 00/01 write to the mapped bank, 8x/9x name a cartridge RAM/WRAM bank,
+/// which is written to whichever bank is mapped.
+fn is_gameshark_type(ty: u8) -> bool {
+  matches!(ty, 0x00 | 0x01 | 0x80..=0x9F)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Cheat {
+  GameShark { addr: u16, val: u8 },
+  GameGenie { addr: u16, val: u8, compare: Option<u8> },
+}
+
+impl Cheat {
+  /// Parses a code, guessing its kind from its length. Dashes and spaces are ignored.
+  pub fn parse(code: &str) -> Result<Self, Error> {
+    let invalid = || Error::InvalidCheat(code.to_string());
+    let digits = code.chars()
+      .filter(|c| *c != '-' && !c.is_whitespace())
+      .map(|c| c.to_digit(16).map(|d| d as u8))
+      .collect::<Option<Vec<_>>>()
+      .ok_or_else(invalid)?;
+
+    let byte = |i: usize| digits[i] << 4 | digits[i+1];
+
+    match digits.len() {
+      8 => {
+        let addr = u16::from_le_bytes([byte(4), byte(6)]);
+        // never write to the mapper or IO registers
+        if !is_gameshark_type(byte(0)) || !SEARCHED.iter().any(|range| range.contains(&addr)) {
+          return Err(invalid());
+        }
+        Ok(Cheat::GameShark { addr, val: byte(2) })
+      }
+      6 | 9 => {
+        let addr = ((digits[5] ^ 0xF) as u16) << 12
+          | (digits[2] as u16) << 8
+          | (digits[3] as u16) << 4
+          | digits[4] as u16;
+        // the 8th digit is only a check of the others
+        let compare = (digits.len() == 9)
+          .then(|| (digits[6] << 4 | digits[8]).rotate_right(2) ^ 0xBA);
+
+        if addr >= 0x8000 { return Err(invalid()); }
+        Ok(Cheat::GameGenie { addr, val: byte(0), compare })
+      }
+      _ => Err(invalid()),
+    }
+  }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CheatEntry {
+  /// The code as it was given
+  pub code: String,
+  pub cheat: Cheat,
+  pub enabled: bool,
+}
+
+/// The cheats of a cartridge, identified by their index.
+#[derive(Clone, Debug, Default)]
+pub struct Cheats {
+  entries: Vec<CheatEntry>,
+}
+
+impl Cheats {
+  /// Adds an enabled cheat, and returns its index.
+  pub fn add(&mut self, code: &str) -> Result<usize, Error> {
+    let cheat = Cheat::parse(code)?;
+    self.entries.push(CheatEntry { code: code.to_string(), cheat, enabled: true });
+    Ok(self.entries.len() - 1)
+  }
+
+  /// Removes a cheat. The indices of the following ones are shifted down.
+  pub fn remove(&mut self, i: usize) {
+    if i < self.entries.len() {
+      self.entries.remove(i);
+    }
+  }
+
+  pub fn set_enabled(&mut self, i: usize, enabled: bool) {
+    if let Some(entry) = self.entries.get_mut(i) {
+      entry.enabled = enabled;
+    }
+  }
+
+  pub fn clear(&mut self) {
+    self.entries.clear();
+  }
+
+  pub fn entries(&self) -> &[CheatEntry] {
+    &self.entries
+  }
+
+  fn enabled(&self) -> impl Iterator<Item = &Cheat> {
+    self.entries.iter().filter(|e| e.enabled).map(|e| &e.cheat)
+  }
+
+  /// Applies the Game Genie codes to a byte read from ROM.
+  pub(crate) fn patch_rom(&self, addr: u16, val: u8) -> u8 {
+    self.enabled()
+      .find_map(|cheat| match *cheat {
+        Cheat::GameGenie { addr: patched, val: new, compare }
+          if patched == addr && compare.is_none_or(|c| c == val) => Some(new),
+        _ => None,
+      })
+      .unwrap_or(val)
+  }
+
+  /// The RAM writes done by the GameShark codes at every frame.
+  pub(crate) fn ram_writes(&self) -> impl Iterator<Item = (u16, u8)> + '_ {
+    self.enabled().filter_map(|cheat| match *cheat {
+      Cheat::GameShark { addr, val } => Some((addr, val)),
+      _ => None,
+    })
+  }
+}
+
+/// The memory searched by [`CheatSearch`]: cartridge RAM (the mapped bank), WRAM and HRAM.
+const SEARCHED: [RangeInclusive<u16>; 3] = [0xA000..=0xBFFF, 0xC000..=0xDFFF, 0xFF80..=0xFFFE];
+
+/// How a byte is compared to its value at the previous search step.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Comparison {
+  /// Equal to this value, whatever it was before
+  Equal(u8),
+  Changed,
+  Unchanged,
+  Increased,
+  Decreased,
+  /// Increased by exactly this much, wrapping
+  IncreasedBy(u8),
+  /// Decreased by exactly this much, wrapping
+  DecreasedBy(u8),
+}
+
+impl Comparison {
+  fn matches(&self, old: u8, new: u8) -> bool {
+    match *self {
+      Comparison::Equal(val) => new == val,
+      Comparison::Changed => new != old,
+      Comparison::Unchanged => new == old,
+      Comparison::Increased => new > old,
+      Comparison::Decreased => new < old,
+      Comparison::IncreasedBy(n) => new == old.wrapping_add(n),
+      Comparison::DecreasedBy(n) => new == old.wrapping_sub(n),
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Candidate {
+  pub addr: u16,
+  /// The value at the last search step
+  pub val: u8,
+}
+
+/// Narrows down the RAM addresses holding a value, by comparing snapshots of memory.
+/// ```ignore
+/// let mut search = CheatSearch::new(gb.get_bus());
+/// // lose a life...
+/// search.filter(gb.get_bus(), Comparison::DecreasedBy(1));
+/// ```
+#[derive(Clone, Debug)]
+pub struct CheatSearch {
+  candidates: Vec<Candidate>,
+}
+
+impl CheatSearch {
+  /// Snapshots the searched memory. Every address is a candidate.
+  pub fn new(bus: &Bus) -> Self {
+    let candidates = SEARCHED.iter()
+      .flat_map(|range| range.clone().zip(bus.peek_range(range.clone())))
+      .map(|(addr, val)| Candidate { addr, val })
+      .collect();
+    Self { candidates }
+  }
+
+  /// Keeps the candidates whose value compares to the previous snapshot, and snapshots them again.
+  pub fn filter(&mut self, bus: &Bus, cmp: Comparison) {
+    self.candidates.retain_mut(|c| {
+      let val = bus.peek(c.addr);
+      let keep = cmp.matches(c.val, val);
+      c.val = val;
+      keep
+    });
+  }
+
+  pub fn candidates(&self) -> &[Candidate] {
+    &self.candidates
+  }
+}
+
+#[cfg(test)]
+mod cheats_tests {
+  use super::*;
+  use crate::{gb::Gameboy, mem::Memory, testing::{asm, RomBuilder}};
+
+  #[test]
+  fn parse_codes() {
+    assert_eq!(Cheat::parse("010538C1"), Ok(Cheat::GameShark { addr: 0xC138, val: 0x05 }));
+    assert_eq!(Cheat::parse("00A-17B-C49"), Ok(Cheat::GameGenie { addr: 0x4A17, val: 0x00, compare: Some(0xC8) }));
+    assert_eq!(Cheat::parse("3EA 0CD"), Ok(Cheat::GameGenie { addr: 0x2A0C, val: 0x3E, compare: None }));
+    assert!(matches!(Cheat::parse("01G538C1"), Err(Error::InvalidCheat(_))));
+    assert!(matches!(Cheat::parse("0105"), Err(Error::InvalidCheat(_))));
+    assert_eq!(Cheat::parse("910580FF"), Ok(Cheat::GameShark { addr: 0xFF80, val: 0x05 }));
+    // MBC and IO registers, and an unknown type
+    assert!(matches!(Cheat::parse("01050020"), Err(Error::InvalidCheat(_))));
+    assert!(matches!(Cheat::parse("010540FF"), Err(Error::InvalidCheat(_))));
+    assert!(matches!(Cheat::parse("420538C1"), Err(Error::InvalidCheat(_))));
+  }
+
+  #[test]
+  fn apply_cheats() {
+    let rom = RomBuilder::new()
+      .code(&asm![
+        "loop:",
+        "LD A, ($0200)",
+        "LD ($C000), A",
+        "LD A, ($C001)",
+        "INC A",
+        "LD ($C001), A",
+        "JR loop",
+      ])
+      .at(0x200, &[0x12])
+      .build();
+    let mut gb = Gameboy::boot_from_bytes(&rom).unwrap();
+
+    // GG: $0200 = $34 if it was $12, $0201 = $56 if it was $FF (it isn't)
+    let patch = gb.get_cheats().add("342-00F-A02").unwrap();
+    gb.get_cheats().add("562-01F-105").unwrap();
+    let freeze = gb.get_cheats().add("01AA01C0").unwrap();
+    assert!(matches!(gb.get_cheats().entries()[1].cheat, Cheat::GameGenie { compare: Some(0xFF), .. }));
+
+    gb.step_until_vblank();
+    let bus = gb.get_bus();
+    assert_eq!(bus.peek(0xC000), 0x34);
+    assert_eq!(bus.peek(0x0201), 0x00);
+    assert_eq!(bus.peek(0xC001), 0xAA);
+
+    gb.get_cheats().set_enabled(patch, false);
+    gb.get_cheats().remove(freeze);
+    gb.step_until_vblank();
+    let bus = gb.get_bus();
+    assert_eq!(bus.peek(0xC000), 0x12);
+    assert_ne!(bus.peek(0xC001), 0xAA);
+  }
+
+  #[test]
+  fn search() {
+    let rom = RomBuilder::new().code(&asm!["loop:", "JR loop"]).build();
+    let mut gb = Gameboy::boot_from_bytes(&rom).unwrap();
+    let bus = gb.get_bus();
+    bus.write(0xC123, 3);
+    bus.write(0xFF90, 3);
+    bus.write(0xD000, 7);
+
+    let mut search = CheatSearch::new(bus);
+    assert_eq!(search.candidates().len(), 0x2000 + 0x2000 + 0x7F);
+    search.filter(bus, Comparison::Equal(3));
+    assert_eq!(search.candidates().len(), 2);
+
+    bus.write(0xC123, 2);
+    bus.write(0xFF90, 4);
+    search.filter(bus, Comparison::DecreasedBy(1));
+    assert_eq!(search.candidates(), [Candidate { addr: 0xC123, val: 2 }]);
+
+    search.filter(bus, Comparison::Unchanged);
+    assert_eq!(search.candidates().len(), 1);
+    search.filter(bus, Comparison::Changed);
+    assert!(search.candidates().is_empty());
+  }
+}
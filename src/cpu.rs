@@ -2,13 +2,14 @@ use std::ops::{Not, Shl, Shr, BitAnd, BitOr, BitXor};
 
 use bitfield_struct::bitfield;
 use bitflags::bitflags;
+use serde::{Deserialize, Serialize};
 
 use crate::{
 	bus::{Bus, IFlags}, lsb, mbc::Cart, mem::{Memory, Ram64kb}, msb
 };
 
 bitflags! {
-	#[derive(Default, Debug)]
+	#[derive(Default, Debug, Serialize, Deserialize)]
 	pub struct Flags: u8 {
 		const z = 0b1000_0000;
 		const n = 0b0100_0000;
@@ -19,6 +20,7 @@ bitflags! {
 }
 
 #[bitfield(u16)]
+#[derive(Serialize, Deserialize)]
 pub struct Register16 {
 	#[bits(8)]
 	pub lo: u8,
@@ -26,7 +28,14 @@ pub struct Register16 {
 	pub hi: u8,
 }
 
-pub struct Cpu<M: Memory> {
+/// The SM83 CPU, running on any [`Memory`]. [`Bus`] is the whole Gameboy, [`Ram64kb`] a flat memory for tests.
+///
+/// It steps an instruction at a time, but ticks the memory once per M-cycle in the hardware order:
+/// a read or write lands right after the tick of its M-cycle, and internal cycles, like the one before a push,
+/// only tick. The M-cycles of every opcode add up to the `cycles` of [`INSTRUCTIONS`](crate::instr::INSTRUCTIONS),
+/// and their bus activity is checked against the SingleStepTests traces.
+#[derive(Serialize, Deserialize)]
+pub struct Cpu<M: Memory = Bus> {
 	pub a: u8,
 	pub f: Flags,
 	pub bc: Register16,
@@ -52,51 +61,19 @@ impl<M: Memory> core::fmt::Debug for Cpu<M> {
 
 impl Cpu<Ram64kb> {
   pub fn with_ram64kb() -> Self {
-		Self {
-			a: 1,
-			f: Flags::from_bits_truncate(0xB0),
-			bc: Register16::from_bits(0x13),
-			de: Register16::from_bits(0xD8),
-			hl: Register16::from_bits(0x14D),
-			sp: 0xFFFE,
-			pc: 0,
-			ime: false,
-			ime_to_set: false,
-			halted: false,
-			halt_bug: false,
-			mcycles: 0,
-      bus: Ram64kb::default(),
-		}
-	}
-
-	pub fn step(&mut self) {
-		if self.ime_to_set {
-			self.ime = true;
-			self.ime_to_set = false;
-		}
-
-		if self.halted {
-			self.halt_tick();
-			return;
-		}
-
-		let opcode = self.pc_fetch();
-		if self.halt_bug {
-			self.halt_bug = false;
-			self.pc = self.pc.wrapping_sub(1);
-		}
-
-		if opcode == 0xCB {
-			let opcode = self.pc_fetch();
-			self.execute_prefix(opcode);
-		} else { 
-			self.execute_no_prefix(opcode)
-		}
+		Self { pc: 0, ..Self::with_memory(Ram64kb::default()) }
 	}
 }
 
 impl Cpu<Bus> {
 	pub fn new(cart: Cart) -> Self {
+		Self::with_memory(Bus::new(cart))
+	}
+}
+
+impl<M: Memory> Cpu<M> {
+	/// A CPU with the registers the DMG boot ROM leaves, starting at the cartridge entry point.
+	pub fn with_memory(bus: M) -> Self {
 		Self {
 			a: 1,
 			f: Flags::from_bits_truncate(0xB0),
@@ -110,25 +87,27 @@ impl Cpu<Bus> {
 			halted: false,
 			halt_bug: false,
 			mcycles: 0,
-			bus: Bus::new(cart),
+			bus,
 		}
 	}
 
 	pub fn step(&mut self) {
-		if self.ime_to_set {
-			self.ime = true;
-			self.ime_to_set = false;
-		} else if self.ime {
-			self.handle_interrupts();
-		}
-
 		if self.halted {
-			if self.bus.has_pending_interrupts() {
-				self.halted = false;
-			} else {
-				self.halt_tick();			
+			if !self.bus.has_pending_interrupts() {
+				self.tick();
 				return;
 			}
+			// a pending interrupt wakes the CPU up even with IME off, where it isn't serviced
+			self.halted = false;
+		}
+
+		// interrupts are checked between instructions, EI enables them after the instruction following it
+		if self.ime {
+			self.handle_interrupts();
+		}
+		if self.ime_to_set {
+			self.ime = true;
+			self.ime_to_set = false;
 		}
 
 		let opcode = self.pc_fetch();
@@ -146,35 +125,38 @@ impl Cpu<Bus> {
 	}
 
 	fn handle_interrupts(&mut self) {
-		let mut intf = self.bus.intf();
-
-		let mut pending_ints = (self.bus.inte & intf)
-			.iter().collect::<Vec<_>>();
-		pending_ints.reverse();
-
-		if let Some(int) = pending_ints.first() {
-			let addr = match *int {
-				IFlags::vblank => 0x40,
-				IFlags::lcd    => 0x48,
-				IFlags::timer  => 0x50,
-				IFlags::serial => 0x58, 
-				IFlags::joypad => 0x60,
-				_ => unreachable!(),
-			};
-
-			// 2 wait states are executed
-			self.tick();
-			self.tick();
+		if !self.bus.has_pending_interrupts() { return; }
+		self.ime = false;
+		self.ime_to_set = false;
 
-			self.stack_push(self.pc);
-			self.pc = addr;
-			self.tick();
-
-			intf.remove(*int);
-			self.bus.set_intf(intf);
+		// 2 wait states are executed
+		self.tick();
+		self.tick();
 
-			self.ime = false;
-		}
+		// like stack_push, the high byte is pushed first. The interrupt is only picked after, so if the push
+		// overwrote IE and no enabled interrupt is left, it is cancelled and the PC jumps to 0
+		let [lo, hi] = self.pc.to_le_bytes();
+		self.sp = self.sp.wrapping_sub(1);
+		self.write(self.sp, hi);
+		let int = self.bus.pending_interrupt();
+		self.sp = self.sp.wrapping_sub(1);
+		self.write(self.sp, lo);
+
+		self.pc = match int {
+			Some(int) => {
+				self.bus.ack_interrupt(int);
+				match int {
+					IFlags::vblank => 0x40,
+					IFlags::lcd    => 0x48,
+					IFlags::timer  => 0x50,
+					IFlags::serial => 0x58,
+					IFlags::joypad => 0x60,
+					_ => unreachable!(),
+				}
+			}
+			None => 0,
+		};
+		self.tick();
 	}
 }
 
@@ -222,19 +204,24 @@ impl<M: Memory> Cpu<M> {
 		self.bus.tick();
 	}
 
-	fn halt_tick(&mut self) {
-		self.mcycles += 1;
-		self.bus.halt_tick();
+	/// Reads memory without ticking or any other side effect.
+	pub fn peek(&self, addr: u16) -> u8 {
+		self.bus.peek(addr)
 	}
 
-	pub fn peek(&mut self, addr: u16) -> u8 {
-		self.bus.read(addr)
+	/// Formats the CPU state as a Gameboy Doctor log line, with the 4 bytes at PC.
+	pub fn doctor_line(&self) -> String {
+		let pcmem = [0, 1, 2, 3].map(|i| self.peek(self.pc.wrapping_add(i)));
+		format!(
+			"A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}",
+			self.a, self.f.bits(), self.bc.hi(), self.bc.lo(), self.de.hi(), self.de.lo(),
+			self.hl.hi(), self.hl.lo(), self.sp, self.pc, pcmem[0], pcmem[1], pcmem[2], pcmem[3]
+		)
 	}
 
 	pub fn read(&mut self, addr: u16) -> u8 {
 		self.tick();
-		let res = self.peek(addr);
-		res
+		self.bus.read(addr)
 	}
 	fn read16(&mut self, addr: u16) -> u16 {
 		u16::from_le_bytes([self.read(addr), self.read(addr.wrapping_add(1))])
@@ -246,8 +233,8 @@ impl<M: Memory> Cpu<M> {
 	}
 	fn write16(&mut self, addr: u16, val: u16){
 		let [lo, hi] = val.to_le_bytes();
-		self.write(addr as u16, lo);
-		self.write(addr.wrapping_add(1) as u16, hi);
+		self.write(addr, lo);
+		self.write(addr.wrapping_add(1), hi);
 	}
 	fn pc_fetch(&mut self) -> u8 {
 		let res = self.read(self.pc);
@@ -260,8 +247,12 @@ impl<M: Memory> Cpu<M> {
 		])
 	}
 	fn stack_push(&mut self, val: u16) {
-		self.write16(self.sp.wrapping_sub(2), val);
-		self.sp = self.sp.wrapping_sub(2);
+		// the high byte goes first, at SP-1
+		let [lo, hi] = val.to_le_bytes();
+		self.sp = self.sp.wrapping_sub(1);
+		self.write(self.sp, hi);
+		self.sp = self.sp.wrapping_sub(1);
+		self.write(self.sp, lo);
 	}
 	fn stack_pop(&mut self) -> u16 {
 		let value = self.read16(self.sp);
@@ -285,17 +276,8 @@ impl<M: Memory> Cpu<M> {
 	fn immediate16(&mut self) -> u16 {
 		self.pc_fetch16()
 	}
-	fn indirect_zero16(&mut self) -> u16 {
-		let offset = self.pc_fetch();
-		self.read16(self.hram(offset))
-	}
-	fn indirect_abs16(&mut self) -> u16 {
-		let addr = self.pc_fetch16();
-		self.read16(addr)
-	}
 
 	fn a(&mut self) -> u8 { self.a }
-	fn a16(&mut self) -> u16 { self.a as u16 }
 	fn b(&mut self) -> u8 { self.bc.hi() }
 	fn c(&mut self) -> u8 { self.bc.lo() }
 	fn c_indirect(&mut self) -> u8 {
@@ -336,10 +318,6 @@ impl<M: Memory> Cpu<M> {
 		let addr = self.pc_fetch16();
 		self.write(addr, val);
 	}
-	fn set_indirect_zero16(&mut self, val: u16) {
-		let offset = self.pc_fetch();
-		self.write16(self.hram(offset), val);
-	}
 	fn set_indirect_abs16(&mut self, val: u16) {
 		let addr = self.pc_fetch16();
 		self.write16(addr, val);
@@ -631,7 +609,7 @@ impl<M: Memory> Cpu<M> {
 		
 		self.tick();
 		self.tick();
-		self.sp = res as u16;
+		self.sp = res;
 	}
 
 	fn shift_acc<FS: Fn(u8) -> u8, FB: Fn(u8) -> bool>(&mut self, f: FS, carry: FB) {
@@ -815,15 +793,17 @@ impl<M: Memory> Cpu<M> {
 
 	fn stop(&mut self, _get: OpGet<M, u8>) {
 		self.halted = true;
-		eprintln!("STOP not implemented");
+		log::warn!("STOP at {:04X} isn't implemented, halting instead", self.pc.wrapping_sub(1));
 	}
 
 	fn halt(&mut self) {
-		if !self.ime && !self.bus.has_pending_interrupts() {
+		// with IME off and an interrupt already pending, HALT exits at once,
+		// and the PC fails to increment after the next opcode fetch
+		if !self.ime && self.bus.has_pending_interrupts() {
 			self.halt_bug = true;
+		} else {
+			self.halted = true;
 		}
-		
-		self.halted = true;
 	}
 }
 
@@ -1059,7 +1039,7 @@ impl<M: Memory> Cpu<M> {
 			0xE7 => self.rst(0x20),
 			0xE8 => self.addsp(Self::immediate8),
 			0xE9 => self.jphl(),
-			0xEA => self.ld(Self::set_indirect_abs16,Self::a16),
+			0xEA => self.ld(Self::set_indirect_abs8,Self::a),
 			0xEE => self.xor(Self::immediate8),
 			0xEF => self.rst(0x28),
 			0xF0 => self.ld(Self::set_a,Self::indirect_zero8),
@@ -1075,7 +1055,8 @@ impl<M: Memory> Cpu<M> {
 			0xFB => self.ei(),
 			0xFE => self.cp(Self::immediate8),
 			0xFF => self.rst(0x38),
-			_ => eprintln!("{:02X} not reachable", opcode)
+			// the illegal opcodes, they lock up the hardware
+			_ => log::warn!("illegal opcode {opcode:02X} at {:04X}", self.pc.wrapping_sub(1)),
     }
   }
 
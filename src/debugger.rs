@@ -0,0 +1,281 @@
+//! Breakpoints, watchpoints and conditional breaks, used by [`Gameboy::run_until_break`].
+//! The strict memory map catches the accesses homebrew shouldn't make, see [`Debugger::set_strict`].
+//!
+//! [`Gameboy::run_until_break`]: crate::gb::Gameboy::run_until_break
+
+use std::{collections::HashSet, ops::RangeInclusive};
+
+use crate::{bus::Bus, cpu::Cpu};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access { Read, Write }
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Reg { A, F, B, C, D, E, H, L, AF, BC, DE, HL, SP, PC }
+
+impl Reg {
+  pub fn get(&self, cpu: &Cpu<Bus>) -> u16 {
+    match self {
+      Reg::A => cpu.a as u16,
+      Reg::F => cpu.f.bits() as u16,
+      Reg::B => cpu.bc.hi() as u16,
+      Reg::C => cpu.bc.lo() as u16,
+      Reg::D => cpu.de.hi() as u16,
+      Reg::E => cpu.de.lo() as u16,
+      Reg::H => cpu.hl.hi() as u16,
+      Reg::L => cpu.hl.lo() as u16,
+      Reg::AF => (cpu.a as u16) << 8 | cpu.f.bits() as u16,
+      Reg::BC => cpu.bc.into_bits(),
+      Reg::DE => cpu.de.into_bits(),
+      Reg::HL => cpu.hl.into_bits(),
+      Reg::SP => cpu.sp,
+      Reg::PC => cpu.pc,
+    }
+  }
+}
+
+/// Breaks when a register holds a value.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Condition {
+  pub reg: Reg,
+  pub value: u16,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Watchpoint {
+  pub range: RangeInclusive<u16>,
+  pub read: bool,
+  pub write: bool,
+}
+
+/// An access the hardware doesn't allow, or Nintendo forbids.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Violation {
+  /// 0xE000 to 0xFDFF, the mirror of WRAM
+  EchoRam,
+  /// 0xFEA0 to 0xFEFF
+  Unusable,
+  /// VRAM while the PPU draws, in mode 3
+  LockedVram,
+  /// OAM while the PPU reads it, in mode 2 and 3
+  LockedOam,
+}
+
+/// What the strict memory map does on a [`Violation`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StrictMode {
+  /// Logs a warning with the [`log`] crate
+  Log,
+  /// Breaks like a watchpoint
+  Break,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BreakReason {
+  /// The PC reached a breakpoint
+  Breakpoint(u16),
+  /// The last instruction accessed a watched address
+  Watchpoint { addr: u16, access: Access, val: u8 },
+  /// A register condition became true
+  Condition(Condition),
+  /// The last instruction made an access the strict memory map catches
+  Violation { addr: u16, access: Access, violation: Violation },
+}
+
+#[derive(Default)]
+pub struct Debugger {
+  breakpoints: HashSet<u16>,
+  watchpoints: Vec<Watchpoint>,
+  conditions: Vec<Condition>,
+  strict: Option<StrictMode>,
+  hit: Option<BreakReason>,
+}
+
+impl Debugger {
+  pub fn add_breakpoint(&mut self, addr: u16) {
+    self.breakpoints.insert(addr);
+  }
+
+  pub fn remove_breakpoint(&mut self, addr: u16) {
+    self.breakpoints.remove(&addr);
+  }
+
+  pub fn breakpoints(&self) -> impl Iterator<Item = u16> + '_ {
+    self.breakpoints.iter().copied()
+  }
+
+  pub fn add_watchpoint(&mut self, range: RangeInclusive<u16>, read: bool, write: bool) {
+    self.watchpoints.push(Watchpoint { range, read, write });
+  }
+
+  /// Removes every watchpoint over exactly this range.
+  pub fn remove_watchpoint(&mut self, range: RangeInclusive<u16>) {
+    self.watchpoints.retain(|w| w.range != range);
+  }
+
+  pub fn watchpoints(&self) -> &[Watchpoint] {
+    &self.watchpoints
+  }
+
+  pub fn add_condition(&mut self, reg: Reg, value: u16) {
+    self.conditions.push(Condition { reg, value });
+  }
+
+  pub fn remove_condition(&mut self, reg: Reg, value: u16) {
+    self.conditions.retain(|c| *c != Condition { reg, value });
+  }
+
+  pub fn conditions(&self) -> &[Condition] {
+    &self.conditions
+  }
+
+  /// Checks the CPU accesses against the hardware constraints: echo RAM, the unusable area, and VRAM and OAM
+  /// while the PPU locks them. Off with `None`, the default.
+  pub fn set_strict(&mut self, mode: Option<StrictMode>) {
+    self.strict = mode;
+  }
+
+  pub fn strict(&self) -> Option<StrictMode> {
+    self.strict
+  }
+
+  pub fn clear(&mut self) {
+    *self = Self::default();
+  }
+
+  /// Called by the bus on every memory access. Only the first hit is kept.
+  pub(crate) fn on_access(&mut self, addr: u16, access: Access, val: u8) {
+    if self.watchpoints.is_empty() || self.hit.is_some() { return; }
+
+    let watched = self.watchpoints.iter().any(|w| {
+      w.range.contains(&addr) && match access {
+        Access::Read => w.read,
+        Access::Write => w.write,
+      }
+    });
+
+    if watched {
+      self.hit = Some(BreakReason::Watchpoint { addr, access, val });
+    }
+  }
+
+  /// Called by the bus on the CPU accesses that break the strict memory map.
+  pub(crate) fn on_violation(&mut self, addr: u16, access: Access, violation: Violation) {
+    match self.strict {
+      Some(StrictMode::Log) => log::warn!("{violation:?} {access:?} at {addr:04X}"),
+      Some(StrictMode::Break) if self.hit.is_none() => {
+        self.hit = Some(BreakReason::Violation { addr, access, violation });
+      }
+      _ => {}
+    }
+  }
+
+  pub(crate) fn take_hit(&mut self) -> Option<BreakReason> {
+    self.hit.take()
+  }
+
+  /// Checks breakpoints and conditions against the CPU state, before an instruction is run.
+  pub(crate) fn check_cpu(&self, cpu: &Cpu<Bus>) -> Option<BreakReason> {
+    if self.breakpoints.contains(&cpu.pc) {
+      return Some(BreakReason::Breakpoint(cpu.pc));
+    }
+
+    self.conditions.iter()
+      .find(|c| c.reg.get(cpu) == c.value)
+      .map(|c| BreakReason::Condition(*c))
+  }
+}
+
+#[cfg(test)]
+mod debugger_tests {
+  use super::*;
+  use crate::{gb::Gameboy, mem::Memory, ppu::PpuMode, testing::{asm, RomBuilder}};
+
+  #[test]
+  fn breaks() {
+    let rom = RomBuilder::new()
+      .code(&asm![
+        "LD B, 0",
+        "loop:",
+        "INC B",
+        "LD A, B",
+        "LD ($C000), A",
+        "target:",
+        "LD A, ($C001)",
+        "JR loop",
+      ])
+      .build();
+    let mut gb = Gameboy::boot_from_bytes(&rom).unwrap();
+    // LD B, n8; INC B; LD A, B; LD (a16), A
+    let target = 0x150 + 2 + 1 + 1 + 3;
+
+    gb.get_debugger().add_breakpoint(target);
+    assert_eq!(gb.run_until_break(), BreakReason::Breakpoint(target));
+    assert_eq!(gb.get_cpu().pc, target);
+    // continuing from a breakpoint runs past it
+    assert_eq!(gb.run_until_break(), BreakReason::Breakpoint(target));
+    assert_eq!(gb.get_cpu().bc.hi(), 2);
+    gb.get_debugger().remove_breakpoint(target);
+
+    gb.get_debugger().add_watchpoint(0xC000..=0xC000, false, true);
+    assert_eq!(gb.run_until_break(), BreakReason::Watchpoint { addr: 0xC000, access: Access::Write, val: 3 });
+    gb.get_debugger().clear();
+
+    gb.get_debugger().add_watchpoint(0xC001..=0xC010, true, false);
+    assert!(matches!(gb.run_until_break(), BreakReason::Watchpoint { addr: 0xC001, access: Access::Read, .. }));
+    gb.get_debugger().clear();
+
+    gb.get_debugger().add_condition(Reg::B, 10);
+    assert_eq!(gb.run_until_break(), BreakReason::Condition(Condition { reg: Reg::B, value: 10 }));
+    assert_eq!(gb.get_cpu().bc.hi(), 10);
+  }
+
+  #[test]
+  fn strict_memory_map() {
+    let rom = RomBuilder::new()
+      .code(&asm![
+        "LD A, ($C000)",
+        "LD ($E010), A",
+        "LD A, ($FEA0)",
+        "loop:",
+        "JR loop",
+      ])
+      .build();
+    let mut gb = Gameboy::boot_from_bytes(&rom).unwrap();
+    gb.get_debugger().set_strict(Some(StrictMode::Break));
+
+    let echo = BreakReason::Violation { addr: 0xE010, access: Access::Write, violation: Violation::EchoRam };
+    assert_eq!(gb.run_until_break(), echo);
+    let unusable = BreakReason::Violation { addr: 0xFEA0, access: Access::Read, violation: Violation::Unusable };
+    assert_eq!(gb.run_until_break(), unusable);
+    // echo RAM mirrors WRAM
+    assert_eq!(gb.get_bus().peek(0xC010), gb.get_bus().peek(0xC000));
+
+    // the PPU locks VRAM in mode 3
+    while gb.get_ppu().mode() != PpuMode::DrawingPixels { gb.step(); }
+    let bus = gb.get_bus();
+    bus.read(0x8000);
+    let locked = BreakReason::Violation { addr: 0x8000, access: Access::Read, violation: Violation::LockedVram };
+    assert_eq!(bus.debugger.take_hit(), Some(locked));
+    bus.write(0xFE00, 0);
+    assert!(matches!(bus.debugger.take_hit(), Some(BreakReason::Violation { violation: Violation::LockedOam, .. })));
+
+    bus.debugger.set_strict(None);
+    bus.read(0xE000);
+    assert_eq!(bus.debugger.take_hit(), None);
+  }
+
+  #[test]
+  fn peeks_have_no_side_effects() {
+    let rom = RomBuilder::new().code(&asm!["loop:", "JR loop"]).build();
+    let mut gb = Gameboy::boot_from_bytes(&rom).unwrap();
+    gb.get_debugger().add_watchpoint(0x0000..=0xFFFF, true, true);
+
+    let bus = gb.get_bus();
+    let mcycles = bus.mcycles();
+    assert_eq!(bus.peek_range(0x0150..=0x0151), [0x18, 0xFE]);
+    assert_eq!(bus.peek(0x0104), 0xCE);
+    assert_eq!(bus.mcycles(), mcycles);
+    assert_eq!(bus.debugger.take_hit(), None);
+  }
+}
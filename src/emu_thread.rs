@@ -0,0 +1,245 @@
+//! Runs a [`Gameboy`] on its own thread, driven over channels.
+//!
+//! The frontend asks for frames with [`EmuThread::run_frames`] and collects them later with [`EmuThread::recv`],
+//! so it can draw and poll input while the next frames are emulated.
+//! Commands are handled in the order they are sent, in between the batches of frames.
+//!
+//! ```no_run
+//! # use tomboy_emulator::{gb::Gameboy, emu_thread::EmuThread, joypad::Button};
+//! # let gb = Gameboy::boot_from_bytes(&[]).unwrap();
+//! let emu = EmuThread::spawn(gb);
+//! emu.run_frames(1);
+//! loop {
+//!   let output = emu.recv().unwrap();
+//!   // ask for the next frame before drawing this one
+//!   emu.run_frames(1);
+//!   emu.press(Button::A);
+//!   // draw output.screen, play output.samples...
+//! }
+//! ```
+
+use std::{path::PathBuf, sync::mpsc::{self, Receiver, Sender}, thread::{self, JoinHandle}};
+
+use crate::{frame::FrameBuffer, gb::Gameboy, joypad::Button, osd::Osd};
+
+enum Command {
+  Press(Button),
+  Release(Button),
+  SetPaused(bool),
+  Reset,
+  Load(Box<Gameboy>),
+  LoadScript(PathBuf),
+  RunFrames(usize),
+  Run(Box<dyn FnOnce(&mut Gameboy) + Send>),
+  Stop,
+}
+
+/// What a batch of [`EmuThread::run_frames`] produced.
+pub struct Output {
+  /// The audio of each frame run. Empty when paused.
+  pub samples: Vec<Vec<f32>>,
+  /// The screen after the last frame, the others were skipped.
+  pub screen: FrameBuffer,
+  /// A copy of [`Gameboy::get_osd`], to draw on a copy of the screen.
+  pub osd: Osd,
+  pub total_frames: usize,
+  pub paused: bool,
+  /// See [`Gameboy::rumble_state`].
+  pub rumble: bool,
+}
+
+/// A [`Gameboy`] running on its own thread. The thread is stopped when dropped.
+pub struct EmuThread {
+  commands: Sender<Command>,
+  outputs: Receiver<Output>,
+  handle: Option<JoinHandle<Gameboy>>,
+}
+
+impl EmuThread {
+  pub fn spawn(emu: Gameboy) -> Self {
+    let (commands, command_rx) = mpsc::channel();
+    let (output_tx, outputs) = mpsc::channel();
+    let handle = thread::Builder::new()
+      .name("emulator".into())
+      .spawn(move || run(emu, command_rx, output_tx))
+      .expect("couldn't spawn the emulator thread");
+    Self { commands, outputs, handle: Some(handle) }
+  }
+
+  fn send(&self, command: Command) {
+    // if the thread panicked, recv reports it
+    let _ = self.commands.send(command);
+  }
+
+  pub fn press(&self, button: Button) {
+    self.send(Command::Press(button));
+  }
+
+  pub fn release(&self, button: Button) {
+    self.send(Command::Release(button));
+  }
+
+  /// See [`Gameboy::set_paused`]. Paused batches still produce an [`Output`], without audio.
+  pub fn set_paused(&self, paused: bool) {
+    self.send(Command::SetPaused(paused));
+  }
+
+  pub fn reset(&self) {
+    self.send(Command::Reset);
+  }
+
+  /// Replaces the emulated console, like with a newly booted ROM.
+  pub fn load(&self, emu: Gameboy) {
+    self.send(Command::Load(Box::new(emu)));
+  }
+
+  /// Loads a [`Script`](crate::script::Script) run after every frame, replacing the previous one.
+  /// Errors are shown on the [`Osd`], and stop the script.
+  pub fn load_script(&self, path: impl Into<PathBuf>) {
+    self.send(Command::LoadScript(path.into()));
+  }
+
+  /// Runs `count` frames with [`Gameboy::run_frame`], drawing only the last one.
+  /// Their [`Output`] is received with [`EmuThread::recv`]. With 0, it has the same screen again.
+  pub fn run_frames(&self, count: usize) {
+    self.send(Command::RunFrames(count));
+  }
+
+  /// Calls `f` on the emulator thread, without waiting for it.
+  pub fn queue(&self, f: impl FnOnce(&mut Gameboy) + Send + 'static) {
+    self.send(Command::Run(Box::new(f)));
+  }
+
+  /// Calls `f` on the emulator thread, and waits for its result.
+  /// It waits for the frames asked before to be run.
+  pub fn with<R: Send + 'static>(&self, f: impl FnOnce(&mut Gameboy) -> R + Send + 'static) -> R {
+    let (tx, rx) = mpsc::sync_channel(1);
+    self.queue(move |emu| { let _ = tx.send(f(emu)); });
+    rx.recv().expect("the emulator thread panicked")
+  }
+
+  /// Waits for the next batch of frames. `None` if the thread panicked.
+  pub fn recv(&self) -> Option<Output> {
+    self.outputs.recv().ok()
+  }
+
+  /// The next batch of frames, if it is done.
+  pub fn try_recv(&self) -> Option<Output> {
+    self.outputs.try_recv().ok()
+  }
+
+  /// Stops the thread, and gives the emulator back.
+  pub fn stop(mut self) -> Gameboy {
+    self.send(Command::Stop);
+    let handle = self.handle.take().expect("the thread is only joined once");
+    handle.join().unwrap_or_else(|e| std::panic::resume_unwind(e))
+  }
+}
+
+impl Drop for EmuThread {
+  fn drop(&mut self) {
+    if let Some(handle) = self.handle.take() {
+      self.send(Command::Stop);
+      let _ = handle.join();
+    }
+  }
+}
+
+fn run(mut emu: Gameboy, commands: Receiver<Command>, outputs: Sender<Output>) -> Gameboy {
+  #[cfg(feature = "scripting")]
+  let mut script: Option<crate::script::Script> = None;
+
+  for command in commands {
+    match command {
+      Command::Press(button) => emu.get_joypad().press(button),
+      Command::Release(button) => emu.get_joypad().release(button),
+      Command::SetPaused(paused) => emu.set_paused(paused),
+      Command::Reset => emu.reset(),
+      Command::Load(new) => emu = *new,
+      #[cfg(feature = "scripting")]
+      Command::LoadScript(path) => match crate::script::Script::load(path) {
+        Ok(loaded) => script = Some(loaded),
+        Err(e) => emu.get_osd().message(e.to_string()),
+      }
+      #[cfg(not(feature = "scripting"))]
+      Command::LoadScript(path) => emu.get_osd().message(format!("Can't run {}, scripting isn't supported", path.display())),
+      Command::Run(f) => f(&mut emu),
+      Command::Stop => break,
+      Command::RunFrames(count) => {
+        let mut samples = Vec::new();
+        for i in 0..count {
+          emu.set_frame_skip(i != count - 1);
+          if !emu.run_frame() { continue; }
+          #[cfg(feature = "scripting")]
+          if let Some(Err(e)) = script.as_mut().map(|s| s.on_frame(&mut emu)) {
+            emu.get_osd().message(e.to_string());
+            script = None;
+          }
+          samples.push(emu.get_samples());
+        }
+
+        emu.get_osd().tick();
+        let screen = emu.get_screen();
+        let output = Output {
+          samples,
+          screen: screen.crop(0, 0, screen.width, screen.height),
+          osd: emu.get_osd().clone(),
+          total_frames: emu.total_frames(),
+          paused: emu.is_paused(),
+          rumble: emu.rumble_state(),
+        };
+        if outputs.send(output).is_err() { break; }
+      }
+    }
+  }
+  emu
+}
+
+#[cfg(test)]
+mod emu_thread_tests {
+  use super::*;
+  use crate::testing::{asm, RomBuilder};
+
+  fn spawn() -> EmuThread {
+    let rom = RomBuilder::new()
+      .code(&asm![
+        "loop:",
+        "JR loop",
+      ])
+      .build();
+    EmuThread::spawn(Gameboy::boot_from_bytes(&rom).unwrap())
+  }
+
+  #[test]
+  fn commands() {
+    let emu = spawn();
+    emu.run_frames(3);
+    let output = emu.recv().unwrap();
+    assert_eq!(output.samples.len(), 3);
+    assert_eq!(output.total_frames, 3);
+    assert!(!output.samples[0].is_empty());
+
+    emu.set_paused(true);
+    emu.run_frames(1);
+    let output = emu.recv().unwrap();
+    assert!(output.paused && output.samples.is_empty());
+    assert_eq!(output.total_frames, 3);
+
+    let released = emu.with(|gb| gb.get_joypad().state());
+    emu.press(Button::Start);
+    assert_ne!(emu.with(|gb| gb.get_joypad().state()), released);
+    emu.release(Button::Start);
+    assert_eq!(emu.with(|gb| gb.get_joypad().state()), released);
+
+    emu.queue(|gb| gb.get_osd().message("hi"));
+    emu.run_frames(1);
+    assert!(!emu.recv().unwrap().osd.is_empty());
+
+    emu.reset();
+    emu.set_paused(false);
+    emu.run_frames(1);
+    assert_eq!(emu.recv().unwrap().total_frames, 1);
+
+    assert_eq!(emu.stop().total_frames(), 1);
+  }
+}
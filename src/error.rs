@@ -0,0 +1,71 @@
+use std::{fmt, io};
+
+/// Errors raised while loading a ROM, or the files that go with it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+  /// The file is too small to hold a header, or smaller than the header says.
+  RomTooSmall { len: usize, expected: usize },
+  /// The Nintendo logo at 0x104 doesn't match.
+  MissingLogo,
+  /// The header checksum at 0x14D doesn't match the header bytes.
+  ChecksumMismatch { expected: u8, computed: u8 },
+  /// A header field holds an unknown value.
+  InvalidHeader(&'static str),
+  /// The cart type is valid, but its mapper isn't emulated.
+  UnsupportedMapper(u8),
+  /// The ROM file couldn't be read.
+  Io(String),
+  /// The ROM archive is corrupted, holds no ROM, or one bigger than a cart can be.
+  Archive(String),
+  /// A movie file line can't be parsed.
+  InvalidMovie { line: usize },
+  /// The movie was recorded with another ROM.
+  MovieMismatch { title: String },
+  /// The cheat code is neither a GameShark nor a Game Genie one, or aims outside the memory it may patch.
+  InvalidCheat(String),
+  /// The script doesn't compile, or failed while running.
+  Script(String),
+  /// The save state is corrupted, or from an incompatible version.
+  InvalidState(String),
+  /// The save state was saved with another ROM.
+  StateMismatch { title: String },
+  /// The boot ROM is neither a DMG (256 bytes) nor a CGB (2304 bytes) one.
+  InvalidBootRom { len: usize },
+  /// The game database can't be parsed.
+  InvalidGameDb(String),
+  /// An IPS or BPS patch is malformed, or made for another ROM.
+  InvalidPatch(String),
+}
+
+impl fmt::Display for Error {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Error::RomTooSmall { len, expected } =>
+        write!(f, "ROM is too small: {len} bytes, expected {expected}"),
+      Error::MissingLogo => write!(f, "Nintendo logo not found"),
+      Error::ChecksumMismatch { expected, computed } =>
+        write!(f, "Invalid header checksum: expected {expected:#04X}, computed {computed:#04X}"),
+      Error::InvalidHeader(field) => write!(f, "Invalid header: {field}"),
+      Error::UnsupportedMapper(code) => write!(f, "Mapper {code:#04X} not implemented"),
+      Error::Io(e) => write!(f, "Couldn't read ROM: {e}"),
+      Error::Archive(e) => write!(f, "Couldn't extract ROM: {e}"),
+      Error::InvalidMovie { line } => write!(f, "Invalid movie file at line {line}"),
+      Error::MovieMismatch { title } => write!(f, "The movie was recorded with another ROM: {title}"),
+      Error::InvalidCheat(code) => write!(f, "Invalid cheat code: {code}"),
+      Error::Script(e) => write!(f, "Script error: {e}"),
+      Error::InvalidState(e) => write!(f, "Invalid save state: {e}"),
+      Error::StateMismatch { title } => write!(f, "The state was saved with another ROM: {title}"),
+      Error::InvalidBootRom { len } => write!(f, "Invalid boot ROM: {len} bytes, expected 256 or 2304"),
+      Error::InvalidGameDb(e) => write!(f, "Invalid game database: {e}"),
+      Error::InvalidPatch(e) => write!(f, "Invalid patch: {e}"),
+    }
+  }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+  fn from(e: io::Error) -> Self {
+    Error::Io(e.to_string())
+  }
+}
@@ -0,0 +1,259 @@
+//! C bindings, declared in `include/tomboy.h`.
+//!
+//! Every function takes the handle returned by [`tomboy_create`], and most return a [`TomboyStatus`].
+//! Build the library with `cargo rustc --lib --features ffi --crate-type staticlib` (or `cdylib`).
+//! The header is generated with `cbindgen --config cbindgen.toml --output include/tomboy.h src/ffi.rs`.
+
+use std::{ptr, slice};
+
+use crate::{cart::LoadOptions, gb::Gameboy, joypad::Button};
+
+pub const TOMBOY_BUTTON_UP: u32 = 0;
+pub const TOMBOY_BUTTON_DOWN: u32 = 1;
+pub const TOMBOY_BUTTON_LEFT: u32 = 2;
+pub const TOMBOY_BUTTON_RIGHT: u32 = 3;
+pub const TOMBOY_BUTTON_A: u32 = 4;
+pub const TOMBOY_BUTTON_B: u32 = 5;
+pub const TOMBOY_BUTTON_START: u32 = 6;
+pub const TOMBOY_BUTTON_SELECT: u32 = 7;
+
+#[repr(C)]
+#[derive(Debug, PartialEq, Eq)]
+pub enum TomboyStatus {
+  Ok = 0,
+  NullPointer,
+  /// No ROM was loaded with `tomboy_load_rom`
+  NoRom,
+  /// The ROM couldn't be loaded
+  InvalidRom,
+  InvalidButton,
+  BufferTooSmall,
+  /// The save state is corrupted, or was saved with another ROM or version
+  InvalidState,
+}
+
+/// An emulator instance, opaque to C.
+pub struct Tomboy {
+  gb: Option<Gameboy>,
+}
+
+impl Tomboy {
+  fn gameboy(&mut self) -> Result<&mut Gameboy, TomboyStatus> {
+    self.gb.as_mut().ok_or(TomboyStatus::NoRom)
+  }
+}
+
+/// # Safety
+/// `tomboy` must be null or come from [`tomboy_create`], and not be destroyed yet.
+unsafe fn with_gameboy(tomboy: *mut Tomboy, f: impl FnOnce(&mut Gameboy) -> TomboyStatus) -> TomboyStatus {
+  let Some(tomboy) = (unsafe { tomboy.as_mut() }) else { return TomboyStatus::NullPointer };
+  match tomboy.gameboy() {
+    Ok(gb) => f(gb),
+    Err(status) => status,
+  }
+}
+
+/// Creates an emulator without a ROM. Free it with [`tomboy_destroy`].
+#[no_mangle]
+pub extern "C" fn tomboy_create() -> *mut Tomboy {
+  Box::into_raw(Box::new(Tomboy { gb: None }))
+}
+
+/// # Safety
+/// `tomboy` must be null or come from [`tomboy_create`], and not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn tomboy_destroy(tomboy: *mut Tomboy) {
+  if !tomboy.is_null() {
+    drop(unsafe { Box::from_raw(tomboy) });
+  }
+}
+
+/// Boots a ROM, with relaxed header checks. The bytes are copied.
+///
+/// # Safety
+/// `rom` must point to `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn tomboy_load_rom(tomboy: *mut Tomboy, rom: *const u8, len: usize) -> TomboyStatus {
+  let Some(tomboy) = (unsafe { tomboy.as_mut() }) else { return TomboyStatus::NullPointer };
+  if rom.is_null() { return TomboyStatus::NullPointer; }
+
+  let rom = unsafe { slice::from_raw_parts(rom, len) };
+  match Gameboy::boot_with_options(rom, &LoadOptions::tolerant()) {
+    Ok(gb) => {
+      tomboy.gb = Some(gb);
+      TomboyStatus::Ok
+    }
+    Err(_) => TomboyStatus::InvalidRom,
+  }
+}
+
+/// Runs until the next frame is completed.
+///
+/// # Safety
+/// `tomboy` must come from [`tomboy_create`].
+#[no_mangle]
+pub unsafe extern "C" fn tomboy_run_frame(tomboy: *mut Tomboy) -> TomboyStatus {
+  unsafe { with_gameboy(tomboy, |gb| { gb.step_until_vblank(); TomboyStatus::Ok }) }
+}
+
+/// # Safety
+/// `tomboy` must come from [`tomboy_create`].
+#[no_mangle]
+pub unsafe extern "C" fn tomboy_reset(tomboy: *mut Tomboy) -> TomboyStatus {
+  unsafe { with_gameboy(tomboy, |gb| { gb.reset(); TomboyStatus::Ok }) }
+}
+
+/// Returns the RGBA screen, `width * height * 4` bytes, and writes its size to `width` and `height` if they aren't null.
+/// The pointer is valid until the next call taking `tomboy`. Returns null if no ROM is loaded.
+///
+/// # Safety
+/// `tomboy` must come from [`tomboy_create`], `width` and `height` must be null or writable.
+#[no_mangle]
+pub unsafe extern "C" fn tomboy_get_framebuffer(tomboy: *mut Tomboy, width: *mut usize, height: *mut usize) -> *const u8 {
+  let mut res = ptr::null();
+  unsafe {
+    with_gameboy(tomboy, |gb| {
+      let screen = gb.get_screen();
+      if !width.is_null() { *width = screen.width; }
+      if !height.is_null() { *height = screen.height; }
+      res = screen.buffer.as_ptr();
+      TomboyStatus::Ok
+    });
+  }
+  res
+}
+
+/// Presses or releases a button, one of the `TOMBOY_BUTTON_` constants.
+///
+/// # Safety
+/// `tomboy` must come from [`tomboy_create`].
+#[no_mangle]
+pub unsafe extern "C" fn tomboy_set_button(tomboy: *mut Tomboy, button: u32, pressed: bool) -> TomboyStatus {
+  let Some(&button) = Button::ALL.get(button as usize) else { return TomboyStatus::InvalidButton };
+  unsafe {
+    with_gameboy(tomboy, |gb| {
+      match pressed {
+        true => gb.get_joypad().press(button),
+        false => gb.get_joypad().release(button),
+      }
+      TomboyStatus::Ok
+    })
+  }
+}
+
+/// Drains up to `len` audio samples into `out`, interleaved stereo at 44100 Hz,
+/// and writes how many were written to `written`. Samples that don't fit are kept for the next call.
+///
+/// # Safety
+/// `tomboy` must come from [`tomboy_create`], `out` must point to `len` writable floats, `written` must be writable.
+#[no_mangle]
+pub unsafe extern "C" fn tomboy_get_samples(tomboy: *mut Tomboy, out: *mut f32, len: usize, written: *mut usize) -> TomboyStatus {
+  if out.is_null() || written.is_null() { return TomboyStatus::NullPointer; }
+  let out = unsafe { slice::from_raw_parts_mut(out, len) };
+
+  unsafe {
+    with_gameboy(tomboy, |gb| {
+      *written = gb.get_apu().drain_samples_to_slice(out);
+      TomboyStatus::Ok
+    })
+  }
+}
+
+/// Size of the buffer needed by [`tomboy_save_state`]. It changes as the emulation runs, ask again before saving.
+///
+/// # Safety
+/// `tomboy` must come from [`tomboy_create`], `size` must be writable.
+#[no_mangle]
+pub unsafe extern "C" fn tomboy_save_state_size(tomboy: *mut Tomboy, size: *mut usize) -> TomboyStatus {
+  if size.is_null() { return TomboyStatus::NullPointer; }
+  unsafe {
+    with_gameboy(tomboy, |gb| {
+      *size = gb.save_state().len();
+      TomboyStatus::Ok
+    })
+  }
+}
+
+/// Writes the emulator state to `buf`, the state size is written to `written`.
+///
+/// # Safety
+/// `tomboy` must come from [`tomboy_create`], `buf` must point to `len` writable bytes, `written` must be writable.
+#[no_mangle]
+pub unsafe extern "C" fn tomboy_save_state(tomboy: *mut Tomboy, buf: *mut u8, len: usize, written: *mut usize) -> TomboyStatus {
+  if buf.is_null() || written.is_null() { return TomboyStatus::NullPointer; }
+  let buf = unsafe { slice::from_raw_parts_mut(buf, len) };
+
+  unsafe {
+    with_gameboy(tomboy, |gb| {
+      let state = gb.save_state();
+      let Some(dst) = buf.get_mut(..state.len()) else { return TomboyStatus::BufferTooSmall };
+      dst.copy_from_slice(&state);
+      *written = state.len();
+      TomboyStatus::Ok
+    })
+  }
+}
+
+/// Restores a state written by [`tomboy_save_state`].
+///
+/// # Safety
+/// `tomboy` must come from [`tomboy_create`], `buf` must point to `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn tomboy_load_state(tomboy: *mut Tomboy, buf: *const u8, len: usize) -> TomboyStatus {
+  if buf.is_null() { return TomboyStatus::NullPointer; }
+  let buf = unsafe { slice::from_raw_parts(buf, len) };
+
+  unsafe {
+    with_gameboy(tomboy, |gb| match gb.load_state(buf) {
+      Ok(()) => TomboyStatus::Ok,
+      Err(_) => TomboyStatus::InvalidState,
+    })
+  }
+}
+
+#[cfg(test)]
+mod ffi_tests {
+  use super::*;
+  use crate::testing::{asm, RomBuilder};
+
+  #[test]
+  fn c_api() {
+    let rom = RomBuilder::new().code(&asm!["loop:", "JR loop"]).build();
+    unsafe {
+      let tomboy = tomboy_create();
+      assert_eq!(tomboy_run_frame(tomboy), TomboyStatus::NoRom);
+      assert_eq!(tomboy_run_frame(ptr::null_mut()), TomboyStatus::NullPointer);
+      assert_eq!(tomboy_load_rom(tomboy, rom.as_ptr(), 0x100), TomboyStatus::InvalidRom);
+      assert_eq!(tomboy_load_rom(tomboy, rom.as_ptr(), rom.len()), TomboyStatus::Ok);
+
+      for _ in 0..3 {
+        assert_eq!(tomboy_run_frame(tomboy), TomboyStatus::Ok);
+      }
+      let (mut width, mut height) = (0, 0);
+      let screen = tomboy_get_framebuffer(tomboy, &mut width, &mut height);
+      assert!(!screen.is_null());
+      assert_eq!((width, height), (160, 144));
+      assert_eq!(*screen.add(3), 0xFF);
+
+      assert_eq!(tomboy_set_button(tomboy, TOMBOY_BUTTON_START, true), TomboyStatus::Ok);
+      assert_eq!(tomboy_set_button(tomboy, 8, true), TomboyStatus::InvalidButton);
+      assert_eq!((*tomboy).gb.as_mut().unwrap().get_joypad().state().0, crate::joypad::Flags::start_down);
+
+      let mut samples = [0.0; 4096];
+      let mut written = 0;
+      assert_eq!(tomboy_get_samples(tomboy, samples.as_mut_ptr(), samples.len(), &mut written), TomboyStatus::Ok);
+      assert!(written > 0 && written % 2 == 0);
+
+      let mut size = 0;
+      assert_eq!(tomboy_save_state_size(tomboy, &mut size), TomboyStatus::Ok);
+      let mut state = vec![0; size];
+      assert_eq!(tomboy_save_state(tomboy, state.as_mut_ptr(), 10, &mut written), TomboyStatus::BufferTooSmall);
+      assert_eq!(tomboy_save_state(tomboy, state.as_mut_ptr(), size, &mut written), TomboyStatus::Ok);
+      assert_eq!(written, size);
+      assert_eq!(tomboy_load_state(tomboy, state.as_ptr(), size), TomboyStatus::Ok);
+      assert_eq!(tomboy_load_state(tomboy, state.as_ptr(), 10), TomboyStatus::InvalidState);
+
+      tomboy_destroy(tomboy);
+    }
+  }
+}
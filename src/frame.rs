@@ -1,48 +1,345 @@
-const PALETTE: [(u8, u8, u8); 4] = [
+use crate::state::Fnv1a;
+
+/// An RGB color.
+pub type Color = (u8, u8, u8);
+
+/// The colors of the 4 shades, from lightest to darkest.
+pub type Palette = [Color; 4];
+
+/// The original DMG LCD green.
+pub const PEA_GREEN: Palette = [
   (155,188,15),
   (139,172,15),
   (48,98,48),
   (15,56,15),
 ];
 
-const PIXEL_BYTES: usize = 4;
+pub const GRAYSCALE: Palette = [
+  (255,255,255),
+  (170,170,170),
+  (85,85,85),
+  (0,0,0),
+];
+
+/// The Gameboy Pocket LCD.
+pub const GB_POCKET: Palette = [
+  (196,207,161),
+  (139,149,109),
+  (77,83,60),
+  (31,31,31),
+];
+
+/// The colors a Gameboy Color uses for DMG games it has no palette for.
+pub const GBC_DEFAULT: Palette = [
+  (255,255,255),
+  (123,255,49),
+  (0,99,197),
+  (0,0,0),
+];
+
+/// Mixes `t` (0.0 to 1.0) of `b` into `a`.
+pub fn mix(a: Color, b: Color, t: f32) -> Color {
+  let channel = |a: u8, b: u8| (a as f32 + (b as f32 - a as f32) * t).round() as u8;
+  (channel(a.0, b.0), channel(a.1, b.1), channel(a.2, b.2))
+}
+
+/// The palettes, by the names used in config files.
+pub const PALETTES: [(&str, Palette); 4] = [
+  ("pea-green", PEA_GREEN),
+  ("grayscale", GRAYSCALE),
+  ("gb-pocket", GB_POCKET),
+  ("gbc", GBC_DEFAULT),
+];
+
+pub const LCD_WIDTH: usize = 160;
+pub const LCD_HEIGHT: usize = 144;
+
+/// The layout of the pixels in [`FrameBuffer::buffer`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PixelFormat {
+  /// 4 bytes per pixel: red, green, blue, alpha
+  #[default]
+  Rgba32,
+  /// 4 bytes per pixel: blue, green, red, alpha
+  Bgra32,
+  /// 3 bytes per pixel: red, green, blue
+  Rgb24,
+  /// 2 bytes per pixel, little endian: 5 bits red, 6 bits green, 5 bits blue (from msb to lsb)
+  Rgb565,
+}
+
+impl PixelFormat {
+  pub fn bytes_per_pixel(&self) -> usize {
+    match self {
+      PixelFormat::Rgba32 | PixelFormat::Bgra32 => 4,
+      PixelFormat::Rgb24 => 3,
+      PixelFormat::Rgb565 => 2,
+    }
+  }
+
+  fn decode(&self, pixel: &[u8]) -> Color {
+    match self {
+      PixelFormat::Rgba32 | PixelFormat::Rgb24 => (pixel[0], pixel[1], pixel[2]),
+      PixelFormat::Bgra32 => (pixel[2], pixel[1], pixel[0]),
+      PixelFormat::Rgb565 => {
+        let rgb = u16::from_le_bytes([pixel[0], pixel[1]]);
+        // the low bits are filled with the high ones, so full intensity stays 255
+        let expand = |v: u16, bits: u32| ((v << (8 - bits)) | (v >> (2*bits - 8))) as u8;
+        (expand(rgb >> 11, 5), expand((rgb >> 5) & 0x3F, 6), expand(rgb & 0x1F, 5))
+      }
+    }
+  }
+}
+
+/// An RGB image, 3 bytes per pixel, row by row.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Screenshot {
+  pub width: usize,
+  pub height: usize,
+  pub rgb: Vec<u8>,
+}
+
+impl Screenshot {
+  #[cfg(feature = "png")]
+  pub fn save_png(&self, path: impl AsRef<std::path::Path>) -> std::io::Result<()> {
+    let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+    let mut encoder = png::Encoder::new(file, self.width as u32, self.height as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    let mut writer = encoder.write_header().map_err(std::io::Error::other)?;
+    writer.write_image_data(&self.rgb).map_err(std::io::Error::other)?;
+    writer.finish().map_err(std::io::Error::other)
+  }
+}
+
+
 pub struct FrameBuffer {
     pub buffer: Vec<u8>,
+    /// The shade (0-3) of every pixel, before the palette is applied. One byte per pixel.
+    pub indexed: Vec<u8>,
     pub width: usize,
     pub height: usize,
+    palette: Palette,
+    format: PixelFormat,
+    /// How much of the previous frame is mixed into each new one, in percent
+    blend: u8,
+    /// The previous frame, as drawn before blending
+    previous: Vec<u8>,
 }
 
 impl FrameBuffer {
   pub fn new(width: usize, height: usize) -> Self {
-    let buffer = vec![0; width * height * PIXEL_BYTES];
-    Self { buffer, width, height }
+    Self::with_format(width, height, PixelFormat::default())
+  }
+
+  pub fn with_format(width: usize, height: usize, format: PixelFormat) -> Self {
+    let buffer = vec![0; width * height * format.bytes_per_pixel()];
+    let indexed = vec![0; width * height];
+    Self { buffer, indexed, width, height, palette: PEA_GREEN, format, blend: 0, previous: Vec::new() }
   }
 
   pub fn gameboy_lcd() -> Self {
-    Self::new(160, 144)
+    Self::new(LCD_WIDTH, LCD_HEIGHT)
+  }
+
+  /// A 256x256 buffer, big enough for the whole background map drawn by
+  /// [`Ppu::debug_render_tilemap`](crate::ppu::Ppu::debug_render_tilemap).
+  pub fn debug_full_bg() -> Self {
+    Self::new(256, 256)
+  }
+
+  /// A 64-bit hash of the shades of the frame, the same whatever the palette and pixel format.
+  /// Cheap enough to run every frame, to catch two runs drifting apart.
+  pub fn hash(&self) -> u64 {
+    let mut hash = Fnv1a::default();
+    hash.update(&self.indexed);
+    hash.0
+  }
+
+  /// The frame with the current palette applied, whatever the pixel format.
+  /// Pixels drawn with [`FrameBuffer::set_color`] are taken with their shade.
+  pub fn screenshot(&self) -> Screenshot {
+    let rgb = self.indexed.iter()
+      .flat_map(|shade| {
+        let (r, g, b) = self.palette[*shade as usize];
+        [r, g, b]
+      })
+      .collect();
+
+    Screenshot { width: self.width, height: self.height, rgb }
+  }
+
+  /// The frame as displayed, including the pixels drawn with [`FrameBuffer::set_color`].
+  pub fn color_screenshot(&self) -> Screenshot {
+    let rgb = (0..self.height)
+      .flat_map(|y| (0..self.width).map(move |x| (x, y)))
+      .flat_map(|(x, y)| {
+        let (r, g, b) = self.get_color(x, y);
+        [r, g, b]
+      })
+      .collect();
+
+    Screenshot { width: self.width, height: self.height, rgb }
+  }
+
+  /// Copies a `width`x`height` area starting at `x`,`y`, wrapping around the edges like the background does.
+  pub fn crop(&self, x: usize, y: usize, width: usize, height: usize) -> Self {
+    let mut res = Self::with_format(width, height, self.format);
+    res.palette = self.palette;
+    let bytes = self.format.bytes_per_pixel();
+
+    for row in 0..height {
+      for col in 0..width {
+        let src = ((y + row) % self.height) * self.width + (x + col) % self.width;
+        let dst = row * width + col;
+        res.indexed[dst] = self.indexed[src];
+        res.buffer[dst*bytes..(dst+1)*bytes].copy_from_slice(&self.buffer[src*bytes..(src+1)*bytes]);
+      }
+    }
+    res
+  }
+
+  pub fn format(&self) -> PixelFormat {
+    self.format
   }
 
   pub fn pitch(&self) -> usize {
-    self.width * PIXEL_BYTES
+    self.width * self.format.bytes_per_pixel()
   }
 
   pub fn reset(&mut self) {
-    for idx in (0..self.buffer.len()).step_by(4) {
-      let color = &PALETTE[0];
-      self.buffer[idx + 0] = color.0;
-      self.buffer[idx + 1] = color.1;
-      self.buffer[idx + 2] = color.2;
-      self.buffer[idx + 3] = 255;
+    self.indexed.fill(0);
+    for i in 0..self.indexed.len() {
+      self.write_color(i, self.palette[0]);
+    }
+  }
+
+  /// Mixes `percent` of the previous frame into each new one, like the slow LCD of the Gameboy does.
+  /// Games flickering sprites every other frame for transparency look as intended with 50.
+  /// Only [`FrameBuffer::buffer`] is blended, the shades and screenshots are of the last frame alone.
+  pub fn set_frame_blend(&mut self, percent: u8) {
+    self.blend = percent.min(100);
+    self.previous.clear();
+  }
+
+  pub fn frame_blend(&self) -> u8 {
+    self.blend
+  }
+
+  /// Blends the frame just drawn with the previous one, see [`FrameBuffer::set_frame_blend`].
+  pub(crate) fn blend_frame(&mut self) {
+    if self.blend == 0 { return; }
+    if self.previous.len() != self.buffer.len() {
+      self.previous = self.buffer.clone();
+      return;
+    }
+
+    let bytes = self.format.bytes_per_pixel();
+    let t = self.blend as f32 / 100.0;
+    for i in 0..self.indexed.len() {
+      let range = i*bytes..(i+1)*bytes;
+      let current = self.format.decode(&self.buffer[range.clone()]);
+      let previous = self.format.decode(&self.previous[range.clone()]);
+      self.previous[range.clone()].copy_from_slice(&self.buffer[range]);
+      self.write_color(i, mix(current, previous, t));
+    }
+  }
+
+  /// The RGB color a shade (0 lightest, 3 darkest) is displayed with.
+  pub fn color(&self, shade: u8) -> Color {
+    self.palette[shade as usize]
+  }
+
+  pub fn palette(&self) -> Palette {
+    self.palette
+  }
+
+  /// Changes the colors of the shades, and redraws the frame with them.
+  /// Pixels drawn with [`FrameBuffer::set_color`] are redrawn with their previous shade.
+  pub fn set_palette(&mut self, palette: Palette) {
+    self.palette = palette;
+    for i in 0..self.indexed.len() {
+      self.write_color(i, palette[self.indexed[i] as usize]);
     }
   }
 
   pub fn set_pixel(&mut self, x: usize, y: usize, color_id: u8) {
-    let color = &PALETTE[color_id as usize];
-    let idx = (y*self.width + x) * PIXEL_BYTES;
-    self.buffer[idx + 0] = color.0;
-    self.buffer[idx + 1] = color.1;
-    self.buffer[idx + 2] = color.2;
-    self.buffer[idx + 3] = 255;
+    self.indexed[y*self.width + x] = color_id;
+    self.set_color(x, y, self.palette[color_id as usize]);
+  }
+
+  /// Sets a pixel to a color outside of the palette, for debug overlays.
+  /// Its shade in [`FrameBuffer::indexed`] isn't changed.
+  pub fn set_color(&mut self, x: usize, y: usize, color: Color) {
+    self.write_color(y*self.width + x, color);
+  }
+
+  fn write_color(&mut self, i: usize, (r, g, b): Color) {
+    let idx = i * self.format.bytes_per_pixel();
+    let pixel = &mut self.buffer[idx..idx + self.format.bytes_per_pixel()];
+    match self.format {
+      PixelFormat::Rgba32 => pixel.copy_from_slice(&[r, g, b, 255]),
+      PixelFormat::Bgra32 => pixel.copy_from_slice(&[b, g, r, 255]),
+      PixelFormat::Rgb24 => pixel.copy_from_slice(&[r, g, b]),
+      PixelFormat::Rgb565 => {
+        let rgb = (r as u16 >> 3) << 11 | (g as u16 >> 2) << 5 | b as u16 >> 3;
+        pixel.copy_from_slice(&rgb.to_le_bytes());
+      }
+    }
+  }
+
+  /// Draws text over the screen, for overlays. Lowercase letters are drawn uppercase,
+  /// unknown characters as `?`, and `\n` starts a new line. The text is clipped to the screen.
+  /// Each glyph cell is filled with `bg` first, if given. Like [`FrameBuffer::set_color`], the shades are untouched.
+  /// The font is 3x5, drawn in cells of [`TEXT_CELL_SIZE`].
+  pub fn draw_text(&mut self, x: i32, y: i32, text: &str, fg: Color, bg: Option<Color>) {
+    self.blend_text(x, y, text, fg, bg, 1.0);
+  }
+
+  /// Like [`FrameBuffer::draw_text`], blended with what is under it by `alpha` (1.0 is opaque).
+  pub fn blend_text(&mut self, x: i32, y: i32, text: &str, fg: Color, bg: Option<Color>, alpha: f32) {
+    let (cell_w, cell_h) = TEXT_CELL_SIZE;
+    let (mut cx, mut cy) = (x, y);
+
+    for c in text.chars() {
+      if c == '\n' {
+        cx = x;
+        cy += cell_h as i32;
+        continue;
+      }
+
+      let c = c.to_ascii_uppercase();
+      let glyph = FONT.iter()
+        .find(|(g, _)| *g == c)
+        .or_else(|| FONT.iter().find(|(g, _)| *g == '?'))
+        .map(|(_, rows)| rows)
+        .unwrap();
+
+      for row in 0..cell_h {
+        for col in 0..cell_w {
+          let (px, py) = (cx + col as i32, cy + row as i32);
+          if px < 0 || py < 0 || px as usize >= self.width || py as usize >= self.height { continue; }
+          let (px, py) = (px as usize, py as usize);
+
+          let lit = col < 3 && glyph.get(row).is_some_and(|bits| bits & (0b100 >> col) != 0);
+          let color = match (lit, bg) {
+            (true, _) => fg,
+            (false, Some(bg)) => bg,
+            _ => continue,
+          };
+          let color = if alpha < 1.0 { mix(self.get_color(px, py), color, alpha) } else { color };
+          self.set_color(px, py, color);
+        }
+      }
+      cx += cell_w as i32;
+    }
+  }
+
+  /// The color of a pixel, as written in [`FrameBuffer::buffer`].
+  pub fn get_color(&self, x: usize, y: usize) -> Color {
+    let idx = (y*self.width + x) * self.format.bytes_per_pixel();
+    self.format.decode(&self.buffer[idx..idx + self.format.bytes_per_pixel()])
   }
 
   pub fn set_tile(&mut self, x: usize, y: usize, tile: &[u8]) {
@@ -57,4 +354,63 @@ impl FrameBuffer {
       }
     }
   }
-}
\ No newline at end of file
+}
+
+/// A 3x5 font, one byte per row with the leftmost pixel in bit 2.
+const FONT: &[(char, [u8; 5])] = &[
+  ('0', [0b111, 0b101, 0b101, 0b101, 0b111]),
+  ('1', [0b010, 0b110, 0b010, 0b010, 0b111]),
+  ('2', [0b111, 0b001, 0b111, 0b100, 0b111]),
+  ('3', [0b111, 0b001, 0b111, 0b001, 0b111]),
+  ('4', [0b101, 0b101, 0b111, 0b001, 0b001]),
+  ('5', [0b111, 0b100, 0b111, 0b001, 0b111]),
+  ('6', [0b111, 0b100, 0b111, 0b101, 0b111]),
+  ('7', [0b111, 0b001, 0b001, 0b001, 0b001]),
+  ('8', [0b111, 0b101, 0b111, 0b101, 0b111]),
+  ('9', [0b111, 0b101, 0b111, 0b001, 0b111]),
+  ('A', [0b010, 0b101, 0b111, 0b101, 0b101]),
+  ('B', [0b110, 0b101, 0b110, 0b101, 0b110]),
+  ('C', [0b011, 0b100, 0b100, 0b100, 0b011]),
+  ('D', [0b110, 0b101, 0b101, 0b101, 0b110]),
+  ('E', [0b111, 0b100, 0b110, 0b100, 0b111]),
+  ('F', [0b111, 0b100, 0b110, 0b100, 0b100]),
+  ('G', [0b011, 0b100, 0b101, 0b101, 0b011]),
+  ('H', [0b101, 0b101, 0b111, 0b101, 0b101]),
+  ('I', [0b111, 0b010, 0b010, 0b010, 0b111]),
+  ('J', [0b001, 0b001, 0b001, 0b101, 0b010]),
+  ('K', [0b101, 0b101, 0b110, 0b101, 0b101]),
+  ('L', [0b100, 0b100, 0b100, 0b100, 0b111]),
+  ('M', [0b101, 0b111, 0b111, 0b101, 0b101]),
+  ('N', [0b110, 0b101, 0b101, 0b101, 0b101]),
+  ('O', [0b010, 0b101, 0b101, 0b101, 0b010]),
+  ('P', [0b110, 0b101, 0b110, 0b100, 0b100]),
+  ('Q', [0b010, 0b101, 0b101, 0b110, 0b011]),
+  ('R', [0b110, 0b101, 0b110, 0b101, 0b101]),
+  ('S', [0b011, 0b100, 0b010, 0b001, 0b110]),
+  ('T', [0b111, 0b010, 0b010, 0b010, 0b010]),
+  ('U', [0b101, 0b101, 0b101, 0b101, 0b111]),
+  ('V', [0b101, 0b101, 0b101, 0b101, 0b010]),
+  ('W', [0b101, 0b101, 0b111, 0b111, 0b101]),
+  ('X', [0b101, 0b101, 0b010, 0b101, 0b101]),
+  ('Y', [0b101, 0b101, 0b010, 0b010, 0b010]),
+  ('Z', [0b111, 0b001, 0b010, 0b100, 0b111]),
+  (' ', [0b000, 0b000, 0b000, 0b000, 0b000]),
+  ('.', [0b000, 0b000, 0b000, 0b000, 0b010]),
+  (',', [0b000, 0b000, 0b000, 0b010, 0b100]),
+  (':', [0b000, 0b010, 0b000, 0b010, 0b000]),
+  ('-', [0b000, 0b000, 0b111, 0b000, 0b000]),
+  ('+', [0b000, 0b010, 0b111, 0b010, 0b000]),
+  ('=', [0b000, 0b111, 0b000, 0b111, 0b000]),
+  ('!', [0b010, 0b010, 0b010, 0b000, 0b010]),
+  ('?', [0b110, 0b001, 0b010, 0b000, 0b010]),
+  ('/', [0b001, 0b001, 0b010, 0b100, 0b100]),
+  ('%', [0b101, 0b001, 0b010, 0b100, 0b101]),
+  ('(', [0b001, 0b010, 0b010, 0b010, 0b001]),
+  (')', [0b100, 0b010, 0b010, 0b010, 0b100]),
+  ('<', [0b001, 0b010, 0b100, 0b010, 0b001]),
+  ('>', [0b100, 0b010, 0b001, 0b010, 0b100]),
+  ('\'', [0b010, 0b010, 0b000, 0b000, 0b000]),
+  ('_', [0b000, 0b000, 0b000, 0b000, 0b111]),
+];
+/// Glyphs are drawn in cells with a pixel of spacing.
+pub const TEXT_CELL_SIZE: (usize, usize) = (4, 6);
@@ -0,0 +1,348 @@
+use std::{collections::HashMap, error::Error, fs, path::{Path, PathBuf}};
+
+use sdl2::{controller, keyboard::Keycode};
+use serde::Deserialize;
+use tomboy_emulator::{frame::{Color, Palette, PALETTES}, joypad::Button, video::{self, FilterChain, VideoFilter}};
+
+use crate::pacing::SyncMode;
+
+/// The configuration shipped with the sources, used when there is no config file.
+const DEFAULT: &str = include_str!("../../config.toml");
+
+/// How many ROMs are remembered in `recent_roms`.
+const MAX_RECENT: usize = 10;
+
+/// What a key or controller button does.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Action {
+  Up, Down, Left, Right, A, B, Start, Select,
+  Reset,
+  /// Runs faster while held
+  FastForward,
+  Pause,
+  /// Runs a single frame, pausing if needed
+  FrameAdvance,
+  /// Toggles slow motion
+  SlowMotion,
+  /// Toggles auto-fire of A
+  TurboA,
+  /// Toggles auto-fire of B
+  TurboB,
+  /// Saves to the selected slot
+  SaveState,
+  /// Loads from the selected slot
+  LoadState,
+  /// Selects a save state slot, from 0 to 9
+  SelectSlot(u8),
+  NextPalette,
+  Screenshot,
+  ReloadConfig,
+  /// Toggles fullscreen
+  Fullscreen,
+  /// Starts or stops recording a video
+  Record,
+}
+
+impl Action {
+  pub fn button(self) -> Option<Button> {
+    let button = match self {
+      Action::Up => Button::Up,
+      Action::Down => Button::Down,
+      Action::Left => Button::Left,
+      Action::Right => Button::Right,
+      Action::A => Button::A,
+      Action::B => Button::B,
+      Action::Start => Button::Start,
+      Action::Select => Button::Select,
+      _ => return None,
+    };
+    Some(button)
+  }
+}
+
+/// A post-processing filter of the [`video`] module, with its default settings.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Filter {
+  LcdGrid,
+  Scanlines,
+  Ghosting,
+  Scale2x,
+  Scale3x,
+  Smooth2x,
+}
+
+impl Filter {
+  fn build(self) -> Box<dyn VideoFilter> {
+    match self {
+      Filter::LcdGrid => Box::new(video::LcdGrid::default()),
+      Filter::Scanlines => Box::new(video::Scanlines::default()),
+      Filter::Ghosting => Box::new(video::Ghosting::default()),
+      Filter::Scale2x => Box::new(video::Scale2x),
+      Filter::Scale3x => Box::new(video::Scale3x),
+      Filter::Smooth2x => Box::new(video::Smooth2x),
+    }
+  }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PaletteSetting {
+  Named(String),
+  Colors([String; 4]),
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AudioSettings {
+  pub sample_rate: u32,
+  pub volume: f32,
+  /// In milliseconds, it may not be in older config files
+  #[serde(default = "default_latency")]
+  pub latency: u32,
+}
+
+fn default_latency() -> u32 { 50 }
+
+/// Unknown keys are refused, so typos are reported instead of ignored.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+  scale: u32,
+  integer_scaling: bool,
+  fullscreen: bool,
+  filters: Vec<Filter>,
+  frame_blend: u8,
+  dmg_quirks: bool,
+  record_format: String,
+  /// Written back by the frontend, it may not be in older config files
+  #[serde(default)]
+  recent_roms: Vec<PathBuf>,
+  palette: PaletteSetting,
+  stick_deadzone: i16,
+  fast_forward_speed: u32,
+  slow_motion_factor: u32,
+  turbo_rate: u32,
+  /// Added later, it may not be in older config files
+  #[serde(default)]
+  sync: SyncMode,
+  #[serde(default)]
+  boot_rom: Option<PathBuf>,
+  audio: AudioSettings,
+  keyboard: HashMap<String, Action>,
+  controller: HashMap<String, Action>,
+}
+
+pub struct Config {
+  pub scale: u32,
+  pub integer_scaling: bool,
+  pub fullscreen: bool,
+  pub filters: Vec<Filter>,
+  pub frame_blend: u8,
+  pub dmg_quirks: bool,
+  pub record_format: String,
+  /// The last ROMs loaded, the most recent first
+  pub recent_roms: Vec<PathBuf>,
+  pub palette: Palette,
+  pub stick_deadzone: i16,
+  pub fast_forward_speed: u32,
+  pub slow_motion_factor: u32,
+  pub turbo_rate: u32,
+  pub sync: SyncMode,
+  pub boot_rom: Option<PathBuf>,
+  pub audio: AudioSettings,
+  pub keyboard: HashMap<Keycode, Action>,
+  pub controller: HashMap<controller::Button, Action>,
+}
+
+impl Default for Config {
+  fn default() -> Self {
+    Self::parse(DEFAULT).expect("the default config is valid")
+  }
+}
+
+impl Config {
+  /// Reads the config file at `path`, or the default one if it doesn't exist.
+  pub fn load(path: impl AsRef<Path>) -> Result<Self, Box<dyn Error>> {
+    match fs::read_to_string(path) {
+      Ok(text) => Self::parse(&text),
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+      Err(e) => Err(e.into()),
+    }
+  }
+
+  /// Puts `rom` first in the recent ROMs, and saves them to the config file at `path`.
+  /// Only their line is rewritten, the rest of the file is kept as it is.
+  pub fn remember_rom(&mut self, path: impl AsRef<Path>, rom: &Path) -> Result<(), Box<dyn Error>> {
+    let rom = rom.canonicalize().unwrap_or_else(|_| rom.to_path_buf());
+    self.recent_roms.retain(|recent| *recent != rom);
+    self.recent_roms.insert(0, rom);
+    self.recent_roms.truncate(MAX_RECENT);
+
+    let text = match fs::read_to_string(&path) {
+      Ok(text) => text,
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => DEFAULT.to_string(),
+      Err(e) => return Err(e.into()),
+    };
+    let roms = self.recent_roms.iter()
+      .map(|rom| toml::Value::String(rom.to_string_lossy().into_owned()))
+      .collect();
+    let line = format!("recent_roms = {}", toml::Value::Array(roms));
+
+    let mut lines: Vec<&str> = text.lines().collect();
+    match lines.iter().position(|l| l.starts_with("recent_roms")) {
+      Some(i) => lines[i] = &line,
+      None => {
+        // top level keys go before the first table
+        let i = lines.iter().position(|l| l.starts_with('[')).unwrap_or(lines.len());
+        lines.splice(i..i, [line.as_str(), ""]);
+      }
+    }
+    fs::write(path, lines.join("\n") + "\n")?;
+    Ok(())
+  }
+
+  pub fn filters(&self) -> FilterChain {
+    let mut chain = FilterChain::default();
+    for filter in &self.filters {
+      chain.push_boxed(filter.build());
+    }
+    chain
+  }
+
+  /// The size of the window for the screen shown with the filters, in the scale closest to `scale` which keeps it sharp.
+  pub fn window_size(&self, screen: (u32, u32), filtered: (u32, u32)) -> (u32, u32) {
+    let factor = (screen.0 * self.scale / filtered.0).max(1);
+    (filtered.0 * factor, filtered.1 * factor)
+  }
+
+  pub fn parse(text: &str) -> Result<Self, Box<dyn Error>> {
+    let file: ConfigFile = toml::from_str(text)?;
+
+    let palette = match file.palette {
+      PaletteSetting::Named(name) => PALETTES.iter()
+        .find(|(n, _)| *n == name)
+        .map(|(_, palette)| *palette)
+        .ok_or_else(|| format!("unknown palette {name}"))?,
+      PaletteSetting::Colors(colors) => {
+        let mut palette = Palette::default();
+        for (color, hex) in palette.iter_mut().zip(&colors) {
+          *color = parse_color(hex).ok_or_else(|| format!("invalid color {hex}"))?;
+        }
+        palette
+      }
+    };
+
+    let keyboard = file.keyboard.into_iter()
+      .map(|(name, action)| Keycode::from_name(&name)
+        .map(|key| (key, action))
+        .ok_or_else(|| format!("unknown key {name}")))
+      .collect::<Result<_, _>>()?;
+
+    let controller = file.controller.into_iter()
+      .map(|(name, action)| controller::Button::from_string(&name)
+        .map(|button| (button, action))
+        .ok_or_else(|| format!("unknown controller button {name}")))
+      .collect::<Result<_, _>>()?;
+
+    if file.scale == 0 { return Err("scale must be at least 1".into()); }
+    if file.fast_forward_speed == 0 { return Err("fast_forward_speed must be at least 1".into()); }
+
+    Ok(Self {
+      scale: file.scale,
+      integer_scaling: file.integer_scaling,
+      fullscreen: file.fullscreen,
+      filters: file.filters,
+      frame_blend: file.frame_blend,
+      dmg_quirks: file.dmg_quirks,
+      record_format: file.record_format,
+      recent_roms: file.recent_roms,
+      palette,
+      stick_deadzone: file.stick_deadzone,
+      fast_forward_speed: file.fast_forward_speed,
+      slow_motion_factor: file.slow_motion_factor,
+      turbo_rate: file.turbo_rate,
+      sync: file.sync,
+      boot_rom: file.boot_rom,
+      audio: file.audio,
+      keyboard,
+      controller,
+    })
+  }
+}
+
+/// Parses a `#RRGGBB` color.
+fn parse_color(hex: &str) -> Option<Color> {
+  let hex = hex.strip_prefix('#')?;
+  if hex.len() != 6 { return None; }
+  let channel = |i: usize| u8::from_str_radix(hex.get(i..i+2)?, 16).ok();
+  Some((channel(0)?, channel(2)?, channel(4)?))
+}
+
+#[cfg(test)]
+mod config_tests {
+  use super::*;
+
+  /// The default config with `from` replaced by `to`.
+  fn edited(from: &str, to: &str) -> String {
+    assert!(DEFAULT.contains(from));
+    DEFAULT.replacen(from, to, 1)
+  }
+
+  #[test]
+  fn parse() {
+    let config = Config::default();
+    assert_eq!((config.scale, config.fast_forward_speed, config.audio.sample_rate), (4, 4, 44100));
+    assert_eq!(config.keyboard.get(&Keycode::Z), Some(&Action::A));
+    assert_eq!(config.keyboard.get(&Keycode::Num3), Some(&Action::SelectSlot(3)));
+    assert_eq!(config.palette, PALETTES[0].1);
+
+    let config = Config::parse(&edited("palette = \"pea-green\"",
+      "palette = [\"#FFFFFF\", \"#AAAAAA\", \"#555555\", \"#000000\"]")).unwrap();
+    assert_eq!(config.palette[1], (0xAA, 0xAA, 0xAA));
+    let config = Config::parse(&edited("filters = []", "filters = [\"scale2x\", \"scanlines\"]")).unwrap();
+    assert_eq!(config.filters, [Filter::Scale2x, Filter::Scanlines]);
+  }
+
+  #[test]
+  fn defaults() {
+    // keys older config files may not have
+    let text = edited("latency = 50\n", "");
+    let text = text.replacen("recent_roms = []\n", "", 1).replacen("sync = \"audio\"\n", "", 1);
+    let config = Config::parse(&text).unwrap();
+    assert_eq!(config.audio.latency, default_latency());
+    assert!(config.recent_roms.is_empty());
+    assert_eq!(config.sync, SyncMode::default());
+    assert_eq!(config.boot_rom, None);
+  }
+
+  #[test]
+  fn invalid() {
+    for (from, to) in [
+      ("scale = 4", "scael = 4"),
+      ("latency = 50", "latency = 50\nbuffer = 2"),
+      ("scale = 4", "scale = 0"),
+      ("fast_forward_speed = 4", "fast_forward_speed = 0"),
+      ("scale = 4", "scale = \"big\""),
+      ("palette = \"pea-green\"", "palette = \"pink\""),
+      ("palette = \"pea-green\"", "palette = [\"#FFFFFF\", \"#AAAAAA\", \"#555555\", \"black\"]"),
+      ("filters = []", "filters = [\"blur\"]"),
+      ("Z = \"a\"", "Z = \"jump\""),
+      ("Z = \"a\"", "NotAKey = \"a\""),
+      ("[audio]", "volume = 1.0\n[audio]"),
+      ("dpup = \"up\"", "trigger = \"up\""),
+    ] {
+      assert!(Config::parse(&edited(from, to)).is_err(), "{to}");
+    }
+  }
+
+  #[test]
+  fn colors() {
+    assert_eq!(parse_color("#E0F8D0"), Some((0xE0, 0xF8, 0xD0)));
+    assert_eq!(parse_color("E0F8D0"), None);
+    assert_eq!(parse_color("#E0F8D"), None);
+    assert_eq!(parse_color("#E0F8DG"), None);
+    assert_eq!(parse_color("#E0F8\u{e9}"), None);
+  }
+}
@@ -0,0 +1,124 @@
+use std::{error::Error, io::{self, Write}, path::Path};
+
+use tomboy_emulator::{cart::{CartHeader, LoadOptions}, debugger::StrictMode, gb::Gameboy, instr, rom_loader};
+
+/// Runs `frames` frames without a window, then saves a screenshot.
+/// The serial output is printed while running, which is how test ROMs report their results.
+/// With `strict`, the accesses the hardware doesn't allow are logged.
+pub fn run(rom: &Path, frames: usize, screenshot: Option<&Path>, serial: bool, strict: bool) -> Result<(), Box<dyn Error>> {
+  let rom = rom_loader::load_file(rom)?;
+  let mut gb = Gameboy::boot_with_options(&rom, &LoadOptions::tolerant())?;
+  if strict {
+    gb.get_debugger().set_strict(Some(StrictMode::Log));
+  }
+  if serial {
+    gb.on_serial_byte(|byte| {
+      let mut stdout = io::stdout();
+      let _ = stdout.write_all(&[byte]);
+      let _ = stdout.flush();
+    });
+  }
+
+  for _ in 0..frames {
+    gb.step_until_vblank();
+  }
+
+  if let Some(path) = screenshot {
+    gb.save_png(path)?;
+  }
+  Ok(())
+}
+
+/// Prints `count` instructions from `addr` to `out`. Addresses in $4000-$7FFF are read from the ROM `bank`.
+pub fn disasm(out: &mut impl Write, rom: &Path, addr: u16, bank: usize, count: usize) -> Result<(), Box<dyn Error>> {
+  let rom = rom_loader::load_file(rom)?;
+  let (offset, end) = match addr {
+    0x0000..=0x3FFF => (addr as usize, 0x4000),
+    0x4000..=0x7FFF => {
+      let start = bank * 0x4000;
+      (start + addr as usize - 0x4000, start + 0x4000)
+    }
+    _ => return Err(format!("{addr:#06X} isn't in the ROM").into()),
+  };
+  let code = rom.get(offset..end.min(rom.len()))
+    .filter(|code| !code.is_empty())
+    .ok_or_else(|| format!("the ROM has no bank {bank}"))?;
+
+  let mut offset = 0;
+  for line in instr::disassemble(code, addr).into_iter().take(count) {
+    let bytes: Vec<_> = code[offset..offset + line.len].iter().map(|b| format!("{b:02X}")).collect();
+    writeln!(out, "{:04X}  {:<8}  {line}", line.addr, bytes.join(" "))?;
+    offset += line.len;
+  }
+  Ok(())
+}
+
+/// Prints the parsed header to `out`, and whether its checksums match the ROM.
+pub fn info(out: &mut impl Write, rom: &Path) -> Result<(), Box<dyn Error>> {
+  let rom = rom_loader::load_file(rom)?;
+  let header = CartHeader::with_options(&rom, &LoadOptions::tolerant())?;
+  writeln!(out, "{header}")?;
+  if rom.len() != header.rom_size {
+    writeln!(out, "The file is {} KiB, the header says {} KiB", rom.len() / 1024, header.rom_size / 1024)?;
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod headless_tests {
+  use std::{fs, path::PathBuf};
+
+  use tomboy_emulator::{asm, testing::RomBuilder};
+
+  use super::*;
+
+  fn rom_file(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("tomboy-headless-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(name);
+    let rom = RomBuilder::new().at(0x134, b"HEADLESS").code(&asm!["LD A, $42", "loop:", "JR loop"]).build();
+    fs::write(&path, rom).unwrap();
+    path
+  }
+
+  #[test]
+  fn disassembles() {
+    let rom = rom_file("disasm.gb");
+    let mut out = Vec::new();
+    disasm(&mut out, &rom, 0x150, 1, 2).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    let lines: Vec<_> = out.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].starts_with("0150  3E 42 "), "{out}");
+    assert!(lines[1].starts_with("0152  18 FE "), "{out}");
+
+    // a 32kb ROM has banks 0 and 1 only
+    assert!(disasm(&mut Vec::new(), &rom, 0x4000, 2, 1).is_err());
+    assert!(disasm(&mut Vec::new(), &rom, 0x8000, 1, 1).is_err());
+  }
+
+  #[test]
+  fn prints_info() {
+    let rom = rom_file("info.gb");
+    let mut out = Vec::new();
+    info(&mut out, &rom).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert!(out.contains("Title:     HEADLESS"), "{out}");
+    assert!(!out.contains("the header says"), "{out}");
+
+    fs::write(&rom, &fs::read(&rom).unwrap()[..0x4000]).unwrap();
+    let mut out = Vec::new();
+    info(&mut out, &rom).unwrap();
+    assert!(String::from_utf8(out).unwrap().contains("The file is 16 KiB, the header says 32 KiB"));
+  }
+
+  #[test]
+  fn runs_headless() {
+    let rom = rom_file("run.gb");
+    let screenshot = rom.with_extension("png");
+    run(&rom, 3, Some(&screenshot), false, true).unwrap();
+    assert!(fs::read(&screenshot).unwrap().starts_with(b"\x89PNG"));
+
+    assert!(run(&rom.with_extension("missing"), 1, None, false, false).is_err());
+  }
+}
@@ -0,0 +1,139 @@
+use std::{error::Error, io, path::PathBuf, process::ExitCode};
+
+use clap::{Parser, Subcommand};
+
+#[cfg(feature = "frontend")]
+mod config;
+mod headless;
+#[cfg(feature = "frontend")]
+mod pacing;
+#[cfg(feature = "frontend")]
+mod picker;
+#[cfg(feature = "frontend")]
+mod sdl;
+
+#[derive(Parser)]
+#[command(name = "tomboy", version, about = "A Gameboy emulator")]
+#[command(args_conflicts_with_subcommands = true)]
+struct Cli {
+  #[command(subcommand)]
+  command: Option<Command>,
+  /// The ROM to run, like `tomboy run`
+  #[cfg(feature = "frontend")]
+  rom: Option<PathBuf>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+  /// Runs a ROM in a window. Without one, the recent ROMs are listed to pick from
+  #[cfg(feature = "frontend")]
+  Run {
+    rom: Option<PathBuf>,
+  },
+  /// Runs a ROM without a window
+  Headless {
+    rom: PathBuf,
+    /// How many frames to run
+    #[arg(long, default_value_t = 600)]
+    frames: usize,
+    /// Saves the last frame as a PNG file
+    #[arg(long)]
+    screenshot: Option<PathBuf>,
+    /// Prints the bytes sent on the serial port, like test ROMs results
+    #[arg(long)]
+    serial: bool,
+    /// Warns about accesses to echo RAM, the unusable area, and VRAM and OAM while the PPU locks them
+    #[arg(long)]
+    strict: bool,
+  },
+  /// Disassembles code from a ROM
+  Disasm {
+    rom: PathBuf,
+    /// The address to start from, in hex with a `0x` or `$` prefix, or decimal
+    #[arg(long, default_value = "0x100", value_parser = parse_addr)]
+    addr: u16,
+    /// The ROM bank addresses from $4000 to $7FFF are read from
+    #[arg(long, default_value_t = 1)]
+    bank: usize,
+    /// How many instructions to print
+    #[arg(long, default_value_t = 20)]
+    count: usize,
+  },
+  /// Prints the ROM header
+  Info {
+    rom: PathBuf,
+  },
+}
+
+fn parse_addr(s: &str) -> Result<u16, String> {
+  let hex = s.strip_prefix("0x").or_else(|| s.strip_prefix('$'));
+  match hex {
+    Some(hex) => u16::from_str_radix(hex, 16),
+    None => s.parse(),
+  }
+  .map_err(|e| format!("invalid address {s}: {e}"))
+}
+
+fn main() -> ExitCode {
+  let cli = Cli::parse();
+  // the core logs what games do wrong, like illegal opcodes, shown with RUST_LOG=warn or --strict
+  let strict = matches!(cli.command, Some(Command::Headless { strict: true, .. }));
+  let level = if strict { "warn" } else { "error" };
+  env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(level)).init();
+  match run(cli) {
+    Ok(()) => ExitCode::SUCCESS,
+    Err(e) => {
+      eprintln!("error: {e}");
+      ExitCode::FAILURE
+    }
+  }
+}
+
+fn run(cli: Cli) -> Result<(), Box<dyn Error>> {
+  match cli.command {
+    #[cfg(feature = "frontend")]
+    None => sdl::run(cli.rom),
+    #[cfg(feature = "frontend")]
+    Some(Command::Run { rom }) => sdl::run(rom),
+    #[cfg(not(feature = "frontend"))]
+    None => {
+      use clap::CommandFactory;
+      Cli::command().print_help()?;
+      Ok(())
+    }
+    Some(Command::Headless { rom, frames, screenshot, serial, strict }) =>
+      headless::run(&rom, frames, screenshot.as_deref(), serial, strict),
+    Some(Command::Disasm { rom, addr, bank, count }) => headless::disasm(&mut io::stdout(), &rom, addr, bank, count),
+    Some(Command::Info { rom }) => headless::info(&mut io::stdout(), &rom),
+  }
+}
+
+#[cfg(test)]
+mod cli_tests {
+  use super::*;
+
+  #[test]
+  fn addresses() {
+    assert_eq!(parse_addr("0x150"), Ok(0x150));
+    assert_eq!(parse_addr("$4000"), Ok(0x4000));
+    assert_eq!(parse_addr("336"), Ok(336));
+    assert!(parse_addr("0x10000").is_err());
+    assert!(parse_addr("$FFG0").is_err());
+  }
+
+  #[test]
+  fn subcommands() {
+    let cli = Cli::try_parse_from(["tomboy", "disasm", "game.gb", "--addr", "$4000", "--bank", "2"]).unwrap();
+    let Some(Command::Disasm { rom, addr, bank, count }) = cli.command else { panic!("not disasm") };
+    assert_eq!((rom, addr, bank, count), (PathBuf::from("game.gb"), 0x4000, 2, 20));
+
+    let cli = Cli::try_parse_from(["tomboy", "headless", "game.gb", "--screenshot", "out.png"]).unwrap();
+    let Some(Command::Headless { frames, screenshot, serial, strict, .. }) = cli.command else { panic!("not headless") };
+    assert_eq!((frames, screenshot, serial, strict), (600, Some(PathBuf::from("out.png")), false, false));
+
+    assert!(matches!(Cli::try_parse_from(["tomboy", "info", "game.gb"]).unwrap().command, Some(Command::Info { .. })));
+    assert!(Cli::try_parse_from(["tomboy", "disasm", "game.gb", "--addr", "start"]).is_err());
+    assert!(Cli::try_parse_from(["tomboy", "headless"]).is_err());
+    assert!(Cli::try_parse_from(["tomboy", "headless", "game.gb", "--frames", "-1"]).is_err());
+  }
+}
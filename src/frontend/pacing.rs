@@ -0,0 +1,87 @@
+//! When frames are run and shown, so the game runs at the Gameboy speed without drifting or stuttering.
+
+use std::{thread, time::{Duration, Instant}};
+
+use serde::Deserialize;
+
+/// What the emulation speed is synced to.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SyncMode {
+  /// The display refresh. Frames are run as the Gameboy time goes, so some are shown twice,
+  /// or skipped on displays slower than its 59.73 Hz.
+  Vsync,
+  /// The audio device. Frames are run as fast as it plays their audio, so it never crackles.
+  #[default]
+  Audio,
+}
+
+/// A Gameboy frame lasts 70224 T-cycles at 4194304 Hz.
+const FRAME: Duration = Duration::from_nanos(16_742_706);
+/// How many frames are run at most to catch up after a hiccup, like a window drag. Older ones are dropped.
+const MAX_CATCH_UP: usize = 4;
+/// How much `thread::sleep` may oversleep, this end of a wait is spun instead.
+const SLEEP_SLACK: Duration = Duration::from_millis(1);
+
+fn sleep_until(deadline: Instant) {
+  let now = Instant::now();
+  if deadline > now + SLEEP_SLACK {
+    thread::sleep(deadline - now - SLEEP_SLACK);
+  }
+  while Instant::now() < deadline {
+    std::hint::spin_loop();
+  }
+}
+
+pub struct Pacer {
+  mode: SyncMode,
+  /// How much audio is kept queued with [`SyncMode::Audio`].
+  latency: Duration,
+  /// When the next frame is due, in Gameboy time.
+  next: Instant,
+}
+
+impl Pacer {
+  pub fn new(mode: SyncMode, latency: Duration) -> Self {
+    Self { mode, latency, next: Instant::now() }
+  }
+
+  pub fn mode(&self) -> SyncMode {
+    self.mode
+  }
+
+  /// How many frames to run before showing the next one, at normal speed. It can be 0 with vsync.
+  pub fn frames_due(&mut self) -> usize {
+    if self.mode == SyncMode::Audio { return 1; }
+
+    let now = Instant::now();
+    let mut due = 0;
+    while self.next <= now && due < MAX_CATCH_UP {
+      self.next += FRAME;
+      due += 1;
+    }
+    if self.next <= now { self.next = now + FRAME; }
+    due
+  }
+
+  /// Waits for the next frame to be shown, after presenting this one.
+  /// `queued` is how much audio the device still has to play, and `playing` whether the last frames made any,
+  /// as a paused game has no audio to wait for.
+  pub fn wait(&mut self, queued: impl Fn() -> Duration, playing: bool) {
+    match self.mode {
+      // presenting the frame already waited for the display
+      SyncMode::Vsync => {}
+      SyncMode::Audio if playing => {
+        while queued() > self.latency {
+          thread::sleep(SLEEP_SLACK);
+        }
+        self.next = Instant::now() + FRAME;
+      }
+      SyncMode::Audio => {
+        sleep_until(self.next);
+        self.next = (self.next + FRAME).max(Instant::now());
+      }
+    }
+  }
+}
+
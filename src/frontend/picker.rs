@@ -0,0 +1,94 @@
+use std::{error::Error, path::{Path, PathBuf}};
+
+use sdl2::{controller::GameController, event::Event, keyboard::Keycode, pixels::PixelFormatEnum, render::WindowCanvas, EventPump, GameControllerSubsystem};
+use tomboy_emulator::frame::{FrameBuffer, LCD_WIDTH, LCD_HEIGHT};
+
+use crate::config::{Action, Config};
+
+/// The longest file name shown, in characters.
+const NAME_LEN: usize = 36;
+
+/// Shows the recent ROMs, until one is picked or a file is dropped on the window.
+/// Returns `None` if the window is closed. `error` is shown at the bottom, like why the last ROM didn't load.
+pub fn pick_rom(
+  canvas: &mut WindowCanvas,
+  events: &mut EventPump,
+  config: &Config,
+  controllers: &GameControllerSubsystem,
+  controller: &mut Option<GameController>,
+  error: Option<&str>,
+) -> Result<Option<PathBuf>, Box<dyn Error>> {
+  let texture_creator = canvas.texture_creator();
+  let mut texture = texture_creator
+    .create_texture_target(PixelFormatEnum::RGBA32, LCD_WIDTH as u32, LCD_HEIGHT as u32)?;
+  let mut screen = FrameBuffer::gameboy_lcd();
+  screen.set_palette(config.palette);
+  let roms = &config.recent_roms;
+  let mut selected: usize = 0;
+
+  loop {
+    for event in events.poll_iter() {
+      let action = match event {
+        Event::Quit { .. } => return Ok(None),
+        Event::DropFile { filename, .. } => return Ok(Some(PathBuf::from(filename))),
+        Event::ControllerDeviceAdded { which, .. } if controller.is_none() => {
+          *controller = controllers.open(which).ok();
+          None
+        }
+        Event::KeyDown { keycode: Some(Keycode::Return), .. } => Some(Action::Start),
+        Event::KeyDown { keycode: Some(keycode), .. } => config.keyboard.get(&keycode).copied(),
+        Event::ControllerButtonDown { button, .. } => config.controller.get(&button).copied(),
+        _ => None,
+      };
+
+      match action {
+        Some(Action::Up) => selected = selected.saturating_sub(1),
+        Some(Action::Down) => selected = (selected + 1).min(roms.len().saturating_sub(1)),
+        Some(Action::A | Action::Start) if !roms.is_empty() => return Ok(Some(roms[selected].clone())),
+        _ => {}
+      }
+    }
+
+    draw(&mut screen, roms, selected, error);
+    canvas.clear();
+    texture.update(None, &screen.buffer, screen.pitch())?;
+    canvas.copy(&texture, None, None)?;
+    canvas.present();
+  }
+}
+
+fn draw(screen: &mut FrameBuffer, roms: &[PathBuf], selected: usize, error: Option<&str>) {
+  let (light, dark) = (screen.color(0), screen.color(3));
+  screen.reset();
+  screen.draw_text(2, 2, "TOMBOY", dark, None);
+
+  if roms.is_empty() {
+    screen.draw_text(2, 20, "DROP A ROM ON THE WINDOW\nTO PLAY IT", dark, None);
+  } else {
+    screen.draw_text(2, 14, "RECENT ROMS", screen.color(2), None);
+    for (i, rom) in roms.iter().enumerate() {
+      let y = 22 + i as i32 * 7;
+      let name = display_name(rom);
+      if i == selected {
+        screen.draw_text(2, y, &format!(">{name}"), light, Some(dark));
+      } else {
+        screen.draw_text(2, y, &format!(" {name}"), dark, None);
+      }
+    }
+  }
+
+  if let Some(error) = error {
+    let error: String = error.chars().take(NAME_LEN + 3).collect();
+    screen.draw_text(2, LCD_HEIGHT as i32 - 8, &error, dark, None);
+  }
+}
+
+fn display_name(rom: &Path) -> String {
+  let name = rom.file_name().unwrap_or(rom.as_os_str()).to_string_lossy();
+  if name.chars().count() > NAME_LEN {
+    let short: String = name.chars().take(NAME_LEN - 2).collect();
+    format!("{short}..")
+  } else {
+    name.into_owned()
+  }
+}
@@ -0,0 +1,434 @@
+use std::{error::Error, fs, path::{Path, PathBuf}, sync::Arc, time};
+
+use sdl2::{audio::{AudioQueue, AudioSpecDesired}, controller::{self, Axis}, event::Event, pixels::PixelFormatEnum, video::FullscreenType};
+use tomboy_emulator::{apu::AudioConfig, cart::LoadOptions, emu_thread::EmuThread, frame::{Palette, LCD_HEIGHT, LCD_WIDTH, PALETTES}, game_db::{self, GameDb}, gb::Gameboy, joypad::Button, rom_loader, recorder::Recorder, video::FilterChain};
+
+use crate::{config::{Action, Config}, pacing::{Pacer, SyncMode}, picker};
+
+const CONFIG_PATH: &str = "./config.toml";
+/// Per-game overrides, looked up before the ones shipped with the emulator
+const GAMES_PATH: &str = "./games.toml";
+
+/// Save states are stored next to the ROM, as `game.ss0` to `game.ss9`.
+fn state_path(rom_path: &Path, slot: u8) -> PathBuf {
+  rom_path.with_extension(format!("ss{slot}"))
+}
+
+fn save_state(emu: &Gameboy, rom_path: &Path, slot: u8) -> String {
+  match fs::write(state_path(rom_path, slot), emu.save_state()) {
+    Ok(()) => format!("State {slot} saved"),
+    Err(e) => format!("Couldn't save state {slot}: {e}"),
+  }
+}
+
+fn save_screenshot(emu: &Gameboy) -> String {
+  let path = format!("screenshot-{}.png", unix_secs());
+  match emu.save_png(&path) {
+    Ok(()) => format!("Saved {path}"),
+    Err(e) => {
+      eprintln!("{path}: {e}");
+      format!("Couldn't save the screenshot: {e}")
+    }
+  }
+}
+
+fn unix_secs() -> u64 {
+  time::SystemTime::now().duration_since(time::UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+/// Shows a message on the osd, once the frames being emulated are done.
+fn message(emu: &EmuThread, text: impl Into<String>) {
+  let text = text.into();
+  emu.queue(move |gb| gb.get_osd().message(text));
+}
+
+fn load_state(emu: &mut Gameboy, rom_path: &Path, slot: u8) -> String {
+  let state = match fs::read(state_path(rom_path, slot)) {
+    Ok(state) => state,
+    Err(_) => return format!("State {slot} is empty"),
+  };
+  match emu.load_state(&state) {
+    Ok(()) => format!("State {slot} loaded"),
+    Err(e) => e.to_string(),
+  }
+}
+
+/// The directions pressed by the left stick, so only those are released when it goes back to the center.
+#[derive(Default)]
+struct Stick {
+  x: Option<Button>,
+  y: Option<Button>,
+}
+
+impl Stick {
+  fn moved(&mut self, emu: &EmuThread, axis: Axis, value: i16, deadzone: i16) {
+    let (held, negative, positive) = match axis {
+      Axis::LeftX => (&mut self.x, Button::Left, Button::Right),
+      Axis::LeftY => (&mut self.y, Button::Up, Button::Down),
+      _ => return,
+    };
+
+    let pressed = match value {
+      v if v < -deadzone => Some(negative),
+      v if v > deadzone => Some(positive),
+      _ => None,
+    };
+    if pressed == *held { return; }
+
+    if let Some(button) = held.take() { emu.release(button); }
+    if let Some(button) = pressed { emu.press(button); }
+    *held = pressed;
+  }
+}
+
+fn open_audio(audio: &sdl2::AudioSubsystem, sample_rate: u32) -> Result<AudioQueue<f32>, String> {
+  let desired_spec = AudioSpecDesired {
+    channels: Some(2),
+    freq: Some(sample_rate as i32),
+    samples: None,
+  };
+  let queue = audio.open_queue::<f32, _>(None, &desired_spec)?;
+  queue.resume();
+  Ok(queue)
+}
+
+/// The stereo audio of all the frames run since the last shown one, squeezed into one frame's length
+/// by averaging, so fast forward plays faster and higher instead of cutting out.
+fn squeeze_audio(frames: &[Vec<f32>], volume: f32) -> Vec<f32> {
+  let pairs: Vec<_> = frames.iter().flat_map(|samples| samples.chunks_exact(2)).collect();
+  let n = frames.len().max(1);
+  pairs.chunks(n)
+    .flat_map(|group| {
+      let (left, right) = group.iter().fold((0.0, 0.0), |(l, r), pair| (l + pair[0], r + pair[1]));
+      let scale = volume / group.len() as f32;
+      [left * scale, right * scale]
+    })
+    .collect()
+}
+
+fn boot(rom_path: &Path, opts: &LoadOptions, config: &Config, palette: Palette) -> Result<Gameboy, tomboy_emulator::Error> {
+  let rom = rom_loader::load_patched(rom_path)?;
+  let mut emu = Gameboy::boot_with_options(&rom, opts)?;
+  if let Some(path) = &config.boot_rom {
+    emu.set_boot_rom(Some(fs::read(path)?))?;
+  }
+  apply_config(config, palette)(&mut emu);
+  if let Some(palette) = game_db::find(opts.game_db.as_deref(), &emu.get_cart()).and_then(|game| game.palette()) {
+    emu.set_palette(palette);
+  }
+  Ok(emu)
+}
+
+/// Swaps the ROM of the running emulator, which keeps its settings.
+fn load_rom(emu: &EmuThread, path: &Path, opts: &LoadOptions) -> Result<(), tomboy_emulator::Error> {
+  let rom = rom_loader::load_patched(path)?;
+  let opts = opts.clone();
+  emu.with(move |gb| gb.load_rom_with_options(&rom, &opts).map(|_| ()))
+}
+
+/// The settings kept by the emulator, applied at boot and when the config is reloaded.
+/// They are applied by the returned closure, so they can be sent to the emulator thread.
+fn apply_config(config: &Config, palette: Palette) -> impl FnOnce(&mut Gameboy) + Send + 'static {
+  let (frame_blend, dmg_quirks) = (config.frame_blend, config.dmg_quirks);
+  let (turbo_rate, sample_rate) = (config.turbo_rate, config.audio.sample_rate);
+  move |emu| {
+    emu.set_palette(palette);
+    emu.set_frame_blend(frame_blend);
+    emu.set_dmg_quirks(dmg_quirks);
+    emu.get_joypad().set_turbo_rate(turbo_rate);
+    let audio = AudioConfig { sample_rate, ..emu.get_apu().config() };
+    emu.set_audio_config(audio);
+  }
+}
+
+/// Runs `rom` in a window, or the one picked from the recent ROMs.
+pub fn run(rom: Option<PathBuf>) -> Result<(), Box<dyn Error>> {
+  let sdl = sdl2::init()?;
+  let video = sdl.video()?;
+  let audio = sdl.audio()?;
+  let controllers = sdl.game_controller()?;
+
+  let mut config = Config::load(CONFIG_PATH)?;
+  let mut palette = config.palette;
+  // the user's games.toml overrides the embedded database
+  let mut load_options = LoadOptions::tolerant();
+  if let Ok(text) = fs::read_to_string(GAMES_PATH) {
+    load_options = load_options.with_game_db(Arc::new(GameDb::parse(&text)?));
+  }
+
+  let (width, height) = (LCD_WIDTH as u32, LCD_HEIGHT as u32);
+  let mut filters = config.filters();
+  let filtered_size = |filters: &FilterChain| {
+    let (w, h) = filters.output_size(width as usize, height as usize);
+    (w as u32, h as u32)
+  };
+  let (mut filtered_width, mut filtered_height) = filtered_size(&filters);
+
+  // the screen is stretched to the window with nearest-neighbor, and letterboxed to keep its aspect ratio
+  sdl2::hint::set("SDL_RENDER_SCALE_QUALITY", "nearest");
+  let (window_width, window_height) = config.window_size((width, height), (filtered_width, filtered_height));
+  let mut window = video.window("TomboyEmu", window_width, window_height)
+    .position_centered().resizable().build()?;
+  window.set_minimum_size(width, height)?;
+  if config.fullscreen { window.set_fullscreen(FullscreenType::Desktop)?; }
+  let mut canvas = window.into_canvas().accelerated().target_texture();
+  if config.sync == SyncMode::Vsync { canvas = canvas.present_vsync(); }
+  let mut canvas = canvas.build()?;
+  canvas.set_logical_size(filtered_width, filtered_height)?;
+  canvas.set_integer_scale(config.integer_scaling)?;
+
+  let mut events = sdl.event_pump()?;
+  // the first controller plugged in
+  let mut controller: Option<controller::GameController> = None;
+
+  // the ROM given on the command line, or picked from the recent ones
+  let mut arg = rom;
+  let mut error = None;
+  let (emu, mut rom_path) = loop {
+    let picked = match arg.take() {
+      Some(path) => Some(path),
+      None => picker::pick_rom(&mut canvas, &mut events, &config, &controllers, &mut controller, error.as_deref())?,
+    };
+    let Some(path) = picked else { return Ok(()) };
+    match boot(&path, &load_options, &config, palette) {
+      Ok(emu) => break (emu, path),
+      Err(e) => error = Some(e.to_string()),
+    }
+  };
+  if let Err(e) = config.remember_rom(CONFIG_PATH, &rom_path) {
+    eprintln!("{CONFIG_PATH}: {e}");
+  }
+
+  let texture_creator = canvas.texture_creator();
+  let mut texture = texture_creator
+    .create_texture_target(PixelFormatEnum::RGBA32, filtered_width, filtered_height)?;
+
+  let mut audio_queue = open_audio(&audio, config.audio.sample_rate)?;
+  let mut recorder: Option<Recorder> = None;
+  let mut stick = Stick::default();
+  let mut fast_forward = false;
+  let mut slot = 0;
+  // emulated frames per second, shown in the osd status
+  let mut fps_since = time::Instant::now();
+  let mut fps_frames = emu.total_frames();
+  let mut fps = 0;
+  let latency = |config: &Config| time::Duration::from_millis(config.audio.latency.into());
+  let mut pacer = Pacer::new(config.sync, latency(&config));
+
+  // the emulator runs the next frames while the last ones are shown, and the input polled
+  let emu = EmuThread::spawn(emu);
+  emu.run_frames(1);
+
+  'running: loop {
+    let output = emu.recv().ok_or("the emulator thread stopped")?;
+    // when fast-forwarding, only the last frame is drawn, and its audio played. All of them are recorded.
+    let speed = if fast_forward { config.fast_forward_speed as usize } else { 1 };
+    emu.run_frames(pacer.frames_due() * speed);
+
+    if let Some(rec) = recorder.as_mut() {
+      let last = output.samples.len().saturating_sub(1);
+      for (i, samples) in output.samples.iter().enumerate() {
+        if let Err(e) = rec.push_frame((i == last).then_some(&output.screen), samples) {
+          message(&emu, format!("Recording stopped: {e}"));
+          recorder = None;
+          break;
+        }
+      }
+    }
+
+    for event in events.poll_iter() {
+      let mut action = None;
+      let mut released = None;
+      match event {
+        Event::Quit { .. } => break 'running,
+        Event::DropFile { filename, .. } if filename.ends_with(".rhai") => emu.load_script(filename),
+        Event::DropFile { filename, .. } => match load_rom(&emu, Path::new(&filename), &load_options) {
+          Ok(()) => {
+            rom_path = PathBuf::from(filename);
+            if let Err(e) = config.remember_rom(CONFIG_PATH, &rom_path) {
+              eprintln!("{CONFIG_PATH}: {e}");
+            }
+          }
+          Err(e) => message(&emu, e.to_string()),
+        }
+        Event::ControllerDeviceAdded { which, .. } if controller.is_none() => {
+          controller = controllers.open(which).ok();
+        }
+        Event::ControllerDeviceRemoved { which, .. }
+          if controller.as_ref().is_some_and(|c| c.instance_id() == which) => {
+          controller = None;
+          stick = Stick::default();
+        }
+        Event::ControllerAxisMotion { axis, value, .. } => {
+          stick.moved(&emu, axis, value, config.stick_deadzone);
+        }
+        Event::ControllerButtonDown { button, .. } => action = config.controller.get(&button).copied(),
+        Event::ControllerButtonUp { button, .. } => released = config.controller.get(&button).copied(),
+        Event::KeyDown { keycode: Some(keycode), repeat: false, .. } => action = config.keyboard.get(&keycode).copied(),
+        Event::KeyUp { keycode: Some(keycode), .. } => released = config.keyboard.get(&keycode).copied(),
+        _ => {}
+      }
+
+      match released {
+        Some(Action::FastForward) => fast_forward = false,
+        Some(action) => if let Some(button) = action.button() { emu.release(button); }
+        None => {}
+      }
+
+      let Some(action) = action else { continue };
+      if let Some(button) = action.button() {
+        emu.press(button);
+        continue;
+      }
+
+      match action {
+        Action::Reset => emu.reset(),
+        Action::FastForward => fast_forward = true,
+        Action::SaveState => {
+          let rom_path = rom_path.clone();
+          emu.queue(move |gb| {
+            let message = save_state(gb, &rom_path, slot);
+            gb.get_osd().message(message);
+          });
+        }
+        Action::LoadState => {
+          let rom_path = rom_path.clone();
+          emu.queue(move |gb| {
+            let message = load_state(gb, &rom_path, slot);
+            gb.get_osd().message(message);
+          });
+        }
+        Action::SelectSlot(n) => {
+          slot = n.min(9);
+          message(&emu, format!("Slot {slot}"));
+        }
+        Action::Pause => emu.queue(|gb| gb.set_paused(!gb.is_paused())),
+        Action::TurboA | Action::TurboB => {
+          let button = if action == Action::TurboA { Button::A } else { Button::B };
+          emu.queue(move |gb| {
+            let joypad = gb.get_joypad();
+            joypad.set_turbo(button, !joypad.is_turbo(button));
+          });
+        }
+        Action::FrameAdvance => emu.queue(|gb| {
+          gb.set_paused(true);
+          gb.advance_frame();
+        }),
+        Action::SlowMotion => {
+          let slow_motion_factor = config.slow_motion_factor;
+          emu.queue(move |gb| {
+            let factor = if gb.slow_motion() == 1 { slow_motion_factor } else { 1 };
+            gb.set_slow_motion(factor);
+          });
+        }
+        Action::NextPalette => {
+          let next = PALETTES.iter().position(|(_, p)| *p == palette).map_or(0, |i| i + 1);
+          palette = PALETTES[next % PALETTES.len()].1;
+          emu.queue(move |gb| gb.set_palette(palette));
+        }
+        Action::Screenshot => emu.queue(|gb| {
+          let message = save_screenshot(gb);
+          gb.get_osd().message(message);
+        }),
+        Action::ReloadConfig => match Config::load(CONFIG_PATH) {
+          Ok(reloaded) => {
+            if reloaded.audio.sample_rate != config.audio.sample_rate {
+              audio_queue = open_audio(&audio, reloaded.audio.sample_rate)?;
+              if let Some(rec) = recorder.take() {
+                let text = match rec.finish() {
+                  Ok(()) => "Recording stopped, the sample rate changed".to_string(),
+                  Err(e) => {
+                    eprintln!("Recording: {e}");
+                    format!("Recording stopped, couldn't save it: {e}")
+                  }
+                };
+                message(&emu, text);
+              }
+            }
+            filters = reloaded.filters();
+            (filtered_width, filtered_height) = filtered_size(&filters);
+            texture = texture_creator
+              .create_texture_target(PixelFormatEnum::RGBA32, filtered_width, filtered_height)?;
+            canvas.set_logical_size(filtered_width, filtered_height)?;
+            if canvas.window().fullscreen_state() == FullscreenType::Off {
+              let (w, h) = reloaded.window_size((width, height), (filtered_width, filtered_height));
+              canvas.window_mut().set_size(w, h)?;
+            }
+            canvas.set_integer_scale(reloaded.integer_scaling)?;
+            config = reloaded;
+            palette = config.palette;
+            emu.queue(apply_config(&config, palette));
+            pacer = Pacer::new(pacer.mode(), latency(&config));
+          }
+          Err(e) => eprintln!("{CONFIG_PATH}: {e}"),
+        }
+        Action::Record => {
+          let text = match recorder.take() {
+            Some(rec) => match rec.finish() {
+              Ok(()) => "Recording saved".to_string(),
+              Err(e) => format!("Couldn't save the recording: {e}"),
+            }
+            None => {
+              let path = format!("recording-{}.{}", unix_secs(), config.record_format);
+              match Recorder::start(&path, width as usize, height as usize, config.audio.sample_rate) {
+                Ok(rec) => {
+                  recorder = Some(rec);
+                  format!("Recording to {path}")
+                }
+                Err(e) => format!("Couldn't record: {e}"),
+              }
+            }
+          };
+          message(&emu, text);
+        }
+        Action::Fullscreen => {
+          let window = canvas.window_mut();
+          let state = match window.fullscreen_state() {
+            FullscreenType::Off => FullscreenType::Desktop,
+            _ => FullscreenType::Off,
+          };
+          window.set_fullscreen(state)?;
+        }
+        _ => unreachable!("buttons are handled above"),
+      }
+    }
+
+    if let Some(controller) = controller.as_mut() {
+      // renewed every frame, so it stops by itself if the game stops toggling it
+      let strength = if output.rumble { u16::MAX } else { 0 };
+      let _ = controller.set_rumble(strength, strength, 100);
+    }
+
+    let samples = squeeze_audio(&output.samples, config.audio.volume);
+    audio_queue.queue_audio(&samples)?;
+
+    if fps_since.elapsed() >= time::Duration::from_secs(1) {
+      fps = output.total_frames.saturating_sub(fps_frames);
+      fps_frames = output.total_frames;
+      fps_since = time::Instant::now();
+    }
+    let mut status = if output.paused { "PAUSED".to_string() }
+      else if fast_forward { format!("{fps} >>") }
+      else { fps.to_string() };
+    if recorder.is_some() { status.insert_str(0, "REC "); }
+
+    // the screen and osd are copies, so the osd doesn't stay on the screen of a paused game
+    let (mut screen, mut osd) = (output.screen, output.osd);
+    osd.set_status(status);
+    if !osd.is_empty() { osd.draw(&mut screen); }
+    let screen = filters.apply(&screen);
+    canvas.clear();
+    texture.update(None, &screen.buffer, screen.pitch())?;
+    canvas.copy(&texture, None, None)?;
+    canvas.present();
+
+    // f32 stereo pairs
+    let bytes_per_sec = 8.0 * config.audio.sample_rate as f64;
+    pacer.wait(|| time::Duration::from_secs_f64(audio_queue.size() as f64 / bytes_per_sec), !samples.is_empty());
+  }
+
+  if let Some(rec) = recorder {
+    rec.finish()?;
+  }
+  Ok(())
+}
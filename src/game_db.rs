@@ -0,0 +1,167 @@
+//! Per-game overrides of what the header says, or can't say: the console mode, the MBC1M multicart wiring,
+//! the palette, and the cartridge RAM.
+//!
+//! The database shipped with the emulator is `games.toml`, which documents the format. A database given with
+//! [`LoadOptions::with_game_db`] is looked up before it. [`Gameboy::boot_with_options`](crate::gb::Gameboy::boot_with_options)
+//! and [`Gameboy::load_rom_with_options`](crate::gb::Gameboy::load_rom_with_options) apply them.
+//!
+//! [`LoadOptions::with_game_db`]: crate::cart::LoadOptions::with_game_db
+
+use std::sync::OnceLock;
+
+use serde::Deserialize;
+
+use crate::{cart::{CartHeader, CgbMode}, frame::{Palette, PALETTES}, mbc::Cart, Error};
+
+const EMBEDDED: &str = include_str!("../games.toml");
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode { Dmg, Cgb }
+
+/// The overrides of a game. Unset fields keep what the header says.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Game {
+  pub title: String,
+  /// The global checksum, to only match a version of the game
+  pub checksum: Option<u16>,
+  pub mode: Option<Mode>,
+  #[serde(default)]
+  pub mbc1m: bool,
+  /// One of the names in [`PALETTES`]
+  pub palette: Option<String>,
+  /// The cartridge RAM size, in KiB
+  pub ram_size: Option<usize>,
+  pub battery: Option<bool>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+pub struct GameDb {
+  #[serde(default, rename = "game")]
+  pub games: Vec<Game>,
+}
+
+impl GameDb {
+  pub fn parse(text: &str) -> Result<Self, Error> {
+    let db: Self = toml::from_str(text).map_err(|e| Error::InvalidGameDb(e.to_string()))?;
+    let palettes = db.games.iter().filter_map(|game| game.palette.as_deref());
+    if let Some(name) = palettes.into_iter().find(|name| palette(name).is_none()) {
+      return Err(Error::InvalidGameDb(format!("unknown palette {name}")));
+    }
+    Ok(db)
+  }
+
+  /// The database shipped with the emulator.
+  pub fn embedded() -> &'static GameDb {
+    static DB: OnceLock<GameDb> = OnceLock::new();
+    DB.get_or_init(|| GameDb::parse(EMBEDDED).expect("the embedded game database is valid"))
+  }
+
+  pub fn find(&self, header: &CartHeader) -> Option<&Game> {
+    self.games.iter().find(|game| {
+      game.title == header.title()
+      && game.checksum.is_none_or(|checksum| checksum == header.global_checksum().expected)
+    })
+  }
+}
+
+/// The overrides of the game with this header, from `user`, then from the embedded database.
+pub fn find<'a>(user: Option<&'a GameDb>, header: &CartHeader) -> Option<&'a Game> {
+  user.and_then(|db| db.find(header)).or_else(|| GameDb::embedded().find(header))
+}
+
+impl Game {
+  pub fn palette(&self) -> Option<Palette> {
+    self.palette.as_deref().and_then(palette)
+  }
+
+  /// Changes the header of `cart`, and rebuilds its mapper and RAM after it.
+  pub(crate) fn apply(&self, cart: &mut Cart) {
+    let header = &mut cart.header;
+    match self.mode {
+      Some(Mode::Dmg) => header.cgb_mode = CgbMode::Monochrome,
+      Some(Mode::Cgb) => header.cgb_mode = CgbMode::CgbEnhanced,
+      None => {}
+    }
+    header.multicart |= self.mbc1m;
+    if let Some(kib) = self.ram_size {
+      header.ram_size = kib * 1024;
+      header.ram_banks = header.ram_size.div_ceil(8 * 1024);
+    }
+    if let Some(battery) = self.battery {
+      header.has_battery = battery;
+    }
+
+    cart.resize_ram();
+    cart.reset();
+  }
+}
+
+fn palette(name: &str) -> Option<Palette> {
+  PALETTES.iter().find(|(n, _)| *n == name).map(|(_, palette)| *palette)
+}
+
+#[cfg(test)]
+mod game_db_tests {
+  use std::sync::Arc;
+
+  use super::*;
+  use crate::{cart::LoadOptions, frame::GB_POCKET, gb::Gameboy, testing::RomBuilder};
+
+  #[test]
+  fn parse() {
+    assert!(GameDb::embedded().games.iter().all(|game| !game.title.is_empty()));
+    assert!(matches!(GameDb::parse("[[game]]\ntitle = \"X\"\npalette = \"pink\""), Err(Error::InvalidGameDb(_))));
+    assert!(matches!(GameDb::parse("[[game]]\ntitle = \"X\"\nspeed = 2"), Err(Error::InvalidGameDb(_))));
+  }
+
+  #[test]
+  fn overrides() {
+    let db = GameDb::parse(r#"
+      [[game]]
+      title = "DBTEST"
+      checksum = 0x1234
+      mode = "cgb"
+
+      [[game]]
+      title = "DBTEST"
+      mode = "dmg"
+      mbc1m = true
+      palette = "gb-pocket"
+      ram_size = 8
+      battery = true
+    "#).unwrap();
+    let opts = LoadOptions::default().with_game_db(Arc::new(db));
+
+    // CGB MBC1 ROM of 1MB without RAM
+    let mut rom = RomBuilder::new().at(0x134, b"DBTEST").at(0x143, &[0x80]).cart_type(0x01).rom_size(5).build();
+    for bank in 0..64 {
+      rom[bank * 0x4000 + 0x100] = bank as u8;
+    }
+    // only with the database
+    assert_eq!(Gameboy::boot_from_bytes(&rom).unwrap().get_cart().cgb_mode, CgbMode::CgbEnhanced);
+    let mut gb = Gameboy::boot_with_options(&rom, &opts).unwrap();
+    let header = gb.get_cart();
+    assert_eq!((header.cgb_mode, header.multicart, header.has_battery), (CgbMode::Monochrome, true, true));
+    assert_eq!(gb.get_bus().cart.ram().len(), 8 * 1024);
+    assert_eq!(gb.get_ppu().lcd.palette(), GB_POCKET);
+
+    // MBC1M: the second game starts at bank 0x10, bit 4 of the ROM bank is unused
+    let cart = &mut gb.get_bus().cart;
+    cart.rom_write(0x4000, 1);
+    cart.rom_write(0x2000, 0x13);
+    assert_eq!(cart.rom_read(0x4100), 0x13);
+    cart.rom_write(0x6000, 1);
+    assert_eq!(cart.rom_read(0x0100), 0x10);
+  }
+
+  #[test]
+  fn detect_multicart() {
+    // 1MB MBC1 with a second game header at bank 0x10
+    let mut rom = RomBuilder::new().cart_type(0x01).rom_size(5).build();
+    assert!(!Gameboy::boot_from_bytes(&rom).unwrap().get_cart().multicart);
+    rom.copy_within(0x100..0x150, 0x10 * 0x4000 + 0x100);
+    assert!(Gameboy::boot_from_bytes(&rom).unwrap().get_cart().multicart);
+  }
+}
@@ -1,27 +1,460 @@
-use crate::{apu::Apu, bus::Bus, cart::CartHeader, cpu::Cpu, frame::FrameBuffer, joypad::Joypad, mbc::Cart, ppu::Ppu};
+use std::{io::{self, Write}, path::Path};
+
+use serde::{de::IgnoredAny, Serialize};
+
+use crate::{apu::{Apu, AudioConfig}, bus::{Bus, FrameTiming}, cheats::Cheats, debugger::{BreakReason, Debugger}, cart::{CartHeader, LoadOptions}, cpu::{Cpu, Flags, Register16}, frame::{FrameBuffer, Palette, PixelFormat, Screenshot}, Error, infrared::Transceiver, input_log::{InputLog, Movie}, joypad::Joypad, mem::PowerOnState, mbc::Cart, osd::Osd, patch, ppu::Ppu, sgb::Sgb, state::{Fnv1a, Hasher, SaveState, STATE_VERSION}};
+
+type FrameCallback = Box<dyn FnMut(&FrameBuffer) + Send>;
+type AudioCallback = Box<dyn FnMut(&[f32]) + Send>;
+type SerialCallback = Box<dyn FnMut(u8) + Send>;
+type ScanlineCallback = Box<dyn FnMut(&Ppu) + Send>;
+type TraceCallback = Box<dyn FnMut(&str) + Send>;
+
+#[derive(Default)]
+struct Callbacks {
+  frame: Option<FrameCallback>,
+  audio: Option<AudioCallback>,
+  serial: Option<SerialCallback>,
+  scanline: Option<ScanlineCallback>,
+  trace: Option<TraceCallback>,
+  audio_buf: Vec<f32>,
+}
+
+/// Pause and slow motion state of [`Gameboy::run_frame`].
+#[derive(Clone, Copy, Debug)]
+struct Pacing {
+  paused: bool,
+  advance: bool,
+  slow_motion: u32,
+  /// Calls to [`Gameboy::run_frame`] since the last frame run in slow motion
+  skipped: u32,
+}
+
+impl Default for Pacing {
+  fn default() -> Self {
+    Self { paused: false, advance: false, slow_motion: 1, skipped: 0 }
+  }
+}
 
 pub struct Gameboy {
-  cpu: Cpu<Bus>
+  cpu: Cpu<Bus>,
+  callbacks: Callbacks,
+  input_log: InputLog,
+  pacing: Pacing,
+  osd: Osd,
+  boot_rom: Option<Vec<u8>>,
+  power_on: Option<PowerOnState>,
+}
+
+/// Applies the overrides of the [game database](crate::game_db) to `cart`, returns the palette they pick.
+#[cfg_attr(not(feature = "game-db"), allow(unused_variables))]
+fn apply_game_db(cart: &mut Cart, opts: &LoadOptions) -> Option<Palette> {
+  #[cfg(feature = "game-db")]
+  if let Some(game) = crate::game_db::find(opts.game_db.as_deref(), &cart.header) {
+    game.apply(cart);
+    return game.palette();
+  }
+  None
 }
 
 impl Gameboy {
-  pub fn boot_from_bytes(rom: &[u8]) -> Result<Self, String> {
-    let cart = Cart::new(rom)?;
-    Ok(Self {cpu: Cpu::new(cart)})
+  /// Boots `rom`, with the overrides of the game database for it, when built with the `game-db` feature.
+  pub fn boot_from_bytes(rom: &[u8]) -> Result<Self, Error> {
+    Self::boot_with_options(rom, &LoadOptions::default())
+  }
+
+  /// Like [`Gameboy::boot_from_bytes`], with relaxed header checks for homebrew.
+  pub fn boot_with_options(rom: &[u8], opts: &LoadOptions) -> Result<Self, Error> {
+    let mut cart = Cart::with_options(rom, opts)?;
+    let palette = apply_game_db(&mut cart, opts);
+    let mut gb = Self::boot_from_cart(cart);
+    if let Some(palette) = palette { gb.set_palette(palette); }
+    Ok(gb)
+  }
+
+  /// Boots a ROM with an IPS or BPS patch applied, as for translations and hacks.
+  pub fn boot_patched(rom: &[u8], patch: &[u8]) -> Result<Self, Error> {
+    Self::boot_from_bytes(&patch::apply(rom, patch)?)
+  }
+
+  /// Boots a cartridge built beforehand, like one with a custom mapper from [`Cart::new_with_mapper`].
+  /// The game database isn't looked up.
+  pub fn boot_from_cart(cart: Cart) -> Self {
+    Self {cpu: Cpu::new(cart), callbacks: Callbacks::default(), input_log: InputLog::Off, pacing: Pacing::default(), osd: Osd::default(), boot_rom: None, power_on: None}
   }
 
   pub fn step(&mut self) {
-    self.get_cpu().step();
+    self.step_and_notify();
   }
 
   pub fn step_until_vblank(&mut self) {
+    while !self.step_and_notify() {}
+  }
+
+  /// Meant to be called once per displayed frame, runs a frame with [`Gameboy::step_until_vblank`] unless paused,
+  /// or slowed down by [`Gameboy::set_slow_motion`]. Returns whether a frame was run.
+  pub fn run_frame(&mut self) -> bool {
+    let pacing = &mut self.pacing;
+    if std::mem::take(&mut pacing.advance) {
+      self.step_until_vblank();
+      return true;
+    }
+    if pacing.paused { return false; }
+
+    pacing.skipped += 1;
+    if pacing.skipped < pacing.slow_motion { return false; }
+    pacing.skipped = 0;
+    self.step_until_vblank();
+    true
+  }
+
+  /// Stops [`Gameboy::run_frame`] from running frames, other than with [`Gameboy::advance_frame`].
+  pub fn set_paused(&mut self, paused: bool) {
+    self.pacing.paused = paused;
+  }
+
+  pub fn is_paused(&self) -> bool {
+    self.pacing.paused
+  }
+
+  /// Makes the next [`Gameboy::run_frame`] run a frame, even when paused.
+  pub fn advance_frame(&mut self) {
+    self.pacing.advance = true;
+  }
+
+  /// Makes [`Gameboy::run_frame`] run a frame only once every `factor` calls, 1 is the normal speed.
+  pub fn set_slow_motion(&mut self, factor: u32) {
+    self.pacing.slow_motion = factor.max(1);
+    self.pacing.skipped = 0;
+  }
+
+  pub fn slow_motion(&self) -> u32 {
+    self.pacing.slow_motion
+  }
+
+  /// Runs until at least `n` T-cycles have elapsed.
+  /// Instructions aren't split, so it returns how many T-cycles it ran past `n`.
+  pub fn run_tcycles(&mut self, n: usize) -> usize {
+    let target = self.total_tcycles() + n;
+    while self.total_tcycles() < target {
+      self.step_and_notify();
+    }
+    self.total_tcycles() - target
+  }
+
+  /// Like [`Gameboy::run_tcycles`], in M-cycles.
+  pub fn run_mcycles(&mut self, n: usize) -> usize {
+    let target = self.total_mcycles() + n;
+    while self.total_mcycles() < target {
+      self.step_and_notify();
+    }
+    self.total_mcycles() - target
+  }
+
+  /// Runs until a breakpoint, watchpoint or condition of [`Gameboy::get_debugger`] is hit.
+  /// At least one instruction is run, so calling it again continues past a breakpoint.
+  /// Without any break set, it never returns.
+  pub fn run_until_break(&mut self) -> BreakReason {
+    self.cpu.bus.debugger.take_hit();
     loop {
-      if self.get_ppu().frame_ready.take().is_some() { break; }
-      self.step();
+      if let Some(reason) = self.step_debug() {
+        return reason;
+      }
     }
   }
 
-  pub fn reset(&mut self) {}
+  /// Runs an instruction, and returns the break of [`Gameboy::get_debugger`] it hit, if any.
+  pub fn step_debug(&mut self) -> Option<BreakReason> {
+    self.step_and_notify();
+    self.cpu.bus.debugger.take_hit()
+      .or_else(|| self.cpu.bus.debugger.check_cpu(&self.cpu))
+  }
+
+  /// M-cycles elapsed since boot or the last reset.
+  pub fn total_mcycles(&self) -> usize {
+    self.cpu.bus.mcycles()
+  }
+
+  /// T-cycles elapsed since boot or the last reset.
+  pub fn total_tcycles(&self) -> usize {
+    self.total_mcycles() * 4
+  }
+
+  /// Frames completed since boot or the last reset.
+  pub fn total_frames(&self) -> usize {
+    self.cpu.bus.ppu.frame_count()
+  }
+
+  /// Runs an instruction, then calls the callbacks of what happened during it.
+  /// Returns true when a frame was completed.
+  fn step_and_notify(&mut self) -> bool {
+    if let Some(trace) = &mut self.callbacks.trace {
+      trace(&self.cpu.doctor_line());
+    }
+
+    self.cpu.step();
+
+    if let Some(byte) = self.cpu.bus.serial.take_sent() {
+      if let Some(on_serial) = &mut self.callbacks.serial {
+        on_serial(byte);
+      }
+    }
+
+    if self.cpu.bus.ppu.line_ready.take().is_some() {
+      if let Some(on_scanline) = &mut self.callbacks.scanline {
+        on_scanline(&self.cpu.bus.ppu);
+      }
+    }
+
+    if self.cpu.bus.ppu.frame_ready.take().is_none() { return false; }
+
+    self.cpu.bus.joypad.on_frame();
+    if let Some(sgb) = &mut self.cpu.bus.sgb {
+      sgb.joypads.iter_mut().for_each(Joypad::on_frame);
+    }
+    self.input_log.on_frame(&mut self.cpu.bus.joypad);
+    let bus = &mut self.cpu.bus;
+    let cheats = std::mem::take(&mut bus.cart.cheats);
+    for (addr, val) in cheats.ram_writes() {
+      bus.write_mapped(addr, val);
+    }
+    bus.cart.cheats = cheats;
+
+    if let Some(on_frame) = &mut self.callbacks.frame {
+      let bus = &self.cpu.bus;
+      on_frame(bus.sgb.as_ref().map_or(&bus.ppu.lcd, |sgb| &sgb.screen));
+    }
+    if let Some(on_audio) = &mut self.callbacks.audio {
+      let buf = &mut self.callbacks.audio_buf;
+      buf.clear();
+      self.cpu.bus.apu.drain_samples_into(buf);
+      on_audio(buf);
+    }
+    true
+  }
+
+  /// Calls `f` with the LCD contents every time a frame is completed.
+  pub fn on_frame(&mut self, f: impl FnMut(&FrameBuffer) + Send + 'static) {
+    self.callbacks.frame = Some(Box::new(f));
+  }
+
+  /// Calls `f` at every frame with the audio generated during it, in the [`Gameboy::get_samples`] format.
+  /// The samples are drained, so `get_samples` won't return them anymore.
+  pub fn on_audio(&mut self, f: impl FnMut(&[f32]) + Send + 'static) {
+    self.callbacks.audio = Some(Box::new(f));
+  }
+
+  /// Calls `f` with every byte sent through the link port.
+  pub fn on_serial_byte(&mut self, f: impl FnMut(u8) + Send + 'static) {
+    self.callbacks.serial = Some(Box::new(f));
+  }
+
+  /// Calls `f` with the PPU every time a visible line is drawn, with [`Ppu::ly`] still on that line.
+  /// The line's pixels are already in [`Ppu::lcd`].
+  pub fn on_scanline(&mut self, f: impl FnMut(&Ppu) + Send + 'static) {
+    self.callbacks.scanline = Some(Box::new(f));
+  }
+
+  /// Calls `f` before every instruction with the CPU state, in the Gameboy Doctor log format.
+  pub fn on_trace(&mut self, f: impl FnMut(&str) + Send + 'static) {
+    self.callbacks.trace = Some(Box::new(f));
+  }
+
+  /// Writes a Gameboy Doctor log line before every instruction.
+  /// Tracing stops at the first write error.
+  pub fn trace_to_writer(&mut self, mut writer: impl Write + Send + 'static) {
+    let mut failed = false;
+    self.on_trace(move |line| {
+      if !failed {
+        failed = writeln!(writer, "{line}").is_err();
+      }
+    });
+  }
+
+  pub fn stop_trace(&mut self) {
+    self.callbacks.trace = None;
+  }
+
+  /// Unregisters every callback.
+  pub fn clear_callbacks(&mut self) {
+    self.callbacks = Callbacks::default();
+  }
+
+  /// Power cycles the console, and records the joypad at every frame until [`Gameboy::stop_recording`].
+  pub fn start_recording(&mut self) {
+    self.reset();
+    let (title, checksum) = self.rom_identity();
+    self.input_log = InputLog::Recording(Movie { title, checksum, frames: Vec::new() });
+  }
+
+  /// Returns the recorded movie, if recording.
+  pub fn stop_recording(&mut self) -> Option<Movie> {
+    match std::mem::take(&mut self.input_log) {
+      InputLog::Recording(movie) => Some(movie),
+      other => { self.input_log = other; None }
+    }
+  }
+
+  /// Power cycles the console, and sets the joypad from the movie at every frame, until it ends.
+  /// Fails if the movie was recorded with another ROM.
+  pub fn play_movie(&mut self, movie: Movie) -> Result<(), Error> {
+    let (title, checksum) = self.rom_identity();
+    if movie.checksum != checksum || movie.title != title {
+      return Err(Error::MovieMismatch { title: movie.title });
+    }
+
+    self.reset();
+    self.input_log = InputLog::Playback { movie, frame: 0 };
+    self.input_log.start(&mut self.cpu.bus.joypad);
+    Ok(())
+  }
+
+  pub fn is_playing(&self) -> bool {
+    matches!(self.input_log, InputLog::Playback { .. })
+  }
+
+  fn rom_identity(&self) -> (String, u8) {
+    let header = &self.cpu.bus.cart.header;
+    (header.title().to_string(), header.header_checksum().expected)
+  }
+
+  /// Saves the whole machine, with the cartridge RAM, for [`Gameboy::load_state`].
+  /// The screen, the debugger, cheats and output settings aren't part of it.
+  pub fn save_state(&self) -> Vec<u8> {
+    let (title, checksum) = self.rom_identity();
+    let state = SaveState { version: STATE_VERSION, title, checksum, cpu: &self.cpu };
+    serde_json::to_vec(&state).expect("the machine state is serializable")
+  }
+
+  /// A 64-bit hash of everything in a save state, without making one: the raw values are hashed,
+  /// without formatting them, so it costs about a pass over the RAMs. Two machines with the same hash are in sync,
+  /// for replays and netplay.
+  pub fn state_hash(&self) -> u64 {
+    let mut hash = Fnv1a::default();
+    self.cpu.serialize(&mut Hasher::new(&mut hash)).expect("hashing doesn't fail");
+    hash.0
+  }
+
+  /// Restores a state from [`Gameboy::save_state`]. Fails if it was saved with another ROM,
+  /// or another version of the emulator, in which case nothing is changed.
+  pub fn load_state(&mut self, state: &[u8]) -> Result<(), Error> {
+    let invalid = |e: serde_json::Error| Error::InvalidState(e.to_string());
+
+    // checked first, as the layout of other versions may not parse
+    let header: SaveState<IgnoredAny> = serde_json::from_slice(state).map_err(invalid)?;
+    if header.version != STATE_VERSION {
+      return Err(Error::InvalidState(format!("version {}, expected {STATE_VERSION}", header.version)));
+    }
+    let (title, checksum) = self.rom_identity();
+    if header.title != title || header.checksum != checksum {
+      return Err(Error::StateMismatch { title: header.title });
+    }
+
+    let state: SaveState<Cpu<Bus>> = serde_json::from_slice(state).map_err(invalid)?;
+    self.cpu.bus.cart.check_mapper(&state.cpu.bus.cart)?;
+    let old = std::mem::replace(&mut self.cpu, state.cpu);
+    self.cpu.bus.keep_from(old.bus);
+    Ok(())
+  }
+
+  /// Power cycles the console, running the boot ROM again if one is set.
+  /// The cartridge stays inserted, and keeps its RAM. The settings are kept: palette, pixel format, frame blend and skip,
+  /// audio config and channel mutes, and a WAV capture in progress goes on.
+  pub fn reset(&mut self) {
+    // the output settings and a WAV capture in progress go on in the new APU
+    let mut apu = std::mem::take(&mut self.cpu.bus.apu);
+    let palette = self.cpu.bus.ppu.lcd.palette();
+    let format = self.cpu.bus.ppu.lcd.format();
+    let blend = self.cpu.bus.ppu.lcd.frame_blend();
+    let frame_skip = self.cpu.bus.ppu.frame_skip;
+    let debugger = std::mem::take(&mut self.cpu.bus.debugger);
+    let transceiver = self.cpu.bus.transceiver.take();
+    let turbo = self.cpu.bus.joypad.turbo;
+    let dmg_quirks = self.cpu.bus.ppu.dmg_quirks;
+    let sgb = self.cpu.bus.sgb.is_some();
+    let hidden_layers = self.cpu.bus.ppu.hidden_layers;
+    let mut cart = std::mem::take(&mut self.cpu.bus.cart);
+    cart.reset();
+    self.cpu = Cpu::new(cart);
+    if let Some(state) = self.power_on { self.cpu.bus.fill_ram(state); }
+    self.cpu.bus.debugger = debugger;
+    self.cpu.bus.set_transceiver(transceiver);
+    self.cpu.bus.joypad.turbo = turbo;
+    self.cpu.bus.ppu.dmg_quirks = dmg_quirks;
+    self.cpu.bus.ppu.hidden_layers = hidden_layers;
+    self.cpu.bus.ppu.frame_skip = frame_skip;
+    if sgb { self.cpu.bus.sgb = Some(Sgb::new(palette, format)); }
+    self.cpu.bus.apu.keep_from(&mut apu);
+    self.set_pixel_format(format);
+    self.set_palette(palette);
+    self.set_frame_blend(blend);
+
+    if let Some(boot) = &self.boot_rom {
+      // the boot ROM starts from blank registers, the other hardware is left in its post boot state,
+      // which the boot ROM mostly sets again
+      let cpu = &mut self.cpu;
+      cpu.bus.bootrom = Some(boot.clone());
+      (cpu.a, cpu.f, cpu.sp, cpu.pc) = (0, Flags::empty(), 0, 0);
+      (cpu.bc, cpu.de, cpu.hl) = (Register16::new(), Register16::new(), Register16::new());
+    }
+  }
+
+  /// Swaps the cartridge for `rom`, and power cycles the console.
+  /// The settings kept by [`Gameboy::reset`], the callbacks, debugger and boot ROM are kept,
+  /// the cheats and movies of the previous game are dropped.
+  /// The game database overrides of the new game are applied, as when booting.
+  /// On error, the previous cartridge stays inserted.
+  pub fn load_rom(&mut self, rom: &[u8]) -> Result<(), Error> {
+    self.load_rom_with_options(rom, &LoadOptions::default())
+  }
+
+  /// Like [`Gameboy::load_rom`], with relaxed header checks for homebrew.
+  pub fn load_rom_with_options(&mut self, rom: &[u8], opts: &LoadOptions) -> Result<(), Error> {
+    let mut cart = Cart::with_options(rom, opts)?;
+    let palette = apply_game_db(&mut cart, opts);
+    if !cart.header.sgb_support { self.cpu.bus.sgb = None; }
+    if let Some(state) = self.power_on { state.fill(cart.ram_mut(), 0xA000); }
+    self.cpu.bus.cart = cart;
+    self.input_log = InputLog::Off;
+    self.reset();
+    if let Some(palette) = palette { self.set_palette(palette); }
+    Ok(())
+  }
+
+  /// Like [`Gameboy::reset`], also erasing the cartridge RAM, so the game starts without its saves.
+  /// The cartridge RAM is filled with 0xFF, or with the [`PowerOnState`] when one is set.
+  pub fn reset_erasing_ram(&mut self) {
+    match self.power_on {
+      Some(state) => state.fill(self.cpu.bus.cart.ram_mut(), 0xA000),
+      None => self.cpu.bus.cart.erase_ram(),
+    }
+    self.reset();
+  }
+
+  /// Sets what the console RAMs hold at every power on, from the next [`Gameboy::reset`]. The cartridge RAM,
+  /// with its saves, is kept: [`Gameboy::reset_erasing_ram`] also fills it, for the game to start from
+  /// the same state every time, for movies and replays, as does loading a ROM.
+  /// With `None`, the console RAMs are zeroed, and the cartridge RAM is erased with 0xFF.
+  pub fn set_power_on_state(&mut self, state: Option<PowerOnState>) {
+    self.power_on = state;
+  }
+
+  pub fn get_power_on_state(&self) -> Option<PowerOnState> {
+    self.power_on
+  }
+
+  /// Runs `rom` at every power on, from now on. The console is power cycled to run it.
+  /// It is either the 256 bytes DMG boot ROM, or the 2304 bytes CGB one. With `None`, games start right away.
+  pub fn set_boot_rom(&mut self, rom: Option<Vec<u8>>) -> Result<(), Error> {
+    if let Some(rom) = &rom {
+      if rom.len() != 0x100 && rom.len() != 0x900 {
+        return Err(Error::InvalidBootRom { len: rom.len() });
+      }
+    }
+    self.boot_rom = rom;
+    self.reset();
+    Ok(())
+  }
 }
 
 impl Gameboy {
@@ -45,17 +478,854 @@ impl Gameboy {
     self.cpu.bus.cart.header.clone()
   }
 
-  pub fn get_resolution(&mut self) -> (usize, usize) { (32*8, 30*8) }
+  pub fn get_resolution(&self) -> (usize, usize) {
+    let screen = self.get_screen();
+    (screen.width, screen.height)
+  }
 
+  /// The LCD, or the Super Game Boy screen with its border when [`Gameboy::set_sgb`] is on.
   pub fn get_screen(&self) -> &FrameBuffer {
-    &self.cpu.bus.ppu.lcd
+    match &self.cpu.bus.sgb {
+      Some(sgb) => &sgb.screen,
+      None => &self.cpu.bus.ppu.lcd,
+    }
+  }
+
+  pub fn screenshot(&self) -> Screenshot {
+    match &self.cpu.bus.sgb {
+      // the SGB colors aren't shades of the palette
+      Some(sgb) => sgb.screen.color_screenshot(),
+      None => self.get_screen().screenshot(),
+    }
+  }
+
+  /// Saves the current frame as a PNG file.
+  #[cfg(feature = "png")]
+  pub fn save_png(&self, path: impl AsRef<Path>) -> io::Result<()> {
+    self.screenshot().save_png(path)
+  }
+
+  /// Sets the layout of the [`Gameboy::get_screen`] buffer. The screen is cleared.
+  pub fn set_pixel_format(&mut self, format: PixelFormat) {
+    let lcd = &mut self.get_ppu().lcd;
+    let mut new_lcd = FrameBuffer::with_format(lcd.width, lcd.height, format);
+    new_lcd.set_palette(lcd.palette());
+    new_lcd.set_frame_blend(lcd.frame_blend());
+    *lcd = new_lcd;
+
+    if let Some(sgb) = &mut self.cpu.bus.sgb {
+      let mut screen = Sgb::screen_with(format);
+      screen.set_frame_blend(sgb.screen.frame_blend());
+      sgb.screen = screen;
+    }
+  }
+
+  /// Sets the LCD colors, see [`frame`](crate::frame) for the built-in ones.
+  pub fn set_palette(&mut self, palette: Palette) {
+    self.get_ppu().lcd.set_palette(palette);
+  }
+
+  /// Mixes `percent` of the previous frame into each new one, see [`FrameBuffer::set_frame_blend`].
+  pub fn set_frame_blend(&mut self, percent: u8) {
+    self.get_ppu().lcd.set_frame_blend(percent);
+    if let Some(sgb) = &mut self.cpu.bus.sgb {
+      sgb.screen.set_frame_blend(percent);
+    }
+  }
+
+  /// Skips drawing to [`Gameboy::get_screen`] while set, for fast-forwarding.
+  /// The screen keeps the last frame drawn, the emulation is otherwise unaffected.
+  pub fn set_frame_skip(&mut self, skip: bool) {
+    self.get_ppu().frame_skip = skip;
+  }
+
+  /// Runs games supporting it as on a Super Game Boy, with their border and colors.
+  /// The screen becomes 256x224, see [`sgb`](crate::sgb). The console is power cycled, as games look for the SGB at boot.
+  /// Does nothing for games without SGB support.
+  pub fn set_sgb(&mut self, enabled: bool) {
+    if enabled == self.is_sgb() || !self.cpu.bus.cart.header.sgb_support { return; }
+
+    let bus = &mut self.cpu.bus;
+    bus.sgb = enabled.then(|| Sgb::new(bus.ppu.lcd.palette(), bus.ppu.lcd.format()));
+    self.reset();
   }
 
+  pub fn is_sgb(&self) -> bool {
+    self.cpu.bus.sgb.is_some()
+  }
+
+  /// Emulates the DMG hardware bugs some games rely on, like the spurious STAT interrupt
+  /// when writing to STAT (Road Rash, Zerd no Densetsu). On by default.
+  pub fn set_dmg_quirks(&mut self, enabled: bool) {
+    self.get_ppu().dmg_quirks = enabled;
+  }
+
+  /// Plugs a transceiver in the CGB infrared port, like one end of [`infrared::loopback`](crate::infrared::loopback).
+  /// It stays plugged in through power cycles and loaded states. DMG games have no infrared port.
+  pub fn set_infrared(&mut self, transceiver: Option<Box<dyn Transceiver>>) {
+    self.cpu.bus.set_transceiver(transceiver);
+  }
+
+  /// Drains the audio generated since the last call.
+  ///
+  /// Samples are interleaved stereo `f32` pairs (left, right) in the -1..1 range,
+  /// at the sample rate set with [`Gameboy::set_audio_config`] (44100 Hz by default).
+  /// About `sample_rate / 59.7` pairs are produced each frame. If they aren't drained,
+  /// the oldest ones are dropped once `AudioConfig::buffer_size` pairs are buffered.
   pub fn get_samples(&mut self) -> Vec<f32> {
     self.get_apu().consume_samples()
   }
 
+  /// Like [`Gameboy::get_samples`], but appends to `out`, so a frontend can reuse its buffer.
+  pub fn get_samples_into(&mut self, out: &mut Vec<f32>) {
+    self.get_apu().drain_samples_into(out);
+  }
+
+  pub fn set_audio_config(&mut self, config: AudioConfig) {
+    self.get_apu().set_config(config);
+  }
+
+  /// Records the audio output to a 16 bit stereo WAV file, until [`Gameboy::stop_audio_capture`].
+  pub fn start_audio_capture(&mut self, path: impl AsRef<Path>) -> io::Result<()> {
+    self.get_apu().start_capture(path)
+  }
+
+  pub fn stop_audio_capture(&mut self) -> io::Result<()> {
+    self.get_apu().stop_capture()
+  }
+
+  /// Audio/video alignment data of the last completed frame.
+  pub fn get_frame_timing(&self) -> FrameTiming {
+    self.cpu.bus.frame_timing
+  }
+
+  /// The on-screen display, for frontends and scripts to show messages. It isn't drawn on [`Gameboy::get_screen`].
+  pub fn get_osd(&mut self) -> &mut Osd {
+    &mut self.osd
+  }
+
+  pub fn get_debugger(&mut self) -> &mut Debugger {
+    &mut self.cpu.bus.debugger
+  }
+
+  /// Whether the rumble motor of an MBC5 rumble cart is on. Poll it once per frame.
+  pub fn rumble_state(&self) -> bool {
+    self.cpu.bus.cart.rumble()
+  }
+
+  /// The GameShark and Game Genie codes, kept across resets.
+  pub fn get_cheats(&mut self) -> &mut Cheats {
+    &mut self.cpu.bus.cart.cheats
+  }
+
   pub fn get_joypad(&mut self) -> &mut Joypad {
     &mut self.cpu.bus.joypad
   }
-}
\ No newline at end of file
+
+  /// The joypad of `player`, from 0 to 3, for frontends with more than one input device.
+  /// Player 0 is [`Gameboy::get_joypad`]. The others only exist with [`Gameboy::set_sgb`],
+  /// and are read by the games asking the SGB for 2 or 4 players, see [`Sgb::players`].
+  pub fn joypad_n(&mut self, player: usize) -> Option<&mut Joypad> {
+    match player {
+      0 => Some(&mut self.cpu.bus.joypad),
+      _ => self.cpu.bus.sgb.as_mut()?.joypad(player),
+    }
+  }
+}
+#[cfg(test)]
+mod gb_tests {
+  use std::sync::{Arc, Mutex};
+
+  use super::*;
+  use crate::{mem::Memory, testing::{asm, RomBuilder}};
+
+  #[test]
+  fn callbacks() {
+    let rom = RomBuilder::new()
+      .code(&asm![
+        "LD A, $42",
+        "LDH ($01), A",
+        "LD A, $81",
+        "LDH ($02), A",
+        "loop:",
+        "JR loop",
+      ])
+      .build();
+    let mut gb = Gameboy::boot_from_bytes(&rom).unwrap();
+
+    let sent = Arc::new(Mutex::new(Vec::new()));
+    let frames = Arc::new(Mutex::new(0));
+    let samples = Arc::new(Mutex::new(0));
+    gb.on_serial_byte({ let sent = sent.clone(); move |b| sent.lock().unwrap().push(b) });
+    gb.on_frame({ let frames = frames.clone(); move |lcd| {
+      assert_eq!((lcd.width, lcd.height), (160, 144));
+      *frames.lock().unwrap() += 1;
+    }});
+    gb.on_audio({ let samples = samples.clone(); move |s| *samples.lock().unwrap() += s.len() });
+
+    for _ in 0..3 { gb.step_until_vblank(); }
+    assert_eq!(*sent.lock().unwrap(), [0x42]);
+    assert_eq!(*frames.lock().unwrap(), 3);
+    assert!(*samples.lock().unwrap() > 0);
+    assert!(gb.get_samples().is_empty());
+
+    gb.clear_callbacks();
+    gb.step_until_vblank();
+    assert_eq!(*frames.lock().unwrap(), 3);
+  }
+
+  #[test]
+  fn trace() {
+    let rom = RomBuilder::new().code(&asm!["LD A, $12", "loop:", "JR loop"]).build();
+    let mut gb = Gameboy::boot_from_bytes(&rom).unwrap();
+
+    let lines = Arc::new(Mutex::new(Vec::new()));
+    gb.on_trace({ let lines = lines.clone(); move |l| lines.lock().unwrap().push(l.to_string()) });
+    for _ in 0..4 { gb.step(); }
+
+    assert_eq!(*lines.lock().unwrap(), [
+      "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,50,01",
+      "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0101 PCMEM:C3,50,01,CE",
+      "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0150 PCMEM:3E,12,18,FE",
+      "A:12 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0152 PCMEM:18,FE,00,00",
+    ]);
+  }
+
+  #[test]
+  fn run_cycles() {
+    let rom = RomBuilder::new().code(&asm!["loop:", "JR loop"]).build();
+    let mut gb = Gameboy::boot_from_bytes(&rom).unwrap();
+
+    let overshoot = gb.run_mcycles(1001);
+    // JR takes 3 M-cycles
+    assert!(overshoot < 3);
+    assert_eq!(gb.total_mcycles(), 1001 + overshoot);
+
+    let start = gb.total_tcycles();
+    let overshoot = gb.run_tcycles(70224 * 2);
+    assert_eq!(gb.total_tcycles(), start + 70224 * 2 + overshoot);
+    assert_eq!(gb.total_tcycles() % 4, 0);
+    assert!((1..=3).contains(&gb.total_frames()));
+
+    gb.reset();
+    assert_eq!((gb.total_tcycles(), gb.total_frames()), (0, 0));
+  }
+
+  #[test]
+  fn pacing() {
+    let rom = RomBuilder::new().code(&asm!["loop:", "JR loop"]).build();
+    let mut gb = Gameboy::boot_from_bytes(&rom).unwrap();
+
+    assert!(gb.run_frame());
+    assert_eq!(gb.total_frames(), 1);
+
+    gb.set_paused(true);
+    assert!(!gb.run_frame());
+    gb.advance_frame();
+    assert!(gb.run_frame());
+    assert!(!gb.run_frame());
+    assert_eq!(gb.total_frames(), 2);
+
+    gb.set_paused(false);
+    gb.set_slow_motion(3);
+    let ran = (0..9).filter(|_| gb.run_frame()).count();
+    assert_eq!((ran, gb.total_frames()), (3, 5));
+  }
+
+  #[test]
+  fn save_state() {
+    let rom = crate::testing::ppu_bound_rom();
+    let mut gb = Gameboy::boot_from_bytes(&rom).unwrap();
+    for _ in 0..10 { gb.step_until_vblank(); }
+    gb.run_mcycles(1234);
+    let state = gb.save_state();
+
+    for _ in 0..10 { gb.step_until_vblank(); }
+    let (screen, tcycles) = (gb.get_screen().buffer.clone(), gb.total_tcycles());
+
+    // loads in another instance too
+    let mut other = Gameboy::boot_from_bytes(&rom).unwrap();
+    for gb in [&mut gb, &mut other] {
+      gb.load_state(&state).unwrap();
+      for _ in 0..10 { gb.step_until_vblank(); }
+      assert_eq!((&gb.get_screen().buffer, gb.total_tcycles()), (&screen, tcycles));
+    }
+
+    let another_rom = RomBuilder::new().at(0x134, b"OTHER").build();
+    let mut another = Gameboy::boot_from_bytes(&another_rom).unwrap();
+    assert!(matches!(another.load_state(&state), Err(Error::StateMismatch { .. })));
+    assert!(matches!(gb.load_state(b"{}"), Err(Error::InvalidState(_))));
+  }
+
+  #[test]
+  fn mapper_state() {
+    // MBC1 with 8 ROM banks, bank 3 starts with 0x33
+    let rom = RomBuilder::new()
+      .cart_type(0x03)
+      .rom_size(2)
+      .ram_size(0x02)
+      .at(3 * 0x4000, &[0x33])
+      .code(&asm![
+        "LD A, $0A",
+        "LD ($0000), A",
+        "LD A, 3",
+        "LD ($2000), A",
+        "LD A, $12",
+        "LD ($A000), A",
+        "loop:",
+        "JR loop",
+      ])
+      .build();
+    let mut gb = Gameboy::boot_from_bytes(&rom).unwrap();
+    gb.step_until_vblank();
+    let state = gb.save_state();
+
+    // the bank selected and RAM enabled after the reset come from the state, not from the mapper in use
+    gb.reset();
+    assert_eq!((gb.cpu.bus.peek(0x4000), gb.cpu.bus.peek(0xA000)), (0x00, 0xFF));
+    gb.load_state(&state).unwrap();
+    assert_eq!((gb.cpu.bus.peek(0x4000), gb.cpu.bus.peek(0xA000)), (0x33, 0x12));
+
+    // the banks layout comes from the header, whatever the state says
+    let mut json: serde_json::Value = serde_json::from_slice(&state).unwrap();
+    let mbc = &mut json["cpu"]["bus"]["cart"]["mbc"];
+    mbc["Mbc1"]["rom_banks"] = serde_json::json!({ "data_size": 0, "bank_size": 0, "banks_count": 0, "banks": [] });
+    gb.load_state(&serde_json::to_vec(&json).unwrap()).unwrap();
+    assert_eq!(gb.cpu.bus.peek(0x4000), 0x33);
+
+    // another kind of mapper is refused
+    json["cpu"]["bus"]["cart"]["mbc"] = serde_json::json!("None");
+    gb.reset();
+    assert!(matches!(gb.load_state(&serde_json::to_vec(&json).unwrap()), Err(Error::InvalidState(_))));
+    assert_eq!(gb.cpu.bus.peek(0x4000), 0x00);
+  }
+
+  #[test]
+  fn banked_states() {
+    // MBC3 and MBC5 with 8 ROM banks and 4 RAM banks, bank 5 starts with 0x55
+    for cart_type in [0x13, 0x1B] {
+      let rom = RomBuilder::new()
+        .cart_type(cart_type)
+        .rom_size(2)
+        .ram_size(0x03)
+        .at(5 * 0x4000, &[0x55])
+        .code(&asm!["loop:", "JR loop"])
+        .build();
+      let mut gb = Gameboy::boot_from_bytes(&rom).unwrap();
+      let bus = gb.get_bus();
+      bus.write(0x0000, 0x0A);
+      bus.write(0x2000, 5);
+      bus.write(0x4000, 2);
+      bus.write(0xA000, 0x77);
+      let state = gb.save_state();
+
+      gb.reset();
+      gb.load_state(&state).unwrap();
+      let bus = gb.get_bus();
+      assert_eq!((bus.peek(0x4000), bus.peek(0xA000)), (0x55, 0x77), "cart type {cart_type:#04X}");
+      bus.write(0x4000, 0);
+      assert_eq!(bus.peek(0xA000), 0xFF);
+    }
+  }
+
+  /// Maps the last bank written at 0x4000.
+  struct LastWriteMapper { bank: u8 }
+
+  impl crate::mbc::Mapper for LastWriteMapper {
+    fn rom_addr(&self, addr: u16) -> usize {
+      match addr {
+        0x0000..=0x3FFF => addr as usize,
+        _ => self.bank as usize * 0x4000 + (addr as usize - 0x4000),
+      }
+    }
+    fn ram_addr(&self, addr: u16) -> (bool, usize) { (true, addr as usize) }
+    fn rom_write(&mut self, _: u16, val: u8) { self.bank = val; }
+    fn reset(&mut self, _: &CartHeader) { self.bank = 1; }
+    fn save_state(&self) -> crate::mbc::MbcState { crate::mbc::MbcState::custom(vec![self.bank]) }
+    fn load_state(&mut self, data: &[u8]) { self.bank = data[0]; }
+  }
+
+  #[test]
+  fn custom_mapper() {
+    let rom = RomBuilder::new()
+      .rom_size(2)
+      .at(2 * 0x4000, &[0x22])
+      .code(&asm![
+        "LD A, 2",
+        "LD ($2000), A",
+        "loop:",
+        "JR loop",
+      ])
+      .build();
+    let mapper = Box::new(LastWriteMapper { bank: 1 });
+    let mut gb = Gameboy::boot_from_cart(Cart::new_with_mapper(&rom, mapper).unwrap());
+    gb.step_until_vblank();
+    assert_eq!(gb.cpu.bus.peek(0x4000), 0x22);
+    let state = gb.save_state();
+
+    gb.reset();
+    assert_eq!(gb.cpu.bus.peek(0x4000), 0x00);
+    gb.load_state(&state).unwrap();
+    assert_eq!(gb.cpu.bus.peek(0x4000), 0x22);
+
+    // banks past the end of the ROM are open bus
+    gb.cpu.bus.write(0x2000, 9);
+    assert_eq!(gb.cpu.bus.peek(0x4000), 0xFF);
+  }
+
+  /// The background scrolls by a pixel each frame.
+  fn scrolling_rom() -> Vec<u8> {
+    RomBuilder::new()
+      .code(&asm![
+        "LD A, 0",
+        "LDH ($40), A",
+        "LD HL, $8000",
+        "vram:",
+        "LD A, L",
+        "LD (HL+), A",
+        "LD A, H",
+        "CP $A0",
+        "JR NZ, vram",
+        "LD A, $E4",
+        "LDH ($47), A",
+        "LD A, $91",
+        "LDH ($40), A",
+        "loop:",
+        "LDH A, ($44)",
+        "CP 144",
+        "JR NZ, loop",
+        "LDH A, ($43)",
+        "INC A",
+        "LDH ($43), A",
+        "wait:",
+        "LDH A, ($44)",
+        "CP 144",
+        "JR Z, wait",
+        "JR loop",
+      ])
+      .build()
+  }
+
+  #[test]
+  fn frame_skip() {
+    let rom = scrolling_rom();
+    let mut skipped = Gameboy::boot_from_bytes(&rom).unwrap();
+    let mut drawn = Gameboy::boot_from_bytes(&rom).unwrap();
+
+    for _ in 0..10 { drawn.step_until_vblank(); }
+    let frame = drawn.get_screen().buffer.clone();
+    for _ in 0..10 { drawn.step_until_vblank(); }
+    assert_ne!(drawn.get_screen().buffer, frame);
+
+    for _ in 0..10 { skipped.step_until_vblank(); }
+    skipped.set_frame_skip(true);
+    for _ in 0..10 { skipped.step_until_vblank(); }
+    // the screen is stuck on the last drawn frame, but the emulation went on the same
+    assert_eq!(skipped.get_screen().buffer, frame);
+    assert_eq!(skipped.total_tcycles(), drawn.total_tcycles());
+
+    skipped.set_frame_skip(false);
+    drawn.step_until_vblank();
+    skipped.step_until_vblank();
+    assert_eq!(skipped.get_screen().buffer, drawn.get_screen().buffer);
+
+    skipped.set_frame_skip(true);
+    skipped.reset();
+    assert!(skipped.get_ppu().frame_skip);
+  }
+
+  #[test]
+  fn frame_blend() {
+    let rom = scrolling_rom();
+    let mut blended = Gameboy::boot_from_bytes(&rom).unwrap();
+    let mut plain = Gameboy::boot_from_bytes(&rom).unwrap();
+    blended.set_frame_blend(50);
+
+    for _ in 0..10 {
+      blended.step_until_vblank();
+      plain.step_until_vblank();
+    }
+    let previous: Vec<_> = (0..160).map(|x| plain.get_screen().get_color(x, 0)).collect();
+    blended.step_until_vblank();
+    plain.step_until_vblank();
+
+    let screen = blended.get_screen();
+    assert!((0..160).any(|x| screen.get_color(x, 0) != plain.get_screen().get_color(x, 0)));
+    for (x, previous) in previous.into_iter().enumerate() {
+      let current = plain.get_screen().get_color(x, 0);
+      assert_eq!(screen.get_color(x, 0), crate::frame::mix(current, previous, 0.5));
+    }
+    // the shades aren't blended
+    assert_eq!(screen.indexed, plain.get_screen().indexed);
+
+    blended.reset();
+    assert_eq!(blended.get_screen().frame_blend(), 50);
+  }
+
+  #[test]
+  fn sgb() {
+    let code = asm!["loop:", "JR loop"];
+    let mut gb = Gameboy::boot_from_bytes(&RomBuilder::new().code(&code).build()).unwrap();
+    gb.set_sgb(true);
+    assert!(!gb.is_sgb());
+    assert!(gb.joypad_n(0).is_some() && gb.joypad_n(1).is_none());
+
+    let rom = RomBuilder::new().code(&code).at(0x146, &[0x03]).at(0x14B, &[0x33]).build();
+    let mut gb = Gameboy::boot_from_bytes(&rom).unwrap();
+    gb.set_sgb(true);
+    assert!(gb.is_sgb());
+    assert!(gb.joypad_n(3).is_some() && gb.joypad_n(4).is_none());
+    gb.step_until_vblank();
+    assert_eq!(gb.get_resolution(), (256, 224));
+    assert_eq!(gb.screenshot().rgb.len(), 256 * 224 * 3);
+
+    // MLT_REQ for 2 players, sent through P1
+    let bus = gb.get_bus();
+    let mut packet = [0u8; 16];
+    packet[..2].copy_from_slice(&[0x11 << 3 | 1, 1]);
+    bus.write(0xFF00, 0x00);
+    bus.write(0xFF00, 0x30);
+    for i in 0..=128 {
+      let bit = i < 128 && packet[i / 8] & (1 << (i % 8)) != 0;
+      bus.write(0xFF00, if bit { 0x10 } else { 0x20 });
+      bus.write(0xFF00, 0x30);
+    }
+    assert_eq!(bus.sgb.as_ref().unwrap().players(), 2);
+    bus.write(0xFF00, 0x10);
+    bus.write(0xFF00, 0x30);
+    let first = bus.read(0xFF00) & 0xF;
+    bus.write(0xFF00, 0x10);
+    bus.write(0xFF00, 0x30);
+    assert_ne!(bus.read(0xFF00) & 0xF, first);
+
+    gb.reset();
+    assert!(gb.is_sgb());
+    gb.set_sgb(false);
+    assert_eq!(gb.get_resolution(), (160, 144));
+  }
+
+  #[test]
+  fn oam_dma() {
+    let rom = RomBuilder::new()
+      .code(&asm![
+        "LD A, $C1",
+        "LDH ($46), A",
+        "loop:",
+        "JR loop",
+      ])
+      .build();
+    let mut gb = Gameboy::boot_from_bytes(&rom).unwrap();
+    let bus = gb.get_bus();
+    for i in 0..160 { bus.write(0xC100 + i, i as u8); }
+
+    // the transfer runs while the CPU keeps going, it doesn't need to halt
+    for _ in 0..200 { gb.step(); }
+    assert_eq!(gb.get_ppu().oam.to_vec(), (0..160).collect::<Vec<u8>>());
+  }
+
+  #[test]
+  fn boot_rom() {
+    let rom = RomBuilder::new()
+      .ram_size(0x02)
+      .cart_type(0x03)
+      .code(&asm![
+        "LD A, $0A",
+        "LD ($0000), A",
+        "LD HL, $A000",
+        "INC (HL)",
+        "loop:",
+        "JR loop",
+      ])
+      .build();
+    let mut gb = Gameboy::boot_from_bytes(&rom).unwrap();
+    assert!(matches!(gb.set_boot_rom(Some(vec![0; 100])), Err(Error::InvalidBootRom { len: 100 })));
+
+    // counts its runs in WRAM, and unmaps itself before jumping to the cartridge entry point
+    let mut boot = crate::testing::assemble(0, &[
+      "LD HL, $C000",
+      "INC (HL)",
+      "LD A, 1",
+      "LDH ($50), A",
+      "JP $0100",
+    ]).unwrap();
+    boot.resize(0x100, 0);
+    gb.set_boot_rom(Some(boot.clone())).unwrap();
+    assert_eq!((gb.cpu.pc, gb.cpu.bus.peek(0x0000)), (0, 0x21));
+    gb.step_until_vblank();
+    assert_eq!(gb.cpu.bus.peek(0xC000), 1);
+    assert_eq!(gb.cpu.bus.peek(0xA000), 0x00);
+    // the cartridge is visible again
+    assert_eq!(gb.cpu.bus.peek(0x0000), rom[0]);
+
+    // WRAM is cleared by the power cycle, the cartridge RAM is kept unless erased
+    gb.reset();
+    gb.step_until_vblank();
+    assert_eq!(gb.cpu.bus.peek(0xC000), 1);
+    assert_eq!(gb.cpu.bus.peek(0xA000), 0x01);
+    gb.reset_erasing_ram();
+    gb.step_until_vblank();
+    assert_eq!(gb.cpu.bus.peek(0xA000), 0x00);
+
+    gb.set_boot_rom(None).unwrap();
+    assert_eq!(gb.cpu.pc, 0x100);
+  }
+
+  #[test]
+  fn reset_settings() {
+    use crate::apu::Channel;
+
+    let mut gb = Gameboy::boot_from_bytes(&RomBuilder::new().build()).unwrap();
+    gb.get_apu().solo_channel(Channel::Wave);
+    gb.reset();
+    let apu = gb.get_apu();
+    assert!(apu.is_channel_enabled(Channel::Wave) && !apu.is_channel_enabled(Channel::Square1));
+
+    // a capture goes on through resets
+    let path = std::env::temp_dir().join(format!("tomboy-reset-{}.wav", std::process::id()));
+    gb.start_audio_capture(&path).unwrap();
+    gb.step_until_vblank();
+    let before = std::fs::metadata(&path).unwrap().len();
+    gb.reset();
+    assert!(gb.get_apu().is_capturing());
+    for _ in 0..10 { gb.step_until_vblank(); }
+    gb.stop_audio_capture().unwrap();
+    assert!(std::fs::metadata(&path).unwrap().len() > before);
+    let _ = std::fs::remove_file(&path);
+  }
+
+  #[test]
+  fn load_rom() {
+    use crate::apu::Channel;
+
+    let mut gb = Gameboy::boot_from_bytes(&RomBuilder::new().build()).unwrap();
+    let palette = [(1, 2, 3); 4];
+    gb.set_palette(palette);
+    gb.set_frame_blend(50);
+    gb.set_frame_skip(true);
+    gb.get_apu().set_channel_enabled(Channel::Noise, false);
+    let config = AudioConfig { sample_rate: 22050, ..AudioConfig::default() };
+    gb.set_audio_config(config);
+    let path = std::env::temp_dir().join(format!("tomboy-load-rom-{}.wav", std::process::id()));
+    gb.start_audio_capture(&path).unwrap();
+    let frames = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let counter = frames.clone();
+    gb.on_frame(move |_| { counter.fetch_add(1, std::sync::atomic::Ordering::Relaxed); });
+    gb.step_until_vblank();
+
+    assert!(matches!(gb.load_rom(&[0; 0x100]), Err(Error::RomTooSmall { .. })));
+    assert_eq!(gb.get_cart().title(), "TEST");
+
+    let other = RomBuilder::new().at(0x134, b"OTHER").build();
+    gb.load_rom(&other).unwrap();
+    assert_eq!(gb.get_cart().title(), "OTHER");
+    assert_eq!((gb.total_frames(), gb.cpu.pc), (0, 0x100));
+    assert_eq!(gb.cpu.bus.ppu.lcd.palette(), palette);
+    assert_eq!((gb.get_screen().frame_blend(), gb.get_ppu().frame_skip), (50, true));
+    assert_eq!(gb.get_apu().config(), config);
+    assert!(!gb.get_apu().is_channel_enabled(Channel::Noise) && gb.get_apu().is_channel_enabled(Channel::Wave));
+    assert!(gb.get_apu().is_capturing());
+    gb.step_until_vblank();
+    assert_eq!(frames.load(std::sync::atomic::Ordering::Relaxed), 2);
+    gb.stop_audio_capture().unwrap();
+    let _ = std::fs::remove_file(&path);
+  }
+
+  #[test]
+  fn hashes() {
+    let rom = crate::testing::ppu_bound_rom();
+    let mut gb = Gameboy::boot_from_bytes(&rom).unwrap();
+    let mut other = Gameboy::boot_from_bytes(&rom).unwrap();
+    let state = gb.save_state();
+    let hash = gb.state_hash();
+    for _ in 0..3 {
+      gb.step_until_vblank();
+      other.step_until_vblank();
+    }
+    assert_eq!(gb.state_hash(), other.state_hash());
+    assert_eq!(gb.get_screen().hash(), other.get_screen().hash());
+
+    // the palette doesn't change the frame hash
+    gb.set_palette([(1, 2, 3); 4]);
+    gb.step_until_vblank();
+    other.step_until_vblank();
+    assert_ne!(gb.get_screen().buffer, other.get_screen().buffer);
+    assert_eq!(gb.get_screen().hash(), other.get_screen().hash());
+
+    other.get_joypad().button_pressed(crate::joypad::Flags::a_right);
+    other.step();
+    assert_ne!(gb.state_hash(), other.state_hash());
+    other.load_state(&state).unwrap();
+    gb.load_state(&state).unwrap();
+    assert_eq!(gb.state_hash(), other.state_hash());
+    // loading a state restores it exactly, floats included
+    assert_eq!(gb.state_hash(), hash);
+  }
+
+  #[test]
+  fn power_on_state() {
+    use crate::mem::PowerOnState;
+
+    let rom = RomBuilder::new().cart_type(0x03).ram_size(0x02).build();
+    let mut gb = Gameboy::boot_from_bytes(&rom).unwrap();
+    assert!(gb.cpu.bus.wram().iter().all(|&b| b == 0));
+
+    // the saves are kept, until the RAM is erased
+    gb.cpu.bus.cart.ram_mut()[0] = 0x42;
+    gb.set_power_on_state(Some(PowerOnState::SeededRandom(1)));
+    assert!(gb.cpu.bus.wram().iter().all(|&b| b == 0));
+    gb.reset();
+    let wram = gb.cpu.bus.wram().to_vec();
+    assert_ne!(wram[..0x100], [0; 0x100]);
+    assert_eq!(gb.cpu.bus.cart.ram()[0], 0x42);
+    gb.reset_erasing_ram();
+    assert_ne!(wram[..0x100], gb.cpu.bus.cart.ram()[..0x100]);
+    // the same seed gives the same memory, at every power on
+    assert_eq!(gb.cpu.bus.wram(), wram);
+    gb.set_power_on_state(Some(PowerOnState::SeededRandom(2)));
+    gb.reset();
+    assert_ne!(gb.cpu.bus.wram(), wram);
+
+    gb.set_power_on_state(Some(PowerOnState::Pattern));
+    gb.reset();
+    assert_eq!(gb.cpu.bus.wram()[6..10], [0x00, 0x00, 0xFF, 0xFF]);
+    // a loaded ROM starts with the state in its RAM too
+    gb.set_power_on_state(Some(PowerOnState::AllZero));
+    gb.load_rom(&rom).unwrap();
+    assert!(gb.cpu.bus.cart.ram().iter().all(|&b| b == 0));
+    gb.set_power_on_state(Some(PowerOnState::AllFF));
+    gb.reset();
+    assert!(gb.get_ppu().vram.iter().all(|&b| b == 0xFF));
+
+    gb.set_power_on_state(None);
+    gb.reset_erasing_ram();
+    assert!(gb.cpu.bus.hram().iter().all(|&b| b == 0));
+    assert!(gb.cpu.bus.cart.ram().iter().all(|&b| b == 0xFF));
+  }
+
+  #[test]
+  fn memory_slices() {
+    let rom = RomBuilder::new()
+      .cart_type(0x03)
+      .ram_size(0x03)
+      .code(&asm![
+        "LD A, $0A",
+        "LD ($0000), A",
+        "LD A, 1",
+        "LD ($6000), A",
+        "LD A, 2",
+        "LD ($4000), A",
+        "LD A, $12",
+        "LD ($A001), A",
+        "LD ($C002), A",
+        "LDH ($83), A",
+        "loop:",
+        "JR loop",
+      ])
+      .build();
+    let mut gb = Gameboy::boot_from_bytes(&rom).unwrap();
+    gb.step_until_vblank();
+
+    let bus = gb.get_bus();
+    assert_eq!((bus.wram().len(), bus.hram().len(), bus.cart.ram().len()), (0x2000, 0x7F, 0x8000));
+    assert_eq!((bus.wram()[2], bus.hram()[3]), (0x12, 0x12));
+    // the RAM of bank 2
+    assert_eq!(bus.cart.ram()[0x4001], 0x12);
+    bus.cart.ram_mut()[0x4001] = 0x34;
+    assert_eq!(bus.peek(0xA001), 0x34);
+  }
+
+  #[test]
+  fn interrupt_timing() {
+    let run = |code: &[&str], at: &[(usize, &[&str])]| {
+      let mut rom = RomBuilder::new().code(&crate::testing::assemble(0x150, code).unwrap());
+      for (addr, code) in at {
+        rom = rom.at(*addr, &crate::testing::assemble(*addr as u16, code).unwrap());
+      }
+      let mut gb = Gameboy::boot_from_bytes(&rom.build()).unwrap();
+      for _ in 0..3 { gb.step_until_vblank(); }
+      gb
+    };
+    let vblank_handler: (usize, &[&str]) = (0x40, &["LD C, B", "handler:", "JR handler"]);
+
+    // waking up from HALT without IME doesn't service the interrupt
+    let gb = run(&["DI", "LD A, 1", "LDH ($FF), A", "XOR A", "LDH ($0F), A", "LD C, A",
+      "HALT", "LDH A, ($0F)", "LD B, A", "loop:", "JR loop"], &[vblank_handler]);
+    assert_eq!(gb.cpu.bc.hi() & 1, 1);
+    assert_eq!(gb.cpu.bc.lo(), 0);
+
+    // HALT with an interrupt pending and IME off runs the next byte twice
+    let gb = run(&["DI", "LD B, 0", "LD A, 1", "LDH ($FF), A", "LDH ($0F), A",
+      "HALT", "INC B", "loop:", "JR loop"], &[]);
+    assert_eq!(gb.cpu.bc.hi(), 2);
+
+    // EI enables interrupts after the next instruction
+    let gb = run(&["LD B, 0", "LD A, 1", "LDH ($FF), A", "LDH ($0F), A",
+      "EI", "INC B", "INC B", "loop:", "JR loop"], &[vblank_handler]);
+    assert_eq!(gb.cpu.bc.lo(), 1);
+
+    // EI followed by DI never lets an interrupt in
+    let gb = run(&["LD BC, $0000", "LD A, 1", "LDH ($FF), A", "LDH ($0F), A",
+      "EI", "DI", "INC B", "loop:", "JR loop"], &[vblank_handler]);
+    assert_eq!(gb.cpu.bc.into_bits(), 0x0100);
+
+    // pushing the PC high byte over IE cancels the interrupt, and jumps to 0
+    let gb = run(&["JP $0200"], &[
+      (0x200, &["DI", "LD SP, $0000", "LD A, 1", "LDH ($FF), A", "LDH ($0F), A", "EI", "NOP", "loop:", "JR loop"]),
+      (0x00, &["LD C, $42", "loop0:", "JR loop0"]),
+    ]);
+    assert_eq!(gb.cpu.bc.lo(), 0x42);
+  }
+
+  #[test]
+  fn echo_ram() {
+    let rom = RomBuilder::new().code(&asm!["loop:", "JR loop"]).build();
+    let mut gb = Gameboy::boot_from_bytes(&rom).unwrap();
+    let bus = gb.get_bus();
+    for addr in 0xE000..=0xFDFF {
+      bus.write(addr, addr as u8 ^ (addr >> 8) as u8);
+    }
+    for addr in 0xC000..=0xDDFF {
+      assert_eq!(bus.read(addr), bus.read(addr + 0x2000), "{addr:04X}");
+    }
+  }
+
+  #[test]
+  fn unmapped_reads() {
+    let rom = RomBuilder::new().code(&asm!["loop:", "JR loop"]).build();
+    let mut gb = Gameboy::boot_from_bytes(&rom).unwrap();
+    gb.get_ppu().write(0xFF40, 0);
+    let bus = gb.get_bus();
+    assert_eq!(bus.read(0xFEA0), 0);
+    assert_eq!(bus.read(0xFF03), 0xFF);
+    assert_eq!(bus.read(0xFF50), 0xFF);
+    bus.write(0xFF72, 0x12);
+    assert_eq!((bus.read(0xFF56), bus.read(0xFF72)), (0xFF, 0xFF));
+    bus.write(0xFF46, 0xC1);
+    assert_eq!(bus.read(0xFF46), 0xC1);
+
+    let rom = RomBuilder::new().code(&asm!["loop:", "JR loop"]).at(0x143, &[0x80]).build();
+    let mut gb = Gameboy::boot_from_bytes(&rom).unwrap();
+    gb.get_ppu().write(0xFF40, 0);
+    assert_eq!(gb.get_bus().read(0xFEA5), 0xAA);
+    assert_eq!(gb.get_bus().read(0xFEF0), 0xFF);
+    let bus = gb.get_bus();
+    bus.write(0xFF56, 0xFF);
+    assert_eq!(bus.read(0xFF56), 0xFF);
+    bus.write(0xFF56, 0);
+    assert_eq!(bus.read(0xFF56), 0x3E);
+    bus.write(0xFF72, 0x12);
+    bus.write(0xFF75, 0);
+    assert_eq!((bus.read(0xFF72), bus.read(0xFF75)), (0x12, 0x8F));
+
+    // blocked like OAM while the PPU reads it
+    gb.get_ppu().write(0xFF40, 0x80);
+    while gb.get_ppu().mode() != crate::ppu::PpuMode::DrawingPixels { gb.step(); }
+    assert_eq!(gb.get_bus().read(0xFEA5), 0xFF);
+  }
+
+  #[test]
+  fn send() {
+    fn assert_send<T: Send>() {}
+    assert_send::<Gameboy>();
+  }
+}
@@ -0,0 +1,389 @@
+//! A GDB remote serial protocol server, to debug homebrew from GDB or an IDE.
+//!
+//! The registers are sent as six little endian 16 bit values: AF, BC, DE, HL, SP, PC.
+//! Software breakpoints (`Z0`) and watchpoints (`Z2`-`Z4`) use the [`Debugger`](crate::debugger::Debugger).
+//! ```no_run
+//! # let rom = vec![];
+//! let mut gb = tomboy_emulator::gb::Gameboy::boot_from_bytes(&rom).unwrap();
+//! // then `target remote localhost:2159` from the client
+//! tomboy_emulator::gdb::listen(&mut gb, "127.0.0.1:2159").unwrap();
+//! ```
+
+use std::{fmt::Write as _, io::{self, Read, Write}, net::{TcpListener, TcpStream, ToSocketAddrs}};
+
+use crate::{cpu::{Flags, Register16}, debugger::{Access, BreakReason}, gb::Gameboy, mem::Memory};
+
+/// Instructions run between two checks for a client interrupt, while continuing.
+const INTERRUPT_CHECK_INTERVAL: usize = 10_000;
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+/// The biggest packet, as told to the client. Memory reads are cut to fit in it.
+const PACKET_SIZE: usize = 0x1000;
+
+/// The stream the protocol is spoken over.
+pub trait Connection: Read + Write {
+  /// Returns true if the client sent an interrupt (Ctrl-C), without blocking.
+  fn poll_interrupt(&mut self) -> io::Result<bool>;
+}
+
+impl Connection for TcpStream {
+  fn poll_interrupt(&mut self) -> io::Result<bool> {
+    self.set_nonblocking(true)?;
+    let mut byte = [0];
+    let res = match self.read(&mut byte) {
+      Ok(1) => Ok(byte[0] == 0x03),
+      Ok(_) => Ok(false),
+      Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+      Err(e) => Err(e),
+    };
+    self.set_nonblocking(false)?;
+    res
+  }
+}
+
+/// Waits for a client on `addr`, and serves it until it detaches or kills the session.
+pub fn listen(gb: &mut Gameboy, addr: impl ToSocketAddrs) -> io::Result<()> {
+  let (stream, _) = TcpListener::bind(addr)?.accept()?;
+  stream.set_nodelay(true)?;
+  serve(gb, stream)
+}
+
+/// Serves a client until it detaches, kills the session, or closes the connection.
+pub fn serve(gb: &mut Gameboy, conn: impl Connection) -> io::Result<()> {
+  Session { gb, conn }.run()
+}
+
+struct Session<'a, C: Connection> {
+  gb: &'a mut Gameboy,
+  conn: C,
+}
+
+enum Packet {
+  Command(String),
+  Interrupt,
+}
+
+impl<C: Connection> Session<'_, C> {
+  fn run(&mut self) -> io::Result<()> {
+    while let Some(packet) = self.read_packet()? {
+      let cmd = match packet {
+        Packet::Interrupt => { self.send(&format!("S{SIGINT:02x}"))?; continue; }
+        Packet::Command(cmd) => cmd,
+      };
+
+      match cmd.as_bytes().first() {
+        Some(b'k') => return Ok(()),
+        Some(b'D') => { self.send("OK")?; return Ok(()); }
+        _ => {
+          let res = self.handle(&cmd)?;
+          self.send(&res)?;
+        }
+      }
+    }
+    Ok(())
+  }
+
+  /// Returns None when the connection is closed.
+  fn read_packet(&mut self) -> io::Result<Option<Packet>> {
+    let mut byte = [0];
+    loop {
+      if self.conn.read(&mut byte)? == 0 { return Ok(None); }
+      match byte[0] {
+        0x03 => return Ok(Some(Packet::Interrupt)),
+        b'$' => break,
+        // acks, and noise
+        _ => {}
+      }
+    }
+
+    let mut data = Vec::new();
+    loop {
+      if self.conn.read(&mut byte)? == 0 { return Ok(None); }
+      if byte[0] == b'#' { break; }
+      data.push(byte[0]);
+    }
+    let mut checksum = [0; 2];
+    self.conn.read_exact(&mut checksum)?;
+
+    self.conn.write_all(b"+")?;
+    Ok(Some(Packet::Command(String::from_utf8_lossy(&data).into_owned())))
+  }
+
+  fn send(&mut self, data: &str) -> io::Result<()> {
+    let checksum = data.bytes().fold(0u8, |acc, b| acc.wrapping_add(b));
+    write!(self.conn, "${data}#{checksum:02x}")?;
+    self.conn.flush()
+  }
+
+  fn handle(&mut self, cmd: &str) -> io::Result<String> {
+    let (kind, args) = cmd.split_at(1);
+    let res = match kind {
+      "?" => format!("S{SIGTRAP:02x}"),
+      "g" => self.read_registers(),
+      "G" => self.write_registers(args).map(|_| "OK".to_string()).unwrap_or_else(error),
+      "p" => parse_hex(args)
+        .and_then(|reg| self.register(reg as usize))
+        .map(|val| hex_bytes(&val.to_le_bytes()))
+        .unwrap_or_else(error),
+      "P" => self.write_register(args).map(|_| "OK".to_string()).unwrap_or_else(error),
+      "m" => self.read_memory(args).unwrap_or_else(error),
+      "M" => self.write_memory(args).map(|_| "OK".to_string()).unwrap_or_else(error),
+      "s" => Self::stop_reply(self.gb.step_debug()),
+      "c" => {
+        let reason = self.resume()?;
+        return Ok(reason);
+      }
+      "Z" | "z" => self.set_break(kind == "Z", args).map(|_| "OK".to_string()).unwrap_or_default(),
+      "H" => "OK".to_string(),
+      "q" => match args {
+        _ if args.starts_with("Supported") => format!("PacketSize={PACKET_SIZE:x}"),
+        "Attached" => "1".to_string(),
+        "C" => "QC1".to_string(),
+        "fThreadInfo" => "m1".to_string(),
+        "sThreadInfo" => "l".to_string(),
+        _ => String::new(),
+      },
+      // unsupported, the client falls back to other commands
+      _ => String::new(),
+    };
+    Ok(res)
+  }
+
+  fn resume(&mut self) -> io::Result<String> {
+    loop {
+      for _ in 0..INTERRUPT_CHECK_INTERVAL {
+        if let Some(reason) = self.gb.step_debug() {
+          return Ok(Self::stop_reply(Some(reason)));
+        }
+      }
+      if self.conn.poll_interrupt()? {
+        return Ok(format!("S{SIGINT:02x}"));
+      }
+    }
+  }
+
+  fn stop_reply(reason: Option<BreakReason>) -> String {
+    match reason {
+      Some(BreakReason::Watchpoint { addr, access, .. }) => {
+        let kind = match access { Access::Write => "watch", Access::Read => "rwatch" };
+        format!("T{SIGTRAP:02x}{kind}:{addr:x};")
+      }
+      _ => format!("S{SIGTRAP:02x}"),
+    }
+  }
+
+  fn register(&mut self, reg: usize) -> Option<u16> {
+    let cpu = self.gb.get_cpu();
+    let val = match reg {
+      0 => u16::from_be_bytes([cpu.a, cpu.f.bits()]),
+      1 => cpu.bc.into_bits(),
+      2 => cpu.de.into_bits(),
+      3 => cpu.hl.into_bits(),
+      4 => cpu.sp,
+      5 => cpu.pc,
+      _ => return None,
+    };
+    Some(val)
+  }
+
+  fn set_register(&mut self, reg: usize, val: u16) -> Option<()> {
+    let cpu = self.gb.get_cpu();
+    match reg {
+      0 => {
+        let [a, f] = val.to_be_bytes();
+        cpu.a = a;
+        cpu.f = Flags::from_bits_retain(f & 0xF0);
+      }
+      1 => cpu.bc = Register16::from_bits(val),
+      2 => cpu.de = Register16::from_bits(val),
+      3 => cpu.hl = Register16::from_bits(val),
+      4 => cpu.sp = val,
+      5 => cpu.pc = val,
+      _ => return None,
+    }
+    Some(())
+  }
+
+  fn read_registers(&mut self) -> String {
+    (0..6)
+      .filter_map(|reg| self.register(reg))
+      .map(|val| hex_bytes(&val.to_le_bytes()))
+      .collect()
+  }
+
+  fn write_registers(&mut self, args: &str) -> Option<()> {
+    let bytes = parse_hex_bytes(args)?;
+    if bytes.len() < 12 { return None; }
+    for (reg, val) in bytes.chunks(2).take(6).enumerate() {
+      self.set_register(reg, u16::from_le_bytes([val[0], val[1]]))?;
+    }
+    Some(())
+  }
+
+  fn write_register(&mut self, args: &str) -> Option<()> {
+    let (reg, val) = args.split_once('=')?;
+    let val = parse_hex_bytes(val)?;
+    self.set_register(parse_hex(reg)? as usize, u16::from_le_bytes([*val.first()?, *val.get(1)?]))
+  }
+
+  fn read_memory(&mut self, args: &str) -> Option<String> {
+    let (addr, len) = args.split_once(',')?;
+    let (addr, len) = (parse_hex(addr)?, parse_hex(len)?);
+    // two hex digits per byte, the client asks for the rest next
+    let len = len.min(PACKET_SIZE as u32 / 2);
+    let bus = self.gb.get_bus();
+    let bytes = (0..len).map(|i| bus.peek(addr.wrapping_add(i) as u16)).collect::<Vec<_>>();
+    Some(hex_bytes(&bytes))
+  }
+
+  fn write_memory(&mut self, args: &str) -> Option<()> {
+    let (range, data) = args.split_once(':')?;
+    let (addr, _) = range.split_once(',')?;
+    let addr = parse_hex(addr)?;
+    let bus = self.gb.get_bus();
+    for (i, byte) in parse_hex_bytes(data)?.into_iter().enumerate() {
+      bus.write_mapped(addr.wrapping_add(i as u32) as u16, byte);
+    }
+    Some(())
+  }
+
+  fn set_break(&mut self, insert: bool, args: &str) -> Option<()> {
+    let mut args = args.split(',');
+    let kind = args.next()?;
+    let addr = parse_hex(args.next()?)? as u16;
+    let len = parse_hex(args.next()?)?.max(1) as u16;
+    let range = addr..=addr.saturating_add(len - 1);
+    let debugger = self.gb.get_debugger();
+
+    let (read, write) = match kind {
+      "0" | "1" => {
+        match insert {
+          true => debugger.add_breakpoint(addr),
+          false => debugger.remove_breakpoint(addr),
+        }
+        return Some(());
+      }
+      "2" => (false, true),
+      "3" => (true, false),
+      "4" => (true, true),
+      _ => return None,
+    };
+
+    match insert {
+      true => debugger.add_watchpoint(range, read, write),
+      false => debugger.remove_watchpoint(range),
+    }
+    Some(())
+  }
+}
+
+fn error() -> String {
+  "E01".to_string()
+}
+
+fn parse_hex(s: &str) -> Option<u32> {
+  u32::from_str_radix(s, 16).ok()
+}
+
+fn parse_hex_bytes(s: &str) -> Option<Vec<u8>> {
+  if !s.len().is_multiple_of(2) { return None; }
+  (0..s.len()).step_by(2)
+    .map(|i| u8::from_str_radix(s.get(i..i+2)?, 16).ok())
+    .collect()
+}
+
+fn hex_bytes(bytes: &[u8]) -> String {
+  bytes.iter().fold(String::new(), |mut res, b| {
+    let _ = write!(res, "{b:02x}");
+    res
+  })
+}
+
+#[cfg(test)]
+mod gdb_tests {
+  use std::collections::VecDeque;
+
+  use super::*;
+  use crate::testing::{asm, RomBuilder};
+
+  struct Script {
+    input: VecDeque<u8>,
+    output: Vec<u8>,
+  }
+
+  impl Read for Script {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+      self.input.read(buf)
+    }
+  }
+
+  impl Write for Script {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+      self.output.write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> { Ok(()) }
+  }
+
+  impl Connection for &mut Script {
+    fn poll_interrupt(&mut self) -> io::Result<bool> { Ok(false) }
+  }
+
+  fn packet(data: &str) -> String {
+    let checksum = data.bytes().fold(0u8, |acc, b| acc.wrapping_add(b));
+    format!("${data}#{checksum:02x}")
+  }
+
+  #[test]
+  fn session() {
+    let rom = RomBuilder::new()
+      .code(&asm![
+        "LD A, $42",
+        "LD ($C000), A",
+        "loop:",
+        "JR loop",
+      ])
+      .build();
+    let mut gb = Gameboy::boot_from_bytes(&rom).unwrap();
+
+    let commands = [
+      "qSupported:swbreak+", "?",
+      // break at the LD A, $42
+      "Z0,150,1", "c", "z0,150,1",
+      "p5", "s", "g",
+      "Z2,c000,1", "c",
+      "m c000,2", "M c001,1:99", "m c000,2",
+      "P5=5001", "p5",
+      "D",
+    ];
+    let input = commands.iter().map(|c| packet(&c.replace(' ', ""))).collect::<String>();
+    let mut script = Script { input: input.bytes().collect(), output: Vec::new() };
+    serve(&mut gb, &mut script).unwrap();
+
+    let output = String::from_utf8(script.output).unwrap();
+    let replies = output.split('+').filter(|r| !r.is_empty()).collect::<Vec<_>>();
+    let expected = [
+      "PacketSize=1000", "S05",
+      "OK", "S05", "OK",
+      "5001", "S05", "b0421300d8004d01feff5201",
+      "OK", "T05watch:c000;",
+      "4200", "OK", "4299",
+      "OK", "5001",
+      "OK",
+    ];
+    assert_eq!(replies, expected.map(packet));
+  }
+
+  #[test]
+  fn huge_read() {
+    let rom = RomBuilder::new().code(&asm!["loop:", "JR loop"]).build();
+    let mut gb = Gameboy::boot_from_bytes(&rom).unwrap();
+    let input = ["m0,ffffffff", "D"].map(packet).concat();
+    let mut script = Script { input: input.bytes().collect(), output: Vec::new() };
+    serve(&mut gb, &mut script).unwrap();
+
+    let output = String::from_utf8(script.output).unwrap();
+    let reply = output.split('+').find(|r| !r.is_empty()).unwrap();
+    // $, the hex bytes, #, the checksum
+    assert_eq!(reply.len(), 1 + PACKET_SIZE + 3);
+  }
+}
@@ -0,0 +1,217 @@
+//! Joypad recording and playback, for TAS movies and regression tests.
+//!
+//! Movies always start from power on, and store the pressed buttons of every frame.
+//! As the emulation is deterministic, playing a movie reproduces the recorded run,
+//! as long as the inputs were only changed between frames.
+//!
+//! The movie file is plain text: a `tomboy movie` line, the ROM `title` and header `checksum`,
+//! then a line per frame with the pressed buttons in `UDLRSsBA` order, `.` for released ones.
+//! ```text
+//! tomboy movie
+//! title TETRIS
+//! checksum 0A
+//! ........
+//! ......BA
+//! ```
+
+use std::{fmt::Write, fs, path::Path};
+
+use crate::{joypad::{Flags, Joypad}, Error};
+
+const MAGIC: &str = "tomboy movie";
+const KEYS: [char; 8] = ['U', 'D', 'L', 'R', 'S', 's', 'B', 'A'];
+// bits of KEYS in the dpad and buttons flags
+const DPAD_KEYS: [Flags; 4] = [Flags::select_up, Flags::start_down, Flags::b_left, Flags::a_right];
+const BUTTON_KEYS: [Flags; 4] = [Flags::start_down, Flags::select_up, Flags::b_left, Flags::a_right];
+
+/// The pressed buttons during a frame.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Input {
+  pub buttons: Flags,
+  pub dpad: Flags,
+}
+
+impl Input {
+  fn to_line(self) -> String {
+    let dpad = DPAD_KEYS.iter().map(|key| self.dpad.contains(*key));
+    let buttons = BUTTON_KEYS.iter().map(|key| self.buttons.contains(*key));
+
+    dpad.chain(buttons)
+      .zip(KEYS)
+      .map(|(pressed, key)| if pressed { key } else { '.' })
+      .collect()
+  }
+
+  fn from_line(line: &str) -> Option<Self> {
+    if line.chars().count() != KEYS.len() { return None; }
+
+    let mut input = Input::default();
+    for (i, (c, key)) in line.chars().zip(KEYS).enumerate() {
+      if c == '.' { continue; }
+      if c != key { return None; }
+
+      match i {
+        0..4 => input.dpad.insert(DPAD_KEYS[i]),
+        _ => input.buttons.insert(BUTTON_KEYS[i-4]),
+      }
+    }
+    Some(input)
+  }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Movie {
+  /// Title in the header of the recorded ROM
+  pub title: String,
+  /// Header checksum of the recorded ROM, checked before playback
+  pub checksum: u8,
+  pub frames: Vec<Input>,
+}
+
+impl Movie {
+  pub fn serialize(&self) -> String {
+    let mut res = format!("{MAGIC}\ntitle {}\nchecksum {:02X}\n", self.title, self.checksum);
+    for input in &self.frames {
+      let _ = writeln!(res, "{}", input.to_line());
+    }
+    res
+  }
+
+  pub fn parse(text: &str) -> Result<Self, Error> {
+    let mut lines = text.lines().enumerate().map(|(i, line)| (i+1, line));
+    let mut next_line = |prefix: &str| match lines.next() {
+      Some((i, line)) => line.strip_prefix(prefix).ok_or(Error::InvalidMovie { line: i }).map(|val| (i, val)),
+      None => Err(Error::InvalidMovie { line: 0 }),
+    };
+
+    next_line(MAGIC)?;
+    let (_, title) = next_line("title ")?;
+    let (i, checksum) = next_line("checksum ")?;
+    let checksum = u8::from_str_radix(checksum, 16)
+      .map_err(|_| Error::InvalidMovie { line: i })?;
+
+    let frames = lines
+      .map(|(i, line)| Input::from_line(line).ok_or(Error::InvalidMovie { line: i }))
+      .collect::<Result<_, _>>()?;
+
+    Ok(Self { title: title.to_string(), checksum, frames })
+  }
+
+  pub fn save(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+    Ok(fs::write(path, self.serialize())?)
+  }
+
+  pub fn load(path: impl AsRef<Path>) -> Result<Self, Error> {
+    Self::parse(&fs::read_to_string(path)?)
+  }
+}
+
+#[derive(Default)]
+pub(crate) enum InputLog {
+  #[default]
+  Off,
+  Recording(Movie),
+  Playback { movie: Movie, frame: usize },
+}
+
+impl InputLog {
+  /// Called before the first frame.
+  pub(crate) fn start(&mut self, joypad: &mut Joypad) {
+    if let InputLog::Playback { movie, .. } = self {
+      let input = movie.frames.first().copied().unwrap_or_default();
+      joypad.set_state(input.buttons, input.dpad);
+    }
+  }
+
+  /// Called when a frame is completed, to record its inputs or to set the next ones.
+  pub(crate) fn on_frame(&mut self, joypad: &mut Joypad) {
+    match self {
+      InputLog::Off => {}
+      InputLog::Recording(movie) => {
+        let (buttons, dpad) = joypad.state();
+        movie.frames.push(Input { buttons, dpad });
+      }
+      InputLog::Playback { movie, frame } => {
+        *frame += 1;
+        match movie.frames.get(*frame) {
+          Some(input) => joypad.set_state(input.buttons, input.dpad),
+          None => {
+            joypad.set_state(Flags::empty(), Flags::empty());
+            *self = InputLog::Off;
+          }
+        }
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod input_log_tests {
+  use super::*;
+  use crate::{gb::Gameboy, mem::Memory, testing::{asm, RomBuilder}};
+
+  #[test]
+  fn movie_file() {
+    let movie = Movie {
+      title: "TEST".to_string(),
+      checksum: 0x3A,
+      frames: vec![
+        Input::default(),
+        Input { buttons: Flags::a_right | Flags::start_down, dpad: Flags::select_up },
+        Input { buttons: Flags::empty(), dpad: Flags::b_left | Flags::a_right },
+      ],
+    };
+
+    let text = movie.serialize();
+    assert_eq!(text, "tomboy movie\ntitle TEST\nchecksum 3A\n........\nU...S..A\n..LR....\n");
+    assert_eq!(Movie::parse(&text).unwrap(), movie);
+    assert_eq!(Movie::parse("tomboy movie\ntitle X\nchecksum 00\n....A...\n"), Err(Error::InvalidMovie { line: 4 }));
+    assert_eq!(Movie::parse("tomboy movie\ntitle X\n"), Err(Error::InvalidMovie { line: 0 }));
+  }
+
+  #[test]
+  fn record_and_replay() {
+    let rom = RomBuilder::new()
+      .code(&asm![
+        // select the buttons, and sum the P1 reads into $C000
+        "LD A, $10",
+        "LDH ($00), A",
+        "loop:",
+        "LDH A, ($00)",
+        "LD B, A",
+        "LD A, ($C000)",
+        "ADD A, B",
+        "LD ($C000), A",
+        "JR loop",
+      ])
+      .build();
+    let mut gb = Gameboy::boot_from_bytes(&rom).unwrap();
+
+    gb.start_recording();
+    for frame in 0..30 {
+      let buttons = if frame % 3 == 0 { Flags::a_right } else { Flags::b_left | Flags::start_down };
+      gb.get_joypad().set_state(buttons, Flags::empty());
+      gb.step_until_vblank();
+    }
+    let movie = gb.stop_recording().unwrap();
+    assert_eq!(movie.frames.len(), 30);
+    assert_eq!(movie.frames[3].buttons, Flags::a_right);
+    let expected = (gb.get_bus().peek(0xC000), gb.total_mcycles());
+
+    gb.play_movie(movie.clone()).unwrap();
+    assert!(gb.is_playing());
+    for _ in 0..30 { gb.step_until_vblank(); }
+    assert!(!gb.is_playing());
+    assert_eq!((gb.get_bus().peek(0xC000), gb.total_mcycles()), expected);
+
+    let other = RomBuilder::new().at(0x134, b"OTHER").build();
+    let mut gb = Gameboy::boot_with_options(&other, &crate::cart::LoadOptions::tolerant()).unwrap();
+    assert!(matches!(gb.play_movie(movie), Err(Error::MovieMismatch { .. })));
+
+    // the CGB flag after the title isn't part of it
+    let cgb = RomBuilder::new().at(0x134, b"FIFTEEN LETTERS").at(0x143, &[0x80]).build();
+    let mut gb = Gameboy::boot_from_bytes(&cgb).unwrap();
+    gb.start_recording();
+    assert_eq!(gb.stop_recording().unwrap().title, "FIFTEEN LETTERS");
+  }
+}
@@ -1,80 +1,37 @@
-use std::{collections::HashMap, sync::LazyLock};
-use serde::Deserialize;
+use std::fmt;
 
-#[derive(Deserialize, Debug)]
+#[derive(Debug)]
 pub struct Instruction {
-  #[serde(skip)]
   pub opcode: u8,
-  #[serde(alias = "mnemonic")]
   pub name: &'static str,
   pub bytes: usize,
-  pub cycles: Vec<usize>,
+  pub cycles: &'static [usize],
   pub immediate: bool,
-  #[serde(skip)]
   pub prefix: bool,
-  pub operands: Vec<InstrTarget>,
+  pub operands: &'static [InstrTarget],
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum TargetKind {
-  #[serde(alias = "n8")]
   Immediate8,
-  #[serde(alias = "n16")]
   Immediate16,
-  #[serde(alias = "a8")]
   Address8,
-  #[serde(alias = "a16")]
   Address16,
-  #[serde(alias = "e8")]
   Signed8,
   A, B, C, D, E, F, H, L,
   AF, BC, DE, HL, SP,
   N, Z,
   NZ, NC, NH,
 
-	#[serde(alias = "$00")]
-  RST00,
-	#[serde(alias = "$08")]
-  RST08,
-	#[serde(alias = "$10")]
-  RST10,
-	#[serde(alias = "$18")]
-  RST18,
-	#[serde(alias = "$20")]
-  RST20,
-	#[serde(alias = "$28")]
-  RST28,
-	#[serde(alias = "$30")]
-  RST30,
-	#[serde(alias = "$38")]
-  RST38,
-
-	#[serde(alias = "0")]
-  Bit0,
-	#[serde(alias = "1")]
-  Bit1,
-	#[serde(alias = "2")]
-  Bit2,
-	#[serde(alias = "3")]
-  Bit3,
-	#[serde(alias = "4")]
-  Bit4,
-	#[serde(alias = "5")]
-  Bit5,
-	#[serde(alias = "6")]
-  Bit6,
-	#[serde(alias = "7")]
-  Bit7,
+  RST00, RST08, RST10, RST18, RST20, RST28, RST30, RST38,
+  Bit0, Bit1, Bit2, Bit3, Bit4, Bit5, Bit6, Bit7,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Debug, Clone)]
 pub struct InstrTarget {
-  #[serde(alias = "name")]
   pub kind: TargetKind,
   pub immediate: bool,
-  #[serde(default)]
   pub increment: bool,
-  #[serde(default)]
   pub decrement: bool,
 }
 
@@ -85,74 +42,185 @@ pub const ACC_TARGET: InstrTarget = InstrTarget {
   decrement: false,
 };
 
-#[derive(Deserialize, Debug)]
-struct InstrGroups {
-  #[serde(borrow)]
-  pub unprefixed: HashMap<&'static str, Instruction>,
-  pub cbprefixed: HashMap<&'static str, Instruction>,
+// The unprefixed instructions, then the 0xCB prefixed ones, indexed by opcode.
+// Generated by build.rs from utils/instr.json.
+include!(concat!(env!("OUT_DIR"), "/instructions.rs"));
+
+/// A decoded instruction.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DisasmLine {
+  pub addr: u16,
+  /// Length in bytes, including the 0xCB prefix
+  pub len: usize,
+  pub mnemonic: &'static str,
+  /// Operands with immediates resolved, relative jumps are shown as their target address
+  pub operands: Vec<String>,
 }
 
-fn get_instructions() -> [Instruction; 256 * 2] {
-	let json = include_str!("../utils/instr.json");
-  let parsed: InstrGroups = serde_json
-	  ::from_str(json)
-	  .unwrap();
-  
-  let mut unprefixed = Vec::new();
-  let mut cbprefixed = Vec::new();
-
-  for (opcode_str, instr) in parsed.unprefixed {
-    let opcode = u8
-      ::from_str_radix(opcode_str.strip_prefix("0x").unwrap(), 16)
-      .unwrap();
-
-    let instr = Instruction {
-      opcode,
-      name: instr.name,
-      bytes: instr.bytes,
-      cycles: instr.cycles.clone(),
-      immediate: instr.immediate,
-      prefix: false,
-      operands: instr.operands.clone(),
-    };
-    unprefixed.push(instr);
+impl fmt::Display for DisasmLine {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}", self.mnemonic)?;
+    if !self.operands.is_empty() {
+      write!(f, " {}", self.operands.join(", "))?;
+    }
+    Ok(())
   }
+}
 
-  for (opcode_str, instr) in parsed.cbprefixed {
-    let opcode = u8
-      ::from_str_radix(opcode_str.strip_prefix("0x").unwrap(), 16)
-      .unwrap();
-
-    let instr = Instruction {
-      opcode,
-      name: instr.name,
-      bytes: instr.bytes,
-      cycles: instr.cycles.clone(),
-      immediate: instr.immediate,
-      prefix: true,
-      operands: instr.operands.clone(),
-    };
+fn format_operand(target: &InstrTarget, args: &[u8], next_pc: u16) -> String {
+  use TargetKind::*;
+  let imm8 = args.first().copied().unwrap_or(0);
+  let imm16 = u16::from_le_bytes([imm8, args.get(1).copied().unwrap_or(0)]);
+
+  let text = match target.kind {
+    Immediate8 => format!("${imm8:02X}"),
+    Immediate16 | Address16 => format!("${imm16:04X}"),
+    Address8 => format!("$FF{imm8:02X}"),
+    // only JR has a relative immediate target, SP+e8 is handled by the caller
+    Signed8 => format!("${:04X}", next_pc.wrapping_add_signed(imm8 as i8 as i16)),
+    RST00 | RST08 | RST10 | RST18 | RST20 | RST28 | RST30 | RST38 =>
+      format!("${:02X}", 8 * (target.kind.clone() as u8 - RST00 as u8)),
+    Bit0 | Bit1 | Bit2 | Bit3 | Bit4 | Bit5 | Bit6 | Bit7 =>
+      (target.kind.clone() as u8 - Bit0 as u8).to_string(),
+    ref reg => {
+      let inc = if target.increment { "+" } else if target.decrement { "-" } else { "" };
+      format!("{reg:?}{inc}")
+    }
+  };
+
+  if target.immediate { text } else { format!("({text})") }
+}
 
-    cbprefixed.push(instr);
+fn disassemble_one(bytes: &[u8], addr: u16) -> Option<DisasmLine> {
+  let (instr, prefix_len) = match *bytes.first()? {
+    0xCB => (&INSTRUCTIONS[256 + *bytes.get(1)? as usize], 1),
+    opcode => (&INSTRUCTIONS[opcode as usize], 0),
+  };
+
+  // the table lengths already count the prefix
+  let len = instr.bytes;
+  let args = bytes.get(1 + prefix_len..len)?;
+  let next_pc = addr.wrapping_add(len as u16);
+
+  let mut operands = Vec::new();
+  let mut targets = instr.operands.iter().peekable();
+  while let Some(target) = targets.next() {
+    let op = match (&target.kind, targets.peek()) {
+      // LD HL, SP+e8
+      (TargetKind::SP, Some(next)) if target.increment && next.kind == TargetKind::Signed8 => {
+        targets.next();
+        format!("SP{:+}", args[0] as i8)
+      }
+      // ADD SP, e8
+      (TargetKind::Signed8, _) if instr.name != "JR" => (args[0] as i8).to_string(),
+      _ => format_operand(target, args, next_pc),
+    };
+    operands.push(op);
   }
-  
-  unprefixed.sort_by(|a, b| a.opcode.cmp(&b.opcode));
-  cbprefixed.sort_by(|a, b| a.opcode.cmp(&b.opcode));
 
-  unprefixed.append(&mut cbprefixed);
-  unprefixed.try_into().unwrap()
+  Some(DisasmLine { addr, len, mnemonic: instr.name, operands })
 }
 
-pub static INSTRUCTIONS: LazyLock<[Instruction; 256*2]> = LazyLock::new(get_instructions);
+/// Disassembles `bytes` as code starting at `pc`.
+/// Stops at the first instruction that doesn't fit in `bytes`.
+pub fn disassemble(bytes: &[u8], pc: u16) -> Vec<DisasmLine> {
+  let mut res = Vec::new();
+  let mut offset = 0;
+
+  while let Some(line) = disassemble_one(&bytes[offset..], pc.wrapping_add(offset as u16)) {
+    offset += line.len;
+    res.push(line);
+  }
+
+  res
+}
 
 #[cfg(test)]
 mod instr_tests {
   use super::*;
 
   #[test]
-  fn parse_test() {
-	let flattened = get_instructions();
+  fn disassemble_code() {
+    let lines = [
+      "NOP",
+      "LD BC, $1234",
+      "LD (HL+), A",
+      "LD A, (HL-)",
+      "LD (HL), $12",
+      "LDH ($FF80), A",
+      "LD ($C000), A",
+      "ADD A, B",
+      "ADD SP, -2",
+      "LD HL, SP+5",
+      "BIT 7, (HL)",
+      "RES 0, A",
+      "RST $38",
+      "JP NZ, $0150",
+      "CALL $4000",
+      "RET Z",
+    ];
+
+    let code = crate::testing::assemble(0x150, &lines).unwrap();
+    let disasm = disassemble(&code, 0x150);
+    let text = disasm.iter().map(|l| l.to_string()).collect::<Vec<_>>();
+    assert_eq!(text, lines);
+    assert_eq!(disasm.iter().map(|l| l.len).sum::<usize>(), code.len());
+    assert_eq!(disasm[1].addr, 0x151);
+
+    // relative jumps show their target, truncated instructions are dropped
+    let disasm = disassemble(&[0x18, 0xFE, 0x20, 0x02, 0xC3, 0x00], 0x200);
+    assert_eq!(disasm.len(), 2);
+    assert_eq!(disasm[0].to_string(), "JR $0200");
+    assert_eq!(disasm[1].to_string(), "JR NZ, $0206");
+  }
 
-	println!("{:#?}", flattened);
+  #[test]
+  fn table() {
+    for (i, instr) in INSTRUCTIONS.iter().enumerate() {
+      assert_eq!(instr.opcode as usize, i % 256);
+      assert_eq!(instr.prefix, i >= 256);
+    }
+
+    let ld = &INSTRUCTIONS[0x01];
+    assert_eq!((ld.name, ld.bytes, ld.cycles), ("LD", 3, &[12][..]));
+    assert_eq!(ld.operands[0].kind, TargetKind::BC);
+    assert_eq!(ld.operands[1].kind, TargetKind::Immediate16);
+    assert!(INSTRUCTIONS[0x22].operands[0].increment);
+    assert_eq!(INSTRUCTIONS[0xFF].operands[0].kind, TargetKind::RST38);
+    assert_eq!(INSTRUCTIONS[256 + 0x7E].operands[0].kind, TargetKind::Bit7);
   }
-}
\ No newline at end of file
+
+  /// Every opcode takes the T-cycles of the table, both when its condition holds and when it doesn't.
+  #[test]
+  fn cpu_cycles() {
+    use crate::{cpu::{Cpu, Flags, Register16}, mem::Memory};
+
+    for instr in &INSTRUCTIONS {
+      // STOP isn't emulated, and CB is only a prefix
+      if !instr.prefix && matches!(instr.opcode, 0x10 | 0xCB) { continue; }
+
+      let mut cycles = [Flags::empty(), Flags::all()].map(|flags| {
+        let mut cpu = Cpu::with_ram64kb();
+        cpu.pc = 0x100;
+        cpu.sp = 0xD000;
+        cpu.hl = Register16::from_bits(0xC000);
+        cpu.f = flags;
+        let code = if instr.prefix { vec![0xCB, instr.opcode] } else { vec![instr.opcode] };
+        for (i, byte) in code.into_iter().enumerate() {
+          cpu.bus.write(0x100 + i as u16, byte);
+        }
+        cpu.bus.record_cycles();
+
+        cpu.step();
+        assert_eq!(cpu.bus.take_cycles().len(), cpu.mcycles, "{instr:?} ticks once per M-cycle");
+        cpu.mcycles * 4
+      }).to_vec();
+      cycles.sort();
+      cycles.dedup();
+
+      let mut expected = instr.cycles.to_vec();
+      expected.sort();
+      assert_eq!(cycles, expected, "{} {:02X}", instr.name, instr.opcode);
+    }
+  }
+}
@@ -1,8 +1,12 @@
+use std::str::FromStr;
+
 use bitflags::bitflags;
+use serde::{Deserialize, Serialize};
 
-use crate::bus;
+use crate::bus::IFlags;
 
 bitflags! {
+  #[derive(Default, Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
   pub struct Flags: u8 {
     const unused     = 0b1100_0000;
     const start_down = 0b0000_1000;
@@ -12,28 +16,94 @@ bitflags! {
   }
 }
 
-#[derive(PartialEq, Clone, Copy)]
+/// A single key, for frontends that don't want to deal with the P1 register layout.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Button { Up, Down, Left, Right, A, B, Start, Select }
+
+impl Button {
+  pub const ALL: [Button; 8] = [Button::Up, Button::Down, Button::Left, Button::Right, Button::A, Button::B, Button::Start, Button::Select];
+
+  /// The flag of the button, and whether it is in the dpad group.
+  fn flag(&self) -> (Flags, bool) {
+    match self {
+      Button::Up => (Flags::select_up, true),
+      Button::Down => (Flags::start_down, true),
+      Button::Left => (Flags::b_left, true),
+      Button::Right => (Flags::a_right, true),
+      Button::A => (Flags::a_right, false),
+      Button::B => (Flags::b_left, false),
+      Button::Start => (Flags::start_down, false),
+      Button::Select => (Flags::select_up, false),
+    }
+  }
+}
+
+impl FromStr for Button {
+  type Err = String;
+
+  /// Parses a button name, ignoring case: `up`, `down`, `left`, `right`, `a`, `b`, `start`, `select`.
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    Button::ALL.into_iter()
+      .find(|b| format!("{b:?}").eq_ignore_ascii_case(s))
+      .ok_or_else(|| format!("Unknown button: {s}"))
+  }
+}
+
+/// Auto-fire: held buttons with turbo on are pressed and released repeatedly, in step with the frames.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Turbo {
+  /// Buttons with turbo on, one bit per [`Button::ALL`] entry
+  enabled: u8,
+  /// Turbo buttons held by the player
+  held: u8,
+  /// Presses per second
+  rate: u32,
+  frames: u32,
+}
+
+impl Default for Turbo {
+  fn default() -> Self {
+    Self { enabled: 0, held: 0, rate: 15, frames: 0 }
+  }
+}
+
+/// Frames per second of the Gameboy.
+const FRAME_RATE: f32 = 59.73;
+
+#[derive(PartialEq, Clone, Copy, Serialize, Deserialize)]
 enum JoypadSelect { None, Dpad, Buttons, Both }
+#[derive(Serialize, Deserialize)]
 pub struct Joypad {
   selected: JoypadSelect,
   buttons: Flags,
   dpad:    Flags,
-  intf: bus::InterruptFlags,
+  /// Interrupts raised since IF was last written, see [`Bus::intf`](crate::bus::Bus::intf)
+  #[serde(default)]
+  pub(crate) intf: IFlags,
+  #[serde(skip)]
+  pub(crate) turbo: Turbo,
+}
+
+impl Default for Joypad {
+  fn default() -> Self {
+    Self::new()
+  }
 }
 
 impl Joypad {
-  pub fn new(intf: bus::InterruptFlags) -> Self {
+  pub fn new() -> Self {
     Self {
       selected: JoypadSelect::None,
       buttons: Flags::all(),
       dpad: Flags::all(),
-      intf,
+      intf: IFlags::empty(),
+      turbo: Turbo::default(),
     }
   }
 
   pub fn button_pressed(&mut self, button: Flags) {
     if self.selected == JoypadSelect::Buttons {
-      bus::send_interrupt(&self.intf, bus::IFlags::joypad);
+      self.intf.insert(IFlags::joypad);
     }
 
     self.buttons.remove(button);
@@ -45,7 +115,7 @@ impl Joypad {
 
   pub fn dpad_pressed(&mut self, button: Flags) {
     if self.selected == JoypadSelect::Dpad {
-      bus::send_interrupt(&self.intf, bus::IFlags::joypad);
+      self.intf.insert(IFlags::joypad);
     }
 
     self.dpad.remove(button);
@@ -55,15 +125,104 @@ impl Joypad {
     self.dpad.insert(button);
   }
 
+  pub fn press(&mut self, button: Button) {
+    if self.is_turbo(button) { self.turbo.held |= 1 << button as u8; }
+    self.set_pressed(button, true);
+  }
+
+  pub fn release(&mut self, button: Button) {
+    self.turbo.held &= !(1 << button as u8);
+    self.set_pressed(button, false);
+  }
+
+  fn set_pressed(&mut self, button: Button, pressed: bool) {
+    match (button.flag(), pressed) {
+      ((flag, true), true) => self.dpad_pressed(flag),
+      ((flag, false), true) => self.button_pressed(flag),
+      ((flag, true), false) => self.dpad_released(flag),
+      ((flag, false), false) => self.button_released(flag),
+    }
+  }
+
+  fn is_pressed(&self, button: Button) -> bool {
+    match button.flag() {
+      (flag, true) => !self.dpad.contains(flag),
+      (flag, false) => !self.buttons.contains(flag),
+    }
+  }
+
+  /// Turns auto-fire on or off for a button, see [`Joypad::set_turbo_rate`].
+  /// Only [`Joypad::press`] and [`Joypad::release`] are affected.
+  pub fn set_turbo(&mut self, button: Button, enabled: bool) {
+    let bit = 1 << button as u8;
+    if enabled {
+      self.turbo.enabled |= bit;
+      if self.is_pressed(button) { self.turbo.held |= bit; }
+    } else {
+      self.turbo.enabled &= !bit;
+      // a held button stays pressed
+      if self.turbo.held & bit != 0 { self.set_pressed(button, true); }
+      self.turbo.held &= !bit;
+    }
+  }
+
+  pub fn is_turbo(&self, button: Button) -> bool {
+    self.turbo.enabled & (1 << button as u8) != 0
+  }
+
+  /// Sets how many times per second turbo buttons are pressed, 15 by default.
+  /// As they change state at frame boundaries, the rate is rounded to a whole number of frames.
+  pub fn set_turbo_rate(&mut self, hz: u32) {
+    self.turbo.rate = hz.max(1);
+  }
+
+  pub fn turbo_rate(&self) -> u32 {
+    self.turbo.rate
+  }
+
+  /// Toggles the held turbo buttons when their half period has elapsed.
+  pub(crate) fn on_frame(&mut self) {
+    if self.turbo.held == 0 { return; }
+
+    let half_period = (FRAME_RATE / (2 * self.turbo.rate) as f32).round().max(1.0) as u32;
+    self.turbo.frames += 1;
+    if self.turbo.frames < half_period { return; }
+    self.turbo.frames = 0;
+
+    for button in Button::ALL {
+      if self.turbo.held & (1 << button as u8) != 0 {
+        self.set_pressed(button, !self.is_pressed(button));
+      }
+    }
+  }
+
+  /// Sets every button at once. Here set flags are the pressed buttons.
+  pub fn set_state(&mut self, buttons: Flags, dpad: Flags) {
+    let buttons = buttons.difference(Flags::unused);
+    let dpad = dpad.difference(Flags::unused);
+
+    if self.selected == JoypadSelect::Buttons && self.buttons.intersects(buttons) 
+    || self.selected == JoypadSelect::Dpad && self.dpad.intersects(dpad)
+    {
+      self.intf.insert(IFlags::joypad);
+    }
+
+    self.buttons = Flags::all().difference(buttons);
+    self.dpad = Flags::all().difference(dpad);
+  }
+
+  /// The pressed buttons and dpad directions, as given to [`Joypad::set_state`].
+  pub fn state(&self) -> (Flags, Flags) {
+    (Flags::all().difference(self.buttons), Flags::all().difference(self.dpad))
+  }
+
   pub fn read(&self) -> u8 {
-    let res = match self.selected {
+    match self.selected {
       JoypadSelect::Both => 0b1100_0000 | (self.dpad.bits() & 0b1111) | (self.buttons.bits() & 0b1111),
       JoypadSelect::Dpad    => 0b1101_0000 | (self.dpad.bits() & 0b1111),
       JoypadSelect::Buttons => 0b1110_0000 | (self.buttons.bits() & 0b1111),
       _ => 0b1100_1111,
-    };
-
-    res
+    }
   }
 
   pub fn write(&mut self, val: u8) {
@@ -74,4 +233,58 @@ impl Joypad {
       _ => JoypadSelect::Both,
     };
   }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod joypad_tests {
+  use super::*;
+
+  #[test]
+  fn buttons() {
+    let mut joypad = Joypad::new();
+    assert_eq!("Start".parse(), Ok(Button::Start));
+    assert_eq!("left".parse(), Ok(Button::Left));
+    assert!("turbo".parse::<Button>().is_err());
+
+    joypad.press(Button::Up);
+    joypad.press(Button::A);
+    joypad.write(0x20);
+    assert_eq!(joypad.read() & 0xF, 0b1011);
+    joypad.write(0x10);
+    assert_eq!(joypad.read() & 0xF, 0b1110);
+
+    joypad.release(Button::A);
+    assert_eq!(joypad.state(), (Flags::empty(), Flags::select_up));
+  }
+
+  #[test]
+  fn turbo() {
+    let mut joypad = Joypad::new();
+    joypad.set_turbo(Button::A, true);
+    joypad.set_turbo_rate(15);
+
+    joypad.press(Button::A);
+    joypad.press(Button::B);
+    // 15 presses per second is 2 frames pressed, 2 released
+    let mut a = Vec::new();
+    for _ in 0..8 {
+      a.push(joypad.is_pressed(Button::A));
+      assert!(joypad.is_pressed(Button::B));
+      joypad.on_frame();
+    }
+    assert_eq!(a, [true, true, false, false, true, true, false, false]);
+
+    joypad.release(Button::A);
+    joypad.on_frame();
+    joypad.on_frame();
+    assert!(!joypad.is_pressed(Button::A));
+
+    joypad.press(Button::A);
+    joypad.on_frame();
+    joypad.on_frame();
+    joypad.set_turbo(Button::A, false);
+    assert!(joypad.is_pressed(Button::A));
+    joypad.on_frame();
+    joypad.on_frame();
+    assert!(joypad.is_pressed(Button::A));
+  }
+}
@@ -1,21 +1,78 @@
+//! Tomboy, a Gameboy emulator core.
+//!
+//! # Feature flags
+//! Accuracy machinery that costs performance or code size is optional,
+//! so lean builds (embedded, wasm) can opt out of it. Desktop builds
+//! should keep the defaults.
+//!
+//! - `dot-renderer` (default): the PPU runs its pixel fetcher and FIFO on
+//!   every dot, so mid-scanline writes to SCX, palettes, LCDC, OAM and the window
+//!   registers are visible, as games doing raster effects expect.
+//!   Objects stop the pixel output while they are fetched, so mode 3 gets longer
+//!   with SCX, the window and the objects on the line, like on hardware.
+//!   Without it, each scanline is drawn in one go at the start of mode 3,
+//!   and mode 3 lasts as long as those penalties add up to.
+//! - `archives` (default): [`rom_loader`] extracts ROMs from `.zip` and `.gz` files.
+//! - `game-db` (default): [`game_db`] applies per-game overrides when booting.
+//! - `png`: [`Gameboy::save_png`](gb::Gameboy::save_png) saves screenshots as PNG files.
+//! - `wasm`: [`WasmGameboy`](wasm::WasmGameboy), bindings to run the emulator in a browser.
+//! - `ffi`: a C API in [`ffi`], declared in `include/tomboy.h`.
+//! - `python`: a `Gameboy` Python class in [`python`], for scripting and bots.
+//! - `scripting`: [`Script`](script::Script) runs Rhai scripts at every frame, with memory, input, text overlay and [`osd`] access.
+//! - `cli`: builds the `tomboy` command line tool, with headless subcommands to run, disassemble and inspect ROMs.
+//! - `frontend`: adds the SDL2 desktop frontend to the `tomboy` tool.
+//!
+//! # Logging
+//! What games do that isn't emulated, like illegal opcodes, is reported with the [`log`] crate,
+//! so frontends pick the verbosity with their logger. Its `max_level_*` features compile the logging out.
+
 pub mod gb;
+pub mod emu_thread;
+mod state;
+pub mod error;
+pub use error::Error;
 
 pub mod cpu;
 pub mod mem;
 pub mod instr;
+pub mod debugger;
+pub mod gdb;
+pub mod netplay;
 
 pub mod bus;
 
 pub mod timer;
 pub mod serial;
+pub mod infrared;
 pub mod joypad;
+pub mod input_log;
 pub mod apu;
 
 pub mod ppu;
 pub mod frame;
+pub mod sgb;
+pub mod osd;
+pub mod video;
+pub mod recorder;
 
 pub mod cart;
 pub mod mbc;
+pub mod cheats;
+pub mod patch;
+#[cfg(feature = "game-db")]
+pub mod game_db;
+#[cfg(feature = "archives")]
+pub mod rom_loader;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "python")]
+pub mod python;
+#[cfg(feature = "scripting")]
+pub mod script;
+
+pub mod testing;
 
 pub fn nth_bit(value: u8, bit: u8) -> bool {
   value & (1 << bit) != 0
@@ -1,91 +1,276 @@
-use std::{u8, usize};
+use serde::{Deserialize, Serialize};
 
-use crate::{cart::CartHeader, nth_bit};
+use crate::{cart::{CartHeader, LoadOptions, Unlicensed}, cheats::Cheats, nth_bit, Error};
 
-pub fn get_mbc(header: &CartHeader) -> Result<Box<dyn Mapper>, String> {
+pub fn get_mbc(header: &CartHeader) -> Result<Box<dyn Mapper>, Error> {
   let code = header.mapper_code;
+  if header.unlicensed == Some(Unlicensed::WisdomTree) {
+    return Ok(WisdomTree::new(header));
+  }
+
   let mbc: Box<dyn Mapper> = match code {
     0x00 | 0x08 | 0x09 => NoMbc::new(header),
-    0x01 | 0x02 | 0x03 => Mbc1::new(header),
+    0x01..=0x03 => Mbc1::new(header),
     0x05 | 0x06 => Mbc2::new(header),
     0x0F ..= 0x13 => Mbc3::new(header),
     0x19 ..= 0x1E => Mbc5::new(header),
-    _ => return Err(format!("Mapper {code} not implemented")),
+    _ => return Err(Error::UnsupportedMapper(code)),
   };
 
   Ok(mbc)
 }
 
+/// Only the cartridge RAM and the mapper registers are part of save states, the rest is kept from the loaded cart.
+#[derive(Serialize, Deserialize)]
 pub struct Cart {
+  #[serde(skip)]
   pub header: CartHeader,
+  #[serde(skip)]
   pub rom: Vec<u8>,
   exram: Vec<u8>,
-  mbc: Box<dyn Mapper>,
+  #[serde(with = "mapper_state")]
+  pub(crate) mbc: Box<dyn Mapper>,
+  #[serde(skip)]
+  pub cheats: Cheats,
+}
+
+fn no_mbc() -> Box<dyn Mapper> { Box::new(NoMbc) }
+
+/// Saves a mapper through its [`MbcState`].
+mod mapper_state {
+  use serde::{Deserialize, Deserializer, Serialize, Serializer};
+  use super::{Mapper, MbcState};
+
+  #[allow(clippy::borrowed_box)]
+  pub fn serialize<S: Serializer>(mbc: &Box<dyn Mapper>, s: S) -> Result<S::Ok, S::Error> {
+    mbc.save_state().serialize(s)
+  }
+
+  pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Box<dyn Mapper>, D::Error> {
+    MbcState::deserialize(d).map(|state| Box::new(super::SavedState(state)) as Box<dyn Mapper>)
+  }
+}
+
+/// The registers of a mapper: the selected banks, whether RAM is enabled, the banking mode, the RTC of MBC3
+/// and the rumble motor. The banks layout isn't saved, it's rebuilt from the cart header. See [`Mapper::save_state`].
+#[derive(Clone, Serialize, Deserialize)]
+pub struct MbcState(Mbc);
+
+#[derive(Clone, Serialize, Deserialize)]
+enum Mbc {
+  None,
+  Mbc1(Mbc1),
+  Mbc2(Mbc2),
+  Mbc3(Mbc3),
+  Mbc5(Mbc5),
+  WisdomTree(WisdomTree),
+  Custom(Vec<u8>),
+}
+
+impl MbcState {
+  /// The state of a custom mapper, in any format its [`Mapper::load_state`] reads.
+  pub fn custom(data: Vec<u8>) -> Self {
+    Self(Mbc::Custom(data))
+  }
+
+  /// The mapper of a cart with `header`, with these registers. A custom mapper can't be rebuilt, `live`,
+  /// the one in use, gets the registers instead.
+  fn into_mapper(self, header: &CartHeader, mut live: Box<dyn Mapper>) -> Box<dyn Mapper> {
+    match self.0 {
+      Mbc::Custom(data) => {
+        live.load_state(&data);
+        live
+      }
+      Mbc::None => NoMbc::new(header),
+      Mbc::Mbc1(regs) => Mbc1::restore(header, regs),
+      Mbc::Mbc2(regs) => Mbc2::restore(header, regs),
+      Mbc::Mbc3(regs) => Mbc3::restore(header, regs),
+      Mbc::Mbc5(regs) => Mbc5::restore(header, regs),
+      Mbc::WisdomTree(regs) => WisdomTree::restore(header, regs),
+    }
+  }
 }
 
 impl Default for Cart {
-  fn default() -> Self { Cart { header: CartHeader::default(), rom: Vec::new(), exram: Vec::new(), mbc: Box::new(NoMbc)} }
+  fn default() -> Self { Cart { header: CartHeader::default(), rom: Vec::new(), exram: Vec::new(), mbc: no_mbc(), cheats: Cheats::default() } }
 }
 
 impl Cart {
-  pub fn new(rom: &[u8]) -> Result<Self, String> {
-    let header = CartHeader::new(rom)?;
-    println!("Loaded Gameboy ROM: {:#?}", header);
+  pub fn new(rom: &[u8]) -> Result<Self, Error> {
+    Self::with_options(rom, &LoadOptions::default())
+  }
 
-    let mbc = get_mbc(&header)?;
-    let exram = vec![0xFF; header.ram_size];
-    let rom = Vec::from(rom);
+  pub fn with_options(rom: &[u8], opts: &LoadOptions) -> Result<Self, Error> {
+    Self::with_mapper(rom, opts, get_mbc)
+  }
+
+  /// A cart with a custom mapper, for hardware which isn't emulated, like flashcarts.
+  /// The header has relaxed checks, as with [`LoadOptions::tolerant`], and its RAM size is the size of the cartridge RAM.
+  pub fn new_with_mapper(rom: &[u8], mapper: Box<dyn Mapper>) -> Result<Self, Error> {
+    Self::with_mapper(rom, &LoadOptions::tolerant(), |_| Ok(mapper))
+  }
+
+  fn with_mapper(
+    rom: &[u8],
+    opts: &LoadOptions,
+    mapper: impl FnOnce(&CartHeader) -> Result<Box<dyn Mapper>, Error>,
+  ) -> Result<Self, Error> {
+    let header = CartHeader::with_options(rom, opts)?;
+    if opts.require_full_rom && rom.len() < header.rom_size {
+      return Err(Error::RomTooSmall { len: rom.len(), expected: header.rom_size });
+    }
 
-    Ok(Self { header, rom, exram, mbc })
+    let mbc = mapper(&header)?;
+    // MBC2 has 512 half bytes of RAM built in, the header declares none
+    let exram_size = match header.mapper_code {
+      0x05 | 0x06 => 512,
+      _ => header.ram_size,
+    };
+    let exram = vec![0xFF; exram_size];
+    let mut rom = Vec::from(rom);
+    if rom.len() < header.rom_size {
+      rom.resize(header.rom_size, 0xFF);
+    }
+
+    Ok(Self { header, rom, exram, mbc, cheats: Cheats::default() })
   }
 
-  pub fn rom_read(&mut self, addr: u16) -> u8 {
-    self.rom[self.mbc.rom_addr(addr)]
+  /// Puts the mapper back in its power-on state. The cartridge RAM is kept, as a battery would.
+  pub fn reset(&mut self) {
+    self.mbc.reset(&self.header);
+  }
+
+  /// Fails when `saved`, a cart read from a save state, holds the registers of another kind of mapper.
+  pub(crate) fn check_mapper(&self, saved: &Cart) -> Result<(), Error> {
+    let kind = |cart: &Cart| std::mem::discriminant(&cart.mbc.save_state().0);
+    match kind(self) == kind(saved) {
+      true => Ok(()),
+      false => Err(Error::InvalidState("saved with another mapper".to_string())),
+    }
+  }
+
+  /// After loading a save state, with the header already restored: only the registers are read from it,
+  /// the mapper is rebuilt around them. `live` is the one in use, kept for custom mappers.
+  pub(crate) fn keep_mapper(&mut self, live: Box<dyn Mapper>) {
+    self.mbc = self.mbc.save_state().into_mapper(&self.header, live);
+  }
+
+  /// The whole cartridge RAM, every bank, laid out like battery save files. Empty for carts without RAM.
+  /// MBC2 only keeps the low nibble of its bytes.
+  pub fn ram(&self) -> &[u8] {
+    &self.exram
+  }
+
+  pub fn ram_mut(&mut self) -> &mut [u8] {
+    &mut self.exram
+  }
+
+  /// Resizes the cartridge RAM to what the header says, after it was changed.
+  #[cfg(feature = "game-db")]
+  pub(crate) fn resize_ram(&mut self) {
+    self.exram.resize(self.header.ram_size, 0xFF);
+  }
+
+  /// Fills the cartridge RAM with 0xFF, like a cartridge never played.
+  pub fn erase_ram(&mut self) {
+    self.exram.fill(0xFF);
+  }
+
+  pub fn rom_read(&self, addr: u16) -> u8 {
+    let val = self.rom.get(self.mbc.rom_addr(addr)).copied().unwrap_or(0xFF);
+    self.cheats.patch_rom(addr, val)
   }
   pub fn rom_write(&mut self, addr: u16, val: u8) {
     self.mbc.rom_write(addr, val);
   }
 
-  pub fn ram_read(&mut self, addr: u16) -> u8 {
+  pub fn ram_read(&self, addr: u16) -> u8 {
     self.mbc.ram_read(&self.exram, addr)
   }
   pub fn ram_write(&mut self, addr: u16, val: u8) {
     self.mbc.ram_write(&mut self.exram, addr, val);
   }
-}
-
-pub trait Mapper {
-  fn new(header: &CartHeader) -> Box<Self> where Self: Sized;
 
-  fn rom_addr(&mut self, addr: u16) -> usize;
-  fn ram_addr(&mut self, addr: u16) -> (bool, usize);
+  /// Whether the rumble motor of the cart is on. Always false for carts without one.
+  pub fn rumble(&self) -> bool {
+    self.mbc.rumble()
+  }
+}
 
-  fn ram_read(&mut self, exram: &[u8], addr: u16) -> u8 {
+/// The cartridge hardware mapping banks of the ROM and RAM into the address space.
+///
+/// Custom mappers, for flashcarts or homebrew hardware, are plugged in with [`Cart::new_with_mapper`].
+/// Addresses are relative to the area accessed: 0x0000-0x7FFF for the ROM, 0x0000-0x1FFF for the RAM at 0xA000.
+pub trait Mapper: Send {
+  /// The offset in the ROM read at `addr`. Offsets past its end read as open bus (0xFF).
+  fn rom_addr(&self, addr: u16) -> usize;
+  /// Whether the RAM is enabled, and the offset in the cartridge RAM accessed at `addr`.
+  fn ram_addr(&self, addr: u16) -> (bool, usize);
+
+  /// Reads are open bus (0xFF) when the RAM is disabled or missing.
+  fn ram_read(&self, exram: &[u8], addr: u16) -> u8 {
     let (enabled, addr) = self.ram_addr(addr);
-    if enabled { exram[addr] } else { 0xFF }
+    match enabled {
+      true => exram.get(addr).copied().unwrap_or(0xFF),
+      false => 0xFF,
+    }
   }
+  /// Writes are ignored when the RAM is disabled or missing.
   fn ram_write(&mut self, exram: &mut[u8], addr: u16, val: u8) {
     let (enabled, addr) = self.ram_addr(addr);
-    if enabled { exram[addr] = val; }
+    if let (true, Some(byte)) = (enabled, exram.get_mut(addr)) {
+      *byte = val;
+    }
   }
 
+  /// A write to the ROM area, which sets the mapper registers.
   fn rom_write(&mut self, addr: u16, val: u8);
 
+  /// Called every M-cycle, for the hardware on the cart keeping time.
   fn tick(&mut self) {}
+
+  fn rumble(&self) -> bool { false }
+
+  /// Puts the registers back in their power-on state, when the console is reset.
+  fn reset(&mut self, header: &CartHeader);
+
+  /// A copy of the registers, for save states.
+  /// Custom mappers save theirs with [`MbcState::custom`], by default they aren't saved.
+  fn save_state(&self) -> MbcState { MbcState::custom(Vec::new()) }
+
+  /// Restores the registers a custom mapper saved with [`MbcState::custom`], when a state is loaded.
+  fn load_state(&mut self, _data: &[u8]) {}
 }
 
+/// The registers read from a save state, until [`Cart::keep_mapper`] rebuilds the mapper with them.
+struct SavedState(MbcState);
+impl Mapper for SavedState {
+  fn rom_addr(&self, addr: u16) -> usize { addr as usize }
+  fn ram_addr(&self, addr: u16) -> (bool, usize) { (false, addr as usize) }
+  fn rom_write(&mut self, _: u16, _: u8) {}
+  fn reset(&mut self, _: &CartHeader) {}
+  fn save_state(&self) -> MbcState { self.0.clone() }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 struct NoMbc;
-impl Mapper for NoMbc {
+impl NoMbc {
   fn new(_: &CartHeader) -> Box<Self> { Box::new(NoMbc) }
+}
+
+impl Mapper for NoMbc {
   fn rom_write(&mut self, _: u16, _: u8) {}
+  fn save_state(&self) -> MbcState { MbcState(Mbc::None) }
+  fn reset(&mut self, _: &CartHeader) {}
   
-  fn rom_addr(&mut self, addr: u16) -> usize { addr as usize }
-  fn ram_addr(&mut self, addr: u16) -> (bool, usize) { (true, addr as usize) }
+  fn rom_addr(&self, addr: u16) -> usize { addr as usize }
+  fn ram_addr(&self, addr: u16) -> (bool, usize) { (true, addr as usize) }
 }
 
-#[derive(Debug)]
+/// Maps the pages of an address range to banks of the ROM or RAM.
+/// With no data (a cart without RAM), every page maps to 0, which is out of bounds.
+#[derive(Debug, Clone, Default)]
 struct Banking {
-  #[allow(unused)]
   data_size: usize,
   bank_size: usize,
   banks_count: usize,
@@ -94,7 +279,8 @@ struct Banking {
 impl Banking {
   pub fn new(data_size: usize, pages_count: usize, bank_size: usize) -> Self {
     let banks = vec![0; pages_count].into_boxed_slice();
-    let banks_count = data_size / bank_size;
+    // RAM smaller than a bank (MBC2, 2kb carts) is a single mirrored bank
+    let banks_count = data_size.div_ceil(bank_size);
     Self {data_size, bank_size, banks_count, banks}
   }
 
@@ -107,6 +293,7 @@ impl Banking {
   }
 
   pub fn set(&mut self, page: usize, bank: usize) {
+    if self.banks_count == 0 { return; }
     let pages_count = self.banks.len();
     self.banks[page % pages_count] = (bank % self.banks_count) * self.bank_size;
   }
@@ -114,53 +301,84 @@ impl Banking {
   fn addr(&self, addr: usize) -> usize {
     let page = addr / self.bank_size;
     let pages_count = self.banks.len();
-    self.banks[page % pages_count] + (addr % self.bank_size)
+    let addr = self.banks[page % pages_count] + (addr % self.bank_size);
+    match self.data_size {
+      0 => addr,
+      size => addr % size,
+    }
   }
 }
 
-// TODO: MBC1M
+#[derive(Clone, Serialize, Deserialize)]
 struct Mbc1 {
+  #[serde(skip)]
   rom_banks: Banking,
+  #[serde(skip)]
   ram_banks: Banking,
   rom_select: usize,
   ram_select: usize,
   ram_enabled: bool,
   extended_mode: bool,
+  /// MBC1M: the upper bank bits select a 256kb game, and bit 4 of `rom_select` is ignored
+  #[serde(skip)]
+  multicart: bool,
 }
 
 impl Mbc1 {
-  fn update_banks(&mut self) {
-    let ext_rom_bank = self.ram_select << 5;
+  fn new(header: &CartHeader) -> Box<Self> {
+    let mut rom_banks = Banking::new_rom(header, 2);
+    let ram_banks = Banking::new_ram(header);
+
+    // Page 1 starts at 1
+    rom_banks.set(1, 1);
+
+    Box::new(Self{
+      rom_banks, ram_banks, 
+      ram_enabled: false, extended_mode: false,
+      // rom_selects always default as 1
+      rom_select: 1, ram_select: 0,
+      multicart: header.multicart,
+    })
+  }
 
-    self.rom_banks.set(0, if self.extended_mode { ext_rom_bank as usize } else { 0 });
-    self.rom_banks.set(1,
-      (ext_rom_bank + self.rom_select) as usize
-    );
-    self.ram_banks.set(0, if self.extended_mode { self.ram_select as usize } else { 0 });
+  fn restore(header: &CartHeader, regs: Self) -> Box<Self> {
+    let mut mbc = Box::new(Self {
+      rom_select: regs.rom_select & 0b1_1111,
+      ram_select: regs.ram_select & 0b11,
+      ram_enabled: regs.ram_enabled,
+      extended_mode: regs.extended_mode,
+      ..*Self::new(header)
+    });
+    mbc.update_banks();
+    mbc
+  }
+
+  fn update_banks(&mut self) {
+    let (ext_rom_bank, rom_select) = match self.multicart {
+      false => (self.ram_select << 5, self.rom_select),
+      true => (self.ram_select << 4, self.rom_select & 0xF),
+    };
+
+    self.rom_banks.set(0, if self.extended_mode { ext_rom_bank } else { 0 });
+    self.rom_banks.set(1, ext_rom_bank + rom_select);
+    self.ram_banks.set(0, if self.extended_mode { self.ram_select } else { 0 });
   }
 }
 
 impl Mapper for Mbc1 {
-    fn new(header: &CartHeader) -> Box<Self> where Self: Sized {
-      let mut rom_banks = Banking::new_rom(header, 2);
-      let ram_banks = Banking::new_ram(header);
-
-      // Page 1 starts at 1
-      rom_banks.set(1, 1);
-
-      Box::new(Self{
-        rom_banks, ram_banks, 
-        ram_enabled: false, extended_mode: false,
-        // rom_selects always default as 1
-        rom_select: 1, ram_select: 0,
-      })
+    fn rom_addr(&self, addr: u16) -> usize {
+      self.rom_banks.addr(addr as usize)
     }
 
-    fn rom_addr(&mut self, addr: u16) -> usize {
-      self.rom_banks.addr(addr as usize)
+    fn save_state(&self) -> MbcState {
+      MbcState(Mbc::Mbc1(self.clone()))
     }
 
-    fn ram_addr(&mut self, addr: u16) -> (bool, usize) {
+    fn reset(&mut self, header: &CartHeader) {
+      *self = *Self::new(header);
+    }
+
+    fn ram_addr(&self, addr: u16) -> (bool, usize) {
       (self.ram_enabled, self.ram_banks.addr(addr as usize))
     }
 
@@ -184,57 +402,84 @@ impl Mapper for Mbc1 {
     }
 }
 
+#[derive(Clone, Serialize, Deserialize)]
 struct Mbc2 {
+  #[serde(skip)]
   rom_banks: Banking,
+  rom_bank: usize,
   ram_enabled: bool,
 }
-impl Mapper for Mbc2 {
+impl Mbc2 {
   fn new(header: &CartHeader) -> Box<Self> {
     let mut rom_banks = Banking::new_rom(header, 2);
     rom_banks.set(1, 1);
-    Box::new(Self {rom_banks,ram_enabled: false})
+    Box::new(Self {rom_banks, rom_bank: 1, ram_enabled: false})
   }
 
-  fn rom_addr(&mut self, addr: u16) -> usize {
+  fn restore(header: &CartHeader, regs: Self) -> Box<Self> {
+    let mut mbc = Box::new(Self { ram_enabled: regs.ram_enabled, ..*Self::new(header) });
+    mbc.rom_banks.set(1, regs.rom_bank);
+    mbc.rom_bank = regs.rom_bank;
+    mbc
+  }
+}
+
+impl Mapper for Mbc2 {
+  fn rom_addr(&self, addr: u16) -> usize {
     self.rom_banks.addr(addr as usize)
   }
 
+  fn save_state(&self) -> MbcState {
+    MbcState(Mbc::Mbc2(self.clone()))
+  }
+
+  fn reset(&mut self, header: &CartHeader) {
+    *self = *Self::new(header);
+  }
+
   fn rom_write(&mut self, addr: u16, val: u8) {
-    match addr {
-      0x0000..=0x3FFF => {
-        match (addr >> 8) & 1 != 0 {
-          false => self.ram_enabled = val == 0x0A,
-          true  => {
-            let bank = (val & 0b1111)
-              .clamp(1, u8::MAX) as usize;
-            self.rom_banks.set(1, bank);
-          }
+    if let 0x0000..=0x3FFF = addr {
+      match (addr >> 8) & 1 != 0 {
+        false => self.ram_enabled = val == 0x0A,
+        true  => {
+          self.rom_bank = (val & 0b1111)
+            .clamp(1, u8::MAX) as usize;
+          self.rom_banks.set(1, self.rom_bank);
         }
       }
-      _ => {}
     }
   }
 
-  fn ram_addr(&mut self, addr: u16) -> (bool, usize) {
+  fn ram_addr(&self, addr: u16) -> (bool, usize) {
     (self.ram_enabled, (addr) as usize % 512)
   }
 
-  fn ram_read(&mut self, exram: &[u8], addr: u16) -> u8 {
+  fn ram_read(&self, exram: &[u8], addr: u16) -> u8 {
     let (enabled, addr) = self.ram_addr(addr);
-    if enabled { exram[addr] | 0xF0 } else { 0xFF }
+    match enabled {
+      true => exram.get(addr).map_or(0xFF, |val| val | 0xF0),
+      false => 0xFF,
+    }
   }
 
   fn ram_write(&mut self, exram: &mut[u8], addr: u16, val: u8) {
     let (enabled, addr) = self.ram_addr(addr);
-    if enabled { exram[addr] = val | 0xF0; }
+    if let (true, Some(byte)) = (enabled, exram.get_mut(addr)) {
+      *byte = val | 0xF0;
+    }
   }
 }
 
 
 #[allow(unused)]
+#[derive(Clone, Serialize, Deserialize)]
 struct Mbc3 {
+  #[serde(skip)]
   rom_banks: Banking,
+  #[serde(skip)]
   ram_banks: Banking,
+  rom_bank: usize,
+  ram_bank: usize,
   ram_enabled: bool,
   
   rtc_select: u8,
@@ -246,7 +491,7 @@ struct Mbc3 {
   rtc_halted: bool,
 }
 
-impl Mapper for Mbc3 {
+impl Mbc3 {
   fn new(header: &CartHeader) -> Box<Self> {
     let mut rom_banks = Banking::new_rom(header, 2);
     let ram_banks = Banking::new_ram(header);
@@ -254,6 +499,7 @@ impl Mapper for Mbc3 {
 
     Box::new(Self {
       rom_banks, ram_banks, ram_enabled: false,
+      rom_bank: 1, ram_bank: 0,
       rtc_select: 0,
       rtc_halted: false,
       rtc_seconds: 0,
@@ -264,11 +510,28 @@ impl Mapper for Mbc3 {
     })
   }
 
-  fn rom_addr(&mut self, addr: u16) -> usize {
+  fn restore(header: &CartHeader, regs: Self) -> Box<Self> {
+    let mut mbc = Box::new(Self { rom_banks: Banking::new_rom(header, 2), ram_banks: Banking::new_ram(header), ..regs });
+    mbc.rom_banks.set(1, mbc.rom_bank);
+    mbc.ram_banks.set(0, mbc.ram_bank);
+    mbc
+  }
+}
+
+impl Mapper for Mbc3 {
+  fn rom_addr(&self, addr: u16) -> usize {
     self.rom_banks.addr(addr as usize)
   }
 
-  fn ram_addr(&mut self, addr: u16) -> (bool, usize) {
+  fn save_state(&self) -> MbcState {
+    MbcState(Mbc::Mbc3(self.clone()))
+  }
+
+  fn reset(&mut self, header: &CartHeader) {
+    *self = *Self::new(header);
+  }
+
+  fn ram_addr(&self, addr: u16) -> (bool, usize) {
     (self.ram_enabled, self.ram_banks.addr(addr as usize))
   }
 
@@ -276,15 +539,16 @@ impl Mapper for Mbc3 {
     match addr {
       0x0000..=0x1FFF => self.ram_enabled = val == 0x0A,
       0x2000..=0x3FFF => {
-        let bank = (val & 0b0111_1111)
-          .clamp(1, u8::MAX);
-        self.rom_banks.set(1, bank as usize);
+        self.rom_bank = (val & 0b0111_1111)
+          .clamp(1, u8::MAX) as usize;
+        self.rom_banks.set(1, self.rom_bank);
       }
       0x4000..=0x5FFF => {
         if (0x8..=0xC).contains(&val) {
           self.rtc_select = val;
         } else {
-          self.ram_banks.set(0, val as usize & 0b11);
+          self.ram_bank = val as usize & 0b11;
+          self.ram_banks.set(0, self.ram_bank);
           self.rtc_select = 0;
         }
       }
@@ -295,7 +559,7 @@ impl Mapper for Mbc3 {
     }
   }
 
-  fn ram_read(&mut self, exram: &[u8], addr: u16) -> u8 {
+  fn ram_read(&self, exram: &[u8], addr: u16) -> u8 {
     let (enabled, addr) = self.ram_addr(addr);
     if !enabled { return 0xFF; }
 
@@ -303,7 +567,7 @@ impl Mapper for Mbc3 {
       // TODO: rtc
       0xFF
     } else {
-      exram[addr]
+      exram.get(addr).copied().unwrap_or(0xFF)
     }
   }
 
@@ -313,8 +577,8 @@ impl Mapper for Mbc3 {
 
     if self.rtc_select != 0 {
       // TODO: rtc
-    } else {
-      exram[addr] = val;
+    } else if let Some(byte) = exram.get_mut(addr) {
+      *byte = val;
     }
   }
 
@@ -323,14 +587,25 @@ impl Mapper for Mbc3 {
   }
 }
 
+#[derive(Clone, Serialize, Deserialize)]
 struct Mbc5 {
+  #[serde(skip)]
   rom_banks: Banking,
+  #[serde(skip)]
   ram_banks: Banking,
   ram_enabled: bool,
   rom_select: usize,
+  ram_bank: usize,
+  /// Rumble carts drive the motor with bit 3 of the RAM bank register
+  #[serde(skip)]
+  has_rumble: bool,
+  motor_on: bool,
+  /// Li Cheng carts ignore writes to 0x2101-0x2FFF
+  #[serde(skip)]
+  li_cheng: bool,
 }
 
-impl Mapper for Mbc5 {
+impl Mbc5 {
   fn new(header: &CartHeader) -> Box<Self> {
     let mut rom_banks = Banking::new_rom(header, 2);
     let ram_banks = Banking::new_ram(header);
@@ -340,21 +615,49 @@ impl Mapper for Mbc5 {
     Box::new(Self{
       rom_banks, ram_banks,
       ram_enabled: false,
-      rom_select: 1
+      rom_select: 1,
+      ram_bank: 0,
+      has_rumble: (0x1C..=0x1E).contains(&header.mapper_code),
+      motor_on: false,
+      li_cheng: header.unlicensed == Some(Unlicensed::LiCheng),
     })
   }
 
-  fn rom_addr(&mut self, addr: u16) -> usize {
+  fn restore(header: &CartHeader, regs: Self) -> Box<Self> {
+    let mut mbc = Box::new(Self {
+      ram_enabled: regs.ram_enabled,
+      rom_select: regs.rom_select,
+      ram_bank: regs.ram_bank,
+      motor_on: regs.motor_on,
+      ..*Self::new(header)
+    });
+    mbc.rom_banks.set(1, mbc.rom_select);
+    mbc.ram_banks.set(0, mbc.ram_bank);
+    mbc
+  }
+}
+
+impl Mapper for Mbc5 {
+  fn rom_addr(&self, addr: u16) -> usize {
     self.rom_banks.addr(addr as usize)
   }
 
-  fn ram_addr(&mut self, addr: u16) -> (bool, usize) {
+  fn save_state(&self) -> MbcState {
+    MbcState(Mbc::Mbc5(self.clone()))
+  }
+
+  fn reset(&mut self, header: &CartHeader) {
+    *self = *Self::new(header);
+  }
+
+  fn ram_addr(&self, addr: u16) -> (bool, usize) {
     (self.ram_enabled, self.ram_banks.addr(addr as usize))
   }
 
   fn rom_write(&mut self, addr: u16, val: u8) {
     match addr {
       0x0000..=0x1FFF => self.ram_enabled = val == 0x0A,
+      0x2101..=0x2FFF if self.li_cheng => {}
       0x2000..=0x2FFF => {
         self.rom_select = (self.rom_select & 0xF0) | val as usize;
         self.rom_banks.set(1, self.rom_select);
@@ -364,8 +667,171 @@ impl Mapper for Mbc5 {
           (self.rom_select & 0x0F) | ((val as usize & 0b10) << 8);
         self.rom_banks.set(1, self.rom_select);
       }
-      0x4000..=0x5FFF => self.ram_banks.set(0, val as usize & 0xF),
+      0x4000..=0x5FFF => {
+        if self.has_rumble { self.motor_on = nth_bit(val, 3); }
+        self.ram_bank = val as usize & if self.has_rumble { 0b111 } else { 0xF };
+        self.ram_banks.set(0, self.ram_bank);
+      }
       _ => {}
     }
   }
+
+  fn rumble(&self) -> bool {
+    self.motor_on
+  }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct WisdomTree {
+  #[serde(skip)]
+  rom_banks: Banking,
+  rom_bank: usize,
+}
+
+impl WisdomTree {
+  fn new(header: &CartHeader) -> Box<Self> {
+    let rom_banks = Banking::new(header.rom_size, 1, 32 * 1024);
+    Box::new(Self { rom_banks, rom_bank: 0 })
+  }
+
+  fn restore(header: &CartHeader, regs: Self) -> Box<Self> {
+    let mut mbc = Self::new(header);
+    mbc.rom_bank = regs.rom_bank;
+    mbc.rom_banks.set(0, mbc.rom_bank);
+    mbc
+  }
+}
+
+impl Mapper for WisdomTree {
+  fn rom_addr(&self, addr: u16) -> usize {
+    self.rom_banks.addr(addr as usize)
+  }
+
+  fn save_state(&self) -> MbcState {
+    MbcState(Mbc::WisdomTree(self.clone()))
+  }
+
+  fn reset(&mut self, header: &CartHeader) {
+    *self = *Self::new(header);
+  }
+
+  fn ram_addr(&self, addr: u16) -> (bool, usize) {
+    (false, addr as usize)
+  }
+
+  fn rom_write(&mut self, addr: u16, _: u8) {
+    // the bank is the low byte of the address, the value is ignored
+    if let 0x0000..=0x3FFF = addr {
+      self.rom_bank = addr as usize & 0xFF;
+      self.rom_banks.set(0, self.rom_bank);
+    }
+  }
+}
+
+#[cfg(test)]
+mod mbc_tests {
+  use super::Cart;
+  use crate::testing::RomBuilder;
+
+  #[test]
+  fn missing_ram() {
+    // no mapper, MBC1, MBC2, MBC3, MBC5, all without RAM
+    for cart_type in [0x00, 0x01, 0x05, 0x11, 0x19] {
+      let rom = RomBuilder::new().cart_type(cart_type).rom_size(1).ram_size(0).build();
+      let mut cart = Cart::new(&rom).unwrap();
+
+      // enable RAM, then select every bank
+      cart.rom_write(0x0000, 0x0A);
+      for bank in 0..=0xFF {
+        cart.rom_write(0x4000, bank);
+        cart.rom_write(0x6000, bank);
+        cart.ram_write(0x0123, 0x42);
+        let expected = match cart_type {
+          0x05 => 0xF2,
+          _ => 0xFF,
+        };
+        assert_eq!(cart.ram_read(0x0123), expected, "cart type {cart_type:#04X}");
+      }
+    }
+  }
+
+  #[test]
+  fn rumble() {
+    // MBC5+RUMBLE+RAM, then plain MBC5+RAM
+    let rom = RomBuilder::new().cart_type(0x1D).ram_size(0x03).build();
+    let mut cart = Cart::new(&rom).unwrap();
+    cart.rom_write(0x0000, 0x0A);
+    cart.rom_write(0x4000, 0b1001);
+    assert!(cart.rumble());
+    cart.ram_write(0x0000, 0x42);
+    cart.rom_write(0x4000, 0b0001);
+    assert!(!cart.rumble());
+    assert_eq!(cart.ram_read(0x0000), 0x42);
+
+    let rom = RomBuilder::new().cart_type(0x1A).ram_size(0x04).build();
+    let mut cart = Cart::new(&rom).unwrap();
+    cart.rom_write(0x4000, 0b1001);
+    assert!(!cart.rumble());
+  }
+
+  #[test]
+  fn unlicensed() {
+    use crate::{cart::Unlicensed, Error};
+
+    // Wisdom Tree declares 32kb, the image is 128kb
+    let mut rom = RomBuilder::new().cart_type(0xC0).at(0x134, b"WISDOM TREE").build();
+    rom.resize(128 * 1024, 0);
+    for bank in 0..4 {
+      rom[bank * 0x8000 + 0x7000] = bank as u8;
+    }
+    let mut cart = Cart::new(&rom).unwrap();
+    assert_eq!(cart.header.unlicensed, Some(Unlicensed::WisdomTree));
+    cart.rom_write(0x0002, 0xFF);
+    assert_eq!(cart.rom_read(0x7000), 2);
+    cart.rom_write(0x3F01, 0x00);
+    assert_eq!(cart.rom_read(0x7000), 1);
+
+    // Li Cheng has its own logo, and ignores writes past 0x2100
+    let mut rom = RomBuilder::new().cart_type(0x19).rom_size(2).build();
+    for (i, byte) in rom[0x104..0x134].iter_mut().enumerate() {
+      *byte = i as u8;
+    }
+    for bank in 0..8 {
+      rom[bank * 0x4000 + 0x1000] = bank as u8;
+    }
+    let fix_global_checksum = |rom: &mut Vec<u8>| {
+      let sum = rom.iter().enumerate()
+        .filter(|(i, _)| !(0x14E..=0x14F).contains(i))
+        .fold(0u16, |sum, (_, byte)| sum.wrapping_add(*byte as u16));
+      rom[0x14E..=0x14F].copy_from_slice(&sum.to_be_bytes());
+    };
+    fix_global_checksum(&mut rom);
+    let mut cart = Cart::new(&rom).unwrap();
+    assert_eq!(cart.header.unlicensed, Some(Unlicensed::LiCheng));
+    cart.rom_write(0x2100, 3);
+    assert_eq!(cart.rom_read(0x5000), 3);
+    cart.rom_write(0x2101, 5);
+    assert_eq!(cart.rom_read(0x5000), 3);
+
+    // a corrupt image, and an erased logo, are still missing the logo
+    let mut corrupt = rom.clone();
+    corrupt[0x7FFF] ^= 1;
+    assert!(matches!(Cart::new(&corrupt), Err(Error::MissingLogo)));
+    let mut erased = rom.clone();
+    erased[0x104..0x134].fill(0xFF);
+    fix_global_checksum(&mut erased);
+    assert!(matches!(Cart::new(&erased), Err(Error::MissingLogo)));
+  }
+
+  #[test]
+  fn small_ram() {
+    // MBC1 with 8kb of RAM, banks past the first mirror it
+    let rom = RomBuilder::new().cart_type(0x03).ram_size(0x02).build();
+    let mut cart = Cart::new(&rom).unwrap();
+    cart.rom_write(0x0000, 0x0A);
+    cart.rom_write(0x6000, 1);
+    cart.ram_write(0x0010, 0x42);
+    cart.rom_write(0x4000, 3);
+    assert_eq!(cart.ram_read(0x0010), 0x42);
+  }
 }
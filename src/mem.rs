@@ -1,25 +1,118 @@
+use crate::bus::IFlags;
+
+/// What the CPU is connected to. The CPU only reads, writes and ticks it once per M-cycle,
+/// the rest of the hardware (DMA, the components raising interrupts) runs behind it.
 pub trait Memory {
   fn read(&mut self, addr: u16) -> u8;
+  /// Reads without any side effect, for debuggers and tooling.
+  fn peek(&self, addr: u16) -> u8;
   fn write(&mut self, addr: u16, val: u8);
   fn tick(&mut self);
-  fn halt_tick(&mut self);
-  fn has_pending_interrupts(&self) -> bool;
+  /// The highest priority interrupt both requested and enabled.
+  fn pending_interrupt(&self) -> Option<IFlags>;
+  /// Clears the request of an interrupt the CPU is servicing.
+  fn ack_interrupt(&mut self, int: IFlags);
+
+  fn has_pending_interrupts(&self) -> bool {
+    self.pending_interrupt().is_some()
+  }
+}
+
+/// What the RAMs hold at power on. The hardware powers on with semi-random contents, which some games read,
+/// so each choice is reproducible, for movies and replays. See [`Gameboy::set_power_on_state`](crate::gb::Gameboy::set_power_on_state).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerOnState {
+  AllZero,
+  AllFF,
+  /// Alternating runs of 8 bytes of 0x00 and 0xFF, close to what DMG work RAM often holds.
+  Pattern,
+  /// Random bytes, the same for the same seed.
+  SeededRandom(u64),
+}
+
+impl PowerOnState {
+  /// Fills `ram` with this state. `salt` tells apart the RAMs, so they don't all get the same random bytes.
+  pub fn fill(&self, ram: &mut [u8], salt: u64) {
+    match *self {
+      Self::AllZero => ram.fill(0),
+      Self::AllFF => ram.fill(0xFF),
+      Self::Pattern => {
+        for (i, byte) in ram.iter_mut().enumerate() {
+          *byte = if (i / 8) % 2 == 0 { 0x00 } else { 0xFF };
+        }
+      }
+      Self::SeededRandom(seed) => {
+        // splitmix64
+        let mut state = seed ^ salt.wrapping_mul(0xD1B5_4A32_D192_ED03);
+        for byte in ram {
+          state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+          let mut z = state;
+          z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+          z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+          *byte = ((z ^ (z >> 31)) >> 56) as u8;
+        }
+      }
+    }
+  }
+}
+
+/// What the bus did during an M-cycle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cycle {
+  Internal,
+  Read(u16, u8),
+  Write(u16, u8),
 }
 
+/// A flat memory, which can record the bus activity of every M-cycle to check the CPU timings.
 pub struct Ram64kb {
-  mem: [u8; 64 * 1024]
+  mem: [u8; 64 * 1024],
+  /// Only while recording, it grows by a cycle per M-cycle
+  cycles: Option<Vec<Cycle>>,
 }
 
 impl Default for Ram64kb {
   fn default() -> Self {
-    Self { mem: [0; 64 * 1024] }
+    Self { mem: [0; 64 * 1024], cycles: None }
+  }
+}
+
+impl Ram64kb {
+  /// Starts recording the M-cycles, dropping those recorded until now.
+  pub fn record_cycles(&mut self) {
+    self.cycles = Some(Vec::new());
+  }
+
+  /// The M-cycles run since the last call, or since recording started. Empty when not recording.
+  pub fn take_cycles(&mut self) -> Vec<Cycle> {
+    self.cycles.as_mut().map(std::mem::take).unwrap_or_default()
+  }
+
+  fn record(&mut self, access: Cycle) {
+    let Some(cycles) = &mut self.cycles else { return };
+    // the CPU ticks before accessing the bus, so the access belongs to the last cycle.
+    // Accesses without a tick, or a second one in the same cycle, are kept apart to show up
+    match cycles.last_mut() {
+      Some(cycle @ Cycle::Internal) => *cycle = access,
+      _ => cycles.push(access),
+    }
   }
 }
 
 impl Memory for Ram64kb {
-  fn read(&mut self, addr: u16) -> u8 { self.mem[addr as usize] }
-  fn write(&mut self, addr: u16, val: u8) { self.mem[addr as usize] = val; }
-  fn tick(&mut self) {}
-  fn halt_tick(&mut self) {}
-  fn has_pending_interrupts(&self) -> bool { false }
+  fn read(&mut self, addr: u16) -> u8 {
+    let val = self.mem[addr as usize];
+    self.record(Cycle::Read(addr, val));
+    val
+  }
+  fn peek(&self, addr: u16) -> u8 { self.mem[addr as usize] }
+  fn write(&mut self, addr: u16, val: u8) {
+    self.mem[addr as usize] = val;
+    self.record(Cycle::Write(addr, val));
+  }
+  fn tick(&mut self) {
+    if let Some(cycles) = &mut self.cycles { cycles.push(Cycle::Internal); }
+  }
+  fn pending_interrupt(&self) -> Option<IFlags> { None }
+  fn ack_interrupt(&mut self, _int: IFlags) {}
 }
\ No newline at end of file
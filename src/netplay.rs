@@ -0,0 +1,490 @@
+//! Netplay between two instances, each playing one player, with input delay and rollback.
+//!
+//! Every frame, each peer sends the inputs of its player, to be pressed a few frames later (the input delay).
+//! When the remote inputs of a frame haven't arrived in time, the frame is run with the last ones received,
+//! after saving the state. If the inputs then arrive and differ, the machine is rolled back to that state
+//! with [`Gameboy::load_state`], and the frames are run again. As the emulation is deterministic, both peers
+//! stay in the same state, which is checked every second by hashing the saved state of a frame once its inputs are known.
+//!
+//! Player 0 plays with the usual joypad. Player 1 plays with the second joypad of a Super Game Boy,
+//! or presses the buttons of the same joypad otherwise. Both peers must boot the same ROM, with the same settings.
+//! ```no_run
+//! # let rom = vec![];
+//! use tomboy_emulator::{gb::Gameboy, input_log::Input, netplay::{Session, SessionConfig}};
+//!
+//! let mut gb = Gameboy::boot_from_bytes(&rom).unwrap();
+//! let config = SessionConfig { local_player: 0, ..SessionConfig::default() };
+//! let mut session = Session::connect("0.0.0.0:7845", "192.168.1.2:7845", config).unwrap();
+//! loop {
+//!   // once per displayed frame, with the pressed buttons
+//!   session.advance_frame(&mut gb, Input::default()).unwrap();
+//! }
+//! ```
+
+use std::{collections::VecDeque, io, net::{ToSocketAddrs, UdpSocket}};
+
+use crate::{gb::Gameboy, input_log::Input, joypad::Flags, state::Fnv1a};
+
+/// Frames between two checks of the state hashes.
+const SYNC_INTERVAL: usize = 60;
+/// Local inputs are sent until the remote peer acknowledges them, so lost packets don't matter.
+const MAX_PACKET_INPUTS: usize = 64;
+/// Hashes kept waiting for the other peer's, for the same frame.
+const KEPT_HASHES: usize = 8;
+const MAGIC: u8 = b'T';
+const NO_HASH: u32 = u32::MAX;
+/// Magic, acknowledged inputs, first input frame, inputs count
+const HEADER_LEN: usize = 10;
+/// Hashed frame, hash
+const FOOTER_LEN: usize = 12;
+
+/// How the packets are exchanged with the remote peer. Neither sending nor receiving blocks.
+pub trait Transport {
+  fn send(&mut self, packet: &[u8]) -> io::Result<()>;
+  /// Receives a packet in `buf`, returns its length, or `None` if there is no packet waiting.
+  fn recv(&mut self, buf: &mut [u8]) -> io::Result<Option<usize>>;
+}
+
+/// A connected, non blocking socket, see [`Session::connect`].
+impl Transport for UdpSocket {
+  fn send(&mut self, packet: &[u8]) -> io::Result<()> {
+    match UdpSocket::send(self, packet) {
+      // the peer isn't listening yet, the inputs will be sent again
+      Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => Ok(()),
+      res => res.map(|_| ()),
+    }
+  }
+
+  fn recv(&mut self, buf: &mut [u8]) -> io::Result<Option<usize>> {
+    match UdpSocket::recv(self, buf) {
+      Ok(len) => Ok(Some(len)),
+      Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::ConnectionRefused) => Ok(None),
+      Err(e) => Err(e),
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SessionConfig {
+  /// The player of this peer, 0 or 1. The other peer plays the other one.
+  pub local_player: usize,
+  /// Frames between an input and the frame it is pressed during. Remote inputs arriving within it cause no rollback.
+  pub input_delay: usize,
+  /// Frames run ahead of the remote inputs before waiting for them. 0 waits for them every frame.
+  pub max_rollback: usize,
+}
+
+impl Default for SessionConfig {
+  fn default() -> Self {
+    Self { local_player: 0, input_delay: 2, max_rollback: 8 }
+  }
+}
+
+/// What happened during a session, see [`Session::stats`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+  /// Frames run, not counting the ones run again after a rollback
+  pub frames: usize,
+  /// Frames run with predicted remote inputs, not yet received
+  pub frames_ahead: usize,
+  /// Times the predicted remote inputs were wrong, and the session rolled back
+  pub rollbacks: usize,
+  /// Frames run again after rollbacks
+  pub rollback_frames: usize,
+  /// Frames the session waited for the remote inputs, being `max_rollback` frames ahead of them
+  pub stalls: usize,
+  pub packets_sent: usize,
+  pub packets_received: usize,
+  /// State hashes compared with the other peer's
+  pub checks: usize,
+  /// The first frame both peers hashed a different state for: from there, they don't play the same game anymore.
+  pub desync: Option<usize>,
+}
+
+pub struct Session<T: Transport = UdpSocket> {
+  transport: T,
+  config: SessionConfig,
+  /// The next frame to run
+  frame: usize,
+  /// Inputs of the local player, by frame, starting with `input_delay` released frames
+  local: Vec<Input>,
+  /// Inputs of the remote player received, by frame
+  remote: Vec<Input>,
+  /// Local inputs the remote peer received
+  acked: usize,
+  /// The remote inputs each frame was last run with, received or predicted
+  used: Vec<Input>,
+  /// The states before the frames run with predicted inputs, to roll back to, and to hash once confirmed
+  states: VecDeque<(usize, Vec<u8>)>,
+  hashes: VecDeque<(usize, u64)>,
+  remote_hashes: VecDeque<(usize, u64)>,
+  stats: Stats,
+}
+
+impl Session<UdpSocket> {
+  /// Binds `local`, and plays with the peer at `peer`, which connects the other way around.
+  pub fn connect(local: impl ToSocketAddrs, peer: impl ToSocketAddrs, config: SessionConfig) -> io::Result<Self> {
+    let socket = UdpSocket::bind(local)?;
+    socket.connect(peer)?;
+    socket.set_nonblocking(true)?;
+    Ok(Self::new(socket, config))
+  }
+}
+
+impl<T: Transport> Session<T> {
+  /// A session starting at frame 0, the Gameboy it runs must be freshly booted.
+  pub fn new(transport: T, config: SessionConfig) -> Self {
+    Self {
+      transport,
+      config,
+      frame: 0,
+      local: vec![Input::default(); config.input_delay],
+      remote: Vec::new(),
+      acked: 0,
+      used: Vec::new(),
+      states: VecDeque::new(),
+      hashes: VecDeque::new(),
+      remote_hashes: VecDeque::new(),
+      stats: Stats::default(),
+    }
+  }
+
+  /// The next frame to run.
+  pub fn frame(&self) -> usize {
+    self.frame
+  }
+
+  pub fn stats(&self) -> Stats {
+    self.stats
+  }
+
+  /// Meant to be called once per displayed frame: runs the next frame, with `input` pressed by the local
+  /// player `input_delay` frames later. Rolls back first, if remote inputs arrived that weren't the predicted ones.
+  /// Returns false without running a frame while waiting for the remote inputs, `input` is then ignored until
+  /// a frame is run. The frames run again after a rollback call the frame and audio callbacks again.
+  pub fn advance_frame(&mut self, gb: &mut Gameboy, input: Input) -> io::Result<bool> {
+    self.receive(gb)?;
+    if self.local.len() <= self.frame + self.config.input_delay {
+      self.local.push(input);
+    }
+    self.send()?;
+
+    if self.frame >= self.remote.len() + self.config.max_rollback {
+      self.stats.stalls += 1;
+      return Ok(false);
+    }
+
+    self.run_frame(gb, self.frame)?;
+    self.frame += 1;
+    self.stats.frames += 1;
+    self.stats.frames_ahead = self.frame.saturating_sub(self.remote.len());
+    Ok(true)
+  }
+
+  /// Exchanges the inputs without running a frame, rolling back if needed, for when the emulation is paused.
+  pub fn poll(&mut self, gb: &mut Gameboy) -> io::Result<()> {
+    self.receive(gb)?;
+    self.send()
+  }
+
+  fn run_frame(&mut self, gb: &mut Gameboy, frame: usize) -> io::Result<()> {
+    let remote = match self.remote.get(frame).copied() {
+      Some(input) => {
+        // every input before this frame is known, its state is the same on both peers
+        if frame.is_multiple_of(SYNC_INTERVAL) { self.add_hash(frame, hash_state(&gb.save_state()), false); }
+        input
+      }
+      None => {
+        self.states.push_back((frame, gb.save_state()));
+        self.remote.last().copied().unwrap_or_default()
+      }
+    };
+
+    let local = self.local[frame];
+    let inputs = match self.config.local_player {
+      0 => [local, remote],
+      _ => [remote, local],
+    };
+    apply_inputs(gb, inputs);
+    gb.step_until_vblank();
+
+    match self.used.get_mut(frame) {
+      Some(used) => *used = remote,
+      None => self.used.push(remote),
+    }
+    Ok(())
+  }
+
+  fn receive(&mut self, gb: &mut Gameboy) -> io::Result<()> {
+    let confirmed = self.remote.len();
+    let mut buf = [0; HEADER_LEN + 2*MAX_PACKET_INPUTS + FOOTER_LEN];
+    while let Some(len) = self.transport.recv(&mut buf)? {
+      self.stats.packets_received += 1;
+      self.read_packet(&buf[..len]);
+    }
+
+    let ran = self.remote.len().min(self.frame);
+    let mispredicted = (confirmed..ran).find(|&frame| self.remote[frame] != self.used[frame]);
+    if let Some(start) = mispredicted {
+      let pos = self.states.iter().position(|(frame, _)| *frame == start)
+        .expect("a state is saved before every frame run with predicted inputs");
+      let state = self.states.drain(pos..).next().map(|(_, state)| state).unwrap();
+      gb.load_state(&state).map_err(io::Error::other)?;
+      for frame in start..self.frame {
+        self.run_frame(gb, frame)?;
+      }
+      self.stats.rollbacks += 1;
+      self.stats.rollback_frames += self.frame - start;
+    }
+
+    // the frames with all their inputs won't be rolled back, and their states are the same on both peers
+    while self.states.front().is_some_and(|(frame, _)| *frame < self.remote.len()) {
+      let (frame, state) = self.states.pop_front().unwrap();
+      if frame.is_multiple_of(SYNC_INTERVAL) { self.add_hash(frame, hash_state(&state), false); }
+    }
+    self.stats.frames_ahead = self.frame.saturating_sub(self.remote.len());
+    Ok(())
+  }
+
+  fn send(&mut self) -> io::Result<()> {
+    let start = self.acked.min(self.local.len());
+    let end = self.local.len().min(start + MAX_PACKET_INPUTS);
+
+    let mut packet = vec![MAGIC];
+    packet.extend((self.remote.len() as u32).to_le_bytes());
+    packet.extend((start as u32).to_le_bytes());
+    packet.push((end - start) as u8);
+    for input in &self.local[start..end] {
+      packet.extend([input.buttons.bits(), input.dpad.bits()]);
+    }
+    let (frame, hash) = self.hashes.back().map_or((NO_HASH, 0), |&(frame, hash)| (frame as u32, hash));
+    packet.extend(frame.to_le_bytes());
+    packet.extend(hash.to_le_bytes());
+
+    self.transport.send(&packet)?;
+    self.stats.packets_sent += 1;
+    Ok(())
+  }
+
+  /// Malformed packets are ignored, as are inputs past a gap, they are sent again.
+  fn read_packet(&mut self, packet: &[u8]) {
+    if packet.len() < HEADER_LEN || packet[0] != MAGIC { return; }
+    let u32_at = |at: usize| u32::from_le_bytes([packet[at], packet[at+1], packet[at+2], packet[at+3]]);
+
+    let count = packet[9] as usize;
+    let inputs_end = HEADER_LEN + 2*count;
+    if packet.len() != inputs_end + FOOTER_LEN { return; }
+
+    self.acked = self.acked.max(u32_at(1) as usize);
+    let start = u32_at(5) as usize;
+    for (frame, input) in (start..).zip(packet[HEADER_LEN..inputs_end].chunks(2)) {
+      if frame == self.remote.len() {
+        self.remote.push(Input { buttons: Flags::from_bits_truncate(input[0]), dpad: Flags::from_bits_truncate(input[1]) });
+      }
+    }
+
+    let frame = u32_at(inputs_end);
+    if frame != NO_HASH {
+      let hash = u64::from_le_bytes(packet[inputs_end+4..].try_into().unwrap());
+      self.add_hash(frame as usize, hash, true);
+    }
+  }
+
+  /// Keeps the hash of the state before `frame`, and compares it with the other peer's.
+  fn add_hash(&mut self, frame: usize, hash: u64, remote: bool) {
+    let (hashes, others) = match remote {
+      false => (&mut self.hashes, &self.remote_hashes),
+      true => (&mut self.remote_hashes, &self.hashes),
+    };
+    if hashes.iter().any(|(hashed, _)| *hashed == frame) { return; }
+
+    if let Some(&(_, other_hash)) = others.iter().find(|(other, _)| *other == frame) {
+      self.stats.checks += 1;
+      if other_hash != hash { self.stats.desync.get_or_insert(frame); }
+    }
+    hashes.push_back((frame, hash));
+    if hashes.len() > KEPT_HASHES { hashes.pop_front(); }
+  }
+}
+
+fn hash_state(state: &[u8]) -> u64 {
+  let mut hash = Fnv1a::default();
+  hash.update(state);
+  hash.0
+}
+
+fn apply_inputs(gb: &mut Gameboy, [first, second]: [Input; 2]) {
+  match gb.joypad_n(1) {
+    Some(joypad) => {
+      joypad.set_state(second.buttons, second.dpad);
+      gb.get_joypad().set_state(first.buttons, first.dpad);
+    }
+    None => gb.get_joypad().set_state(first.buttons | second.buttons, first.dpad | second.dpad),
+  }
+}
+
+#[cfg(test)]
+mod netplay_tests {
+  use std::{cell::{Cell, RefCell}, collections::VecDeque, rc::Rc};
+
+  use super::*;
+  use crate::testing::{asm, RomBuilder};
+
+  type Queue = Rc<RefCell<VecDeque<(usize, Vec<u8>)>>>;
+
+  /// An end of a link delivering the packets `latency` frames after they were sent, and losing some.
+  struct Link {
+    incoming: Queue,
+    outgoing: Queue,
+    clock: Rc<Cell<usize>>,
+    latency: usize,
+    sent: usize,
+  }
+
+  impl Transport for Link {
+    fn send(&mut self, packet: &[u8]) -> io::Result<()> {
+      self.sent += 1;
+      if !self.sent.is_multiple_of(4) {
+        self.outgoing.borrow_mut().push_back((self.clock.get() + self.latency, packet.to_vec()));
+      }
+      Ok(())
+    }
+
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<Option<usize>> {
+      let mut incoming = self.incoming.borrow_mut();
+      match incoming.front() {
+        Some((arrival, _)) if *arrival <= self.clock.get() => {
+          let (_, packet) = incoming.pop_front().unwrap();
+          buf[..packet.len()].copy_from_slice(&packet);
+          Ok(Some(packet.len()))
+        }
+        _ => Ok(None),
+      }
+    }
+  }
+
+  fn links(latency: usize) -> (Link, Link, Rc<Cell<usize>>) {
+    let clock = Rc::new(Cell::new(0));
+    let (a, b) = (Queue::default(), Queue::default());
+    let link = |incoming: &Queue, outgoing: &Queue| Link {
+      incoming: incoming.clone(), outgoing: outgoing.clone(), clock: clock.clone(), latency, sent: 0,
+    };
+    (link(&a, &b), link(&b, &a), clock)
+  }
+
+  fn sum_inputs_rom() -> Vec<u8> {
+    RomBuilder::new()
+      .code(&asm![
+        // select the buttons, and sum the P1 reads into $C000
+        "LD A, $10",
+        "LDH ($00), A",
+        "loop:",
+        "LDH A, ($00)",
+        "LD B, A",
+        "LD A, ($C000)",
+        "ADD A, B",
+        "LD ($C000), A",
+        "JR loop",
+      ])
+      .build()
+  }
+
+  fn input(player: usize, frame: usize) -> Input {
+    match (player, frame % 7) {
+      (0, 0..=2) => Input { buttons: Flags::a_right, dpad: Flags::empty() },
+      (1, 4) => Input { buttons: Flags::b_left, dpad: Flags::empty() },
+      _ => Input::default(),
+    }
+  }
+
+  #[test]
+  fn rollback() {
+    const FRAMES: usize = 2 * SYNC_INTERVAL + 10;
+    let rom = sum_inputs_rom();
+    let (link0, link1, clock) = links(3);
+    let config = SessionConfig { input_delay: 1, max_rollback: 6, ..SessionConfig::default() };
+    let mut peers = [
+      (Gameboy::boot_from_bytes(&rom).unwrap(), Session::new(link0, config)),
+      (Gameboy::boot_from_bytes(&rom).unwrap(), Session::new(link1, SessionConfig { local_player: 1, ..config })),
+    ];
+
+    while peers.iter().any(|(_, session)| session.frame() < FRAMES) {
+      for (player, (gb, session)) in peers.iter_mut().enumerate() {
+        if session.frame() < FRAMES {
+          session.advance_frame(gb, input(player, session.frame() + 1)).unwrap();
+        }
+      }
+      clock.set(clock.get() + 1);
+    }
+    while peers.iter().any(|(_, session)| session.stats().frames_ahead > 0) {
+      for (gb, session) in &mut peers { session.poll(gb).unwrap(); }
+      clock.set(clock.get() + 1);
+    }
+
+    // the same run, with every input known in time
+    let mut expected = Gameboy::boot_from_bytes(&rom).unwrap();
+    for frame in 0..FRAMES {
+      // the inputs of the first frame are taken by the input delay
+      let inputs = [0, 1].map(|player| if frame == 0 { Input::default() } else { input(player, frame) });
+      apply_inputs(&mut expected, inputs);
+      expected.step_until_vblank();
+    }
+
+    for (gb, session) in &peers {
+      let stats = session.stats();
+      assert!(stats.rollbacks > 0, "{stats:?}");
+      // frames 0 and 60, at least, were hashed on both peers, even if run with predicted inputs
+      assert!(stats.checks >= 2, "{stats:?}");
+      assert_eq!(stats.desync, None);
+      assert_eq!(gb.state_hash(), expected.state_hash());
+    }
+  }
+
+  #[test]
+  fn desync() {
+    // with the remote inputs arriving within the input delay, then arriving late
+    for (latency, input_delay) in [(1, 3), (5, 1)] {
+      let rom = sum_inputs_rom();
+      let (link0, link1, clock) = links(latency);
+      let config = SessionConfig { input_delay, ..SessionConfig::default() };
+      let mut gb0 = Gameboy::boot_from_bytes(&rom).unwrap();
+      let mut gb1 = Gameboy::boot_from_bytes(&rom).unwrap();
+      // a cheat only one peer has, writing to WRAM after every frame
+      gb1.get_cheats().add("010100C1").unwrap();
+      let mut session0 = Session::new(link0, config);
+      let mut session1 = Session::new(link1, SessionConfig { local_player: 1, ..config });
+
+      for _ in 0..SYNC_INTERVAL + 20 {
+        session0.advance_frame(&mut gb0, Input::default()).unwrap();
+        session1.advance_frame(&mut gb1, Input::default()).unwrap();
+        clock.set(clock.get() + 1);
+      }
+      for session in [session0.stats(), session1.stats()] {
+        assert_eq!(session.desync, Some(SYNC_INTERVAL), "latency {latency}: {session:?}");
+        assert_eq!(session.rollbacks, 0);
+      }
+    }
+  }
+
+  #[test]
+  fn udp() {
+    let sockets = [0, 1].map(|_| UdpSocket::bind("127.0.0.1:0").unwrap());
+    let addrs = sockets.each_ref().map(|socket| socket.local_addr().unwrap());
+    let rom = sum_inputs_rom();
+    let mut peers = sockets.into_iter().zip([addrs[1], addrs[0]]).enumerate().map(|(player, (socket, peer))| {
+      socket.connect(peer).unwrap();
+      socket.set_nonblocking(true).unwrap();
+      let config = SessionConfig { local_player: player, max_rollback: 0, ..SessionConfig::default() };
+      (Gameboy::boot_from_bytes(&rom).unwrap(), Session::new(socket, config))
+    }).collect::<Vec<_>>();
+
+    for _ in 0..10_000 {
+      if peers.iter().all(|(_, session)| session.frame() >= 5) { break; }
+      for (gb, session) in &mut peers {
+        if session.frame() < 5 { session.advance_frame(gb, Input::default()).unwrap(); }
+      }
+      std::thread::sleep(std::time::Duration::from_micros(100));
+    }
+    assert!(peers.iter().all(|(_, session)| session.frame() == 5 && session.stats().rollbacks == 0));
+  }
+}
@@ -0,0 +1,113 @@
+//! On-screen display: timed messages and a status line, drawn over a [`FrameBuffer`] with its built-in 3x5 font.
+//!
+//! The [`Osd`] of [`Gameboy::get_osd`](crate::gb::Gameboy::get_osd) is shared by the frontend and scripts.
+//! It isn't drawn on the emulated screen, the frontend draws it over what it displays:
+//! ```no_run
+//! # let mut gb = tomboy_emulator::gb::Gameboy::boot_from_bytes(&[]).unwrap();
+//! gb.get_osd().message("State saved");
+//! // once per displayed frame
+//! gb.get_osd().tick();
+//! let screen = gb.get_screen();
+//! let mut shown = screen.crop(0, 0, screen.width, screen.height);
+//! gb.get_osd().draw(&mut shown);
+//! ```
+
+use crate::frame::{Color, FrameBuffer, TEXT_CELL_SIZE};
+
+/// How many frames a message is shown.
+pub const MESSAGE_FRAMES: u32 = 120;
+/// Messages fade out during their last frames.
+pub const FADE_FRAMES: u32 = 30;
+/// Older messages are dropped past this.
+const MAX_MESSAGES: usize = 4;
+
+const TEXT_COLOR: Color = (255, 255, 255);
+const BACKGROUND: Color = (0, 0, 0);
+
+#[derive(Clone)]
+struct Message {
+  text: String,
+  frames_left: u32,
+}
+
+#[derive(Clone, Default)]
+pub struct Osd {
+  messages: Vec<Message>,
+  status: String,
+}
+
+impl Osd {
+  /// Shows a message at the bottom left for [`MESSAGE_FRAMES`] frames. Newer messages are shown below older ones.
+  pub fn message(&mut self, text: impl Into<String>) {
+    if self.messages.len() == MAX_MESSAGES {
+      self.messages.remove(0);
+    }
+    self.messages.push(Message { text: text.into(), frames_left: MESSAGE_FRAMES });
+  }
+
+  /// Sets the line shown at the top right until changed, like an FPS counter. Empty to hide it.
+  pub fn set_status(&mut self, text: impl Into<String>) {
+    self.status = text.into();
+  }
+
+  pub fn status(&self) -> &str {
+    &self.status
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.messages.is_empty() && self.status.is_empty()
+  }
+
+  /// Ages the messages by a frame, call it once per displayed frame.
+  pub fn tick(&mut self) {
+    for msg in &mut self.messages {
+      msg.frames_left -= 1;
+    }
+    self.messages.retain(|msg| msg.frames_left > 0);
+  }
+
+  pub fn draw(&self, fb: &mut FrameBuffer) {
+    let (cell_w, cell_h) = TEXT_CELL_SIZE;
+
+    let width = (self.status.chars().count() * cell_w) as i32;
+    fb.draw_text(fb.width as i32 - width, 1, &self.status, TEXT_COLOR, Some(BACKGROUND));
+
+    let bottom = fb.height as i32 - cell_h as i32;
+    for (i, msg) in self.messages.iter().rev().enumerate() {
+      let alpha = (msg.frames_left as f32 / FADE_FRAMES as f32).min(1.0);
+      let y = bottom - (i * cell_h) as i32;
+      fb.blend_text(1, y, &msg.text, TEXT_COLOR, Some(BACKGROUND), alpha);
+    }
+  }
+}
+
+#[cfg(test)]
+mod osd_tests {
+  use super::*;
+
+  #[test]
+  fn messages() {
+    let mut osd = Osd::default();
+    let mut fb = FrameBuffer::gameboy_lcd();
+    fb.set_palette([(200, 200, 200); 4]);
+    assert!(osd.is_empty());
+
+    osd.message("SAVED");
+    osd.set_status("60");
+    osd.draw(&mut fb);
+    // the top middle pixel of the S of the message is lit, and the 6 of the status ends at the right edge
+    let bottom = fb.height - TEXT_CELL_SIZE.1;
+    assert_eq!(fb.get_color(2, bottom), TEXT_COLOR);
+    assert_eq!(fb.get_color(fb.width - 2 * TEXT_CELL_SIZE.0, 1), TEXT_COLOR);
+
+    // fading out halfway through the fade
+    for _ in 0..MESSAGE_FRAMES - FADE_FRAMES / 2 { osd.tick(); }
+    fb.set_palette([(200, 200, 200); 4]);
+    osd.draw(&mut fb);
+    assert_eq!(fb.get_color(2, bottom), (228, 228, 228));
+
+    for _ in 0..FADE_FRAMES / 2 { osd.tick(); }
+    osd.set_status("");
+    assert!(osd.is_empty());
+  }
+}
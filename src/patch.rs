@@ -0,0 +1,298 @@
+//! Applies IPS and BPS patches to ROM images, to play translations and hacks
+//! without patching the files, see [`Gameboy::boot_patched`](crate::gb::Gameboy::boot_patched).
+
+use crate::{cart::MAX_ROM_SIZE, Error};
+
+const IPS_MAGIC: &[u8] = b"PATCH";
+const IPS_EOF: &[u8] = b"EOF";
+const BPS_MAGIC: &[u8] = b"BPS1";
+/// The source, target and patch CRC32s ending BPS patches
+const BPS_FOOTER_LEN: usize = 12;
+
+/// Applies an IPS or BPS patch, told apart by their magic number.
+pub fn apply(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, Error> {
+  if patch.starts_with(IPS_MAGIC) {
+    apply_ips(rom, patch)
+  } else if patch.starts_with(BPS_MAGIC) {
+    apply_bps(rom, patch)
+  } else {
+    Err(invalid("neither an IPS nor a BPS patch"))
+  }
+}
+
+fn invalid(reason: &str) -> Error {
+  Error::InvalidPatch(reason.to_string())
+}
+
+/// Reads the patch bytes in order, failing at its end.
+struct Reader<'a> {
+  bytes: &'a [u8],
+  pos: usize,
+}
+
+impl<'a> Reader<'a> {
+  fn take(&mut self, len: usize) -> Result<&'a [u8], Error> {
+    let end = self.pos.checked_add(len).ok_or_else(|| invalid("truncated"))?;
+    let bytes = self.bytes.get(self.pos..end).ok_or_else(|| invalid("truncated"))?;
+    self.pos = end;
+    Ok(bytes)
+  }
+
+  fn be(&mut self, len: usize) -> Result<usize, Error> {
+    Ok(self.take(len)?.iter().fold(0, |acc, b| acc << 8 | *b as usize))
+  }
+
+  /// BPS numbers: 7 bits per byte, the last one has bit 7 set. Each byte also adds one to the bytes after it,
+  /// so a number has a single encoding.
+  fn varint(&mut self) -> Result<usize, Error> {
+    let (mut val, mut shift) = (0usize, 1usize);
+    loop {
+      let byte = self.take(1)?[0] as usize;
+      val = (byte & 0x7F).checked_mul(shift).and_then(|n| val.checked_add(n)).ok_or_else(|| invalid("number too big"))?;
+      if byte & 0x80 != 0 { return Ok(val); }
+      shift = shift.checked_shl(7).filter(|shift| *shift != 0).ok_or_else(|| invalid("number too big"))?;
+      val = val.checked_add(shift).ok_or_else(|| invalid("number too big"))?;
+    }
+  }
+}
+
+/// IPS: records of a 24 bit offset and 16 bit size, followed by the bytes, or by a 16 bit run length
+/// and its byte when the size is 0. After `EOF`, an optional 24 bit size truncates the ROM.
+pub fn apply_ips(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, Error> {
+  let mut out = rom.to_vec();
+  let mut reader = Reader { bytes: patch, pos: IPS_MAGIC.len() };
+
+  loop {
+    if reader.bytes[reader.pos..].starts_with(IPS_EOF) {
+      reader.pos += IPS_EOF.len();
+      break;
+    }
+
+    let offset = reader.be(3)?;
+    let (len, data) = match reader.be(2)? {
+      0 => {
+        let len = reader.be(2)?;
+        (len, None)
+      }
+      len => (len, Some(reader.take(len)?)),
+    };
+
+    if offset + len > MAX_ROM_SIZE {
+      return Err(invalid("the patched ROM is too big"));
+    }
+    if out.len() < offset + len { out.resize(offset + len, 0); }
+    match data {
+      Some(data) => out[offset..offset + len].copy_from_slice(data),
+      None => out[offset..offset + len].fill(reader.take(1)?[0]),
+    }
+  }
+
+  if let Ok(size) = reader.be(3) {
+    out.truncate(size);
+  }
+  Ok(out)
+}
+
+/// BPS: the target is built from actions copying bytes from the source, the target itself, or the patch.
+/// The CRC32s of the source, target and patch are checked.
+pub fn apply_bps(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, Error> {
+  if patch.len() < BPS_MAGIC.len() + BPS_FOOTER_LEN {
+    return Err(invalid("truncated"));
+  }
+  let footer = patch.len() - BPS_FOOTER_LEN;
+  let crc_at = |at: usize| u32::from_le_bytes([patch[at], patch[at+1], patch[at+2], patch[at+3]]);
+  if crc32(&patch[..patch.len() - 4]) != crc_at(footer + 8) {
+    return Err(invalid("the patch is corrupted"));
+  }
+  if crc32(rom) != crc_at(footer) {
+    return Err(invalid("made for another ROM"));
+  }
+
+  let mut reader = Reader { bytes: &patch[..footer], pos: BPS_MAGIC.len() };
+  let source_size = reader.varint()?;
+  let target_size = reader.varint()?;
+  let metadata_size = reader.varint()?;
+  reader.take(metadata_size)?;
+  if source_size != rom.len() {
+    return Err(invalid("made for another ROM"));
+  }
+  if target_size > MAX_ROM_SIZE {
+    return Err(invalid("the patched ROM is too big"));
+  }
+
+  let mut out = Vec::with_capacity(target_size);
+  let (mut source_pos, mut target_pos) = (0usize, 0usize);
+  let range = |start: usize, len: usize| start..start.saturating_add(len);
+  let relative = |reader: &mut Reader, pos: usize| -> Result<usize, Error> {
+    let offset = reader.varint()?;
+    let pos = match offset & 1 {
+      0 => pos.checked_add(offset >> 1),
+      _ => pos.checked_sub(offset >> 1),
+    };
+    pos.ok_or_else(|| invalid("copy out of bounds"))
+  };
+
+  while reader.pos < reader.bytes.len() {
+    let action = reader.varint()?;
+    let len = (action >> 2) + 1;
+    if len > target_size - out.len() {
+      return Err(invalid("bigger than its target size"));
+    }
+
+    match action & 3 {
+      // source read: the source bytes at the same offset
+      0 => {
+        let start = out.len();
+        out.extend_from_slice(rom.get(range(start, len)).ok_or_else(|| invalid("copy out of bounds"))?);
+      }
+      // target read: bytes from the patch
+      1 => out.extend_from_slice(reader.take(len)?),
+      // source copy: from anywhere in the source
+      2 => {
+        source_pos = relative(&mut reader, source_pos)?;
+        out.extend_from_slice(rom.get(range(source_pos, len)).ok_or_else(|| invalid("copy out of bounds"))?);
+        source_pos += len;
+      }
+      // target copy: from what was written, byte by byte, as it can overlap with what's being written
+      _ => {
+        target_pos = relative(&mut reader, target_pos)?;
+        for _ in 0..len {
+          let byte = *out.get(target_pos).ok_or_else(|| invalid("copy out of bounds"))?;
+          out.push(byte);
+          target_pos += 1;
+        }
+      }
+    }
+  }
+
+  if out.len() != target_size {
+    return Err(invalid("smaller than its target size"));
+  }
+  if crc32(&out) != crc_at(footer + 4) {
+    return Err(invalid("the patched ROM is corrupted"));
+  }
+  Ok(out)
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+  !bytes.iter().fold(!0u32, |crc, byte| {
+    (0..8).fold(crc ^ *byte as u32, |crc, _| (crc >> 1) ^ (0xEDB8_8320 & 0u32.wrapping_sub(crc & 1)))
+  })
+}
+
+#[cfg(test)]
+mod patch_tests {
+  use super::*;
+
+  fn varint(mut val: usize, out: &mut Vec<u8>) {
+    loop {
+      let byte = (val & 0x7F) as u8;
+      val >>= 7;
+      if val == 0 {
+        out.push(byte | 0x80);
+        return;
+      }
+      out.push(byte);
+      val -= 1;
+    }
+  }
+
+  #[test]
+  fn ips() {
+    let rom = b"hello world".to_vec();
+    let mut patch = b"PATCH".to_vec();
+    // "HELLO" at 0, a run of 3 '!' past the end
+    patch.extend([0, 0, 0, 0, 5]);
+    patch.extend(b"HELLO");
+    patch.extend([0, 0, 11, 0, 0, 0, 3, b'!']);
+    patch.extend(b"EOF");
+    assert_eq!(apply(&rom, &patch).unwrap(), b"HELLO world!!!");
+
+    // truncated to 5 bytes
+    patch.extend([0, 0, 5]);
+    assert_eq!(apply(&rom, &patch).unwrap(), b"HELLO");
+
+    assert!(matches!(apply(&rom, b"PATCH\x00\x00"), Err(Error::InvalidPatch(_))));
+    assert!(matches!(apply(&rom, b"UPS1"), Err(Error::InvalidPatch(_))));
+    assert!(matches!(apply(&rom, b"PATCH\xFF\xFF\xFF\x00\x00\xFF\xFF\x00EOF"), Err(Error::InvalidPatch(_))));
+  }
+
+  /// A BPS patch with valid CRCs around the given body
+  fn bps_patch(source: &[u8], target: &[u8], body: &[u8]) -> Vec<u8> {
+    let mut patch = b"BPS1".to_vec();
+    patch.extend(body);
+    for crc in [crc32(source), crc32(target)] {
+      patch.extend(crc.to_le_bytes());
+    }
+    patch.extend(crc32(&patch).to_le_bytes());
+    patch
+  }
+
+  #[test]
+  fn malformed_bps() {
+    let source = b"hello".to_vec();
+    let header = |target_size: usize, metadata_size: usize| {
+      let mut body = Vec::new();
+      varint(source.len(), &mut body);
+      varint(target_size, &mut body);
+      varint(metadata_size, &mut body);
+      body
+    };
+    let fails = |body: &[u8]| matches!(apply(&source, &bps_patch(&source, b"", body)), Err(Error::InvalidPatch(_)));
+
+    // huge target and metadata sizes
+    assert!(fails(&header(usize::MAX, 0)));
+    assert!(fails(&header(MAX_ROM_SIZE + 1, 0)));
+    assert!(fails(&header(5, usize::MAX)));
+    // a varint overflowing
+    assert!(fails(&[0x7F; 16]));
+
+    // a source read past the end of the source, then copies far out of range
+    let mut body = header(16, 0);
+    varint((10 - 1) << 2, &mut body);
+    assert!(fails(&body));
+    for action in [2, 3] {
+      let mut body = header(16, 0);
+      varint(((4 - 1) << 2) | action, &mut body);
+      varint(usize::MAX - 1, &mut body);
+      assert!(fails(&body));
+      let mut body = header(16, 0);
+      varint(((4 - 1) << 2) | action, &mut body);
+      varint(2 << 1, &mut body);
+      assert!(fails(&body));
+    }
+    // an action bigger than the target
+    let mut body = header(2, 0);
+    varint(usize::MAX & !3, &mut body);
+    assert!(fails(&body));
+  }
+
+  #[test]
+  fn bps() {
+    assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+
+    let source = b"hello world".to_vec();
+    let target = b"hello there, hello hello!".to_vec();
+    let mut body = Vec::new();
+    varint(source.len(), &mut body);
+    varint(target.len(), &mut body);
+    varint(0, &mut body);
+    // source read "hello ", target read "there, ", source copy "hello" from 0, target copy " hello" from 12
+    varint((6 - 1) << 2, &mut body);
+    varint(((7 - 1) << 2) | 1, &mut body);
+    body.extend(b"there, ");
+    varint(((5 - 1) << 2) | 2, &mut body);
+    varint(0, &mut body);
+    varint(((6 - 1) << 2) | 3, &mut body);
+    varint(12 << 1, &mut body);
+    varint(1, &mut body);
+    body.push(b'!');
+    let mut patch = bps_patch(&source, &target, &body);
+
+    assert_eq!(apply(&source, &patch).unwrap(), target);
+    assert_eq!(apply(b"hello moon!", &patch), Err(Error::InvalidPatch("made for another ROM".to_string())));
+    let last = patch.len() - 1;
+    patch[last] ^= 1;
+    assert_eq!(apply(&source, &patch), Err(Error::InvalidPatch("the patch is corrupted".to_string())));
+  }
+}
@@ -1,10 +1,11 @@
 use std::collections::VecDeque;
 
-use crate::{bus::{self, IFlags, InterruptFlags}, frame::FrameBuffer, nth_bit};
+use crate::{bus::IFlags, frame::{Color, FrameBuffer}, nth_bit, state};
 use bitflags::bitflags;
+use serde::{Deserialize, Serialize};
 
 bitflags! {
-  #[derive(Default, Clone, Copy)]
+  #[derive(Default, Clone, Copy, Serialize, Deserialize)]
   pub struct Ctrl: u8 {
     const bg_wnd_enabled = 0b0000_0001;
     const obj_enabled    = 0b0000_0010;
@@ -17,7 +18,7 @@ bitflags! {
     const lcd_enabled  = 0b1000_0000;
   }
 
-  #[derive(Default, Clone, Copy, PartialEq)]
+  #[derive(Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
   pub struct Stat: u8 {
     const lyc_eq_ly = 0b0000_0100;
     const mode0_int = 0b0000_1000;
@@ -35,8 +36,53 @@ const VRAM2: u16 = 0x9000;
 const MAP0: u16 = 0x9800;
 const MAP1: u16 = 0x9C00; 
 
-#[derive(Default, Clone, Copy, PartialEq)]
-enum PpuMode {
+/// Size in pixels of the [`Ppu::debug_render_tiles`] output.
+pub const TILES_VIEW_SIZE: (usize, usize) = (16*8, 24*8);
+/// Size in pixels of the [`Ppu::debug_render_tilemap`] output, see [`FrameBuffer::debug_full_bg`].
+pub const TILEMAP_VIEW_SIZE: (usize, usize) = (256, 256);
+const VIEWPORT_COLOR: Color = (255, 0, 0);
+
+/// Dots in a scanline, mode 2 to mode 0 or a vblank line.
+const LINE_DOTS: usize = 456;
+/// Lines in a frame, 144 drawn and 10 of vblank.
+const FRAME_LINES: u8 = 154;
+/// Dots in a frame.
+const FRAME_DOTS: usize = LINE_DOTS * FRAME_LINES as usize;
+/// Dots LY reads 153 for on the last line, before it reads 0.
+const LY_153_DOTS: usize = 4;
+/// The first line after the LCD is turned on starts this many dots late.
+const LCD_ON_DELAY: usize = 4;
+/// Dots of mode 2, 2 for each OAM entry.
+const OAM_SCAN_DOTS: usize = 80;
+/// The shortest mode 3, with no scrolling, window or objects.
+#[cfg_attr(feature = "dot-renderer", allow(dead_code))]
+const MODE3_DOTS: usize = 172;
+/// Together with our first tile fetch, makes the 12 dots before the first pixel is out.
+const LINE_START_DELAY: u8 = 4;
+/// Dots the fetcher restarts for when the window starts on a line.
+const WND_PENALTY: u8 = 6;
+/// Dots an object fetch takes, without waiting for the background fetch.
+const OBJ_PENALTY: u8 = 6;
+
+/// The DMG palettes, as the colors each color id is displayed with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Palettes {
+  pub bgp: [Color; 4],
+  pub obp0: [Color; 4],
+  pub obp1: [Color; 4],
+}
+
+/// Which tilemap [`Ppu::debug_render_tilemap`] draws.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Tilemap { Bg, Window }
+
+/// A layer [`Ppu::set_layer_enabled`] can hide.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Layer { Background, Window, Objects }
+
+/// What the PPU is doing, as reported in the low bits of STAT.
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PpuMode {
   Hblank, // Mode0
   Vblank, // Mode1
   #[default]
@@ -44,22 +90,34 @@ enum PpuMode {
   DrawingPixels, // Mode3
 }
 
-#[derive(Default)]
+#[derive(Default, Serialize, Deserialize)]
+#[cfg_attr(not(feature = "dot-renderer"), allow(dead_code))]
 enum FetcherState {
   #[default] Tile, DataLow, DataHigh, Push
 }
 
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(not(feature = "dot-renderer"), allow(dead_code))]
 struct Fetcher {
   state: FetcherState,
   obj_visible: Vec<OamObject>,
   bg_fifo: VecDeque<u8>,
-  obj_scanline: [Option<ObjFifoEntry>; 160],
+  obj_fifo: VecDeque<ObjFifoEntry>,
   should_do_step: bool,
   x: u8,
   wnd_hit: bool,
+  /// The window was drawn on this line, even if it was disabled afterwards.
+  wnd_drawn: bool,
   pixel_x: u8,
   bg_scroll_x: u8,
   wnd_scroll_x: u8,
+  /// Dots the fetcher waits at the start of the line: the hardware throws away its first tile fetch.
+  delay: u8,
+  /// Dots the pixel output is stopped for, while objects are fetched.
+  /// The scanline renderer keeps all the mode 3 penalties of the line here instead.
+  stall: u8,
+  /// The background or window tile the last object fetch penalty was counted for.
+  obj_tile: Option<u8>,
   
   tile_y: u8,
   tileset_id: u8,
@@ -70,38 +128,61 @@ struct Fetcher {
 
 impl Default for Fetcher {
   fn default() -> Self {
-    Self { state: Default::default(), obj_visible: Default::default(), bg_fifo: Default::default(), obj_scanline: [const {None}; 160], should_do_step: Default::default(), x: Default::default(), wnd_hit: Default::default(), pixel_x: Default::default(), bg_scroll_x: Default::default(), wnd_scroll_x: Default::default(), tile_y: Default::default(), tileset_id: Default::default(), tileset_addr: Default::default(), tile_lo: Default::default(), tile_hi: Default::default() }
+    Self { state: Default::default(), obj_visible: Default::default(), bg_fifo: Default::default(), obj_fifo: Default::default(), should_do_step: Default::default(), x: Default::default(), wnd_hit: Default::default(), wnd_drawn: Default::default(), pixel_x: Default::default(), bg_scroll_x: Default::default(), wnd_scroll_x: Default::default(), delay: LINE_START_DELAY, stall: Default::default(), obj_tile: Default::default(), tile_y: Default::default(), tileset_id: Default::default(), tileset_addr: Default::default(), tile_lo: Default::default(), tile_hi: Default::default() }
   }
 }
 
 impl Fetcher {
   pub fn reset(&mut self) {
     self.bg_fifo.clear();
+    self.obj_fifo.clear();
     self.x = 0;
     self.wnd_hit = false;
+    self.wnd_drawn = false;
     self.pixel_x = 0;
     self.bg_scroll_x = 0;
     self.wnd_scroll_x = 0;
+    self.delay = LINE_START_DELAY;
+    self.stall = 0;
+    self.obj_tile = None;
     self.should_do_step = false;
     self.state = FetcherState::Tile;
   }
 }
 
-#[derive(Default, Clone)]
+#[derive(Default, Clone, Serialize, Deserialize)]
 struct ObjFifoEntry {
   color: u8,
   palette: bool,
   priority: bool,
-}
-struct OamObject {
+  /// Index in OAM, for the OAM index priority mode
+  #[serde(default)]
   i: u8,
-  y: u8,
-  x: u8,
-  tile_id: u8,
-  priority: bool,
-  x_flip: bool,
-  y_flip: bool,
-  dmg_palette: bool,
+}
+impl ObjFifoEntry {
+  /// Puts `pixel` over this one, an object pixel fetched before it.
+  /// By X coordinate the object fetched first has priority, and only its transparent pixels are replaced.
+  /// By OAM index (CGB OPRI=0) the lower index wins instead.
+  fn merge(&mut self, pixel: ObjFifoEntry, by_index: bool) {
+    if self.color == 0 || (by_index && pixel.color != 0 && pixel.i < self.i) {
+      *self = pixel;
+    }
+  }
+}
+/// An OAM entry. `x` and `y` are the raw values, offset by 8 and 16 from the screen position.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OamObject {
+  /// Index in OAM
+  pub i: u8,
+  pub y: u8,
+  pub x: u8,
+  pub tile_id: u8,
+  /// Drawn above the background (attribute bit 7 cleared)
+  pub priority: bool,
+  pub x_flip: bool,
+  pub y_flip: bool,
+  /// Uses OBP1 instead of OBP0
+  pub dmg_palette: bool,
 }
 impl OamObject {
   pub fn new(bytes: &[u8], i: u8) -> Self {
@@ -120,15 +201,42 @@ impl OamObject {
   }
 }
 
+#[derive(Serialize, Deserialize)]
 pub struct Ppu {
+  #[serde(skip, default = "FrameBuffer::gameboy_lcd")]
   pub lcd: FrameBuffer,
   fetcher: Fetcher,
 
+  #[serde(with = "state::array")]
   pub vram: [u8; 8*1024],
+  #[serde(with = "state::array")]
   pub oam: [u8; 160],
 
   mode: PpuMode,
   pub frame_ready: Option<()>,
+  /// Set to the line number when a visible line has been drawn.
+  #[serde(skip)]
+  pub line_ready: Option<u8>,
+  frames: usize,
+  /// When set, pixels aren't written to [`Ppu::lcd`], which keeps the last drawn frame.
+  /// Timing and interrupts are the same, this only saves the rendering work when fast-forwarding.
+  #[serde(skip)]
+  pub frame_skip: bool,
+  /// Emulates the DMG hardware bugs some games rely on, like the spurious interrupt on STAT writes.
+  #[serde(skip)]
+  pub dmg_quirks: bool,
+  /// Draws even with [`Ppu::frame_skip`] set, while the SGB waits for a frame with transferred data.
+  #[serde(skip)]
+  pub(crate) force_output: bool,
+  /// Layers hidden by [`Ppu::set_layer_enabled`], indexed by [`Layer`].
+  #[serde(skip)]
+  pub(crate) hidden_layers: [bool; 3],
+  /// The LCD was just turned on: nothing is shown until the next frame.
+  #[serde(default)]
+  lcd_on_frame: bool,
+  /// The first line after the LCD is turned on has no OAM scan, it stays in mode 0 instead.
+  #[serde(default)]
+  lcd_on_line: bool,
 
   ctrl: Ctrl,
   stat: Stat,
@@ -136,7 +244,14 @@ pub struct Ppu {
   oam_enabled: bool,
   ly: u8,
   wnd_line: u8,
+  /// LY matched WY in this frame: the window can show from then on, even if WY changes.
+  wy_hit: bool,
+  /// The window reached the last pixel with WX=166, it then fills the next line from its start.
+  wnd_next_line: bool,
   lyc: u8,
+  /// OPRI bit 0 clear: overlapping objects are drawn by OAM index, as on CGB, instead of by X coordinate.
+  #[serde(default)]
+  obj_index_priority: bool,
   scy: u8,
   scx: u8,
   wy: u8,
@@ -146,12 +261,20 @@ pub struct Ppu {
   obp1: u8,
 
   tcycles: usize,
-  intf: InterruptFlags,
+  /// Interrupts raised since IF was last written, see [`Bus::intf`](crate::bus::Bus::intf)
+  #[serde(default)]
+  pub(crate) intf: IFlags,
   stat_int_flag: bool,
 }
 
+impl Default for Ppu {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
 impl Ppu {
-  pub fn new(intf: InterruptFlags) -> Self {
+  pub fn new() -> Self {
     Self {
       lcd: FrameBuffer::gameboy_lcd(),
       fetcher: Fetcher::default(),
@@ -160,6 +283,14 @@ impl Ppu {
 
       mode: Default::default(),
       frame_ready: None,
+      line_ready: None,
+      frames: 0,
+      frame_skip: false,
+      dmg_quirks: true,
+      force_output: false,
+      hidden_layers: [false; 3],
+      lcd_on_frame: false,
+      lcd_on_line: false,
 
       // ctrl: Ctrl::from_bits_retain(0x91),
       // stat: Stat::from_bits_retain(0x81),
@@ -170,7 +301,10 @@ impl Ppu {
       oam_enabled: false,
       ly: 0,
       wnd_line: 0,
+      wy_hit: false,
+      wnd_next_line: false,
       lyc: 0,
+      obj_index_priority: false,
       scy: 0,
       scx: 0,
       wy: 0,
@@ -180,88 +314,105 @@ impl Ppu {
       obp1: 0,
 
       tcycles: Default::default(), 
-      intf,
+      intf: IFlags::empty(),
       stat_int_flag: false,
     }
   }
 
   pub fn tick(&mut self) {
     if !self.is_lcd_enabled() {
+      // LY stays at 0 and nothing is drawn, but frames go on with a blank screen, for the frontends pacing on them
       self.tcycles += 1;
-      if self.tcycles >= 70224 {
-        self.frame_ready = Some(());
+      if self.tcycles >= FRAME_DOTS {
+        self.tcycles = 0;
+        self.end_frame();
       }
+      return;
     }
 
     self.tcycles += 1;
-    if self.tcycles > 456 {
+    if self.tcycles >= LINE_DOTS {
       self.tcycles = 0;
-      self.ly_inc();
+      self.ly = (self.ly + 1) % FRAME_LINES;
     }
     
     use PpuMode::*;
     match self.mode {
       OamScan => {
-        if self.tcycles >= 80 {
-          // we do this in one go
-          self.oam_scan();
-          self.fill_obj_scanline();
+        if self.ly == self.wy { self.wy_hit = true; }
+
+        // an entry every 2 dots, on the second one
+        if self.tcycles % 2 == 1 {
+          self.oam_scan_entry(self.tcycles as u8 / 2);
+        }
 
-          self.mode = DrawingPixels;
-          self.vram_enabled = false;
+        if self.tcycles >= OAM_SCAN_DOTS {
+          self.sort_obj_visible();
+          self.start_drawing();
         }
       }
       DrawingPixels => {
-        if self.fetcher.pixel_x >= 160 {
+        #[cfg(feature = "dot-renderer")]
+        let line_done = self.fetcher.pixel_x >= 160;
+        // the scanline is already drawn, just wait for the mode 3 length
+        #[cfg(not(feature = "dot-renderer"))]
+        let line_done = self.tcycles >= OAM_SCAN_DOTS + MODE3_DOTS + self.fetcher.stall as usize;
+
+        if line_done {
           self.oam_enabled = true;
           self.vram_enabled = true;
+
+          // the window line counter only moves on the lines the window was drawn
+          if self.fetcher.wnd_drawn { self.wnd_line += 1; }
+          self.wnd_next_line = self.fetcher.wnd_drawn && self.wx == 166;
           self.fetcher.reset();
           
+          self.line_ready = Some(self.ly);
           self.mode = Hblank;
-          // self.send_lcd_int(Stat::mode0_int);
           self.send_stat_int();
         } else {
+          #[cfg(feature = "dot-renderer")]
           self.fetcher_step();
         }
       }
       Hblank => {
-        if self.tcycles >= 456 {
-          if self.ly >= 143 {
-            
+        if self.lcd_on_line && self.tcycles >= OAM_SCAN_DOTS {
+          self.lcd_on_line = false;
+          self.start_drawing();
+        // the line just ended
+        } else if self.tcycles == 0 {
+          if self.ly >= 144 {
             self.mode = Vblank;
             self.send_vblank_int();
-            // self.send_lcd_int(Stat::mode1_int);
             self.send_stat_int();
           } else {
             self.mode = OamScan;
-            // self.send_lcd_int(Stat::mode2_int);
+            self.oam_enabled = false;
             self.send_stat_int();
           };
         }
       }
       Vblank => {
-        if self.ly >= 154 {
+        // the frame just ended
+        if self.tcycles == 0 && self.ly == 0 {
           self.mode = OamScan;
-          // self.send_lcd_int(Stat::mode2_int);
-          self.send_stat_int();
-          
           self.oam_enabled = false;
 
-          self.ly = 0;
           self.wnd_line = 0;
+          self.wy_hit = false;
+          self.wnd_next_line = false;
         }
       }
     };
 
-    self.stat.set(Stat::lyc_eq_ly, self.lyc == self.ly);
+    self.stat.set(Stat::lyc_eq_ly, self.lyc == self.ly_register());
     self.send_stat_int();
-    // self.stat.set(Stat::lyc_eq_ly, self.ly == self.lyc);
-    // let lyc = self.ly == self.lyc;
-    // self.stat.insert(Stat::lyc_eq_ly);
-    // if old_stat != self.stat {
-    //   // self.send_lyc_int();
-    //   self.send_stat_int();
-    // }
+  }
+
+  /// LY as the CPU reads it. On the last vblank line, it reads 153 only for the first 4 dots, and 0 for the rest,
+  /// so LYC=0 matches there before line 0 begins.
+  fn ly_register(&self) -> u8 {
+    if self.ly == FRAME_LINES - 1 && self.tcycles >= LY_153_DOTS { 0 } else { self.ly }
   }
 
   pub fn read(&self, addr: u16) -> u8 {
@@ -275,13 +426,14 @@ impl Ppu {
       },
       0xFF42 => self.scy,
       0xFF43 => self.scx,
-      0xFF44 => self.ly,
+      0xFF44 => self.ly_register(),
       0xFF45 => self.lyc,
       0xFF4A => self.wy,
       0xFF4B => self.wx,
       0xFF47 => self.bgp,
       0xFF48 => self.obp0,
       0xFF49 => self.obp1,
+      0xFF6C => 0xFE | !self.obj_index_priority as u8,
       _ => 0xFF
     }
   }
@@ -289,25 +441,32 @@ impl Ppu {
   pub fn write(&mut self, addr: u16, val: u8) {
     match addr {
       0xFF40 => {
-        let old_ctrl = self.ctrl.clone();
+        let old_ctrl = self.ctrl;
         self.ctrl = Ctrl::from_bits_retain(val);
 
         // lcd enabling/disabling logic
         if old_ctrl.contains(Ctrl::lcd_enabled) != self.ctrl.contains(Ctrl::lcd_enabled) {
           // it is turned on
           if self.ctrl.contains(Ctrl::lcd_enabled) {
-            self.tcycles = 80;
+            self.tcycles = LCD_ON_DELAY;
             self.ly = 0;
             self.wnd_line = 0;
-            self.mode = PpuMode::DrawingPixels;
+            self.wy_hit = self.wy == 0;
+            self.wnd_next_line = false;
+            self.mode = PpuMode::Hblank;
+            self.lcd_on_line = true;
+            self.lcd_on_frame = true;
+            self.fetcher.obj_visible.clear();
             self.stat.set(Stat::lyc_eq_ly, self.ly == self.lyc);
             self.send_stat_int();
-            // self.send_stat_int();
           // it is turned off
           } else {
-            self.tcycles = 0;
+            // the frame pacing carries on from where the frame was
+            self.tcycles += self.ly as usize * LINE_DOTS;
             self.ly = 0;
             self.wnd_line = 0;
+            self.wy_hit = false;
+            self.wnd_next_line = false;
             self.mode = PpuMode::Hblank;
             self.fetcher.reset();
             self.lcd.reset();
@@ -318,6 +477,14 @@ impl Ppu {
         }
       }
       0xFF41 => {
+        if self.dmg_quirks {
+          // STAT bug: for a cycle, the sources are all enabled as if 0xFF was written,
+          // so a write in hblank, vblank or with LY=LYC raises an interrupt. The mode 2 source isn't affected.
+          let lyc_eq_ly = self.stat & Stat::lyc_eq_ly;
+          self.stat = lyc_eq_ly | Stat::lyc_int | Stat::mode0_int | Stat::mode1_int;
+          self.send_stat_int();
+        }
+
         let mut res = Stat::from_bits_retain(val & 0b0111_1000);
         res.set(Stat::lyc_eq_ly, self.stat.contains(Stat::lyc_eq_ly));
         self.stat = res;
@@ -326,9 +493,7 @@ impl Ppu {
       0xFF43 => self.scx = val,
       0xFF45 => {
         self.lyc = val;
-        // self.send_lyc_int();
-        // self.send_stat_int();
-        self.stat.set(Stat::lyc_eq_ly, self.lyc == self.ly);
+        self.stat.set(Stat::lyc_eq_ly, self.lyc == self.ly_register());
         self.send_stat_int();
       }
       0xFF4A => self.wy = val,
@@ -336,6 +501,7 @@ impl Ppu {
       0xFF47 => self.bgp = val,
       0xFF48 => self.obp0 = val,
       0xFF49 => self.obp1 = val,
+      0xFF6C => self.obj_index_priority = val & 1 == 0,
       _ => {}
     }
   }
@@ -345,17 +511,34 @@ impl Ppu {
   }
 
   fn send_vblank_int(&mut self) {
-    if self.is_lcd_enabled() {
-      bus::send_interrupt(&self.intf, bus::IFlags::vblank);
-    }
+    self.intf.insert(IFlags::vblank);
+    self.end_frame();
+  }
 
+  fn end_frame(&mut self) {
+    if !self.frame_skip { self.lcd.blend_frame(); }
+    self.lcd_on_frame = false;
     self.frame_ready = Some(());
+    self.frames += 1;
   }
 
-  fn send_lcd_int(&mut self, flag: Stat) {
-    if self.stat.contains(flag) && self.is_lcd_enabled() {
-      bus::send_interrupt(&self.intf, bus::IFlags::lcd);
+  /// Pixels aren't drawn when frames are skipped, or in the first frame after the LCD is turned on.
+  fn is_output_enabled(&self) -> bool {
+    (!self.frame_skip || self.force_output) && !self.lcd_on_frame
+  }
+
+  /// Mode 3 begins, after the OAM scan.
+  fn start_drawing(&mut self) {
+    #[cfg(not(feature = "dot-renderer"))]
+    {
+      self.fetcher.wnd_drawn = self.wnd_start().is_some();
+      self.fetcher.stall = self.mode3_penalties();
+      self.render_scanline();
     }
+
+    self.mode = PpuMode::DrawingPixels;
+    self.oam_enabled = false;
+    self.vram_enabled = false;
   }
 
   fn send_stat_int(&mut self) {
@@ -367,37 +550,46 @@ impl Ppu {
     );
 
     if int && !self.stat_int_flag {
-      bus::send_interrupt(&self.intf, IFlags::lcd);
+      self.intf.insert(IFlags::lcd);
     }
 
     self.stat_int_flag = int;
   }
 
-  fn send_lyc_int(&mut self) {
-    self.stat.set(Stat::lyc_eq_ly, self.ly == self.lyc);
+  pub fn frame_count(&self) -> usize {
+    self.frames
+  }
 
-    if self.stat.contains(Stat::lyc_eq_ly) {
-      self.send_lcd_int(Stat::lyc_int);
-    }
+  /// The current scanline, as read from LY.
+  pub fn ly(&self) -> u8 {
+    self.ly_register()
+  }
+
+  pub fn mode(&self) -> PpuMode {
+    self.mode
   }
 
   pub fn is_lcd_enabled(&self) -> bool {
     self.ctrl.contains(Ctrl::lcd_enabled)
   }
 
-  fn ly_inc(&mut self) {
-    // wnd_line is only incremented when window is VISIBLE and HIT
-    if self.ly >= self.wy
-    && self.wy < 143
-    && self.wx < 166
-    {
-      self.wnd_line += 1;
-    }
-    self.ly += 1;
+  /// Debug switch to hide a layer from the output, to see what draws what.
+  /// Only the drawn pixels change: a hidden background or window is drawn with color 0, and the timing stays the same.
+  pub fn set_layer_enabled(&mut self, layer: Layer, enabled: bool) {
+    self.hidden_layers[layer as usize] = !enabled;
+  }
 
-    self.stat.set(Stat::lyc_eq_ly, self.lyc == self.ly);
-    self.send_stat_int();
-    // self.send_lyc_int();
+  pub fn is_layer_enabled(&self, layer: Layer) -> bool {
+    !self.hidden_layers[layer as usize]
+  }
+
+  /// The CPU can't access OAM while the PPU reads it, in mode 2 and 3.
+  pub fn is_oam_accessible(&self) -> bool {
+    self.oam_enabled || !self.is_lcd_enabled()
+  }
+
+  pub fn is_vram_accessible(&self) -> bool {
+    self.vram_enabled || !self.is_lcd_enabled()
   }
 
   pub fn tileset_addr(&self, tileset_id: u8) -> u16 {
@@ -410,6 +602,101 @@ impl Ppu {
     }
   }
 
+  /// Draws the 384 tiles of VRAM in a 16x24 tiles grid, with the current BGP applied.
+  /// The framebuffer should be at least [`TILES_VIEW_SIZE`] pixels.
+  pub fn debug_render_tiles(&self, fb: &mut FrameBuffer) {
+    for i in 0..384 {
+      let (tile_x, tile_y) = (i % 16 * 8, i / 16 * 8);
+      let tile = &self.vram[i*16..i*16 + 16];
+
+      for row in 0..8 {
+        let (tile_lo, tile_hi) = (tile[row*2], tile[row*2 + 1]);
+        for bit in 0..8 {
+          let color = (((tile_hi >> bit) & 1) << 1) | ((tile_lo >> bit) & 1);
+          fb.set_pixel(tile_x + 7-bit, tile_y + row, self.bg_palette(color));
+        }
+      }
+    }
+  }
+
+  /// Draws the whole 32x32 tiles map currently used by the background or the window, with the current BGP applied.
+  /// For the background, the 160x144 area shown on screen is outlined, wrapping around the edges.
+  /// The framebuffer should be at least [`TILEMAP_VIEW_SIZE`] pixels.
+  pub fn debug_render_tilemap(&self, which: Tilemap, fb: &mut FrameBuffer) {
+    let tilemap = match which {
+      Tilemap::Bg => self.bg_tilemap(),
+      Tilemap::Window => self.wnd_tilemap(),
+    };
+
+    for y in 0..256u16 {
+      for x in 0..256u16 {
+        let tilemap_id = tilemap + 32 * (y/8) + x/8;
+        let tile_addr = self.tileset_addr(self.vram_read(tilemap_id)) + 2*(y % 8);
+        let tile_lo = self.vram_read(tile_addr);
+        let tile_hi = self.vram_read(tile_addr+1);
+
+        let bit = 7 - x % 8;
+        let color = (((tile_hi >> bit) & 1) << 1) | ((tile_lo >> bit) & 1);
+        fb.set_pixel(x as usize, y as usize, self.bg_palette(color));
+      }
+    }
+
+    if which == Tilemap::Bg {
+      for i in 0..160u8 {
+        let x = self.scx.wrapping_add(i) as usize;
+        fb.set_color(x, self.scy as usize, VIEWPORT_COLOR);
+        fb.set_color(x, self.scy.wrapping_add(143) as usize, VIEWPORT_COLOR);
+      }
+      for i in 0..144u8 {
+        let y = self.scy.wrapping_add(i) as usize;
+        fb.set_color(self.scx as usize, y, VIEWPORT_COLOR);
+        fb.set_color(self.scx.wrapping_add(159) as usize, y, VIEWPORT_COLOR);
+      }
+    }
+  }
+
+  /// Decodes BGP, OBP0 and OBP1 to the colors of the LCD.
+  /// Color 0 of the object palettes is transparent, but is decoded like the others.
+  pub fn palettes(&self) -> Palettes {
+    let decode = |palette: u8| std::array::from_fn(|id| {
+      self.lcd.color((palette >> (id*2)) & 0b11)
+    });
+
+    Palettes {
+      bgp: decode(self.bgp),
+      obp0: decode(self.obp0),
+      obp1: decode(self.obp1),
+    }
+  }
+
+  /// The 40 OAM entries, in OAM order.
+  pub fn debug_oam(&self) -> [OamObject; 40] {
+    std::array::from_fn(|i| OamObject::new(&self.oam[i*4..i*4 + 4], i as u8))
+  }
+
+  /// Draws an object at the top left of the framebuffer, as it would appear on screen:
+  /// 8x8 or 8x16 depending on LCDC, flipped, and with its palette applied.
+  /// Transparent pixels are left untouched.
+  pub fn debug_render_obj(&self, obj: &OamObject, fb: &mut FrameBuffer) {
+    let tile_id = if self.ctrl.contains(Ctrl::obj_size) {
+      obj.tile_id & 0xFE
+    } else { obj.tile_id };
+
+    for row in 0..self.obj_size() {
+      let y_offset = if obj.y_flip { self.obj_size()-1 - row } else { row };
+      let tile_addr = VRAM0 + 16*tile_id as u16 + 2*y_offset as u16;
+      let tile_lo = self.vram_read(tile_addr);
+      let tile_hi = self.vram_read(tile_addr+1);
+
+      for col in 0..8 {
+        let bit = if obj.x_flip { col } else { 7 - col };
+        let color = (((tile_hi >> bit) & 1) << 1) | ((tile_lo >> bit) & 1);
+        if color == 0 { continue; }
+        fb.set_pixel(col as usize, row as usize, self.obj_palette(obj.dmg_palette, color));
+      }
+    }
+  }
+
   fn bg_tilemap(&self) -> u16 {
     match self.ctrl.contains(Ctrl::bg_tilemap) {
       false => MAP0,
@@ -445,100 +732,132 @@ impl Ppu {
     }
   }
 
-  fn oam_scan(&mut self) {
-    self.fetcher.obj_visible.clear();
+  /// Checks the OAM entry `i` during mode 2, with the LCDC object size and LY of this dot.
+  fn oam_scan_entry(&mut self, i: u8) {
+    if i == 0 { self.fetcher.obj_visible.clear(); }
+    // the first 10 objects on the line are kept, the others are ignored
+    if self.fetcher.obj_visible.len() >= 10 { return; }
 
-    for i in (0..160).step_by(4) {
-      let y = self.oam[i];
-
-      if self.ly.wrapping_add(16) >= y
-      && self.ly.wrapping_add(16) < y.wrapping_add(self.obj_size())
-      {
-        let obj = OamObject::new(&self.oam[i..i+4], i as u8/4);
-        self.fetcher.obj_visible.push(obj);
-      }
-
-      if self.fetcher.obj_visible.len() >= 10 { break; }
+    let addr = i as usize * 4;
+    let y = self.oam[addr];
+    if self.ly.wrapping_add(16) >= y
+    && self.ly.wrapping_add(16) < y.wrapping_add(self.obj_size())
+    {
+      let obj = OamObject::new(&self.oam[addr..addr+4], i);
+      self.fetcher.obj_visible.push(obj);
     }
+  }
 
-    // we sort them in reverse (lower to higher), so that we always set for last to the scanline the higher priority object
+  fn sort_obj_visible(&mut self) {
+    // we sort them in reverse (lower to higher priority): the dot renderer pops the next object to fetch from the back,
+    // and the scanline renderer draws the higher priority objects last
     self.fetcher.obj_visible.sort_by(|a, b| {
       if a.x == b.x { b.i.cmp(&a.i) } else { b.x.cmp(&a.x) } 
     });
   }
 
-  fn fill_obj_scanline(&mut self) {
-    if !self.is_lcd_enabled() { return; }
-    if !self.ctrl.contains(Ctrl::obj_enabled) { return; }
-    self.fetcher.obj_scanline.fill(None);
-
-    for obj in &self.fetcher.obj_visible {
-      if obj.x == 0 || obj.x >= 168 { continue; }
+  /// The pixels of an object on this line, from left to right.
+  /// Its tile and attributes are read from OAM now, as they may have changed since the OAM scan.
+  fn obj_pixels(&self, obj: &OamObject) -> [ObjFifoEntry; 8] {
+    let i = obj.i as usize * 4;
+    let attrs = OamObject::new(&self.oam[i..i+4], obj.i);
+    let row = self.ly.wrapping_add(16).wrapping_sub(obj.y);
+    
+    // Sprite 8x16 tile handling
+    let tile_id = if self.ctrl.contains(Ctrl::obj_size) {        
+      attrs.tile_id & 0xFE
+    } else { attrs.tile_id };
+
+    // Y flipping (simply reverse the y offset)
+    let y_offset = if attrs.y_flip {
+      row.abs_diff(self.obj_size()-1)
+    } else { row };
+
+    let tileset_addr = VRAM0 
+      + 16*tile_id as u16
+      + 2*y_offset as u16;
+
+    let mut tile_lo = self.vram_read(tileset_addr);
+    let mut tile_hi = self.vram_read(tileset_addr+1);
+
+    // X flipping (reverse the bits, knowing that they are reversed without flipping)
+    if !attrs.x_flip {
+      tile_lo = tile_lo.reverse_bits();
+      tile_hi = tile_hi.reverse_bits();
+    }
 
-      let y = obj.y.saturating_sub(16);
-      let row = self.ly.abs_diff(y);
-      
-      // Sprite 8x16 tile handling
-      let tile_id = if self.ctrl.contains(Ctrl::obj_size) {        
-        obj.tile_id & 0xFE
-      } else { obj.tile_id };
-
-      // Y flipping (simply reverse the y offset)
-      let y_offset = if obj.y_flip {
-        row.abs_diff(self.obj_size()-1)
-      } else { row };
-
-      let tileset_addr = VRAM0 
-        + 16*tile_id as u16
-        + 2*y_offset as u16;
-
-      let mut tile_lo = self.vram_read(tileset_addr);
-      let mut tile_hi = self.vram_read(tileset_addr+1);
-
-      // X flipping (reverse the bits, knowing that they are reversed without flipping)
-      if !obj.x_flip {
-        tile_lo = tile_lo.reverse_bits();
-        tile_hi = tile_hi.reverse_bits();
+    std::array::from_fn(|i| {
+      let pixel_lo = (tile_lo >> i) & 1;
+      let pixel_hi = (tile_hi >> i) & 1;
+      ObjFifoEntry {
+        color: (pixel_hi << 1) | pixel_lo,
+        palette: attrs.dmg_palette,
+        priority: attrs.priority,
+        i: obj.i,
       }
+    })
+  }
 
-      // push each pixel
-      for i in 0..8 {
-        if obj.x + i < 8 || obj.x + i >= 168 { continue; }
-
-        let x = obj.x + i - 8;
-
-        let pixel_lo = (tile_lo >> i) & 1;
-        let pixel_hi = (tile_hi >> i) & 1;
-        let color = (pixel_hi << 1) | pixel_lo;
-        if color == 0 { continue; }
-
-        let data = ObjFifoEntry { 
-          color,
-          palette: obj.dmg_palette,
-          priority: obj.priority
-        };
-
-        self.fetcher.obj_scanline[x as usize] = Some(data);
+  /// The object pixels of the whole line, for the scanline renderer.
+  #[cfg(not(feature = "dot-renderer"))]
+  fn obj_line(&self) -> [ObjFifoEntry; 160] {
+    let mut line = std::array::from_fn(|_| ObjFifoEntry::default());
+    if !self.ctrl.contains(Ctrl::obj_enabled) { return line; }
+
+    // in the order the dot renderer fetches them
+    for obj in self.fetcher.obj_visible.iter().rev() {
+      for (i, pixel) in self.obj_pixels(obj).into_iter().enumerate() {
+        let x = obj.x as usize + i;
+        if !(8..168).contains(&x) { continue; }
+        line[x - 8].merge(pixel, self.obj_index_priority);
       }
     }
+    line
   }
 
+  #[cfg(feature = "dot-renderer")]
   fn fetcher_step(&mut self) {
+    if self.fetcher.delay > 0 {
+      self.fetcher.delay -= 1;
+      return;
+    }
+
+    if self.fetcher.wnd_hit && !self.ctrl.contains(Ctrl::wnd_enabled) {
+      // back to the background, the fetcher tile counter carries on from the window
+      self.fetcher.wnd_hit = false;
+    }
+
+    // the window starts when the output reaches WX exactly, enabling it later on the line does nothing
+    let wnd_reached = if self.wnd_next_line {
+      self.fetcher.pixel_x == 0
+    } else {
+      self.fetcher.pixel_x + 7 == self.wx || (self.fetcher.pixel_x == 0 && self.wx < 7)
+    };
+
     if !self.fetcher.wnd_hit && self.ctrl.contains(Ctrl::wnd_enabled) 
-      && self.fetcher.pixel_x + 7 >= self.wx
-      && self.ly >= self.wy
+      && self.wy_hit && wnd_reached
     {
       self.fetcher.wnd_hit = true;
+      self.fetcher.wnd_drawn = true;
       self.fetcher.x = 0;
       
-      if self.wx < 7 {
+      if !self.wnd_next_line && self.wx < 7 {
         self.fetcher.wnd_scroll_x = 7- self.wx;
+        // with WX=0 the window starts during the SCX fine scroll, and its pixels are thrown away instead
+        if self.wx == 0 { self.fetcher.wnd_scroll_x += self.scx % 8; }
       }
 
-      self.fetcher.bg_fifo.clear();
-      self.fetcher.should_do_step = false;
+      // the fetcher restarts on the window, throwing away the background pixels left:
+      // the window tile fetch starts right away, and takes WND_PENALTY dots
       self.fetcher.state = FetcherState::Tile;
-      return;
+      if self.fetcher.pixel_x == 0 && self.fetcher.bg_fifo.is_empty() {
+        // nothing was fetched yet, the penalty is still paid, counting this dot
+        self.fetcher.delay = WND_PENALTY - 1;
+        self.fetcher.should_do_step = false;
+        return;
+      }
+      self.fetcher.bg_fifo.clear();
+      self.fetcher.should_do_step = true;
     }
 
     if self.fetcher.should_do_step {
@@ -597,16 +916,80 @@ impl Ppu {
     }
     
     self.fetcher.should_do_step = !self.fetcher.should_do_step;
-    self.push_pixel();
+
+    // the background fetcher keeps going while objects are fetched, only the pixel output stops
+    if self.fetcher.stall == 0 { self.fetch_objs(); }
+    if self.fetcher.stall > 0 {
+      self.fetcher.stall -= 1;
+    } else {
+      self.push_pixel();
+    }
   }
 
-  fn push_pixel(&mut self) {
-    if !self.is_lcd_enabled() {
-      self.lcd.set_pixel(self.fetcher.pixel_x as usize, self.ly as usize, self.bg_palette(0));
-      self.fetcher.pixel_x += 1;
-      return;
+  /// Fetches the objects the pixel output has reached into the object FIFO, stopping the output meanwhile.
+  #[cfg(feature = "dot-renderer")]
+  fn fetch_objs(&mut self) {
+    // the fetch waits for the background pixels under the object, the wait is part of its penalty
+    if self.fetcher.bg_fifo.is_empty() { return; }
+
+    // they are sorted by decreasing x, then by decreasing OAM index
+    while self.fetcher.obj_visible.last()
+      .is_some_and(|obj| obj.x <= self.fetcher.pixel_x + 8)
+    {
+      let obj = self.fetcher.obj_visible.pop().unwrap();
+      // the objects reached while they are disabled are never fetched
+      if !self.ctrl.contains(Ctrl::obj_enabled) { continue; }
+
+      let mut last_tile = self.fetcher.obj_tile;
+      self.fetcher.stall += self.obj_penalty(obj.x, self.fetcher.wnd_hit, &mut last_tile);
+      self.fetcher.obj_tile = last_tile;
+
+      // the pixels left of the screen are never shown
+      let skip = (self.fetcher.pixel_x + 8 - obj.x) as usize;
+      for (i, pixel) in self.obj_pixels(&obj).into_iter().skip(skip).enumerate() {
+        match self.fetcher.obj_fifo.get_mut(i) {
+          Some(old) => old.merge(pixel, self.obj_index_priority),
+          None => self.fetcher.obj_fifo.push_back(pixel),
+        }
+      }
     }
+  }
 
+  /// The dots an object at OAM `x` stops mode 3 for: its fetch, and the wait for the background fetch
+  /// of the tile under its leftmost pixel. Only the first object on a tile waits for it.
+  fn obj_penalty(&self, x: u8, in_window: bool, last_tile: &mut Option<u8>) -> u8 {
+    let pos = if in_window {
+      x.wrapping_add(7).wrapping_sub(self.wx)
+    } else { x.wrapping_add(self.scx) };
+
+    let tile = pos / 8;
+    if *last_tile == Some(tile) { return OBJ_PENALTY; }
+    *last_tile = Some(tile);
+    OBJ_PENALTY + 5u8.saturating_sub(pos % 8)
+  }
+
+  /// The dots mode 3 lasts more than [`MODE3_DOTS`] on this line, for the scanline renderer.
+  /// The dot renderer gets the same length from its fetcher.
+  #[cfg(not(feature = "dot-renderer"))]
+  fn mode3_penalties(&self) -> u8 {
+    // the pixels scrolled out of the first tile are still fetched and thrown away
+    let mut dots = self.scx % 8;
+
+    let wnd_start = self.wnd_start().map(|(x, _)| x);
+    if wnd_start.is_some() { dots += WND_PENALTY; }
+
+    if self.ctrl.contains(Ctrl::obj_enabled) {
+      let mut last_tile = None;
+      for obj in self.fetcher.obj_visible.iter().rev().filter(|obj| obj.x < 168) {
+        let in_window = wnd_start.is_some_and(|x| obj.x > x + 7);
+        dots += self.obj_penalty(obj.x, in_window, &mut last_tile);
+      }
+    }
+    dots
+  }
+
+  #[cfg(feature = "dot-renderer")]
+  fn push_pixel(&mut self) {
     // we always have at least 8 pixels ready
     if self.fetcher.bg_fifo.is_empty() { return; }
 
@@ -622,10 +1005,20 @@ impl Ppu {
       return;
     }
 
-    let obj = &self.fetcher.obj_scanline[self.fetcher.pixel_x as usize]
-      .take().unwrap_or_default();
+    let obj = &self.fetcher.obj_fifo.pop_front().unwrap_or_default();
+
+    if self.is_output_enabled() {
+      let color = self.mix_pixel(bg_color, obj, self.fetcher.wnd_hit);
+      self.lcd.set_pixel(self.fetcher.pixel_x as usize, self.ly as usize, color);
+    }
+    self.fetcher.pixel_x += 1;
+  }
+
+  fn mix_pixel(&self, bg_color: u8, obj: &ObjFifoEntry, in_window: bool) -> u8 {
+    let bg_layer = if in_window { Layer::Window } else { Layer::Background };
+    let bg_color = if self.is_layer_enabled(bg_layer) { bg_color } else { 0 };
 
-    let color = if self.ctrl.contains(Ctrl::obj_enabled) 
+    if self.ctrl.contains(Ctrl::obj_enabled) && self.is_layer_enabled(Layer::Objects)
       && obj.color != 0 && (obj.priority || bg_color == 0)
     {
       self.obj_palette(obj.palette, obj.color)
@@ -633,9 +1026,49 @@ impl Ppu {
       self.bg_palette(bg_color)
     } else {
       self.bg_palette(0)
-    };
+    }
+  }
 
-    self.lcd.set_pixel(self.fetcher.pixel_x as usize, self.ly as usize, color);
-    self.fetcher.pixel_x += 1;
+  /// Where the window starts on this line for the scanline renderer, and how many of its pixels are hidden left of it.
+  #[cfg(not(feature = "dot-renderer"))]
+  fn wnd_start(&self) -> Option<(u8, u8)> {
+    if !self.ctrl.contains(Ctrl::wnd_enabled) || !self.wy_hit { return None; }
+
+    match self.wx {
+      _ if self.wnd_next_line => Some((0, 0)),
+      // with WX=0 the window starts during the SCX fine scroll, and its pixels are thrown away instead
+      0 => Some((0, 7 + self.scx % 8)),
+      1..7 => Some((0, 7 - self.wx)),
+      7..167 => Some((self.wx - 7, 0)),
+      _ => None,
+    }
+  }
+
+  // Fast path: the whole scanline is drawn at the start of mode 3,
+  // so mid-scanline register writes are not visible.
+  #[cfg(not(feature = "dot-renderer"))]
+  fn render_scanline(&mut self) {
+    if !self.is_output_enabled() { return; }
+    let objs = self.obj_line();
+    let wnd_start = self.wnd_start();
+    for x in 0..160u8 {
+      let (tilemap, px, py, in_window) = if let Some((start, skip)) = wnd_start.filter(|&(start, _)| x >= start) {
+        (self.wnd_tilemap(), x - start + skip, self.wnd_line, true)
+      } else {
+        (self.bg_tilemap(), x.wrapping_add(self.scx), self.ly.wrapping_add(self.scy), false)
+      };
+
+      let tilemap_id = tilemap + 32 * (py/8) as u16 + (px/8) as u16;
+      let tile_addr = self.tileset_addr(self.vram_read(tilemap_id)) + 2*(py % 8) as u16;
+      let tile_lo = self.vram_read(tile_addr);
+      let tile_hi = self.vram_read(tile_addr+1);
+
+      let bit = 7 - px % 8;
+      let bg_color = (((tile_hi >> bit) & 1) << 1) | ((tile_lo >> bit) & 1);
+      let obj = &objs[x as usize];
+
+      let color = self.mix_pixel(bg_color, obj, in_window);
+      self.lcd.set_pixel(x as usize, self.ly as usize, color);
+    }
   }
 }
\ No newline at end of file
@@ -0,0 +1,143 @@
+//! Python bindings, through PyO3. Build the `tomboy_emulator` extension with `maturin build --features python`.
+//!
+//! ```python
+//! import numpy as np
+//! from tomboy_emulator import Gameboy
+//!
+//! gb = Gameboy()
+//! gb.load_rom(open("tetris.gb", "rb").read())
+//! gb.press("start")
+//! gb.run_frame()
+//! screen = np.frombuffer(gb.screen(), dtype=np.uint8).reshape(gb.height, gb.width, 4)
+//! ```
+
+use pyo3::{exceptions::{PyRuntimeError, PyValueError}, prelude::*, types::PyBytes};
+
+use crate::{cart::LoadOptions, gb::Gameboy, joypad::Button, mem::Memory};
+
+#[pyclass(name = "Gameboy", unsendable)]
+pub struct PyGameboy {
+  gb: Option<Gameboy>,
+}
+
+impl PyGameboy {
+  fn gameboy(&mut self) -> PyResult<&mut Gameboy> {
+    self.gb.as_mut().ok_or_else(|| PyRuntimeError::new_err("no ROM loaded"))
+  }
+}
+
+fn parse_button(name: &str) -> PyResult<Button> {
+  name.parse().map_err(PyValueError::new_err)
+}
+
+#[pymethods]
+impl PyGameboy {
+  #[new]
+  fn new() -> Self {
+    Self { gb: None }
+  }
+
+  /// Boots a ROM, with relaxed header checks.
+  fn load_rom(&mut self, rom: &[u8]) -> PyResult<()> {
+    let gb = Gameboy::boot_with_options(rom, &LoadOptions::tolerant())
+      .map_err(|e| PyValueError::new_err(e.to_string()))?;
+    self.gb = Some(gb);
+    Ok(())
+  }
+
+  fn run_frame(&mut self) -> PyResult<()> {
+    self.gameboy()?.step_until_vblank();
+    Ok(())
+  }
+
+  fn reset(&mut self) -> PyResult<()> {
+    self.gameboy()?.reset();
+    Ok(())
+  }
+
+  /// The RGBA screen, `height` rows of `width` pixels.
+  fn screen<'py>(&mut self, py: Python<'py>) -> PyResult<Bound<'py, PyBytes>> {
+    Ok(PyBytes::new(py, &self.gameboy()?.get_screen().buffer))
+  }
+
+  #[getter]
+  fn width(&mut self) -> PyResult<usize> {
+    Ok(self.gameboy()?.get_resolution().0)
+  }
+
+  #[getter]
+  fn height(&mut self) -> PyResult<usize> {
+    Ok(self.gameboy()?.get_resolution().1)
+  }
+
+  #[getter]
+  fn frame_count(&mut self) -> PyResult<usize> {
+    Ok(self.gameboy()?.total_frames())
+  }
+
+  /// Presses a button by name: up, down, left, right, a, b, start, select.
+  fn press(&mut self, button: &str) -> PyResult<()> {
+    let button = parse_button(button)?;
+    self.gameboy()?.get_joypad().press(button);
+    Ok(())
+  }
+
+  fn release(&mut self, button: &str) -> PyResult<()> {
+    let button = parse_button(button)?;
+    self.gameboy()?.get_joypad().release(button);
+    Ok(())
+  }
+
+  /// Reads memory without side effects.
+  fn peek(&mut self, addr: u16) -> PyResult<u8> {
+    Ok(self.gameboy()?.get_bus().peek(addr))
+  }
+
+  /// Writes memory as the CPU would.
+  fn poke(&mut self, addr: u16, val: u8) -> PyResult<()> {
+    self.gameboy()?.get_bus().write(addr, val);
+    Ok(())
+  }
+}
+
+#[pymodule]
+fn tomboy_emulator(m: &Bound<'_, PyModule>) -> PyResult<()> {
+  m.add_class::<PyGameboy>()
+}
+
+#[cfg(test)]
+mod python_tests {
+  use super::*;
+  use crate::testing::{asm, RomBuilder};
+
+  #[test]
+  fn bindings() {
+    let rom = RomBuilder::new().code(&asm!["LD A, $42", "LD ($C000), A", "loop:", "JR loop"]).build();
+    let mut gb = PyGameboy::new();
+    assert!(gb.run_frame().is_err());
+    assert!(gb.peek(0xC000).is_err());
+    assert!(gb.load_rom(&rom[..0x100]).is_err());
+    gb.load_rom(&rom).unwrap();
+
+    for _ in 0..3 {
+      gb.run_frame().unwrap();
+    }
+    assert_eq!(gb.frame_count().unwrap(), 3);
+    assert_eq!((gb.width().unwrap(), gb.height().unwrap()), (160, 144));
+    assert_eq!(gb.peek(0xC000).unwrap(), 0x42);
+    gb.poke(0xC001, 0x24).unwrap();
+    assert_eq!(gb.peek(0xC001).unwrap(), 0x24);
+
+    gb.press("start").unwrap();
+    assert_eq!(gb.gameboy().unwrap().get_joypad().state().0, crate::joypad::Flags::start_down);
+    gb.release("start").unwrap();
+    assert!(gb.press("jump").is_err());
+
+    gb.reset().unwrap();
+    gb.run_frame().unwrap();
+    assert_eq!(gb.peek(0xC000).unwrap(), 0x42);
+
+    Python::initialize();
+    Python::attach(|py| assert_eq!(gb.screen(py).unwrap().as_bytes().len(), 160 * 144 * 4));
+  }
+}
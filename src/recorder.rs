@@ -0,0 +1,275 @@
+//! Video recording of the screen and audio.
+//!
+//! A [`Recorder`] takes every emulated frame with its audio. Frames are written by a background thread:
+//! - to `.y4m` files as raw video, with the audio in a `.wav` file next to it;
+//! - to any other file through an `ffmpeg` child process, which picks the format from the extension.
+//!   The audio is muxed in with a second `ffmpeg` run, once the recording is finished.
+//!
+//! The video has a constant frame rate, the Gameboy one of about 59.73 fps, so it stays in sync with the audio.
+//! When a frame isn't drawn, or the writer thread can't keep up, the previous frame is repeated in its place.
+//! ```no_run
+//! # use tomboy_emulator::{gb::Gameboy, recorder::Recorder};
+//! # let mut gb = Gameboy::boot_from_bytes(&[]).unwrap();
+//! let mut recorder = Recorder::start("run.mp4", 160, 144, 44100)?;
+//! for _ in 0..600 {
+//!   gb.step_until_vblank();
+//!   let audio = gb.get_samples();
+//!   recorder.push_frame(Some(gb.get_screen()), &audio)?;
+//! }
+//! recorder.finish()?;
+//! # Ok::<(), std::io::Error>(())
+//! ```
+
+use std::{fs::{self, File}, io::{self, BufWriter, Write}, path::{Path, PathBuf}, process::{Child, Command, Stdio}, sync::mpsc::{self, Receiver, SyncSender, TrySendError}, thread::JoinHandle};
+
+use crate::{apu::wav::WavWriter, frame::FrameBuffer};
+
+/// The frame rate, as a fraction: the CPU clock over the cycles in a frame.
+const FRAME_RATE: (u32, u32) = (4194304, 70224);
+/// How many frames can wait for the writer thread, before they are dropped.
+const QUEUE_LEN: usize = 16;
+
+/// A frame as RGB24, preceded by `repeat` copies of the previous one, with the audio of all of them.
+/// The frame is empty when only repeats are sent, at the end.
+struct Chunk {
+  rgb: Vec<u8>,
+  repeat: u32,
+  audio: Vec<f32>,
+}
+
+enum Video {
+  Y4m(BufWriter<File>),
+  Ffmpeg { child: Child, video: PathBuf },
+}
+
+/// Records frames and audio to a video file, see the [module](self) docs.
+pub struct Recorder {
+  sender: Option<SyncSender<Chunk>>,
+  writer: Option<JoinHandle<io::Result<()>>>,
+  width: usize,
+  height: usize,
+  /// The last frame recorded, repeated in place of the frames which weren't drawn
+  last: Vec<u8>,
+  /// How many times the previous frame sent is repeated before the next one, and their audio
+  repeat: u32,
+  audio: Vec<f32>,
+  dropped: u64,
+}
+
+impl Recorder {
+  /// Starts recording `width`x`height` frames and stereo `sample_rate` Hz audio to `path`.
+  pub fn start(path: impl AsRef<Path>, width: usize, height: usize, sample_rate: u32) -> io::Result<Self> {
+    let path = path.as_ref().to_path_buf();
+    let wav_path = path.with_extension("wav");
+    let wav = WavWriter::new(BufWriter::new(File::create(&wav_path)?), sample_rate)?;
+
+    let video = if path.extension().is_some_and(|ext| ext == "y4m") {
+      let mut out = BufWriter::new(File::create(&path)?);
+      let (num, den) = FRAME_RATE;
+      writeln!(out, "YUV4MPEG2 W{width} H{height} F{num}:{den} Ip A1:1 C444")?;
+      Video::Y4m(out)
+    } else {
+      let ext = path.extension().and_then(|ext| ext.to_str()).unwrap_or("mp4");
+      let video = path.with_extension(format!("video.{ext}"));
+      let child = Command::new("ffmpeg")
+        .args(["-y", "-loglevel", "error", "-f", "rawvideo", "-pixel_format", "rgb24"])
+        .args(["-video_size", &format!("{width}x{height}")])
+        .args(["-framerate", &format!("{}/{}", FRAME_RATE.0, FRAME_RATE.1)])
+        .args(["-i", "-"])
+        // the pixel format most players support, scaled up so they don't blur the pixels
+        .args(["-pix_fmt", "yuv420p", "-vf", "scale=iw*4:ih*4:flags=neighbor"])
+        .arg(&video)
+        .stdin(Stdio::piped())
+        .spawn()?;
+      Video::Ffmpeg { child, video }
+    };
+
+    let (sender, receiver) = mpsc::sync_channel(QUEUE_LEN);
+    let writer = std::thread::spawn(move || write_chunks(receiver, video, wav, path, wav_path));
+
+    Ok(Self {
+      sender: Some(sender),
+      writer: Some(writer),
+      width,
+      height,
+      last: vec![0; width * height * 3],
+      repeat: 0,
+      audio: Vec::new(),
+      dropped: 0,
+    })
+  }
+
+  /// Records an emulated frame and its audio. `frame` is `None` when it wasn't drawn, and the previous one is shown again.
+  /// Fails if writing failed, the recording is then finished.
+  pub fn push_frame(&mut self, frame: Option<&FrameBuffer>, audio: &[f32]) -> io::Result<()> {
+    self.audio.extend_from_slice(audio);
+    let Some(frame) = frame else {
+      self.repeat += 1;
+      return Ok(());
+    };
+
+    if frame.width != self.width || frame.height != self.height {
+      return Err(io::Error::new(io::ErrorKind::InvalidInput, "the frame size changed while recording"));
+    }
+    for y in 0..self.height {
+      for x in 0..self.width {
+        let (r, g, b) = frame.get_color(x, y);
+        let i = (y*self.width + x) * 3;
+        self.last[i..i+3].copy_from_slice(&[r, g, b]);
+      }
+    }
+
+    let Some(sender) = &self.sender else { return Ok(()) };
+    let chunk = Chunk { rgb: self.last.clone(), repeat: self.repeat, audio: std::mem::take(&mut self.audio) };
+    match sender.try_send(chunk) {
+      Ok(()) => {
+        self.repeat = 0;
+        Ok(())
+      }
+      Err(TrySendError::Full(chunk)) => {
+        // the previous frame sent is repeated instead, the audio goes with the next one
+        self.dropped += 1;
+        self.repeat += 1;
+        self.audio = chunk.audio;
+        Ok(())
+      }
+      Err(TrySendError::Disconnected(_)) => {
+        self.sender = None;
+        self.join().and(Err(io::Error::other("the recording stopped")))
+      }
+    }
+  }
+
+  /// Frames which were replaced by the previous one, because the writer couldn't keep up.
+  pub fn dropped_frames(&self) -> u64 {
+    self.dropped
+  }
+
+  fn join(&mut self) -> io::Result<()> {
+    match self.writer.take().map(JoinHandle::join) {
+      Some(Ok(res)) => res,
+      Some(Err(_)) => Err(io::Error::other("the recording thread panicked")),
+      None => Ok(()),
+    }
+  }
+
+  /// Writes the frames left, and finalizes the files.
+  pub fn finish(mut self) -> io::Result<()> {
+    if let Some(sender) = self.sender.take() {
+      let chunk = Chunk { rgb: Vec::new(), repeat: self.repeat, audio: std::mem::take(&mut self.audio) };
+      // blocks until the writer has room, nothing is dropped at the end
+      let _ = sender.send(chunk);
+    }
+    self.join()
+  }
+}
+
+impl Drop for Recorder {
+  fn drop(&mut self) {
+    self.sender = None;
+    let _ = self.join();
+  }
+}
+
+fn write_chunks(receiver: Receiver<Chunk>, mut video: Video, mut wav: WavWriter<BufWriter<File>>, path: PathBuf, wav_path: PathBuf) -> io::Result<()> {
+  let mut last = Vec::new();
+  for chunk in receiver {
+    // nothing was recorded yet to repeat
+    if !last.is_empty() {
+      for _ in 0..chunk.repeat {
+        write_frame(&mut video, &last)?;
+      }
+    }
+    if !chunk.rgb.is_empty() {
+      last = chunk.rgb;
+      write_frame(&mut video, &last)?;
+    }
+    for pair in chunk.audio.chunks_exact(2) {
+      wav.write(pair[0], pair[1])?;
+    }
+  }
+  wav.finish()?;
+
+  match video {
+    Video::Y4m(mut out) => out.flush(),
+    Video::Ffmpeg { mut child, video } => {
+      drop(child.stdin.take());
+      if !child.wait()?.success() {
+        return Err(io::Error::other("ffmpeg failed to encode the video"));
+      }
+      let status = Command::new("ffmpeg")
+        .args(["-y", "-loglevel", "error", "-i"]).arg(&video)
+        .arg("-i").arg(&wav_path)
+        .args(["-c:v", "copy", "-shortest"])
+        .arg(&path)
+        .status()?;
+      if !status.success() {
+        return Err(io::Error::other("ffmpeg failed to mux the audio"));
+      }
+      fs::remove_file(video)?;
+      fs::remove_file(wav_path)
+    }
+  }
+}
+
+fn write_frame(video: &mut Video, rgb: &[u8]) -> io::Result<()> {
+  match video {
+    Video::Y4m(out) => {
+      out.write_all(b"FRAME\n")?;
+      // full resolution chroma (C444), in the limited range of BT.601
+      let planes = [
+        |r: f32, g: f32, b: f32| 16.0 + 0.257*r + 0.504*g + 0.098*b,
+        |r: f32, g: f32, b: f32| 128.0 - 0.148*r - 0.291*g + 0.439*b,
+        |r: f32, g: f32, b: f32| 128.0 + 0.439*r - 0.368*g - 0.071*b,
+      ];
+      for plane in planes {
+        let bytes: Vec<u8> = rgb.chunks_exact(3)
+          .map(|p| plane(p[0] as f32, p[1] as f32, p[2] as f32).round() as u8)
+          .collect();
+        out.write_all(&bytes)?;
+      }
+      Ok(())
+    }
+    Video::Ffmpeg { child, .. } => match child.stdin.as_mut() {
+      Some(stdin) => stdin.write_all(rgb),
+      None => Err(io::Error::other("ffmpeg closed its input")),
+    },
+  }
+}
+
+#[cfg(test)]
+mod recorder_tests {
+  use super::*;
+
+  #[test]
+  fn y4m_repeats_skipped_frames() {
+    let dir = std::env::temp_dir().join(format!("tomboy-recorder-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("run.y4m");
+
+    let mut fb = FrameBuffer::new(4, 2);
+    fb.set_palette(crate::frame::GRAYSCALE);
+    fb.reset();
+    let mut recorder = Recorder::start(&path, 4, 2, 48000).unwrap();
+    recorder.push_frame(Some(&fb), &[0.5, -0.5]).unwrap();
+    recorder.push_frame(None, &[0.5, -0.5]).unwrap();
+    fb.set_pixel(0, 0, 3);
+    recorder.push_frame(Some(&fb), &[0.0, 0.0]).unwrap();
+    recorder.finish().unwrap();
+
+    let video = fs::read(&path).unwrap();
+    let header = b"YUV4MPEG2 W4 H2 F4194304:70224 Ip A1:1 C444\n";
+    assert!(video.starts_with(header));
+    let frame_len = b"FRAME\n".len() + 4*2*3;
+    assert_eq!(video.len(), header.len() + 3*frame_len);
+    let frame = |n: usize| &video[header.len() + n*frame_len..][..frame_len];
+    assert_eq!(frame(0), frame(1));
+    // white is 235 in the limited range, black 16
+    assert_eq!(frame(0)[6], 235);
+    assert_eq!(frame(2)[6], 16);
+
+    let wav = fs::read(dir.join("run.wav")).unwrap();
+    assert_eq!(wav.len(), 44 + 3*4);
+    fs::remove_dir_all(dir).unwrap();
+  }
+}
@@ -0,0 +1,119 @@
+//! Reads ROM files, transparently extracting `.zip` and `.gz` archives.
+
+use std::{fs, io::{Cursor, Read}, path::Path};
+
+use crate::{cart::MAX_ROM_SIZE, patch, Error};
+
+const ZIP_MAGIC: [u8; 4] = [0x50, 0x4B, 0x03, 0x04];
+const GZIP_MAGIC: [u8; 2] = [0x1F, 0x8B];
+
+pub fn load_file(path: impl AsRef<Path>) -> Result<Vec<u8>, Error> {
+  let bytes = fs::read(path)?;
+  extract(bytes)
+}
+
+/// Like [`load_file`], applying the `.ips` or `.bps` patch next to the ROM, if there's one.
+pub fn load_patched(path: impl AsRef<Path>) -> Result<Vec<u8>, Error> {
+  let path = path.as_ref();
+  let rom = load_file(path)?;
+  for ext in ["ips", "bps"] {
+    let patch_path = path.with_extension(ext);
+    if patch_path.is_file() {
+      return patch::apply(&rom, &fs::read(patch_path)?);
+    }
+  }
+  Ok(rom)
+}
+
+/// Returns the ROM inside an archive, or the bytes untouched if they aren't one.
+/// Archives are detected by their magic number, not their extension.
+/// From a zip, the first `.gb`/`.gbc` entry is taken.
+pub fn extract(bytes: Vec<u8>) -> Result<Vec<u8>, Error> {
+  if bytes.starts_with(&ZIP_MAGIC) {
+    extract_zip(&bytes)
+  } else if bytes.starts_with(&GZIP_MAGIC) {
+    read_rom(flate2::read::GzDecoder::new(bytes.as_slice()), 0)
+  } else {
+    Ok(bytes)
+  }
+}
+
+/// Reads a decompressed ROM, refusing anything bigger than [`MAX_ROM_SIZE`]:
+/// a small archive can expand to gigabytes.
+fn read_rom(reader: impl Read, size_hint: usize) -> Result<Vec<u8>, Error> {
+  let mut rom = Vec::with_capacity(size_hint.min(MAX_ROM_SIZE));
+  reader.take(MAX_ROM_SIZE as u64 + 1).read_to_end(&mut rom)?;
+  if rom.len() > MAX_ROM_SIZE {
+    return Err(Error::Archive("the ROM is bigger than 8 MiB".to_string()));
+  }
+  Ok(rom)
+}
+
+fn is_rom_name(name: &str) -> bool {
+  let name = name.to_ascii_lowercase();
+  name.ends_with(".gb") || name.ends_with(".gbc")
+}
+
+fn extract_zip(bytes: &[u8]) -> Result<Vec<u8>, Error> {
+  let archive_err = |e: zip::result::ZipError| Error::Archive(e.to_string());
+  let mut zip = zip::ZipArchive::new(Cursor::new(bytes)).map_err(archive_err)?;
+
+  for i in 0..zip.len() {
+    let file = zip.by_index(i).map_err(archive_err)?;
+    if file.is_file() && file.name().is_ok_and(|name| is_rom_name(&name)) {
+      let size = file.size() as usize;
+      return read_rom(file, size);
+    }
+  }
+
+  Err(Error::Archive("no .gb or .gbc file in the archive".to_string()))
+}
+
+#[cfg(test)]
+mod rom_loader_tests {
+  use std::io::Write;
+
+  use super::*;
+  use crate::testing::RomBuilder;
+
+  #[test]
+  fn extract_archives() {
+    let rom = RomBuilder::new().build();
+    assert_eq!(extract(rom.clone()).unwrap(), rom);
+
+    let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    gz.write_all(&rom).unwrap();
+    assert_eq!(extract(gz.finish().unwrap()).unwrap(), rom);
+
+    let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+    let opts = zip::write::SimpleFileOptions::default();
+    zip.start_file("README.txt", opts).unwrap();
+    zip.write_all(b"not a rom").unwrap();
+    zip.start_file("Game.GB", opts).unwrap();
+    zip.write_all(&rom).unwrap();
+    let zip = zip.finish().unwrap().into_inner();
+    assert_eq!(extract(zip).unwrap(), rom);
+
+    let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+    zip.start_file("README.txt", opts).unwrap();
+    let zip = zip.finish().unwrap().into_inner();
+    assert!(matches!(extract(zip), Err(Error::Archive(_))));
+  }
+
+  #[test]
+  fn archive_bombs() {
+    let big = vec![0; MAX_ROM_SIZE + 1];
+    let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    gz.write_all(&big).unwrap();
+    assert!(matches!(extract(gz.finish().unwrap()), Err(Error::Archive(_))));
+
+    let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+    zip.start_file("bomb.gb", zip::write::SimpleFileOptions::default()).unwrap();
+    zip.write_all(&big).unwrap();
+    assert!(matches!(extract(zip.finish().unwrap().into_inner()), Err(Error::Archive(_))));
+
+    let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    gz.write_all(&big[1..]).unwrap();
+    assert_eq!(extract(gz.finish().unwrap()).unwrap().len(), MAX_ROM_SIZE);
+  }
+}
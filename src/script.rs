@@ -0,0 +1,212 @@
+//! Rhai scripts run at every frame, for bots, HUDs and auto-splitters.
+//!
+//! A script defines an `on_frame()` function, called by [`Script::on_frame`] after each frame.
+//! Its top level statements run once, when it is loaded. Functions can't see the top level variables,
+//! keep the state across frames in the `this` object map instead:
+//! ```rhai
+//! fn on_frame() {
+//!   let lives = read(0xC0A0);
+//!   if lives < (this.lives ?? lives) { print(`lost a life at frame ${frame()}`); }
+//!   this.lives = lives;
+//!   text(1, 1, `LIVES ${lives}`);
+//!   if frame() % 2 == 0 { press("a"); } else { release("a"); }
+//! }
+//! ```
+//! The functions available to scripts are:
+//! - `read(addr)`, `read16(addr)`: reads memory as it was at the end of the frame, plus the script's own writes.
+//! - `write(addr, val)`: writes memory as the CPU would.
+//! - `press(button)`, `release(button)`: holds or releases a button, by name (`"a"`, `"start"`, `"up"`...).
+//! - `text(x, y, string)`: draws text over the screen, until the next frame is drawn. Glyphs are 4x6 pixels.
+//! - `message(string)`: shows a message on the [`osd`](crate::osd), which fades out after a few seconds.
+//! - `frame()`: the frames completed since boot or the last reset.
+//!
+//! Writes, inputs and text are applied once `on_frame` returns.
+
+use std::{cell::RefCell, path::Path, rc::Rc};
+
+use rhai::{CallFnOptions, Dynamic, Engine, EvalAltResult, Map, Scope, AST};
+
+use crate::{frame::FrameBuffer, gb::Gameboy, joypad::Button, mem::Memory, Error};
+
+/// What the script did during a call, applied to the emulator afterwards.
+#[derive(Default)]
+struct Host {
+  mem: Vec<u8>,
+  frame: usize,
+  writes: Vec<(u16, u8)>,
+  inputs: Vec<(Button, bool)>,
+  texts: Vec<(i32, i32, String)>,
+  messages: Vec<String>,
+}
+
+pub struct Script {
+  engine: Engine,
+  ast: AST,
+  scope: Scope<'static>,
+  this: Dynamic,
+  host: Rc<RefCell<Host>>,
+}
+
+fn script_error(e: impl ToString) -> Error {
+  Error::Script(e.to_string())
+}
+
+impl Script {
+  /// Compiles a script, and runs its top level statements.
+  pub fn new(source: &str) -> Result<Self, Error> {
+    let host = Rc::new(RefCell::new(Host::default()));
+    let mut engine = Engine::new();
+    register_api(&mut engine, &host);
+
+    let ast = engine.compile(source).map_err(script_error)?;
+    let mut scope = Scope::new();
+    engine.run_ast_with_scope(&mut scope, &ast).map_err(script_error)?;
+
+    Ok(Self { engine, ast, scope, this: Dynamic::from_map(Map::new()), host })
+  }
+
+  pub fn load(path: impl AsRef<Path>) -> Result<Self, Error> {
+    Self::new(&std::fs::read_to_string(path)?)
+  }
+
+  /// Calls the script's `on_frame` function, if it has one, and applies what it did.
+  /// Call it after [`Gameboy::step_until_vblank`], so the text is drawn over the finished frame.
+  pub fn on_frame(&mut self, gb: &mut Gameboy) -> Result<(), Error> {
+    let has_callback = self.ast.iter_functions().any(|f| f.name == "on_frame" && f.params.is_empty());
+    if !has_callback { return Ok(()); }
+
+    {
+      let mut host = self.host.borrow_mut();
+      *host = Host::default();
+      host.mem = gb.get_bus().peek_range(0..=0xFFFF);
+      host.frame = gb.total_frames();
+    }
+
+    let options = CallFnOptions::new().eval_ast(false).bind_this_ptr(&mut self.this);
+    let res = self.engine
+      .call_fn_with_options::<Dynamic>(options, &mut self.scope, &self.ast, "on_frame", ());
+
+    // what was done before an error still applies
+    let host = std::mem::take(&mut *self.host.borrow_mut());
+    for (addr, val) in host.writes {
+      gb.get_bus().write(addr, val);
+    }
+    for (button, pressed) in host.inputs {
+      match pressed {
+        true => gb.get_joypad().press(button),
+        false => gb.get_joypad().release(button),
+      }
+    }
+    for text in host.messages {
+      gb.get_osd().message(text);
+    }
+    let lcd = &mut gb.get_ppu().lcd;
+    for (x, y, text) in host.texts {
+      draw_overlay(lcd, x, y, &text);
+    }
+
+    res.map(|_| ()).map_err(script_error)
+  }
+}
+
+/// Draws in the darkest shade over the lightest, so the text stays readable over the game.
+fn draw_overlay(lcd: &mut FrameBuffer, x: i32, y: i32, text: &str) {
+  let (fg, bg) = (lcd.color(3), lcd.color(0));
+  lcd.draw_text(x, y, text, fg, Some(bg));
+}
+
+fn register_api(engine: &mut Engine, host: &Rc<RefCell<Host>>) {
+  let addr = |addr: i64| -> Result<u16, Box<EvalAltResult>> {
+    u16::try_from(addr).map_err(|_| format!("address out of range: {addr}").into())
+  };
+
+  let h = host.clone();
+  engine.register_fn("read", move |a: i64| -> Result<i64, Box<EvalAltResult>> {
+    Ok(h.borrow().mem[addr(a)? as usize] as i64)
+  });
+
+  let h = host.clone();
+  engine.register_fn("read16", move |a: i64| -> Result<i64, Box<EvalAltResult>> {
+    let a = addr(a)?;
+    let h = h.borrow();
+    let bytes = [h.mem[a as usize], h.mem[a.wrapping_add(1) as usize]];
+    Ok(u16::from_le_bytes(bytes) as i64)
+  });
+
+  let h = host.clone();
+  engine.register_fn("write", move |a: i64, val: i64| -> Result<(), Box<EvalAltResult>> {
+    let a = addr(a)?;
+    let mut h = h.borrow_mut();
+    h.mem[a as usize] = val as u8;
+    h.writes.push((a, val as u8));
+    Ok(())
+  });
+
+  for (name, pressed) in [("press", true), ("release", false)] {
+    let h = host.clone();
+    engine.register_fn(name, move |button: &str| -> Result<(), Box<EvalAltResult>> {
+      let button = button.parse::<Button>()?;
+      h.borrow_mut().inputs.push((button, pressed));
+      Ok(())
+    });
+  }
+
+  let h = host.clone();
+  engine.register_fn("text", move |x: i64, y: i64, text: &str| {
+    h.borrow_mut().texts.push((x as i32, y as i32, text.to_string()));
+  });
+
+  let h = host.clone();
+  engine.register_fn("message", move |text: &str| h.borrow_mut().messages.push(text.to_string()));
+
+  let h = host.clone();
+  engine.register_fn("frame", move || h.borrow().frame as i64);
+}
+
+#[cfg(test)]
+mod script_tests {
+  use super::*;
+  use crate::testing::{asm, RomBuilder};
+
+  #[test]
+  fn callbacks() {
+    let rom = RomBuilder::new().code(&asm!["loop:", "JR loop"]).build();
+    let mut gb = Gameboy::boot_from_bytes(&rom).unwrap();
+
+    let mut script = Script::new(r#"
+      fn on_frame() {
+        this.calls = (this.calls ?? 0) + 1;
+        write(0xC000, this.calls);
+        write(0xC001, read(0xC000) + 1);
+        if this.calls == 2 { press("Start"); }
+        text(0, 0, "HI");
+        if this.calls == 1 { message("STARTED"); }
+      }
+    "#).unwrap();
+
+    for _ in 0..2 {
+      gb.step_until_vblank();
+      script.on_frame(&mut gb).unwrap();
+    }
+
+    let bus = gb.get_bus();
+    assert_eq!(bus.peek(0xC000), 2);
+    assert_eq!(bus.peek(0xC001), 3);
+    assert_eq!(gb.get_joypad().state().0, crate::joypad::Flags::start_down);
+    assert!(!gb.get_osd().is_empty());
+
+    // the top left pixel of the H is lit, the next one isn't
+    let lcd = gb.get_screen();
+    let (dark, light) = (lcd.color(3), lcd.color(0));
+    let pixel = |x: usize| {
+      let px = &lcd.buffer[x*4..x*4 + 3];
+      (px[0], px[1], px[2])
+    };
+    assert_eq!(pixel(0), dark);
+    assert_eq!(pixel(1), light);
+
+    assert!(matches!(Script::new("fn on_frame( {"), Err(Error::Script(_))));
+    let mut failing = Script::new(r#"fn on_frame() { press("turbo"); }"#).unwrap();
+    assert!(matches!(failing.on_frame(&mut gb), Err(Error::Script(_))));
+  }
+}
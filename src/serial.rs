@@ -1,9 +1,13 @@
 use bitflags::bitflags;
+use serde::{Deserialize, Serialize};
 
-use crate::bus::InterruptFlags;
+use crate::bus::IFlags;
+
+// internal clock is 8192 Hz, 128 M-cycles per bit
+const TRANSFER_MCYCLES: u16 = 8 * 128;
 
 bitflags! {
-  #[derive(Clone, Copy)]
+  #[derive(Clone, Copy, Serialize, Deserialize)]
   struct Flags: u8 {
     const master  = 0b0000_0001;
     const speed   = 0b0000_0010;
@@ -12,25 +16,37 @@ bitflags! {
   }
 }
 
+#[derive(Serialize, Deserialize)]
 pub struct Serial {
-  dummy: u8,
+  data: u8,
   flags: Flags,
-  #[allow(unused)]
-  intf: InterruptFlags
+  /// Interrupts raised since IF was last written, see [`Bus::intf`](crate::bus::Bus::intf)
+  #[serde(default)]
+  pub(crate) intf: IFlags,
+  transfer_cycles: u16,
+  sent: Option<u8>,
+}
+
+impl Default for Serial {
+  fn default() -> Self {
+    Self::new()
+  }
 }
 
 impl Serial {
-  pub fn new(intf: InterruptFlags) -> Self {    
+  pub fn new() -> Self {
     Self {
-      dummy: 0xFF,
+      data: 0xFF,
       flags: Flags::empty(),
-      intf,
+      intf: IFlags::empty(),
+      transfer_cycles: 0,
+      sent: None,
     }
   }
 
-  pub fn read(&mut self, addr: u16) -> u8 {
+  pub fn read(&self, addr: u16) -> u8 {
     match addr {
-      0xFF01 => self.dummy,
+      0xFF01 => self.data,
       0xFF02 => (self.flags | Flags::unused).bits(),
       _ => unreachable!()
     }
@@ -38,9 +54,33 @@ impl Serial {
 
   pub fn write(&mut self, addr: u16, val: u8) {
     match addr {
-      0xFF01 => self.dummy = val,
-      0xFF02 => self.flags = Flags::from_bits_retain(val),
+      0xFF01 => self.data = val,
+      0xFF02 => {
+        self.flags = Flags::from_bits_retain(val);
+        if self.flags.contains(Flags::enabled | Flags::master) {
+          self.transfer_cycles = TRANSFER_MCYCLES;
+        }
+      }
       _ => {}
     }
   }
+
+  /// Clocks an internally clocked transfer. Nothing is plugged in the link port,
+  /// so the received byte is always 0xFF.
+  pub fn tick(&mut self) {
+    if self.transfer_cycles == 0 { return; }
+
+    self.transfer_cycles -= 1;
+    if self.transfer_cycles == 0 {
+      self.sent = Some(self.data);
+      self.data = 0xFF;
+      self.flags.remove(Flags::enabled);
+      self.intf.insert(IFlags::serial);
+    }
+  }
+
+  /// Takes the last byte shifted out, if a transfer completed since the last call.
+  pub fn take_sent(&mut self) -> Option<u8> {
+    self.sent.take()
+  }
 }
\ No newline at end of file
@@ -0,0 +1,503 @@
+//! Super Game Boy: the command packets games send through the joypad port, and the border and palettes they set.
+//!
+//! Packets are 16 bytes sent one bit per P1 write, after a reset pulse. The bigger data (palettes, border tiles)
+//! is sent by showing it on screen, as 256 tiles in a row, after a `*_TRN` command.
+
+use serde::{Deserialize, Serialize};
+
+use crate::{frame::{Color, FrameBuffer, Palette, PixelFormat, LCD_HEIGHT, LCD_WIDTH}, joypad::Joypad};
+
+/// Size of the [`Sgb::screen`], the border with the game in the middle.
+pub const SGB_WIDTH: usize = 256;
+pub const SGB_HEIGHT: usize = 224;
+/// Where the game area is in the border.
+const GAME_X: usize = 48;
+const GAME_Y: usize = 40;
+
+/// Game area size in 8x8 tiles, the unit of the palette attributes.
+const ATTR_WIDTH: usize = LCD_WIDTH / 8;
+const ATTR_HEIGHT: usize = LCD_HEIGHT / 8;
+/// Size of an attribute file, sent with ATTR_TRN: 2 bits per tile.
+const ATTR_FILE_SIZE: usize = ATTR_WIDTH * ATTR_HEIGHT / 4;
+const ATTR_FILES: usize = 45;
+
+/// Bytes sent by a VRAM transfer, 256 2bpp tiles.
+const TRANSFER_SIZE: usize = 4096;
+/// Frames completed after a `*_TRN` command before the screen is read.
+/// The one being drawn when the command is sent may not show the data yet.
+const TRANSFER_DELAY: u8 = 2;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+enum Mask {
+  #[default]
+  None,
+  /// The game area keeps the last frame
+  Freeze,
+  Black,
+  /// The game area is filled with color 0
+  Color0,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+enum Transfer {
+  /// PAL_TRN: the 512 system palettes
+  Palettes,
+  /// CHR_TRN: half of the border tiles, the upper half if set
+  Tiles(bool),
+  /// PCT_TRN: the border tilemap and palettes
+  Border,
+  /// ATTR_TRN: the attribute files
+  Attributes,
+}
+
+/// Receives the bits of a packet from the P1 writes.
+#[derive(Default, Serialize, Deserialize)]
+struct PacketReader {
+  /// P14 and P15 of the last write
+  last: u8,
+  receiving: bool,
+  bits: usize,
+  packet: [u8; 16],
+  /// The packets received so far of the current command
+  command: Vec<u8>,
+}
+
+impl PacketReader {
+  /// Returns a whole command once all its packets are received.
+  fn write(&mut self, val: u8) -> Option<Vec<u8>> {
+    let lines = (val >> 4) & 0b11;
+    let last = std::mem::replace(&mut self.last, lines);
+
+    // a reset pulse starts a packet
+    if lines == 0b00 {
+      self.receiving = true;
+      self.bits = 0;
+      self.packet = [0; 16];
+      return None;
+    }
+    // a bit is sent by pulling one line low, then both go high again
+    if !self.receiving || last != 0b11 || lines == 0b11 { return None; }
+
+    let bit = lines == 0b01;
+    if self.bits < 128 {
+      if bit { self.packet[self.bits / 8] |= 1 << (self.bits % 8); }
+      self.bits += 1;
+      return None;
+    }
+
+    // the stop bit
+    self.receiving = false;
+    self.command.extend_from_slice(&self.packet);
+    let packets = (self.command[0] & 0b111).max(1) as usize;
+    (self.command.len() >= packets * 16).then(|| std::mem::take(&mut self.command))
+  }
+}
+
+/// Converts a SNES color, 5 bits per channel from red in the lsb.
+fn snes_color(lo: u8, hi: u8) -> Color {
+  let color = u16::from_le_bytes([lo, hi]);
+  let expand = |v: u16| ((v << 3) | (v >> 2)) as u8;
+  (expand(color & 0x1F), expand((color >> 5) & 0x1F), expand((color >> 10) & 0x1F))
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Sgb {
+  reader: PacketReader,
+  /// The 4 palettes of the game area. Color 0 is the same for all of them.
+  palettes: [Palette; 4],
+  /// The 512 palettes PAL_SET picks from, 4 SNES colors each, sent with PAL_TRN
+  system_palettes: Vec<u8>,
+  /// The palette of every tile of the game area
+  attrs: Vec<u8>,
+  /// Sent with ATTR_TRN, loaded in `attrs` with ATTR_SET or PAL_SET
+  attr_files: Vec<u8>,
+  /// 256 tiles in the SNES 4bpp format
+  border_tiles: Vec<u8>,
+  /// 32x28 entries: tile, palette (4 to 7), and flips
+  border_map: Vec<u16>,
+  border_palettes: [[Color; 16]; 4],
+  mask: Mask,
+  /// MLT_REQ: how many joypads the game reads, and which one is read next
+  players: u8,
+  player: u8,
+  /// The joypads of players 2 to 4, the first one is the usual [`Bus::joypad`](crate::bus::Bus::joypad)
+  pub(crate) joypads: [Joypad; 3],
+  transfer: Option<(Transfer, u8)>,
+  /// The border and the game area, colored with the SGB palettes
+  #[serde(skip, default = "Sgb::default_screen")]
+  pub screen: FrameBuffer,
+}
+
+impl Sgb {
+  /// The game area starts with `palette`, until the game sets its own.
+  pub fn new(palette: Palette, format: PixelFormat) -> Self {
+    Self {
+      reader: PacketReader::default(),
+      palettes: [palette; 4],
+      system_palettes: vec![0; 512 * 8],
+      attrs: vec![0; ATTR_WIDTH * ATTR_HEIGHT],
+      attr_files: vec![0; ATTR_FILES * ATTR_FILE_SIZE],
+      border_tiles: vec![0; 256 * 32],
+      border_map: vec![0; 32 * 28],
+      border_palettes: [[(0, 0, 0); 16]; 4],
+      mask: Mask::None,
+      players: 1,
+      player: 0,
+      joypads: std::array::from_fn(|_| Joypad::new()),
+      transfer: None,
+      screen: Self::screen_with(format),
+    }
+  }
+
+  fn default_screen() -> FrameBuffer {
+    Self::screen_with(PixelFormat::default())
+  }
+
+  pub(crate) fn screen_with(format: PixelFormat) -> FrameBuffer {
+    FrameBuffer::with_format(SGB_WIDTH, SGB_HEIGHT, format)
+  }
+
+  /// The number of joypads the game asked for with MLT_REQ, 1, 2 or 4.
+  pub fn players(&self) -> u8 {
+    self.players
+  }
+
+  /// The joypad of `player`, from 1 to 3. Player 0 is the usual joypad, which the SGB doesn't own.
+  pub fn joypad(&mut self, player: usize) -> Option<&mut Joypad> {
+    self.joypads.get_mut(player.checked_sub(1)?)
+  }
+
+  /// Waiting for a frame with the data of a `*_TRN` command, which has to be drawn even when frames are skipped.
+  pub(crate) fn is_transferring(&self) -> bool {
+    self.transfer.is_some()
+  }
+
+  pub(crate) fn write_joypad(&mut self, val: u8) {
+    for joypad in &mut self.joypads { joypad.write(val); }
+
+    // the next joypad is selected when P15 goes high
+    if self.players > 1 && val & 0x20 != 0 && self.reader.last & 0b10 == 0 {
+      self.player = (self.player + 1) % self.players;
+    }
+
+    if let Some(command) = self.reader.write(val) {
+      self.run_command(&command);
+    }
+  }
+
+  /// P1 reads the joypad of the current player. With more than one player,
+  /// it reads the id of the current joypad when no line is selected.
+  pub(crate) fn read_joypad(&self, main: &Joypad) -> u8 {
+    let val = match self.player {
+      0 => main.read(),
+      player => self.joypads[player as usize - 1].read(),
+    };
+
+    if self.players > 1 && self.reader.last == 0b11 {
+      (val & 0xF0) | (0xF - self.player)
+    } else { val }
+  }
+
+  fn run_command(&mut self, data: &[u8]) {
+    match data[0] >> 3 {
+      0x00 => self.set_palette_pair(data, 0, 1),
+      0x01 => self.set_palette_pair(data, 2, 3),
+      0x02 => self.set_palette_pair(data, 0, 3),
+      0x03 => self.set_palette_pair(data, 1, 2),
+      0x04 => self.attr_blk(data),
+      0x0A => self.pal_set(data),
+      0x0B => self.transfer = Some((Transfer::Palettes, TRANSFER_DELAY)),
+      0x11 => {
+        self.players = match data[1] & 0b11 { 1 => 2, 3 => 4, _ => 1 };
+        self.player = 0;
+      }
+      0x13 => self.transfer = Some((Transfer::Tiles(data[1] & 1 != 0), TRANSFER_DELAY)),
+      0x14 => self.transfer = Some((Transfer::Border, TRANSFER_DELAY)),
+      0x15 => self.transfer = Some((Transfer::Attributes, TRANSFER_DELAY)),
+      0x16 => {
+        self.load_attr_file(data[1] & 0x3F);
+        if data[1] & 0x40 != 0 { self.mask = Mask::None; }
+      }
+      0x17 => self.mask = match data[1] & 0b11 {
+        1 => Mask::Freeze,
+        2 => Mask::Black,
+        3 => Mask::Color0,
+        _ => Mask::None,
+      },
+      // sound, SNES program and the other attribute commands aren't supported
+      _ => {}
+    }
+  }
+
+  /// PAL01, PAL23, PAL03 and PAL12: the shared color 0, then 3 colors for each palette.
+  fn set_palette_pair(&mut self, data: &[u8], a: usize, b: usize) {
+    let color = |i: usize| snes_color(data[1 + i*2], data[2 + i*2]);
+    for palette in &mut self.palettes { palette[0] = color(0); }
+    for i in 1..4 {
+      self.palettes[a][i] = color(i);
+      self.palettes[b][i] = color(i + 3);
+    }
+  }
+
+  /// ATTR_BLK: rectangles of tiles, with a palette for their inside, border and outside.
+  fn attr_blk(&mut self, data: &[u8]) {
+    let sets = data[1] as usize;
+    for set in data[2..].chunks_exact(6).take(sets) {
+      let ctrl = set[0] & 0b111;
+      let (inside, mut line, outside) = (set[1] & 0b11, (set[1] >> 2) & 0b11, (set[1] >> 4) & 0b11);
+      // with only the inside or the outside changed, the border goes with it
+      if ctrl == 0b001 { line = inside; }
+      if ctrl == 0b100 { line = outside; }
+      let [x1, y1, x2, y2] = [set[2], set[3], set[4], set[5]].map(|v| v as usize);
+
+      for y in 0..ATTR_HEIGHT {
+        for x in 0..ATTR_WIDTH {
+          let in_rect = (x1..=x2).contains(&x) && (y1..=y2).contains(&y);
+          let on_line = in_rect && (x == x1 || x == x2 || y == y1 || y == y2);
+          let palette = match (in_rect, on_line) {
+            (true, true) if ctrl & 0b010 != 0 || ctrl == 0b001 || ctrl == 0b100 => line,
+            (true, false) if ctrl & 0b001 != 0 => inside,
+            (false, _) if ctrl & 0b100 != 0 => outside,
+            _ => continue,
+          };
+          self.attrs[y * ATTR_WIDTH + x] = palette;
+        }
+      }
+    }
+  }
+
+  /// PAL_SET: 4 of the system palettes, and optionally an attribute file.
+  fn pal_set(&mut self, data: &[u8]) {
+    for i in 0..4 {
+      let id = u16::from_le_bytes([data[1 + i*2], data[2 + i*2]]) as usize & 0x1FF;
+      let colors = &self.system_palettes[id*8..id*8 + 8];
+      self.palettes[i] = std::array::from_fn(|c| snes_color(colors[c*2], colors[c*2 + 1]));
+    }
+    let color0 = self.palettes[0][0];
+    for palette in &mut self.palettes { palette[0] = color0; }
+
+    let flags = data[9];
+    if flags & 0x80 != 0 { self.load_attr_file(flags & 0x3F); }
+    if flags & 0x40 != 0 { self.mask = Mask::None; }
+  }
+
+  fn load_attr_file(&mut self, file: u8) {
+    let file = file as usize;
+    if file >= ATTR_FILES { return; }
+
+    let data = &self.attr_files[file * ATTR_FILE_SIZE..(file + 1) * ATTR_FILE_SIZE];
+    for (i, attr) in self.attrs.iter_mut().enumerate() {
+      *attr = (data[i / 4] >> (6 - 2 * (i % 4))) & 0b11;
+    }
+  }
+
+  /// Reads the transferred data from the screen: the first 256 tiles, from left to right and top to bottom.
+  fn transfer_data(lcd: &FrameBuffer) -> Vec<u8> {
+    let mut data = vec![0; TRANSFER_SIZE];
+    for (tile, bytes) in data.chunks_exact_mut(16).enumerate() {
+      let (tx, ty) = (tile % ATTR_WIDTH * 8, tile / ATTR_WIDTH * 8);
+      for row in 0..8 {
+        for col in 0..8 {
+          let shade = lcd.indexed[(ty + row) * lcd.width + tx + col];
+          bytes[row*2] |= (shade & 1) << (7 - col);
+          bytes[row*2 + 1] |= (shade >> 1) << (7 - col);
+        }
+      }
+    }
+    data
+  }
+
+  fn run_transfer(&mut self, transfer: Transfer, data: &[u8]) {
+    match transfer {
+      Transfer::Palettes => self.system_palettes.copy_from_slice(data),
+      Transfer::Tiles(upper) => {
+        let start = if upper { TRANSFER_SIZE } else { 0 };
+        self.border_tiles[start..start + TRANSFER_SIZE].copy_from_slice(data);
+      }
+      Transfer::Border => {
+        for (entry, bytes) in self.border_map.iter_mut().zip(data.chunks_exact(2)) {
+          *entry = u16::from_le_bytes([bytes[0], bytes[1]]);
+        }
+        for (i, palette) in self.border_palettes.iter_mut().enumerate() {
+          let colors = &data[0x800 + i*32..];
+          *palette = std::array::from_fn(|c| snes_color(colors[c*2], colors[c*2 + 1]));
+        }
+      }
+      Transfer::Attributes => {
+        let len = self.attr_files.len();
+        self.attr_files.copy_from_slice(&data[..len]);
+      }
+    }
+  }
+
+  /// Called at every frame with the game screen, draws the border and the game area if `draw` is set.
+  pub(crate) fn on_frame(&mut self, lcd: &FrameBuffer, draw: bool) {
+    if let Some((transfer, frames)) = self.transfer {
+      if frames > 1 {
+        self.transfer = Some((transfer, frames - 1));
+      } else {
+        self.transfer = None;
+        self.run_transfer(transfer, &Self::transfer_data(lcd));
+      }
+    }
+
+    if draw { self.render(lcd); }
+  }
+
+  fn render(&mut self, lcd: &FrameBuffer) {
+    let backdrop = self.palettes[0][0];
+    let game_area = |x: usize, y: usize| (GAME_X..GAME_X + LCD_WIDTH).contains(&x) && (GAME_Y..GAME_Y + LCD_HEIGHT).contains(&y);
+
+    for y in 0..SGB_HEIGHT {
+      for x in 0..SGB_WIDTH {
+        let color = if !game_area(x, y) { backdrop } else {
+          let (gx, gy) = (x - GAME_X, y - GAME_Y);
+          match self.mask {
+            Mask::None => {
+              let palette = self.attrs[gy / 8 * ATTR_WIDTH + gx / 8];
+              self.palettes[palette as usize][lcd.indexed[gy * lcd.width + gx] as usize]
+            }
+            Mask::Freeze => continue,
+            Mask::Black => (0, 0, 0),
+            Mask::Color0 => backdrop,
+          }
+        };
+        self.screen.set_color(x, y, color);
+      }
+    }
+
+    // the border goes over the game area, where it is usually transparent
+    for (i, &entry) in self.border_map.iter().enumerate() {
+      let (tx, ty) = (i % 32 * 8, i / 32 * 8);
+      if ty >= SGB_HEIGHT { break; }
+
+      let tile = &self.border_tiles[(entry & 0xFF) as usize * 32..][..32];
+      let palette = &self.border_palettes[((entry >> 10) & 0b11) as usize];
+      let (x_flip, y_flip) = (entry & 0x4000 != 0, entry & 0x8000 != 0);
+      for row in 0..8 {
+        let planes = [tile[row*2], tile[row*2 + 1], tile[16 + row*2], tile[16 + row*2 + 1]];
+        for col in 0..8 {
+          let bit = 7 - col;
+          let color = planes.iter().enumerate()
+            .fold(0, |color, (plane, bits)| color | (((bits >> bit) & 1) << plane));
+          if color == 0 { continue; }
+
+          let x = tx + if x_flip { 7 - col } else { col };
+          let y = ty + if y_flip { 7 - row } else { row };
+          self.screen.set_color(x, y, palette[color as usize]);
+        }
+      }
+    }
+
+    self.screen.blend_frame();
+  }
+}
+
+#[cfg(test)]
+mod sgb_tests {
+  use super::*;
+  use crate::{frame::GRAYSCALE, joypad::Button};
+
+  fn new_sgb() -> Sgb {
+    Sgb::new(GRAYSCALE, PixelFormat::Rgba32)
+  }
+
+  fn send(sgb: &mut Sgb, command: &[u8]) {
+    for packet in command.chunks(16) {
+      sgb.write_joypad(0x00);
+      sgb.write_joypad(0x30);
+      for i in 0..128 {
+        let bit = packet.get(i / 8).is_some_and(|byte| byte & (1 << (i % 8)) != 0);
+        sgb.write_joypad(if bit { 0x10 } else { 0x20 });
+        sgb.write_joypad(0x30);
+      }
+      sgb.write_joypad(0x20);
+      sgb.write_joypad(0x30);
+    }
+  }
+
+  #[test]
+  fn palettes() {
+    let mut sgb = new_sgb();
+    // PAL01: red for color 0, green, blue and white for both palettes
+    send(&mut sgb, &[0x01, 0x1F, 0x00, 0xE0, 0x03, 0x00, 0x7C, 0xFF, 0x7F, 0xE0, 0x03, 0x00, 0x7C, 0xFF, 0x7F]);
+    // ATTR_BLK: palette 1 inside and on the border of tiles 2,2 to 4,4
+    send(&mut sgb, &[0x04 << 3 | 1, 1, 0b011, 0b0101, 2, 2, 4, 4]);
+
+    let mut lcd = FrameBuffer::gameboy_lcd();
+    lcd.indexed.fill(1);
+    sgb.on_frame(&lcd, true);
+    assert_eq!(sgb.screen.get_color(0, 0), (255, 0, 0));
+    assert_eq!(sgb.screen.get_color(GAME_X, GAME_Y), (0, 255, 0));
+    assert_eq!(sgb.palettes[1][1], (0, 255, 0));
+    assert_eq!(sgb.attrs[2 * ATTR_WIDTH + 2], 1);
+    assert_eq!(sgb.attrs[3 * ATTR_WIDTH + 3], 1);
+    assert_eq!(sgb.attrs[5 * ATTR_WIDTH + 5], 0);
+
+    // MASK_EN: black
+    send(&mut sgb, &[0x17 << 3 | 1, 2]);
+    sgb.on_frame(&lcd, true);
+    assert_eq!(sgb.screen.get_color(GAME_X, GAME_Y), (0, 0, 0));
+  }
+
+  #[test]
+  fn border_transfer() {
+    let mut sgb = new_sgb();
+    let mut lcd = FrameBuffer::gameboy_lcd();
+
+    // CHR_TRN: tile 0 has its first pixel set in plane 0 (color 1)
+    lcd.indexed[0] = 1;
+    send(&mut sgb, &[0x13 << 3 | 1, 0]);
+    sgb.on_frame(&lcd, false);
+    assert_eq!(sgb.border_tiles[0], 0);
+    sgb.on_frame(&lcd, false);
+    assert_eq!(sgb.border_tiles[0], 0x80);
+    assert!(!sgb.is_transferring());
+
+    // PCT_TRN: every entry is tile 0 with palette 4, whose color 1 is red
+    let mut lcd = FrameBuffer::gameboy_lcd();
+    let mut data = vec![0u8; TRANSFER_SIZE];
+    for entry in data[..0x700].chunks_exact_mut(2) { entry.copy_from_slice(&(4u16 << 10).to_le_bytes()); }
+    data[0x802..0x804].copy_from_slice(&[0x1F, 0x00]);
+    for (tile, bytes) in data.chunks_exact(16).enumerate() {
+      let (tx, ty) = (tile % ATTR_WIDTH * 8, tile / ATTR_WIDTH * 8);
+      for row in 0..8 {
+        for col in 0..8 {
+          let shade = ((bytes[row*2] >> (7 - col)) & 1) | (((bytes[row*2 + 1] >> (7 - col)) & 1) << 1);
+          lcd.indexed[(ty + row) * LCD_WIDTH + tx + col] = shade;
+        }
+      }
+    }
+    send(&mut sgb, &[0x14 << 3 | 1]);
+    sgb.on_frame(&lcd, false);
+    sgb.on_frame(&lcd, true);
+    assert_eq!(sgb.screen.get_color(8, 0), (255, 0, 0));
+    assert_eq!(sgb.screen.get_color(9, 0), GRAYSCALE[0]);
+  }
+
+  #[test]
+  fn multiplayer() {
+    let mut sgb = new_sgb();
+    send(&mut sgb, &[0x11 << 3 | 1, 1]);
+    assert_eq!(sgb.players(), 2);
+
+    let main = Joypad::new();
+    let mut ids = Vec::new();
+    for _ in 0..3 {
+      sgb.write_joypad(0x10);
+      sgb.write_joypad(0x30);
+      ids.push(sgb.read_joypad(&main) & 0xF);
+    }
+    assert_eq!(ids, [0xE, 0xF, 0xE]);
+
+    // the buttons are read from the current player's joypad
+    sgb.joypad(1).unwrap().press(Button::A);
+    assert!(sgb.joypad(4).is_none());
+    sgb.write_joypad(0x10);
+    assert_eq!(sgb.player, 1);
+    assert_eq!(sgb.read_joypad(&main) & 0xF, 0b1110);
+    sgb.write_joypad(0x30);
+    sgb.write_joypad(0x10);
+    assert_eq!(sgb.read_joypad(&main) & 0xF, 0b1111);
+  }
+}
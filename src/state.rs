@@ -0,0 +1,249 @@
+//! Serialization helpers for save states, see [`Gameboy::save_state`](crate::gb::Gameboy::save_state).
+
+use serde::{de::Error, ser, Deserialize, Deserializer, Serialize, Serializer};
+
+/// Bumped when the state layout changes, older states are refused.
+pub const STATE_VERSION: u32 = 4;
+
+/// What is stored in a save state file, as JSON.
+#[derive(Serialize, Deserialize)]
+pub struct SaveState<C> {
+  pub version: u32,
+  /// The ROM title and header checksum, states are only loaded on the ROM they were saved with
+  pub title: String,
+  pub checksum: u8,
+  pub cpu: C,
+}
+
+/// 64-bit FNV-1a, a fast non cryptographic hash, stable across runs and platforms.
+/// Fed by [`Hasher`], or with [`std::io::Write`], so a state is hashed while serialized, without a copy.
+pub(crate) struct Fnv1a(pub u64);
+
+impl Default for Fnv1a {
+  fn default() -> Self { Self(0xcbf2_9ce4_8422_2325) }
+}
+
+impl Fnv1a {
+  pub fn update(&mut self, bytes: &[u8]) {
+    for byte in bytes {
+      self.0 = (self.0 ^ *byte as u64).wrapping_mul(0x100_0000_01b3);
+    }
+  }
+}
+
+impl std::io::Write for Fnv1a {
+  fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+    self.update(buf);
+    Ok(buf.len())
+  }
+
+  fn flush(&mut self) -> std::io::Result<()> { Ok(()) }
+}
+
+/// Hashes the raw values of what it serializes: integers as little endian bytes, floats by their bits,
+/// lengths and enum variants as integers. Much cheaper than hashing a JSON state.
+pub(crate) struct Hasher<'a> {
+  hash: &'a mut Fnv1a,
+  /// For each sequence or map being hashed, whether its length is unknown. The elements of those are
+  /// prefixed with a 1 and the end with a 0 instead, so where they end is hashed too.
+  unknown_len: Vec<bool>,
+}
+
+/// Hashing never fails, serde needs an error type anyway.
+#[derive(Debug)]
+pub(crate) struct HashError;
+
+impl std::fmt::Display for HashError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result { write!(f, "hashing failed") }
+}
+impl std::error::Error for HashError {}
+impl serde::ser::Error for HashError {
+  fn custom<T: std::fmt::Display>(_: T) -> Self { HashError }
+}
+
+impl<'a> Hasher<'a> {
+  pub fn new(hash: &'a mut Fnv1a) -> Self {
+    Self { hash, unknown_len: Vec::new() }
+  }
+
+  fn len(&mut self, len: usize) {
+    self.hash.update(&(len as u64).to_le_bytes());
+  }
+
+  fn element(&mut self) {
+    if self.unknown_len.last() == Some(&true) { self.hash.update(&[1]); }
+  }
+
+  fn end(&mut self) {
+    if self.unknown_len.pop() == Some(true) { self.hash.update(&[0]); }
+  }
+}
+
+impl Serializer for &mut Hasher<'_> {
+  type Ok = ();
+  type Error = HashError;
+  type SerializeSeq = Self;
+  type SerializeTuple = Self;
+  type SerializeTupleStruct = Self;
+  type SerializeTupleVariant = Self;
+  type SerializeMap = Self;
+  type SerializeStruct = Self;
+  type SerializeStructVariant = Self;
+
+  fn serialize_bool(self, v: bool) -> Result<(), HashError> { self.hash.update(&[v as u8]); Ok(()) }
+  fn serialize_i8(self, v: i8) -> Result<(), HashError> { self.hash.update(&v.to_le_bytes()); Ok(()) }
+  fn serialize_i16(self, v: i16) -> Result<(), HashError> { self.hash.update(&v.to_le_bytes()); Ok(()) }
+  fn serialize_i32(self, v: i32) -> Result<(), HashError> { self.hash.update(&v.to_le_bytes()); Ok(()) }
+  fn serialize_i64(self, v: i64) -> Result<(), HashError> { self.hash.update(&v.to_le_bytes()); Ok(()) }
+  fn serialize_u8(self, v: u8) -> Result<(), HashError> { self.hash.update(&[v]); Ok(()) }
+  fn serialize_u16(self, v: u16) -> Result<(), HashError> { self.hash.update(&v.to_le_bytes()); Ok(()) }
+  fn serialize_u32(self, v: u32) -> Result<(), HashError> { self.hash.update(&v.to_le_bytes()); Ok(()) }
+  fn serialize_u64(self, v: u64) -> Result<(), HashError> { self.hash.update(&v.to_le_bytes()); Ok(()) }
+  fn serialize_f32(self, v: f32) -> Result<(), HashError> { self.hash.update(&v.to_bits().to_le_bytes()); Ok(()) }
+  fn serialize_f64(self, v: f64) -> Result<(), HashError> { self.hash.update(&v.to_bits().to_le_bytes()); Ok(()) }
+  fn serialize_char(self, v: char) -> Result<(), HashError> { self.serialize_u32(v as u32) }
+  fn serialize_str(self, v: &str) -> Result<(), HashError> { self.serialize_bytes(v.as_bytes()) }
+  fn serialize_bytes(self, v: &[u8]) -> Result<(), HashError> {
+    self.len(v.len());
+    self.hash.update(v);
+    Ok(())
+  }
+  fn serialize_none(self) -> Result<(), HashError> { self.serialize_u8(0) }
+  fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<(), HashError> {
+    self.serialize_u8(1)?;
+    value.serialize(self)
+  }
+  fn serialize_unit(self) -> Result<(), HashError> { Ok(()) }
+  fn serialize_unit_struct(self, _: &'static str) -> Result<(), HashError> { Ok(()) }
+  fn serialize_unit_variant(self, _: &'static str, index: u32, _: &'static str) -> Result<(), HashError> {
+    self.serialize_u32(index)
+  }
+  fn serialize_newtype_struct<T: Serialize + ?Sized>(self, _: &'static str, value: &T) -> Result<(), HashError> {
+    value.serialize(self)
+  }
+  fn serialize_newtype_variant<T: Serialize + ?Sized>(
+    self, _: &'static str, index: u32, _: &'static str, value: &T,
+  ) -> Result<(), HashError> {
+    self.serialize_u32(index)?;
+    value.serialize(self)
+  }
+  fn serialize_seq(self, len: Option<usize>) -> Result<Self, HashError> {
+    self.unknown_len.push(len.is_none());
+    if let Some(len) = len { self.len(len); }
+    Ok(self)
+  }
+  fn serialize_tuple(self, _: usize) -> Result<Self, HashError> { Ok(self) }
+  fn serialize_tuple_struct(self, _: &'static str, _: usize) -> Result<Self, HashError> { Ok(self) }
+  fn serialize_tuple_variant(self, _: &'static str, index: u32, _: &'static str, _: usize) -> Result<Self, HashError> {
+    self.serialize_u32(index)?;
+    Ok(self)
+  }
+  fn serialize_map(self, len: Option<usize>) -> Result<Self, HashError> { self.serialize_seq(len) }
+  fn serialize_struct(self, _: &'static str, _: usize) -> Result<Self, HashError> { Ok(self) }
+  fn serialize_struct_variant(self, _: &'static str, index: u32, _: &'static str, _: usize) -> Result<Self, HashError> {
+    self.serialize_u32(index)?;
+    Ok(self)
+  }
+}
+
+macro_rules! hash_compound {
+  ($($trait:ident :: $method:ident),*) => {$(
+    impl ser::$trait for &mut Hasher<'_> {
+      type Ok = ();
+      type Error = HashError;
+      fn $method<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), HashError> { value.serialize(&mut **self) }
+      fn end(self) -> Result<(), HashError> { Ok(()) }
+    }
+  )*};
+}
+hash_compound!(SerializeTuple::serialize_element, SerializeTupleStruct::serialize_field,
+  SerializeTupleVariant::serialize_field);
+
+impl ser::SerializeSeq for &mut Hasher<'_> {
+  type Ok = ();
+  type Error = HashError;
+  fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), HashError> {
+    self.element();
+    value.serialize(&mut **self)
+  }
+  fn end(self) -> Result<(), HashError> {
+    Hasher::end(self);
+    Ok(())
+  }
+}
+
+impl ser::SerializeMap for &mut Hasher<'_> {
+  type Ok = ();
+  type Error = HashError;
+  fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), HashError> {
+    self.element();
+    key.serialize(&mut **self)
+  }
+  fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), HashError> { value.serialize(&mut **self) }
+  fn end(self) -> Result<(), HashError> {
+    Hasher::end(self);
+    Ok(())
+  }
+}
+
+impl ser::SerializeStruct for &mut Hasher<'_> {
+  type Ok = ();
+  type Error = HashError;
+  fn serialize_field<T: Serialize + ?Sized>(&mut self, _: &'static str, value: &T) -> Result<(), HashError> {
+    value.serialize(&mut **self)
+  }
+  fn end(self) -> Result<(), HashError> { Ok(()) }
+}
+
+impl ser::SerializeStructVariant for &mut Hasher<'_> {
+  type Ok = ();
+  type Error = HashError;
+  fn serialize_field<T: Serialize + ?Sized>(&mut self, _: &'static str, value: &T) -> Result<(), HashError> {
+    value.serialize(&mut **self)
+  }
+  fn end(self) -> Result<(), HashError> { Ok(()) }
+}
+
+/// Serde only handles arrays up to 32 elements, bigger ones go through this with `#[serde(with = "state::array")]`.
+pub mod array {
+  use super::*;
+
+  pub fn serialize<S: Serializer, T: Serialize, const N: usize>(array: &[T; N], s: S) -> Result<S::Ok, S::Error> {
+    array.as_slice().serialize(s)
+  }
+
+  pub fn deserialize<'de, D: Deserializer<'de>, T: Deserialize<'de>, const N: usize>(d: D) -> Result<[T; N], D::Error> {
+    let items = Vec::<T>::deserialize(d)?;
+    let len = items.len();
+    items.try_into().map_err(|_| D::Error::invalid_length(len, &"an array of the right size"))
+  }
+}
+
+#[cfg(test)]
+mod state_tests {
+  use super::*;
+
+  /// Serialized as a sequence of unknown length.
+  struct Unsized(Vec<u8>);
+
+  impl Serialize for Unsized {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+      s.collect_seq(self.0.iter().filter(|_| true))
+    }
+  }
+
+  fn hash(value: &impl Serialize) -> u64 {
+    let mut hash = Fnv1a::default();
+    value.serialize(&mut Hasher::new(&mut hash)).unwrap();
+    hash.0
+  }
+
+  #[test]
+  fn unknown_lengths() {
+    let split = |a: &[u8], b: &[u8]| (Unsized(a.to_vec()), Unsized(b.to_vec()));
+    assert_eq!(hash(&split(&[1, 2], &[3])), hash(&split(&[1, 2], &[3])));
+    assert_ne!(hash(&split(&[1, 2], &[3])), hash(&split(&[1], &[2, 3])));
+    assert_ne!(hash(&split(&[], &[0])), hash(&split(&[0], &[])));
+    assert_ne!(hash(&(vec![1u8, 2], vec![3u8])), hash(&(vec![1u8], vec![2u8, 3])));
+  }
+}
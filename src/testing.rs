@@ -0,0 +1,484 @@
+//! Helpers to build small test ROMs at test time, and to compare the screen against reference images.
+//!
+//! ```
+//! use tomboy_emulator::{asm, testing::RomBuilder};
+//!
+//! let rom = RomBuilder::new()
+//!   .code(&asm![
+//!     "LD A, $42",
+//!     "loop:",
+//!     "JR loop",
+//!   ])
+//!   .build();
+//! ```
+
+use std::{collections::HashMap, env, fs, path::Path};
+
+use crate::{cart::{LoadOptions, NINTENDO_LOGO}, frame::FrameBuffer, gb::Gameboy, instr::{InstrTarget, Instruction, TargetKind, INSTRUCTIONS}, Error};
+
+/// Address where the code given to [`RomBuilder::code`] is placed.
+pub const CODE_START: u16 = 0x150;
+
+/// Assembles the given lines into a byte vector, starting at [`CODE_START`].
+/// Panics on invalid assembly.
+#[macro_export]
+macro_rules! asm {
+  ($($line:expr),* $(,)?) => {
+    $crate::testing::assemble($crate::testing::CODE_START, &[$($line),*]).unwrap()
+  };
+}
+pub use crate::asm;
+
+/// Builds a 32kb, no mapper cart image with a valid header.
+/// The entry point at 0x100 jumps to [`CODE_START`].
+pub struct RomBuilder {
+  rom: Vec<u8>,
+}
+
+impl Default for RomBuilder {
+  fn default() -> Self { Self::new() }
+}
+
+impl RomBuilder {
+  pub fn new() -> Self {
+    let mut rom = vec![0; 32*1024];
+    // NOP; JP $0150
+    rom[0x100..0x104].copy_from_slice(&[0x00, 0xC3, 0x50, 0x01]);
+    rom[0x104..=0x133].copy_from_slice(&NINTENDO_LOGO);
+    rom[0x134..0x138].copy_from_slice(b"TEST");
+    Self { rom }
+  }
+
+  /// Places the code at [`CODE_START`].
+  pub fn code(self, code: &[u8]) -> Self {
+    self.at(CODE_START as usize, code)
+  }
+
+  /// Places the bytes at the given ROM offset.
+  pub fn at(mut self, addr: usize, bytes: &[u8]) -> Self {
+    self.rom[addr..addr+bytes.len()].copy_from_slice(bytes);
+    self
+  }
+
+  /// Sets the cart type byte (0x147).
+  pub fn cart_type(mut self, code: u8) -> Self {
+    self.rom[0x147] = code;
+    self
+  }
+
+  /// Sets the ROM size byte (0x148) and resizes the image accordingly.
+  pub fn rom_size(mut self, code: u8) -> Self {
+    self.rom[0x148] = code;
+    self.rom.resize((32*1024) << code, 0);
+    self
+  }
+
+  /// Sets the RAM size byte (0x149).
+  pub fn ram_size(mut self, code: u8) -> Self {
+    self.rom[0x149] = code;
+    self
+  }
+
+  /// Returns the image, with the header checksum fixed.
+  pub fn build(mut self) -> Vec<u8> {
+    let mut check = 0u8;
+    for byte in &self.rom[0x134..=0x14C] {
+      check = check.wrapping_sub(*byte).wrapping_sub(1);
+    }
+    self.rom[0x14D] = check;
+    self.rom
+  }
+}
+
+enum Operand {
+  Reg { kind: TargetKind, indirect: bool, inc: bool, dec: bool },
+  Num { val: i32, indirect: bool },
+  Label { name: String, indirect: bool },
+}
+
+fn parse_reg(s: &str) -> Option<(TargetKind, bool, bool)> {
+  use TargetKind::*;
+  let reg = match s {
+    "A" => A, "B" => B, "C" => C, "D" => D, "E" => E, "H" => H, "L" => L,
+    "AF" => AF, "BC" => BC, "DE" => DE, "HL" => HL, "SP" => SP,
+    "NZ" => NZ, "Z" => Z, "NC" => NC,
+    "HL+" | "HLI" => return Some((HL, true, false)),
+    "HL-" | "HLD" => return Some((HL, false, true)),
+    _ => return None,
+  };
+  Some((reg, false, false))
+}
+
+fn parse_num(s: &str) -> Option<i32> {
+  let (neg, s) = match s.strip_prefix('-') {
+    Some(s) => (true, s),
+    None => (false, s),
+  };
+
+  let val = if let Some(hex) = s.strip_prefix('$').or(s.strip_prefix("0x")) {
+    i32::from_str_radix(hex, 16).ok()?
+  } else {
+    s.parse().ok()?
+  };
+
+  Some(if neg { -val } else { val })
+}
+
+fn parse_operands(s: &str) -> Result<Vec<Operand>, String> {
+  let mut res = Vec::new();
+
+  for op in s.split(',').map(str::trim).filter(|op| !op.is_empty()) {
+    let upper = op.to_uppercase();
+    let (inner, indirect) = match upper.strip_prefix('(').and_then(|s| s.strip_suffix(')')) {
+      Some(inner) => (inner.trim(), true),
+      None => (upper.as_str(), false),
+    };
+
+    // LD HL, SP+e8
+    if let Some(offset) = inner.strip_prefix("SP").filter(|o| !o.is_empty()) {
+      let offset = offset.strip_prefix('+').unwrap_or(offset);
+      let val = parse_num(offset).ok_or(format!("Invalid offset {op}"))?;
+      res.push(Operand::Reg { kind: TargetKind::SP, indirect: false, inc: true, dec: false });
+      res.push(Operand::Num { val, indirect: false });
+    } else if let Some((kind, inc, dec)) = parse_reg(inner) {
+      res.push(Operand::Reg { kind, indirect, inc, dec });
+    } else if let Some(val) = parse_num(inner) {
+      res.push(Operand::Num { val, indirect });
+    } else {
+      let name = op.trim_start_matches('(').trim_end_matches(')').trim().to_string();
+      res.push(Operand::Label { name, indirect });
+    }
+  }
+
+  Ok(res)
+}
+
+fn operand_matches(target: &InstrTarget, op: &Operand) -> bool {
+  use TargetKind::*;
+  match op {
+    Operand::Reg { kind, indirect, inc, dec } => {
+      target.kind == *kind && target.immediate != *indirect
+      && target.increment == *inc && target.decrement == *dec
+    }
+    Operand::Num { val, indirect } => match target.kind {
+      Immediate8 => !indirect && (-128..=0xFF).contains(val),
+      Signed8 => !indirect && (-128..=127).contains(val),
+      Address8 => *indirect && ((0..=0xFF).contains(val) || (0xFF00..=0xFFFF).contains(val)),
+      Immediate16 => !indirect && (-0x8000..=0xFFFF).contains(val),
+      Address16 => target.immediate != *indirect && (0..=0xFFFF).contains(val),
+      RST00 | RST08 | RST10 | RST18 | RST20 | RST28 | RST30 | RST38 => {
+        !indirect && *val == 8 * (target.kind.clone() as i32 - RST00 as i32)
+      }
+      Bit0 | Bit1 | Bit2 | Bit3 | Bit4 | Bit5 | Bit6 | Bit7 => {
+        !indirect && *val == target.kind.clone() as i32 - Bit0 as i32
+      }
+      _ => false,
+    }
+    Operand::Label { indirect, .. } => match target.kind {
+      Signed8 => !indirect,
+      Immediate16 => !indirect,
+      Address16 => target.immediate != *indirect,
+      _ => false,
+    }
+  }
+}
+
+fn find_instruction(name: &str, ops: &[Operand]) -> Option<&'static Instruction> {
+  INSTRUCTIONS.iter().find(|instr| {
+    instr.name == name
+    && instr.operands.len() == ops.len()
+    && instr.operands.iter().zip(ops).all(|(target, op)| operand_matches(target, op))
+  })
+}
+
+/// Assembles SM83 instructions, one per line, using the mnemonics of the instructions table.
+///
+/// Supports `;` comments, `label:` definitions (usable in jumps, calls and 16 bit loads),
+/// `(HL+)`/`(HL-)`, `SP+e8`, the short form of alu instructions (`AND n8` for `AND A, n8`)
+/// and the `DB` directive for raw bytes.
+/// Numbers can be decimal, `$` or `0x` prefixed hexadecimal.
+pub fn assemble(origin: u16, lines: &[&str]) -> Result<Vec<u8>, String> {
+  let mut parsed = Vec::new();
+  let mut labels = HashMap::new();
+  let mut addr = origin;
+
+  // first pass: find instructions and label addresses
+  for line in lines {
+    let line = line.split(';').next().unwrap().trim();
+    if line.is_empty() { continue; }
+
+    if let Some(label) = line.strip_suffix(':') {
+      labels.insert(label.trim().to_string(), addr);
+      continue;
+    }
+
+    let (name, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    let name = name.to_uppercase();
+    let mut ops = parse_operands(rest)?;
+
+    if name == "DB" {
+      addr = addr.wrapping_add(ops.len() as u16);
+      parsed.push((None, ops, addr));
+      continue;
+    }
+
+    let instr = match find_instruction(&name, &ops) {
+      Some(instr) => instr,
+      // short form of the alu instructions, like AND n8 for AND A, n8
+      None => {
+        ops.insert(0, Operand::Reg { kind: TargetKind::A, indirect: false, inc: false, dec: false });
+        find_instruction(&name, &ops).ok_or(format!("Unknown instruction: {line}"))?
+      }
+    };
+    addr = addr.wrapping_add(instr.bytes as u16);
+    parsed.push((Some(instr), ops, addr));
+  }
+
+  // second pass: encode
+  let mut res = Vec::new();
+  for (instr, ops, next_addr) in parsed {
+    let Some(instr) = instr else {
+      for op in ops {
+        match op {
+          Operand::Num { val, .. } => res.push(val as u8),
+          _ => return Err("DB only accepts numbers".to_string()),
+        }
+      }
+      continue;
+    };
+
+    if instr.prefix { res.push(0xCB); }
+    res.push(instr.opcode);
+
+    for (target, op) in instr.operands.iter().zip(ops) {
+      let val = match op {
+        Operand::Num { val, .. } => val,
+        Operand::Label { name, .. } => {
+          let dest = *labels.get(&name).ok_or(format!("Unknown label: {name}"))?;
+          if target.kind == TargetKind::Signed8 {
+            let offset = dest as i32 - next_addr as i32;
+            if !(-128..=127).contains(&offset) {
+              return Err(format!("Label {name} is too far for a relative jump"));
+            }
+            offset
+          } else { dest as i32 }
+        }
+        Operand::Reg { .. } => continue,
+      };
+
+      match target.kind {
+        TargetKind::Immediate8 | TargetKind::Signed8 | TargetKind::Address8 => res.push(val as u8),
+        TargetKind::Immediate16 | TargetKind::Address16 => res.extend((val as u16).to_le_bytes()),
+        _ => {}
+      }
+    }
+  }
+
+  Ok(res)
+}
+
+/// Boots a ROM, with relaxed header checks, and runs it for `frames` frames.
+pub fn run_frames(rom: &[u8], frames: usize) -> Result<Gameboy, Error> {
+  let mut gb = Gameboy::boot_with_options(rom, &LoadOptions::tolerant())?;
+  for _ in 0..frames {
+    gb.step_until_vblank();
+  }
+  Ok(gb)
+}
+
+/// Serializes the shades of the screen as an ASCII PGM image, with shade 0 as white.
+fn to_pgm(screen: &FrameBuffer) -> String {
+  let mut res = format!("P2\n{} {}\n3\n", screen.width, screen.height);
+  for row in screen.indexed.chunks(screen.width) {
+    let row = row.iter().map(|shade| (3 - shade).to_string()).collect::<Vec<_>>();
+    res.push_str(&row.join(" "));
+    res.push('\n');
+  }
+  res
+}
+
+fn parse_pgm(pgm: &str) -> Option<(usize, usize, Vec<u8>)> {
+  let mut values = pgm.lines()
+    .filter(|line| !line.starts_with('#'))
+    .flat_map(str::split_whitespace);
+  if values.next()? != "P2" { return None; }
+  let width = values.next()?.parse().ok()?;
+  let height = values.next()?.parse().ok()?;
+  if values.next()? != "3" { return None; }
+  let shades = values
+    .map(|v| v.parse::<u8>().ok().filter(|v| *v <= 3).map(|v| 3 - v))
+    .collect::<Option<Vec<_>>>()?;
+  Some((width, height, shades))
+}
+
+/// Compares the shades of the screen with a reference PGM image, ignoring the palette.
+/// On mismatch, the screen is saved next to the reference with an `.actual.pgm` extension.
+///
+/// With the `BLESS` environment variable set, the reference is overwritten instead.
+pub fn compare_screen(screen: &FrameBuffer, reference: impl AsRef<Path>) -> Result<(), String> {
+  let reference = reference.as_ref();
+  let actual = to_pgm(screen);
+
+  if env::var_os("BLESS").is_some() {
+    if let Some(dir) = reference.parent() {
+      fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+    }
+    return fs::write(reference, actual).map_err(|e| e.to_string());
+  }
+
+  let expected = fs::read_to_string(reference)
+    .map_err(|e| format!("{}: {e}, run with BLESS=1 to record it", reference.display()))?;
+  let (width, height, expected) = parse_pgm(&expected)
+    .ok_or(format!("{}: not an ASCII PGM image with 4 shades", reference.display()))?;
+
+  let diffs = (width, height) != (screen.width, screen.height) || expected != screen.indexed;
+  if !diffs { return Ok(()); }
+
+  let actual_path = reference.with_extension("actual.pgm");
+  let _ = fs::write(&actual_path, actual);
+  let mismatches = expected.iter().zip(&screen.indexed).filter(|(a, b)| a != b).count();
+  let first = expected.iter().zip(&screen.indexed).position(|(a, b)| a != b)
+    .map(|i| format!(", first at ({}, {})", i % width, i / width))
+    .unwrap_or_default();
+  Err(format!(
+    "{}: {mismatches} pixels differ{first}, the screen was saved to {}",
+    reference.display(), actual_path.display(),
+  ))
+}
+
+/// A ROM keeping the CPU busy with the LCD off, to measure the CPU and bus alone.
+pub fn cpu_bound_rom() -> Vec<u8> {
+  RomBuilder::new()
+    .code(&asm![
+      "LD A, 0",
+      "LD ($FF40), A",
+      "loop:",
+      "LD HL, $C000",
+      "LD B, 0",
+      "inner:",
+      "LD A, (HL)",
+      "ADD B",
+      "LD (HL+), A",
+      "PUSH HL",
+      "POP DE",
+      "DEC B",
+      "JR NZ, inner",
+      "JR loop",
+    ])
+    .build()
+}
+
+/// A ROM drawing the background, the window and 40 8x16 objects on every line, scrolling every frame,
+/// to measure the PPU.
+pub fn ppu_bound_rom() -> Vec<u8> {
+  RomBuilder::new()
+    .code(&asm![
+      "LD A, 0",
+      "LD ($FF40), A",
+      // tiles and tilemaps, with the low byte of their address
+      "LD HL, $8000",
+      "vram:",
+      "LD A, L",
+      "LD (HL+), A",
+      "LD A, H",
+      "CP $A0",
+      "JR NZ, vram",
+      // objects spread over the screen, with every attribute
+      "LD HL, $FE00",
+      "oam:",
+      "LD A, L",
+      "LD (HL+), A",
+      "CP $9F",
+      "JR NZ, oam",
+      "LD A, 72",
+      "LD ($FF4A), A",
+      "LD A, 87",
+      "LD ($FF4B), A",
+      "LD A, $E7",
+      "LD ($FF40), A",
+      "LD HL, $FF43",
+      "loop:",
+      "INC (HL)",
+      // wait for the next vblank
+      "vblank_end:",
+      "LD A, ($FF44)",
+      "CP 144",
+      "JR Z, vblank_end",
+      "vblank:",
+      "LD A, ($FF44)",
+      "CP 144",
+      "JR NZ, vblank",
+      "JR loop",
+    ])
+    .build()
+}
+
+#[cfg(test)]
+mod testing_tests {
+  use super::*;
+  use crate::gb::Gameboy;
+
+  #[test]
+  fn assemble_instructions() {
+    let code = assemble(0x150, &[
+      "start:",
+      "LD A, $12",
+      "AND $0F",
+      "LD (HL+), A",
+      "LDH ($80), A",
+      "LD ($C000), A",
+      "BIT 7, (HL)",
+      "RST $38",
+      "LD HL, SP+2",
+      "JR start",
+      "JP start",
+      "DB $DE, $AD",
+    ]).unwrap();
+
+    assert_eq!(code, [
+      0x3E, 0x12,
+      0xE6, 0x0F,
+      0x22,
+      0xE0, 0x80,
+      0xEA, 0x00, 0xC0,
+      0xCB, 0x7E,
+      0xFF,
+      0xF8, 0x02,
+      0x18, 0xEF,
+      0xC3, 0x50, 0x01,
+      0xDE, 0xAD,
+    ]);
+  }
+
+  #[test]
+  fn run_built_rom() {
+    let rom = RomBuilder::new()
+      .code(&asm![
+        "LD A, 20",
+        "LD B, 22",
+        "ADD A, B",
+        "LD ($C000), A",
+        "loop:",
+        "JR loop",
+      ])
+      .build();
+
+    let mut gb = Gameboy::boot_from_bytes(&rom).unwrap();
+    for _ in 0..16 { gb.step(); }
+    assert_eq!(gb.get_cpu().peek(0xC000), 42);
+  }
+
+  #[test]
+  fn bench_scenes() {
+    let mut gb = Gameboy::boot_from_bytes(&ppu_bound_rom()).unwrap();
+    gb.run_tcycles(70224 * 10);
+    gb.step_until_vblank();
+    let scx = gb.get_cpu().peek(0xFF43);
+    gb.step_until_vblank();
+    assert_eq!(gb.get_cpu().peek(0xFF43), scx.wrapping_add(1));
+
+    let mut gb = Gameboy::boot_from_bytes(&cpu_bound_rom()).unwrap();
+    gb.run_tcycles(70224);
+    assert!((0x150..0x165).contains(&gb.get_cpu().pc));
+  }
+}
@@ -3,12 +3,13 @@
 // CPU freq / Timer divider =  4194304 Hz / 16384 Hz = 256
 
 use bitflags::bitflags;
+use serde::{Deserialize, Serialize};
 
-use crate::bus;
+use crate::bus::IFlags;
 
 
 bitflags! {
-  #[derive(Default, Clone, Copy)]
+  #[derive(Default, Clone, Copy, Serialize, Deserialize)]
   struct Flags: u8 {
     const unused = 0b1111_1000;
     const enable = 0b100;
@@ -16,6 +17,7 @@ bitflags! {
   }
 }
 
+#[derive(Serialize, Deserialize)]
 pub struct Timer {
   pub div: u16,
   tima: u8,
@@ -24,11 +26,22 @@ pub struct Timer {
   tima_just_reloaded: bool,
   tma: u8,
   tac: Flags,
-  intf: bus::InterruptFlags,
+  /// Falling edges of DIV bit 12 since the bus last took them, which clock the APU frame sequencer
+  #[serde(default)]
+  pub(crate) div_apu_edges: u8,
+  /// Interrupts raised since IF was last written, see [`Bus::intf`](crate::bus::Bus::intf)
+  #[serde(default)]
+  pub(crate) intf: IFlags,
+}
+
+impl Default for Timer {
+  fn default() -> Self {
+    Self::new()
+  }
 }
 
 impl Timer {
-  pub fn new(intf: bus::InterruptFlags) -> Self {
+  pub fn new() -> Self {
     Self {
       div: 0xABCC,
       tima: 0,
@@ -37,16 +50,32 @@ impl Timer {
       tima_just_reloaded: false,
       tma: 0,
       tac: Flags::default(),
-      intf,
+      div_apu_edges: 0,
+      intf: IFlags::empty(),
     }
   }
 
+  /// TIMA is clocked on the falling edges of the DIV bit selected by TAC, ANDed with the enable bit.
+  /// So resetting DIV, or changing TAC, clocks it when they make this signal fall.
+  fn tima_signal(&self) -> bool {
+    self.tac.contains(Flags::enable) && self.div & self.tima_clock != 0
+  }
+
   fn tick_tima(&mut self) {
-    if self.tac.contains(Flags::enable) {
-      let (res, overflow) = self.tima.overflowing_add(1);
-      self.tima = res;
-      self.tima_overflow_delay = if overflow { 4 } else { 0 };
-    }
+    let (res, overflow) = self.tima.overflowing_add(1);
+    self.tima = res;
+    self.tima_overflow_delay = if overflow { 4 } else { 0 };
+  }
+
+  /// Sets DIV, clocking what its falling bits drive.
+  fn set_div(&mut self, div: u16) {
+    let signal = self.tima_signal();
+    // in double speed it would be bit 13
+    let apu_bit = self.div & (1 << 12) != 0;
+
+    self.div = div;
+    if signal && !self.tima_signal() { self.tick_tima(); }
+    if apu_bit && self.div & (1 << 12) == 0 { self.div_apu_edges += 1; }
   }
 
   pub fn tick(&mut self) {
@@ -57,16 +86,11 @@ impl Timer {
         if self.tima_overflow_delay == 0 {
           self.tima = self.tma;
           self.tima_just_reloaded = true;
-          bus::send_interrupt(&self.intf, bus::IFlags::timer);
+          self.intf.insert(IFlags::timer);
         }
     }
       
-    let new_div = self.div.wrapping_add(1);
-    if self.div & self.tima_clock != 0 && new_div & self.tima_clock == 0 {
-      self.tick_tima();
-    }
-
-    self.div = new_div;
+    self.set_div(self.div.wrapping_add(1));
   }
 
   fn tima_clock_bit(&self) -> u16 {
@@ -91,13 +115,7 @@ impl Timer {
 
   pub fn write(&mut self, addr: u16, val: u8) {
     match addr {
-      0xFF04 => {
-        if self.div & self.tima_clock != 0 {
-          self.tick_tima();
-        }
-
-        self.div = 0;
-      }
+      0xFF04 => self.set_div(0),
       0xFF05 => {
         // https://gbdev.io/pandocs/Timer_Obscure_Behaviour.html#timer-overflow-behavior
 
@@ -116,16 +134,68 @@ impl Timer {
         }
       }
       0xFF07 => {
+        let signal = self.tima_signal();
         self.tac = Flags::from_bits_retain(val & 0b111);
         self.tima_clock = self.tima_clock_bit();
-
-        if self.div & self.tima_clock != 0 && !self.tac.contains(Flags::enable) {
-          self.tick_tima();
-        }
-
-        // TODO: TAC TIMA increment glitch
+        if signal && !self.tima_signal() { self.tick_tima(); }
       }
       _ => {}
     }
   }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod timer_tests {
+  use super::*;
+
+  fn timer(div: u16, tac: u8) -> Timer {
+    let mut timer = Timer::new();
+    timer.write(0xFF07, tac);
+    timer.div = div;
+    timer
+  }
+
+  #[test]
+  fn div_write_glitch() {
+    // 262144 Hz, clocked by bit 3
+    let mut t = timer(1 << 3, 0b101);
+    t.write(0xFF04, 0);
+    assert_eq!(t.read(0xFF05), 1);
+
+    let mut t = timer(1 << 2, 0b101);
+    t.write(0xFF04, 0);
+    assert_eq!(t.read(0xFF05), 0);
+  }
+
+  #[test]
+  fn tac_glitch() {
+    // disabling while the selected bit is set
+    let mut t = timer(1 << 3, 0b101);
+    t.write(0xFF07, 0b001);
+    assert_eq!(t.read(0xFF05), 1);
+
+    // switching to a clear bit
+    let mut t = timer(1 << 3, 0b101);
+    t.write(0xFF07, 0b110);
+    assert_eq!(t.read(0xFF05), 1);
+
+    // switching to a set bit, or from a clear one
+    let mut t = timer(1 << 3 | 1 << 5, 0b101);
+    t.write(0xFF07, 0b110);
+    let mut u = timer(1 << 5, 0b101);
+    u.write(0xFF07, 0b100);
+    assert_eq!((t.read(0xFF05), u.read(0xFF05)), (0, 0));
+  }
+
+  #[test]
+  fn div_apu_edges() {
+    let mut t = timer(0, 0);
+    for _ in 0..8192 * 2 { t.tick(); }
+    assert_eq!(t.div_apu_edges, 2);
+
+    // resetting DIV with bit 12 set is an edge too
+    let mut t = timer(1 << 12, 0);
+    t.write(0xFF04, 0);
+    assert_eq!(t.div_apu_edges, 1);
+  }
+}
@@ -0,0 +1,394 @@
+//! Post-processing of the [`FrameBuffer`] before it is displayed.
+//!
+//! Filters implement [`VideoFilter`], and are chained with a [`FilterChain`]:
+//! ```no_run
+//! # use tomboy_emulator::video::{FilterChain, Scale2x, Scanlines};
+//! # let gb = tomboy_emulator::gb::Gameboy::boot_from_bytes(&[]).unwrap();
+//! let mut filters = FilterChain::default();
+//! filters.push(Scale2x);
+//! filters.push(Scanlines::default());
+//! // 640x576, for the 160x144 screen
+//! let (width, height) = filters.output_size(160, 144);
+//! let shown = filters.apply(gb.get_screen());
+//! ```
+//! The output keeps the pixel format of the input. Its [`FrameBuffer::indexed`] holds the shade
+//! of the source pixel every output pixel comes from, but not its palette.
+
+use crate::frame::{mix, Color, FrameBuffer};
+
+/// A filter drawing `src` onto `dst`, which is already sized with [`VideoFilter::output_size`].
+pub trait VideoFilter {
+  /// The size of the output for a `width`x`height` input.
+  fn output_size(&self, width: usize, height: usize) -> (usize, usize) {
+    (width, height)
+  }
+
+  fn apply(&mut self, src: &FrameBuffer, dst: &mut FrameBuffer);
+}
+
+/// Filters applied one after the other, with a buffer for the output of each.
+#[derive(Default)]
+pub struct FilterChain {
+  filters: Vec<(Box<dyn VideoFilter>, FrameBuffer)>,
+}
+
+impl FilterChain {
+  pub fn push(&mut self, filter: impl VideoFilter + 'static) {
+    self.push_boxed(Box::new(filter));
+  }
+
+  pub fn push_boxed(&mut self, filter: Box<dyn VideoFilter>) {
+    self.filters.push((filter, FrameBuffer::new(0, 0)));
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.filters.is_empty()
+  }
+
+  pub fn clear(&mut self) {
+    self.filters.clear();
+  }
+
+  pub fn output_size(&self, width: usize, height: usize) -> (usize, usize) {
+    self.filters.iter().fold((width, height), |(w, h), (filter, _)| filter.output_size(w, h))
+  }
+
+  /// Runs the filters on `src`, and returns the output of the last one, or `src` if there are none.
+  pub fn apply<'a>(&'a mut self, src: &'a FrameBuffer) -> &'a FrameBuffer {
+    let mut input = src;
+    for (filter, output) in self.filters.iter_mut() {
+      let (width, height) = filter.output_size(input.width, input.height);
+      if output.width != width || output.height != height || output.format() != input.format() {
+        *output = FrameBuffer::with_format(width, height, input.format());
+      }
+      filter.apply(input, output);
+      input = output;
+    }
+    input
+  }
+}
+
+fn darken(color: Color, amount: f32) -> Color {
+  mix(color, (0, 0, 0), amount)
+}
+
+/// The color at `x`,`y`, clamped to the edges.
+fn pixel(src: &FrameBuffer, x: isize, y: isize) -> Color {
+  let x = x.clamp(0, src.width as isize - 1) as usize;
+  let y = y.clamp(0, src.height as isize - 1) as usize;
+  src.get_color(x, y)
+}
+
+/// Draws the `scale`x`scale` block of output pixels of the source pixel at `x`,`y`,
+/// with the color `block` gives for each of them.
+fn scale_pixel(src: &FrameBuffer, dst: &mut FrameBuffer, x: usize, y: usize, scale: usize, block: impl Fn(usize, usize) -> Color) {
+  let shade = src.indexed[y*src.width + x];
+  for dy in 0..scale {
+    for dx in 0..scale {
+      let (ox, oy) = (x*scale + dx, y*scale + dy);
+      dst.set_color(ox, oy, block(dx, dy));
+      dst.indexed[oy*dst.width + ox] = shade;
+    }
+  }
+}
+
+/// Scales by `scale`, darkening the right and bottom edges of every pixel, like the gaps between LCD dots.
+pub struct LcdGrid {
+  pub scale: usize,
+  /// From 0.0 (no grid) to 1.0 (black grid)
+  pub strength: f32,
+}
+
+impl Default for LcdGrid {
+  fn default() -> Self {
+    Self { scale: 3, strength: 0.3 }
+  }
+}
+
+impl VideoFilter for LcdGrid {
+  fn output_size(&self, width: usize, height: usize) -> (usize, usize) {
+    (width * self.scale, height * self.scale)
+  }
+
+  fn apply(&mut self, src: &FrameBuffer, dst: &mut FrameBuffer) {
+    let last = self.scale - 1;
+    for y in 0..src.height {
+      for x in 0..src.width {
+        let color = src.get_color(x, y);
+        scale_pixel(src, dst, x, y, self.scale, |dx, dy| {
+          if dx == last || dy == last { darken(color, self.strength) } else { color }
+        });
+      }
+    }
+  }
+}
+
+/// Doubles the size, darkening every other row, like a CRT.
+pub struct Scanlines {
+  /// From 0.0 (no scanlines) to 1.0 (black scanlines)
+  pub strength: f32,
+}
+
+impl Default for Scanlines {
+  fn default() -> Self {
+    Self { strength: 0.4 }
+  }
+}
+
+impl VideoFilter for Scanlines {
+  fn output_size(&self, width: usize, height: usize) -> (usize, usize) {
+    (width * 2, height * 2)
+  }
+
+  fn apply(&mut self, src: &FrameBuffer, dst: &mut FrameBuffer) {
+    for y in 0..src.height {
+      for x in 0..src.width {
+        let color = src.get_color(x, y);
+        scale_pixel(src, dst, x, y, 2, |_, dy| {
+          if dy == 1 { darken(color, self.strength) } else { color }
+        });
+      }
+    }
+  }
+}
+
+/// The slow response of the dot-matrix LCD: pixels take a few frames to change color, leaving trails behind moving objects.
+pub struct Ghosting {
+  /// How much of the previous output is kept, from 0.0 (none) to 1.0 (the screen never changes)
+  pub persistence: f32,
+  previous: Vec<(f32, f32, f32)>,
+}
+
+impl Ghosting {
+  pub fn new(persistence: f32) -> Self {
+    Self { persistence, previous: Vec::new() }
+  }
+}
+
+impl Default for Ghosting {
+  fn default() -> Self {
+    Self::new(0.4)
+  }
+}
+
+impl VideoFilter for Ghosting {
+  fn apply(&mut self, src: &FrameBuffer, dst: &mut FrameBuffer) {
+    let fresh = self.previous.len() != src.width * src.height;
+    if fresh {
+      self.previous = vec![(0.0, 0.0, 0.0); src.width * src.height];
+    }
+
+    for y in 0..src.height {
+      for x in 0..src.width {
+        let i = y*src.width + x;
+        let (r, g, b) = src.get_color(x, y);
+        let current = (r as f32, g as f32, b as f32);
+        let prev = if fresh { current } else { self.previous[i] };
+        let t = self.persistence;
+        let out = (
+          prev.0*t + current.0*(1.0-t),
+          prev.1*t + current.1*(1.0-t),
+          prev.2*t + current.2*(1.0-t),
+        );
+        self.previous[i] = out;
+        dst.set_color(x, y, (out.0.round() as u8, out.1.round() as u8, out.2.round() as u8));
+        dst.indexed[i] = src.indexed[i];
+      }
+    }
+  }
+}
+
+/// Doubles the size, rounding diagonal edges by copying neighbors (AdvMAME2x/EPX).
+pub struct Scale2x;
+
+impl VideoFilter for Scale2x {
+  fn output_size(&self, width: usize, height: usize) -> (usize, usize) {
+    (width * 2, height * 2)
+  }
+
+  fn apply(&mut self, src: &FrameBuffer, dst: &mut FrameBuffer) {
+    for y in 0..src.height {
+      for x in 0..src.width {
+        let (xi, yi) = (x as isize, y as isize);
+        let e = pixel(src, xi, yi);
+        let b = pixel(src, xi, yi-1);
+        let d = pixel(src, xi-1, yi);
+        let f = pixel(src, xi+1, yi);
+        let h = pixel(src, xi, yi+1);
+
+        let block = if b != h && d != f {
+          [
+            if d == b { d } else { e },
+            if b == f { f } else { e },
+            if d == h { d } else { e },
+            if h == f { f } else { e },
+          ]
+        } else {
+          [e; 4]
+        };
+        scale_pixel(src, dst, x, y, 2, |dx, dy| block[dy*2 + dx]);
+      }
+    }
+  }
+}
+
+/// Triples the size, rounding diagonal edges by copying neighbors (AdvMAME3x).
+pub struct Scale3x;
+
+impl VideoFilter for Scale3x {
+  fn output_size(&self, width: usize, height: usize) -> (usize, usize) {
+    (width * 3, height * 3)
+  }
+
+  fn apply(&mut self, src: &FrameBuffer, dst: &mut FrameBuffer) {
+    for y in 0..src.height {
+      for x in 0..src.width {
+        let (xi, yi) = (x as isize, y as isize);
+        let [a, b, c] = [-1, 0, 1].map(|dx| pixel(src, xi+dx, yi-1));
+        let [d, e, f] = [-1, 0, 1].map(|dx| pixel(src, xi+dx, yi));
+        let [g, h, i] = [-1, 0, 1].map(|dx| pixel(src, xi+dx, yi+1));
+
+        let block = if b != h && d != f {
+          [
+            if d == b { d } else { e },
+            if (d == b && e != c) || (b == f && e != a) { b } else { e },
+            if b == f { f } else { e },
+            if (d == b && e != g) || (d == h && e != a) { d } else { e },
+            e,
+            if (b == f && e != i) || (h == f && e != c) { f } else { e },
+            if d == h { d } else { e },
+            if (d == h && e != i) || (h == f && e != g) { h } else { e },
+            if h == f { f } else { e },
+          ]
+        } else {
+          [e; 9]
+        };
+        scale_pixel(src, dst, x, y, 3, |dx, dy| block[dy*3 + dx]);
+      }
+    }
+  }
+}
+
+/// Doubles the size, blending along the edges it detects. A simplified hq2x, not hq2x itself:
+/// colors are compared in YUV with its thresholds, but the corners are interpolated
+/// with a few rules, instead of its 256 cases table.
+pub struct Smooth2x;
+
+impl Smooth2x {
+  fn similar(a: Color, b: Color) -> bool {
+    let yuv = |(r, g, b): Color| {
+      let (r, g, b) = (r as f32, g as f32, b as f32);
+      (
+        0.299*r + 0.587*g + 0.114*b,
+        -0.169*r - 0.331*g + 0.5*b,
+        0.5*r - 0.419*g - 0.081*b,
+      )
+    };
+    let (a, b) = (yuv(a), yuv(b));
+    (a.0 - b.0).abs() <= 48.0 && (a.1 - b.1).abs() <= 7.0 && (a.2 - b.2).abs() <= 6.0
+  }
+
+  /// The color of the corner of `e` facing its neighbors `h` (horizontal), `v` (vertical) and `d` (diagonal).
+  fn corner(e: Color, h: Color, v: Color, d: Color) -> Color {
+    let edge_h = !Self::similar(e, h);
+    let edge_v = !Self::similar(e, v);
+    if edge_h && edge_v && Self::similar(h, v) {
+      // a diagonal edge crosses the corner
+      if Self::similar(h, d) { mix(e, h, 0.5) } else { mix(e, h, 0.25) }
+    } else if edge_h && edge_v {
+      mix(e, mix(h, v, 0.5), 0.25)
+    } else {
+      e
+    }
+  }
+}
+
+impl VideoFilter for Smooth2x {
+  fn output_size(&self, width: usize, height: usize) -> (usize, usize) {
+    (width * 2, height * 2)
+  }
+
+  fn apply(&mut self, src: &FrameBuffer, dst: &mut FrameBuffer) {
+    for y in 0..src.height {
+      for x in 0..src.width {
+        let (xi, yi) = (x as isize, y as isize);
+        let e = pixel(src, xi, yi);
+        let block = [(-1, -1), (1, -1), (-1, 1), (1, 1)].map(|(dx, dy)| {
+          let h = pixel(src, xi+dx, yi);
+          let v = pixel(src, xi, yi+dy);
+          let d = pixel(src, xi+dx, yi+dy);
+          Self::corner(e, h, v, d)
+        });
+        scale_pixel(src, dst, x, y, 2, |dx, dy| block[dy*2 + dx]);
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod video_tests {
+  use super::*;
+
+  /// A 3x3 frame with the shades of `rows`, in grayscale.
+  fn frame(rows: [[u8; 3]; 3]) -> FrameBuffer {
+    let mut fb = FrameBuffer::new(3, 3);
+    fb.set_palette(crate::frame::GRAYSCALE);
+    for (y, row) in rows.iter().enumerate() {
+      for (x, shade) in row.iter().enumerate() {
+        fb.set_pixel(x, y, *shade);
+      }
+    }
+    fb
+  }
+
+  #[test]
+  fn chain() {
+    let mut filters = FilterChain::default();
+    let src = frame([[0; 3]; 3]);
+    assert!(std::ptr::eq(filters.apply(&src), &src));
+
+    filters.push(Scale2x);
+    filters.push(LcdGrid::default());
+    assert_eq!(filters.output_size(160, 144), (960, 864));
+
+    let out = filters.apply(&src);
+    assert_eq!((out.width, out.height), (18, 18));
+    assert_eq!(out.get_color(0, 0), (255, 255, 255));
+    assert_eq!(out.get_color(2, 2), darken((255, 255, 255), 0.3));
+  }
+
+  #[test]
+  fn scale2x_rounds_diagonals() {
+    // a black diagonal going down to the right
+    let src = frame([
+      [3, 0, 0],
+      [0, 3, 0],
+      [0, 0, 3],
+    ]);
+    let mut dst = FrameBuffer::new(6, 6);
+    Scale2x.apply(&src, &mut dst);
+
+    let black = (0, 0, 0);
+    let white = (255, 255, 255);
+    // the white pixel right of the center one gets its lower left corner filled
+    assert_eq!(dst.get_color(4, 3), black);
+    assert_eq!(dst.get_color(5, 3), white);
+    assert_eq!(dst.get_color(4, 2), white);
+    // the center pixel of the line stays solid
+    assert_eq!(dst.get_color(2, 2), black);
+    assert_eq!(dst.get_color(3, 3), black);
+  }
+
+  #[test]
+  fn ghosting_fades() {
+    let mut ghosting = Ghosting::new(0.5);
+    let mut dst = FrameBuffer::new(3, 3);
+    ghosting.apply(&frame([[3; 3]; 3]), &mut dst);
+    assert_eq!(dst.get_color(1, 1), (0, 0, 0));
+
+    ghosting.apply(&frame([[0; 3]; 3]), &mut dst);
+    assert_eq!(dst.get_color(1, 1), (128, 128, 128));
+    ghosting.apply(&frame([[0; 3]; 3]), &mut dst);
+    assert_eq!(dst.get_color(1, 1), (191, 191, 191));
+  }
+}
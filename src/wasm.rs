@@ -0,0 +1,81 @@
+//! Browser bindings, through `wasm-bindgen`. The crate only builds an rlib, build the module with
+//! `cargo rustc --lib --release --features wasm --target wasm32-unknown-unknown --crate-type cdylib`.
+//!
+//! ```js
+//! const gb = new WasmGameboy(new Uint8Array(await file.arrayBuffer()));
+//! function frame() {
+//!   gb.step_frame();
+//!   const pixels = new Uint8ClampedArray(wasm.memory.buffer, gb.screen_ptr(), gb.screen_len());
+//!   ctx.putImageData(new ImageData(pixels, gb.width(), gb.height()), 0, 0);
+//!   queueAudio(gb.samples());
+//!   requestAnimationFrame(frame);
+//! }
+//! ```
+
+use wasm_bindgen::prelude::*;
+
+use crate::{cart::LoadOptions, gb::Gameboy, joypad::Button};
+
+#[wasm_bindgen]
+pub struct WasmGameboy {
+  gb: Gameboy,
+}
+
+#[wasm_bindgen]
+impl WasmGameboy {
+  /// Boots a ROM, with relaxed header checks.
+  #[wasm_bindgen(constructor)]
+  pub fn new(rom: &[u8]) -> Result<WasmGameboy, JsError> {
+    let gb = Gameboy::boot_with_options(rom, &LoadOptions::tolerant())?;
+    Ok(Self { gb })
+  }
+
+  pub fn step_frame(&mut self) {
+    self.gb.step_until_vblank();
+  }
+
+  pub fn reset(&mut self) {
+    self.gb.reset();
+  }
+
+  pub fn width(&self) -> usize {
+    self.gb.get_resolution().0
+  }
+
+  pub fn height(&self) -> usize {
+    self.gb.get_resolution().1
+  }
+
+  /// Address of the RGBA screen in the wasm memory.
+  /// It is only valid until the next call that mutably borrows the emulator.
+  pub fn screen_ptr(&self) -> *const u8 {
+    self.gb.get_screen().buffer.as_ptr()
+  }
+
+  pub fn screen_len(&self) -> usize {
+    self.gb.get_screen().buffer.len()
+  }
+
+  /// Presses a button by name, see [`Button`]'s `FromStr`.
+  pub fn press(&mut self, button: &str) -> Result<(), JsError> {
+    let button = button.parse::<Button>().map_err(|e| JsError::new(&e))?;
+    self.gb.get_joypad().press(button);
+    Ok(())
+  }
+
+  pub fn release(&mut self, button: &str) -> Result<(), JsError> {
+    let button = button.parse::<Button>().map_err(|e| JsError::new(&e))?;
+    self.gb.get_joypad().release(button);
+    Ok(())
+  }
+
+  /// Drains the interleaved stereo samples, see [`Gameboy::get_samples`].
+  pub fn samples(&mut self) -> Vec<f32> {
+    self.gb.get_samples()
+  }
+
+  pub fn set_sample_rate(&mut self, sample_rate: u32) {
+    let config = self.gb.get_apu().config();
+    self.gb.set_audio_config(crate::apu::AudioConfig { sample_rate, ..config });
+  }
+}
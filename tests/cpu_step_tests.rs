@@ -1,31 +1,33 @@
 #[cfg(test)]
 mod cpu_step_tests {
-    use core::fmt;
-    use std::fs;
+  use core::fmt;
+  use std::{env, fs, path::PathBuf};
 
-    use prettydiff::diff_words;
-    use serde::Deserialize;
-    use tomboy_emulator::{cpu::{self, Cpu}, instr::INSTRUCTIONS, mem::Ram64kb};
+  use prettydiff::diff_words;
+  use serde::Deserialize;
+  use tomboy_emulator::{cpu::{self, Cpu}, mem::{Cycle, Memory, Ram64kb}};
 
   #[derive(Deserialize, Debug, PartialEq, Eq)]
   struct CpuMock {
     a: u8, b: u8, c: u8, d: u8, e: u8, f: u8, h: u8, l: u8,
     pc: u16, sp: u16, ram: Vec<(u16, u8)>,
   }
-  
+
   impl fmt::Display for CpuMock {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-      write!(f, "{}", format!("{:X?}", self))
+      write!(f, "{self:X?}")
     }
   }
+
   impl CpuMock {
-    fn from_cpu(cpu: &Cpu<Ram64kb>) -> Self {
+    /// The CPU registers, and the memory at the addresses of `expected`.
+    fn from_cpu(cpu: &Cpu<Ram64kb>, expected: &CpuMock) -> Self {
       Self {
-        pc: cpu.pc, sp: cpu.sp, 
-        a: cpu.a, b: cpu.bc.hi(), c: cpu.bc.lo(), 
-        d: cpu.de.hi(), e: cpu.de.lo(), f: cpu.f.bits(), 
+        pc: cpu.pc, sp: cpu.sp,
+        a: cpu.a, b: cpu.bc.hi(), c: cpu.bc.lo(),
+        d: cpu.de.hi(), e: cpu.de.lo(), f: cpu.f.bits(),
         h: cpu.hl.hi(), l: cpu.hl.lo(),
-        ram: Vec::new(),
+        ram: expected.ram.iter().map(|(addr, _)| (*addr, cpu.peek(*addr))).collect(),
       }
     }
   }
@@ -45,10 +47,11 @@ mod cpu_step_tests {
     cpu.pc = mock.pc;
 
     for (addr, byte) in &mock.ram {
-      cpu.write(*addr, *byte);
+      cpu.bus.write(*addr, *byte);
     }
 
     cpu.mcycles = 0;
+    cpu.bus.record_cycles();
     cpu
   }
 
@@ -59,79 +62,95 @@ mod cpu_step_tests {
     start: CpuMock,
     #[serde(alias = "final")]
     end: CpuMock,
-    cycles: Vec<Option<(u16, u8, String)>>,
+    /// The bus activity of every M-cycle: the address, the value and `r-m` or `-wm` for reads and writes.
+    /// Internal cycles are `null`, or have no `r` or `w`.
+    cycles: Vec<Option<(u16, Option<u8>, String)>>,
   }
 
-  #[test]
-  fn cpu_test_one() {
-    let json = include_str!("sm83/v1/00.json");
-    let test: Vec<Test> = serde_json::from_str(json).unwrap();
-  
-    let mut cpu = cpu_from_mock(&test[0].start);
-
-    while cpu.mcycles < test[0].cycles.len() {
-      println!("{:#X?}", cpu);
-      println!("{:#X?}", &INSTRUCTIONS[cpu.peek(cpu.pc) as usize]);
-      cpu.step();
+  fn expected_cycle(cycle: &Option<(u16, Option<u8>, String)>) -> Cycle {
+    match cycle {
+      Some((addr, Some(val), kind)) if kind.contains('r') => Cycle::Read(*addr, *val),
+      Some((addr, Some(val), kind)) if kind.contains('w') => Cycle::Write(*addr, *val),
+      _ => Cycle::Internal,
     }
+  }
 
+  /// Runs one instruction, and returns a description of the difference on failure.
+  fn run_test(test: &Test) -> Result<(), String> {
+    let mut cpu = cpu_from_mock(&test.start);
+    cpu.step();
 
-    let mut my_end = CpuMock::from_cpu(&cpu);
-    for (addr, _) in &test[0].end.ram {
-      my_end.ram.push((*addr, cpu.peek(*addr)))
+    let mine = CpuMock::from_cpu(&cpu, &test.end);
+    if mine != test.end {
+      return Err(format!("{}: {}", test.name, diff_words(&mine.to_string(), &test.end.to_string())));
     }
-
-    assert_eq!(test[0].end, my_end, 
-      "Found error {:#X?}\n{}",
-      test[0].name, diff_words(&my_end.to_string(), &test[0].end.to_string()));
+    if cpu.mcycles != test.cycles.len() {
+      return Err(format!("{}: took {} M-cycles, expected {}", test.name, cpu.mcycles, test.cycles.len()));
+    }
+    let expected = test.cycles.iter().map(expected_cycle).collect::<Vec<_>>();
+    let cycles = cpu.bus.take_cycles();
+    if cycles != expected {
+      return Err(format!("{}: bus activity {cycles:X?}, expected {expected:X?}", test.name));
+    }
+    Ok(())
   }
 
-  #[test]
-fn cpu_test() {
-  let mut dir = fs::read_dir("./tests/sm83/v1/")
-    .expect("directory not found")
-    .enumerate();
-
-  while let Some((i, Ok(f))) = dir.next() {
-    let json_test = fs::read(f.path()).expect("couldnt't read file");
-    let tests: Vec<Test> = serde_json::from_slice(&json_test).expect("couldn't parse json");
-
-    println!("Testing file {i}: {:?}", f.file_name());
-
-    'testing: for test in tests.iter() {
-      let mut cpu = cpu_from_mock(&test.start);
-
-      while cpu.mcycles < test.cycles.len() {
-        cpu.step();
-      }
+  const INLINE_TESTS: &str = r#"[
+    {
+      "name": "3c 0000",
+      "initial": { "pc": 256, "sp": 0, "a": 15, "b": 0, "c": 0, "d": 0, "e": 0, "f": 16, "h": 0, "l": 0, "ime": 0, "ram": [[256, 60]] },
+      "final": { "pc": 257, "sp": 0, "a": 16, "b": 0, "c": 0, "d": 0, "e": 0, "f": 48, "h": 0, "l": 0, "ime": 0, "ram": [[256, 60]] },
+      "cycles": [[256, 60, "r-m"]]
+    },
+    {
+      "name": "e5 0000",
+      "initial": { "pc": 256, "sp": 53248, "a": 0, "b": 0, "c": 0, "d": 0, "e": 0, "f": 0, "h": 18, "l": 52, "ime": 0, "ram": [[256, 229]] },
+      "final": { "pc": 257, "sp": 53246, "a": 0, "b": 0, "c": 0, "d": 0, "e": 0, "f": 0, "h": 18, "l": 52, "ime": 0, "ram": [[53247, 18], [53246, 52]] },
+      "cycles": [[256, 229, "r-m"], null, [53247, 18, "-wm"], [53246, 52, "-wm"]]
+    },
+    {
+      "name": "cb 37 0000",
+      "initial": { "pc": 256, "sp": 0, "a": 240, "b": 0, "c": 0, "d": 0, "e": 0, "f": 240, "h": 0, "l": 0, "ime": 0, "ram": [[256, 203], [257, 55]] },
+      "final": { "pc": 258, "sp": 0, "a": 15, "b": 0, "c": 0, "d": 0, "e": 0, "f": 0, "h": 0, "l": 0, "ime": 0, "ram": [] },
+      "cycles": [[256, 203, "r-m"], [257, 55, "r-m"]]
+    }
+  ]"#;
 
-      let mut my_end = CpuMock::from_cpu(&cpu);
-      for (addr, _) in &test.end.ram {
-        my_end.ram.push((*addr, cpu.read(*addr)))
-      }
+  #[test]
+  fn inline_tests() {
+    let tests: Vec<Test> = serde_json::from_str(INLINE_TESTS).unwrap();
+    for test in &tests {
+      run_test(test).unwrap();
+    }
+  }
 
-      if my_end != test.end {
-        // let mut builder = colog::basic_builder();
-        // builder.filter_level(log::LevelFilter::Trace);
-        // builder.init();
-        
-        let mut log_cpu = cpu_from_mock(&test.start);
-
-        while log_cpu.mcycles < test.cycles.len() {
-          println!("{:X?}", log_cpu);
-          println!("{:X?}", &INSTRUCTIONS[log_cpu.peek(log_cpu.pc) as usize]);
-          log_cpu.step();
-        }
-
-        println!("{:X?}", log_cpu);
-
-        assert_eq!(my_end, test.end,
-          "Found error in file {:?}, test {:?}\n{}",
-          f.file_name(), test.name, 
-            diff_words(&my_end.to_string(), &test.end.to_string())
-        );
+  /// Runs the SingleStepTests SM83 suite placed in `tests/sm83/v1`, or in the directory in `TOMBOY_SM83_TESTS`.
+  /// The suite isn't distributed with the sources, the test passes without it.
+  #[test]
+  fn cpu_test() {
+    let dir = env::var_os("TOMBOY_SM83_TESTS")
+      .map(PathBuf::from)
+      .unwrap_or_else(|| PathBuf::from("./tests/sm83/v1/"));
+    let Ok(entries) = fs::read_dir(&dir) else {
+      println!("{} not found, skipping", dir.display());
+      return;
+    };
+
+    let mut files = entries.map(|e| e.unwrap().path()).collect::<Vec<_>>();
+    files.sort();
+
+    let mut failures = Vec::new();
+    for path in files {
+      let json = fs::read(&path).expect("couldn't read file");
+      let tests: Vec<Test> = serde_json::from_slice(&json).expect("couldn't parse json");
+
+      // the first failure of each opcode is enough
+      if let Some(e) = tests.iter().find_map(|test| run_test(test).err()) {
+        println!("{}: {e}", path.display());
+        failures.push(path);
       }
     }
+
+    assert!(failures.is_empty(), "{} opcodes failed: {failures:?}", failures.len());
   }
 }
-}
\ No newline at end of file
@@ -0,0 +1,182 @@
+#[cfg(test)]
+mod determinism_test {
+  use std::fs;
+
+  use tomboy_emulator::{asm, gb::Gameboy, joypad, testing::{assemble, RomBuilder}};
+
+  const FRAMES: usize = 600;
+  const GOLDEN: &str = "./tests/golden/surrogate.txt";
+
+  // A small title screen surrogate: scrolling background, a sprite moved with the dpad,
+  // a note every 32 frames on square1, and square2/noise triggered with A.
+  fn surrogate_rom() -> Vec<u8> {
+    let vblank_handler = assemble(0x40, &["RETI"]).unwrap();
+
+    let main = asm![
+      // OAM can't be written while the PPU scans it
+      "XOR A",
+      "LDH ($40), A",
+      "LD A, $E4",
+      "LDH ($47), A",
+      "LDH ($48), A",
+
+      // tile 1
+      "LD HL, $8010",
+      "LD B, 16",
+      "tile_loop:",
+      "LD A, B",
+      "LD (HL+), A",
+      "DEC B",
+      "JR NZ, tile_loop",
+
+      // checkerboard tilemap
+      "LD HL, $9800",
+      "LD BC, $0400",
+      "map_loop:",
+      "LD A, L",
+      "AND 1",
+      "LD (HL+), A",
+      "DEC BC",
+      "LD A, B",
+      "OR C",
+      "JR NZ, map_loop",
+
+      // sprite 0
+      "LD HL, $FE00",
+      "LD A, 80",
+      "LD (HL+), A",
+      "LD (HL+), A",
+      "LD A, 1",
+      "LD (HL+), A",
+      "XOR A",
+      "LD (HL), A",
+
+      "LD A, $93",
+      "LDH ($40), A",
+
+      // sound
+      "LD A, $80",
+      "LDH ($26), A",
+      "LD A, $FF",
+      "LDH ($25), A",
+      "LD A, $77",
+      "LDH ($24), A",
+      "LD A, $F3",
+      "LDH ($12), A",
+      "LDH ($17), A",
+      "LDH ($21), A",
+      "LD A, $80",
+      "LDH ($16), A",
+
+      "LD A, 1",
+      "LDH ($FF), A",
+      "EI",
+
+      "main:",
+      "HALT",
+      "NOP",
+      "LDH A, ($80)",
+      "INC A",
+      "LDH ($80), A",
+      "LD D, A",
+
+      "LDH A, ($43)",
+      "INC A",
+      "LDH ($43), A",
+
+      "LD A, $20",
+      "LDH ($00), A",
+      "LDH A, ($00)",
+      "LD HL, $FE01",
+      "BIT 0, A",
+      "JR NZ, no_right",
+      "INC (HL)",
+      "no_right:",
+      "BIT 1, A",
+      "JR NZ, no_left",
+      "DEC (HL)",
+      "no_left:",
+
+      "LD A, $10",
+      "LDH ($00), A",
+      "LDH A, ($00)",
+      "BIT 0, A",
+      "JR NZ, no_a",
+      "LD A, D",
+      "LDH ($18), A",
+      "LD A, $87",
+      "LDH ($19), A",
+      "LD A, $45",
+      "LDH ($22), A",
+      "LD A, $80",
+      "LDH ($23), A",
+      "no_a:",
+
+      "LD A, D",
+      "AND $1F",
+      "JR NZ, main",
+      "LD A, D",
+      "LDH ($13), A",
+      "LD A, $86",
+      "LDH ($14), A",
+      "JR main",
+    ];
+
+    RomBuilder::new()
+      .at(0x40, &vblank_handler)
+      .code(&main)
+      .build()
+  }
+
+  fn apply_input(gb: &mut Gameboy, frame: usize) {
+    let joypad = gb.get_joypad();
+    match frame {
+      60 => joypad.dpad_pressed(joypad::Flags::a_right),
+      180 => joypad.dpad_released(joypad::Flags::a_right),
+      240 => joypad.dpad_pressed(joypad::Flags::b_left),
+      300 => joypad.dpad_released(joypad::Flags::b_left),
+      400 => joypad.button_pressed(joypad::Flags::a_right),
+      420 => joypad.button_released(joypad::Flags::a_right),
+      _ => {}
+    }
+  }
+
+  fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, b| (hash ^ *b as u64).wrapping_mul(0x100000001b3))
+  }
+
+  fn golden_run() -> Vec<String> {
+    let mut gb = Gameboy::boot_from_bytes(&surrogate_rom()).unwrap();
+    let mut res = Vec::new();
+
+    for frame in 0..FRAMES {
+      apply_input(&mut gb, frame);
+      gb.step_until_vblank();
+
+      let video = fnv1a(0xcbf29ce484222325, &gb.get_screen().buffer);
+      let audio = gb.get_samples().iter()
+        .fold(0xcbf29ce484222325, |hash, s| fnv1a(hash, &s.to_le_bytes()));
+      res.push(format!("{video:016x} {audio:016x}"));
+    }
+
+    res
+  }
+
+  #[test]
+  fn surrogate_golden_run() {
+    let run = golden_run();
+    assert_eq!(run, golden_run(), "Two runs of the same input diverged");
+
+    // BLESS=1 cargo test --test determinism_test, to record a new golden run
+    if std::env::var("BLESS").is_ok() {
+      fs::write(GOLDEN, run.join("\n")).unwrap();
+      return;
+    }
+
+    let golden = fs::read_to_string(GOLDEN).expect("golden run not recorded");
+    assert_eq!(golden.lines().count(), FRAMES);
+    for (frame, (mine, expected)) in run.iter().zip(golden.lines()).enumerate() {
+      assert_eq!(mine, expected, "Frame {frame} diverged from the golden run (video audio)");
+    }
+  }
+}
@@ -1,185 +1,657 @@
-extern crate tomboy_emulator;
-
-#[cfg(test)]
-mod ppu_test {
-    use std::{error::Error, fs, time};
-
-    use sdl2::{event::Event, pixels::PixelFormatEnum};
-    use tomboy_emulator::{cpu::Cpu, frame::FrameBuffer};
-
-
-  #[test]
-  fn background() -> Result<(), Box<dyn Error>> {
-    let sdl = sdl2::init()?;
-    let video = sdl.video()?;
-    let ms_frame = time::Duration::from_secs_f64(1.0 / 60.0);
-  
-    const WIN_WIDTH: u32 = 32*16;
-    const WIN_HEIGHT: u32 = 12*16;
-  
-    let mut canvas = video.window("TomboyEmu", WIN_WIDTH*2, WIN_HEIGHT*2)
-      .position_centered().build()?.into_canvas()
-      .accelerated().target_texture().present_vsync()
-      .build()?;
-  
-    let mut events = sdl.event_pump()?;
-  
-    let mut emu = Cpu::new();
-    let rom = fs::read("./tests/roms/02-interrupts.gb")?;
-    // let rom = fs::read("./roms/Tetris.gb")?;
-
-  
-    let mut bus = emu.bus.borrow_mut();
-    let (left, _) = bus.mem.split_at_mut(rom.len());
-    left.copy_from_slice(&rom);
-    drop(bus);
-  
-    let texture_creator = canvas.texture_creator();
-    let mut texture = texture_creator
-      .create_texture_target(PixelFormatEnum::RGBA32, WIN_WIDTH, WIN_HEIGHT)?;
-  
-    let mut framebuf = FrameBuffer::new(WIN_WIDTH as usize, WIN_HEIGHT as usize);
-  
-    'running: loop {
-      let ms_since_frame_start = time::Instant::now();
-  
-      while emu.ppu.vblank.take().is_none() {
-        emu.step();
-      }
-  
-      for event in events.poll_iter() {
-        match event {
-          Event::Quit { .. } => break 'running,
-          Event::DropFile { filename, .. } => {
-            emu = Cpu::new();
-  
-            let rom = fs::read(filename)?;
-            let mut bus = emu.bus.borrow_mut();
-            let (left, _) = bus.mem.split_at_mut(rom.len());
-            left.copy_from_slice(&rom);
-            drop(bus);
-          }
-          _ => {}
-        }
-      }
-
-        
-      for i in 0..384 {
-        let x = i % (WIN_WIDTH as usize/16);
-        let y = i / (WIN_WIDTH as usize/16);
-        let tile_start = 0x8000 + i*16;
-        let tile = &emu.bus.borrow().mem[tile_start..tile_start+16];
-        framebuf.set_tile(x*16, y*16, &tile);
-      }
-
-      // for i in 0..20*18 {
-      //   let x = i % 20;
-      //   let y = i / 20;
-
-      //   let tile_id = emu.read(0x9800 + y*20 + x);
-      //   let tile_addr = emu.ppu.tile_addr(tile_id) as usize;
-      //   let tile = &emu.bus.borrow().mem[tile_addr..tile_addr+16];
-      //   framebuf.set_tile(x as usize*8, y as usize*8, &tile);
-      // }
-
-      canvas.clear();
-      texture.update(None, &framebuf.buffer, framebuf.pitch())?;
-      canvas.copy(&texture, None, None)?;
-      canvas.present();
-
-      let ms_elapsed = time::Instant::now() - ms_since_frame_start;
-      if ms_frame > ms_elapsed {
-        std::thread::sleep(ms_frame - ms_elapsed);
-      }
+use std::sync::{Arc, Mutex};
+
+use tomboy_emulator::{bus::IFlags, mem::Memory, frame::{Color, FrameBuffer, PixelFormat, GRAYSCALE, LCD_HEIGHT, LCD_WIDTH}, gb::Gameboy, ppu::{Layer, OamObject, PpuMode, Tilemap, TILEMAP_VIEW_SIZE, TILES_VIEW_SIZE}, testing::{asm, RomBuilder}};
+
+fn tiles_view(gb: &mut Gameboy) -> FrameBuffer {
+  let (width, height) = TILES_VIEW_SIZE;
+  let mut fb = FrameBuffer::new(width, height);
+  gb.get_ppu().debug_render_tiles(&mut fb);
+  fb
+}
+
+#[test]
+fn tiles() {
+  let rom = RomBuilder::new().code(&asm!["loop:", "JR loop"]).build();
+  let mut gb = Gameboy::boot_from_bytes(&rom).unwrap();
+  gb.step_until_vblank();
+
+  // tile 17 (second row, second column): a vertical stripe of each color
+  let ppu = gb.get_ppu();
+  for row in 0..8 {
+    ppu.vram[17*16 + row*2] = 0b0101_0101;
+    ppu.vram[17*16 + row*2 + 1] = 0b0011_0011;
+  }
+  // identity palette
+  ppu.write(0xFF47, 0b11_10_01_00);
+  let fb = tiles_view(&mut gb);
+
+  let mut expected = FrameBuffer::new(fb.width, fb.height);
+  for y in 0..fb.height {
+    for x in 0..fb.width {
+      let color = if (8..16).contains(&x) && (8..16).contains(&y) {
+        x % 4
+      } else { 0 };
+      expected.set_pixel(x, y, color as u8);
     }
-  
-    Ok(())
   }
+  assert!(fb.buffer == expected.buffer);
+
+  // inverted palette
+  gb.get_ppu().write(0xFF47, 0b00_01_10_11);
+  let fb = tiles_view(&mut gb);
+  let mut pixel = FrameBuffer::new(1, 1);
+  pixel.set_pixel(0, 0, 3);
+  assert_eq!(fb.buffer[..4], pixel.buffer[..]);
+  pixel.set_pixel(0, 0, 2);
+  let idx = (9*fb.width + 9) * 4;
+  assert_eq!(fb.buffer[idx..idx+4], pixel.buffer[..]);
+}
+
+#[test]
+fn tilemap() {
+  let rom = RomBuilder::new().code(&asm!["loop:", "JR loop"]).build();
+  let mut gb = Gameboy::boot_from_bytes(&rom).unwrap();
+  gb.step_until_vblank();
+
+  let ppu = gb.get_ppu();
+  // unsigned tile addressing, BG map at 0x9800, window map at 0x9C00
+  ppu.write(0xFF40, 0b1101_0001);
+  ppu.write(0xFF47, 0b11_10_01_00);
+  ppu.write(0xFF42, 200);
+  ppu.write(0xFF43, 0);
+  // tile 1 is solid color 3, placed at map position (2, 1) of the BG map only
+  ppu.vram[16..32].fill(0xFF);
+  ppu.vram[0x1800 + 32 + 2] = 1;
+
+  let (width, height) = TILEMAP_VIEW_SIZE;
+  let mut bg = FrameBuffer::new(width, height);
+  let mut wnd = FrameBuffer::new(width, height);
+  gb.get_ppu().debug_render_tilemap(Tilemap::Bg, &mut bg);
+  gb.get_ppu().debug_render_tilemap(Tilemap::Window, &mut wnd);
+
+  let pixel = |fb: &FrameBuffer, x: usize, y: usize| {
+    let idx = (y*fb.width + x) * 4;
+    fb.buffer[idx..idx+4].to_vec()
+  };
+  let mut colors = FrameBuffer::new(4, 1);
+  for i in 0..4 { colors.set_pixel(i, 0, i as u8); }
+
+  assert_eq!(pixel(&bg, 20, 12), pixel(&colors, 3, 0));
+  assert_eq!(pixel(&wnd, 20, 12), pixel(&colors, 0, 0));
+  assert_eq!(pixel(&bg, 20, 100), pixel(&colors, 0, 0));
+
+  // the viewport outline wraps from y=200 to y=87
+  let red = [255, 0, 0, 255].to_vec();
+  assert_eq!(pixel(&bg, 50, 200), red);
+  assert_eq!(pixel(&bg, 50, 87), red);
+  assert_eq!(pixel(&bg, 159, 0), red);
+  assert_eq!(pixel(&bg, 50, 0), pixel(&colors, 0, 0));
+  assert_eq!(pixel(&wnd, 50, 200), pixel(&colors, 0, 0));
+}
+
+#[test]
+fn oam() {
+  let rom = RomBuilder::new().code(&asm!["loop:", "JR loop"]).build();
+  let mut gb = Gameboy::boot_from_bytes(&rom).unwrap();
+  gb.step_until_vblank();
+
+  let ppu = gb.get_ppu();
+  ppu.write(0xFF48, 0b11_10_01_00);
+  ppu.write(0xFF49, 0b00_00_00_11);
+  ppu.oam[4..8].copy_from_slice(&[32, 16, 2, 0b0011_0000]);
+  // tile 2: only the top left pixel is set, color 1
+  ppu.vram[32] = 0x80;
+
+  let objs = gb.get_ppu().debug_oam();
+  assert_eq!(objs[1], OamObject {
+    i: 1, y: 32, x: 16, tile_id: 2,
+    priority: true, x_flip: true, y_flip: false, dmg_palette: true,
+  });
+  assert_eq!(objs[0].tile_id, 0);
+
+  let mut fb = FrameBuffer::new(8, 8);
+  gb.get_ppu().debug_render_obj(&objs[1], &mut fb);
+
+  let mut expected = FrameBuffer::new(8, 8);
+  // x flipped, and OBP1 maps color 1 to 0
+  expected.set_pixel(7, 0, 0);
+  assert_eq!(fb.buffer[7*4..8*4], expected.buffer[7*4..8*4]);
+  // transparent pixels aren't drawn
+  assert_eq!(fb.buffer[..7*4], [0; 7*4]);
+}
+
+#[test]
+fn palettes() {
+  let rom = RomBuilder::new().code(&asm!["loop:", "JR loop"]).build();
+  let mut gb = Gameboy::boot_from_bytes(&rom).unwrap();
+
+  let ppu = gb.get_ppu();
+  ppu.write(0xFF47, 0b11_10_01_00);
+  ppu.write(0xFF48, 0b00_01_10_11);
+  ppu.write(0xFF49, 0b10_10_10_10);
+
+  let shades: [Color; 4] = std::array::from_fn(|i| ppu.lcd.color(i as u8));
+  let palettes = ppu.palettes();
+  assert_eq!(palettes.bgp, shades);
+  assert_eq!(palettes.obp0, [shades[3], shades[2], shades[1], shades[0]]);
+  assert_eq!(palettes.obp1, [shades[2]; 4]);
+}
+
+#[test]
+fn lcd_palette() {
+  let rom = RomBuilder::new().code(&asm!["loop:", "JR loop"]).build();
+  let mut gb = Gameboy::boot_from_bytes(&rom).unwrap();
+  gb.set_palette(GRAYSCALE);
+  gb.step_until_vblank();
+  gb.step_until_vblank();
+  assert_eq!(gb.get_screen().buffer[..4], [255, 255, 255, 255]);
+
+  gb.reset();
+  assert_eq!(gb.get_screen().palette(), GRAYSCALE);
+  assert_eq!(gb.get_ppu().palettes().bgp[0], (255, 255, 255));
+}
+
+#[test]
+fn indexed_output() {
+  let rom = RomBuilder::new().code(&asm!["loop:", "JR loop"]).build();
+  let mut gb = Gameboy::boot_from_bytes(&rom).unwrap();
+  gb.step_until_vblank();
+
+  // BG tile 0 is filled with color 1, shown as shade 2
+  let ppu = gb.get_ppu();
+  ppu.vram[..16].copy_from_slice(&[0xFF, 0x00].repeat(8));
+  ppu.write(0xFF40, 0b1001_0001);
+  ppu.write(0xFF47, 0b00_00_10_00);
+  gb.step_until_vblank();
+  gb.step_until_vblank();
+
+  let screen = gb.get_screen();
+  assert_eq!(screen.indexed.len(), screen.width * screen.height);
+  assert!(screen.indexed.iter().all(|&shade| shade == 2));
+
+  gb.set_palette(GRAYSCALE);
+  assert_eq!(gb.get_screen().buffer[..4], [85, 85, 85, 255]);
+}
+
+#[test]
+fn pixel_formats() {
+  let color = (0xF8, 0xFC, 0x08);
+  let expected: [(PixelFormat, &[u8]); 4] = [
+    (PixelFormat::Rgba32, &[0xF8, 0xFC, 0x08, 0xFF]),
+    (PixelFormat::Bgra32, &[0x08, 0xFC, 0xF8, 0xFF]),
+    (PixelFormat::Rgb24, &[0xF8, 0xFC, 0x08]),
+    (PixelFormat::Rgb565, &[0xE1, 0xFF]),
+  ];
+
+  for (format, bytes) in expected {
+    let mut fb = FrameBuffer::with_format(3, 2, format);
+    assert_eq!(fb.pitch(), 3 * bytes.len());
+    fb.set_color(1, 1, color);
+    let idx = fb.pitch() + bytes.len();
+    assert_eq!(&fb.buffer[idx..idx + bytes.len()], bytes, "{format:?}");
+  }
+
+  let rom = RomBuilder::new().code(&asm!["loop:", "JR loop"]).build();
+  let mut gb = Gameboy::boot_from_bytes(&rom).unwrap();
+  gb.set_pixel_format(PixelFormat::Rgb565);
+  gb.step_until_vblank();
+  gb.reset();
+  let screen = gb.get_screen();
+  assert_eq!(screen.format(), PixelFormat::Rgb565);
+  assert_eq!(screen.buffer.len(), screen.width * screen.height * 2);
+}
+
+#[test]
+fn full_bg_crop() {
+  let rom = RomBuilder::new().code(&asm!["loop:", "JR loop"]).build();
+  let mut gb = Gameboy::boot_from_bytes(&rom).unwrap();
+  gb.step_until_vblank();
+
+  let ppu = gb.get_ppu();
+  ppu.write(0xFF40, 0b1001_0001);
+  ppu.write(0xFF47, 0b11_10_01_00);
+  ppu.write(0xFF42, 200);
+  ppu.write(0xFF43, 100);
+  // a different tile on every map row
+  for tile in 0..4 {
+    ppu.vram[tile*16..tile*16 + 16].fill((tile as u8) * 0x55);
+  }
+  for i in 0..32*32 {
+    ppu.vram[0x1800 + i] = (i / 32 % 4) as u8;
+  }
+  gb.step_until_vblank();
+  gb.step_until_vblank();
 
-  #[test]
-  fn sprite() -> Result<(), Box<dyn Error>> {
-    let sdl = sdl2::init()?;
-    let video = sdl.video()?;
-    let ms_frame = time::Duration::from_secs_f64(1.0 / 60.0);
-  
-    const WIN_WIDTH: u32 = 10*16;
-    const WIN_HEIGHT: u32 = 4*16;
-  
-    let mut canvas = video.window("TomboyEmu", WIN_WIDTH*2, WIN_HEIGHT*2)
-      .position_centered().build()?.into_canvas()
-      .accelerated().target_texture().present_vsync()
-      .build()?;
-  
-    let mut events = sdl.event_pump()?;
-  
-    let mut emu = Cpu::new();
-    let rom = fs::read("./tests/roms/02-interrupts.gb")?;
-    // let rom = fs::read("./roms/Tetris.gb")?;
-
-  
-    let mut bus = emu.bus.borrow_mut();
-    let (left, _) = bus.mem.split_at_mut(rom.len());
-    left.copy_from_slice(&rom);
-    drop(bus);
-  
-    let texture_creator = canvas.texture_creator();
-    let mut texture = texture_creator
-      .create_texture_target(PixelFormatEnum::RGBA32, WIN_WIDTH, WIN_HEIGHT)?;
-  
-    let mut framebuf = FrameBuffer::new(WIN_WIDTH as usize, WIN_HEIGHT as usize);
-  
-    'running: loop {
-      let ms_since_frame_start = time::Instant::now();
-  
-      while emu.ppu.vblank.take().is_none() {
-        emu.step();
-      }
-  
-      for event in events.poll_iter() {
-        match event {
-          Event::Quit { .. } => break 'running,
-          Event::DropFile { filename, .. } => {
-            emu = Cpu::new();
-  
-            let rom = fs::read(filename)?;
-            let mut bus = emu.bus.borrow_mut();
-            let (left, _) = bus.mem.split_at_mut(rom.len());
-            left.copy_from_slice(&rom);
-            drop(bus);
-          }
-          _ => {}
-        }
-      }
-
-      // for i in 0..40 {
-      //   let x = i % (WIN_WIDTH as usize/16);
-      //   let y = i / (WIN_WIDTH as usize/16);
-      //   let tile_id = emu.read(0xFE00 + 4*i as u16 + 2);
-      //   let tile_start = emu.read(0x8000 + 16*tile_id as u16) as usize;
-      //   let tile = &emu.bus.borrow().mem[tile_start..tile_start+16];
-      //   framebuf.set_tile(x*16, y*16, &tile);
-      // }
-      for i in 0..40 {
-        let x = i % (WIN_WIDTH as usize/16);
-        let y = i / (WIN_WIDTH as usize/16);
-        let tile_start = 0x8000 + i*16;
-        // let tile_id = emu.read(0xFE00 + 4*i as u16 + 2);
-        let tile = &emu.bus.borrow().mem[tile_start..tile_start+16];
-        framebuf.set_tile(x*16, y*16, &tile);
-      }
-
-
-      canvas.clear();
-      texture.update(None, &framebuf.buffer, framebuf.pitch())?;
-      canvas.copy(&texture, None, None)?;
-      canvas.present();
-
-      let ms_elapsed = time::Instant::now() - ms_since_frame_start;
-      if ms_frame > ms_elapsed {
-        std::thread::sleep(ms_frame - ms_elapsed);
-      }
+  let mut bg = FrameBuffer::debug_full_bg();
+  gb.get_ppu().debug_render_tilemap(Tilemap::Bg, &mut bg);
+  let view = bg.crop(100, 200, LCD_WIDTH, LCD_HEIGHT);
+  let screen = gb.get_screen();
+  assert_eq!((screen.width, screen.height), (LCD_WIDTH, LCD_HEIGHT));
+  assert_eq!(gb.get_resolution(), (LCD_WIDTH, LCD_HEIGHT));
+  assert!(view.indexed == screen.indexed);
+}
+
+#[test]
+fn screenshot() {
+  let rom = RomBuilder::new().code(&asm!["loop:", "JR loop"]).build();
+  let mut gb = Gameboy::boot_from_bytes(&rom).unwrap();
+  gb.set_pixel_format(PixelFormat::Rgb565);
+  gb.set_palette(GRAYSCALE);
+  gb.step_until_vblank();
+  gb.step_until_vblank();
+
+  let shot = gb.screenshot();
+  assert_eq!((shot.width, shot.height), (LCD_WIDTH, LCD_HEIGHT));
+  assert_eq!(shot.rgb.len(), LCD_WIDTH * LCD_HEIGHT * 3);
+  assert!(shot.rgb.iter().all(|&c| c == 255));
+
+  #[cfg(feature = "png")]
+  {
+    let path = std::env::temp_dir().join("tomboy_screenshot_test.png");
+    gb.save_png(&path).unwrap();
+    let decoder = png::Decoder::new(std::io::BufReader::new(std::fs::File::open(&path).unwrap()));
+    let info = decoder.read_info().unwrap().info().clone();
+    assert_eq!((info.width, info.height), (LCD_WIDTH as u32, LCD_HEIGHT as u32));
+    std::fs::remove_file(path).unwrap();
+  }
+}
+
+// Dots of mode 3 on line 10, read from STAT like a game would
+fn mode3_dots(gb: &mut Gameboy) -> usize {
+  let ppu = gb.get_ppu();
+  while ppu.read(0xFF44) != 10 { ppu.tick(); }
+  while ppu.read(0xFF41) & 0b11 != 3 { ppu.tick(); }
+  let mut dots = 0;
+  while ppu.read(0xFF41) & 0b11 == 3 {
+    ppu.tick();
+    dots += 1;
+  }
+  while ppu.read(0xFF44) != 0 { ppu.tick(); }
+  dots
+}
+
+#[test]
+fn mode3_timing() {
+  let rom = RomBuilder::new().code(&asm!["loop:", "JR loop"]).build();
+  let mut gb = Gameboy::boot_from_bytes(&rom).unwrap();
+  gb.step_until_vblank();
+  assert_eq!(mode3_dots(&mut gb), 172);
+
+  // the scrolled out pixels are fetched and thrown away
+  gb.get_ppu().write(0xFF43, 3);
+  assert_eq!(mode3_dots(&mut gb), 175);
+  gb.get_ppu().write(0xFF43, 0);
+
+  // the window restarts the fetcher
+  gb.get_ppu().write(0xFF40, 0b1010_0001);
+  gb.get_ppu().write(0xFF4B, 87);
+  assert_eq!(mode3_dots(&mut gb), 178);
+
+  // objects on line 10: y = 10 + 16
+  gb.get_ppu().write(0xFF40, 0b1000_0011);
+  let mut objs_dots = |xs: &[u8], scx: u8| {
+    let ppu = gb.get_ppu();
+    ppu.oam.fill(0);
+    ppu.write(0xFF43, scx);
+    for (i, &x) in xs.iter().enumerate() {
+      ppu.oam[i*4..i*4 + 2].copy_from_slice(&[26, x]);
     }
-  
-    Ok(())
+    mode3_dots(&mut gb)
+  };
+  // aligned with a tile, the fetch waits for the whole background fetch
+  assert_eq!(objs_dots(&[0], 0), 172 + 11);
+  assert_eq!(objs_dots(&[16], 0), 172 + 11);
+  assert_eq!(objs_dots(&[13], 0), 172 + 6);
+  assert_eq!(objs_dots(&[16], 3), 172 + 3 + 8);
+  // only the first object on a tile waits for the background
+  assert_eq!(objs_dots(&[16, 16], 0), 172 + 11 + 6);
+  assert_eq!(objs_dots(&[16, 24], 0), 172 + 11 + 11);
+  // off screen on the right, never fetched
+  assert_eq!(objs_dots(&[168], 0), 172);
+}
+
+#[cfg(feature = "dot-renderer")]
+#[test]
+fn obj_fetched_mid_line() {
+  let rom = RomBuilder::new().code(&asm!["loop:", "JR loop"]).build();
+  let mut gb = Gameboy::boot_from_bytes(&rom).unwrap();
+  gb.step_until_vblank();
+
+  let ppu = gb.get_ppu();
+  ppu.write(0xFF40, 0b1000_0011);
+  ppu.write(0xFF47, 0b11_10_01_00);
+  ppu.write(0xFF48, 0b11_10_01_00);
+  // solid tiles: 1 is color 3, 2 is color 1, 3 is color 2
+  ppu.vram[16..32].fill(0xFF);
+  ppu.vram[32..48].copy_from_slice(&[0xFF, 0x00].repeat(8));
+  ppu.vram[48..64].copy_from_slice(&[0x00, 0xFF].repeat(8));
+  // on screen at x 80 and x 76, on line 10
+  ppu.oam[..4].copy_from_slice(&[26, 88, 1, 0]);
+  ppu.oam[4..8].copy_from_slice(&[26, 84, 2, 0]);
+
+  while ppu.read(0xFF44) != 10 { ppu.tick(); }
+  while ppu.read(0xFF41) & 0b11 != 3 { ppu.tick(); }
+  for _ in 0..40 { ppu.tick(); }
+  // the output hasn't reached the object yet, so it is drawn with the new tile
+  ppu.oam[2] = 3;
+  while ppu.read(0xFF44) != 11 { ppu.tick(); }
+
+  let line = &gb.get_screen().indexed[10*LCD_WIDTH..11*LCD_WIDTH];
+  assert_eq!(line[75], 0);
+  // the object with the lower x is drawn over the other
+  assert_eq!(line[76..84], [1; 8]);
+  assert_eq!(line[84..88], [2; 4]);
+  assert_eq!(line[88], 0);
+}
+
+#[test]
+fn oam_scan_timing() {
+  let rom = RomBuilder::new().code(&asm!["loop:", "JR loop"]).build();
+  let mut gb = Gameboy::boot_from_bytes(&rom).unwrap();
+  gb.step_until_vblank();
+
+  let ppu = gb.get_ppu();
+  ppu.write(0xFF40, 0b1000_0011);
+  ppu.write(0xFF47, 0b11_10_01_00);
+  ppu.write(0xFF48, 0b11_10_01_00);
+  ppu.vram[16..32].fill(0xFF);
+  ppu.oam.fill(0);
+
+  while ppu.read(0xFF44) != 10 { ppu.tick(); }
+  // 10 dots in mode 2, the first 5 entries are scanned
+  for _ in 0..10 { ppu.tick(); }
+  assert_eq!(ppu.read(0xFF41) & 0b11, 2);
+  ppu.oam[..4].copy_from_slice(&[26, 8, 1, 0]);
+  ppu.oam[80..84].copy_from_slice(&[26, 48, 1, 0]);
+  assert_eq!(gb.get_bus().read(0xFE00), 0xFF, "OAM is blocked in mode 2");
+  gb.get_bus().write(0xFE01, 100);
+  assert_eq!(gb.get_ppu().oam[1], 8);
+
+  let ppu = gb.get_ppu();
+  while ppu.read(0xFF41) & 0b11 != 0 { ppu.tick(); }
+  assert_eq!(gb.get_bus().read(0xFE00), 26);
+  let ppu = gb.get_ppu();
+  while ppu.read(0xFF44) != 12 { ppu.tick(); }
+
+  // the first entry was written after it was scanned, it only shows from the next line
+  let screen = &gb.get_screen().indexed;
+  assert_eq!(screen[10*LCD_WIDTH], 0);
+  assert_eq!(screen[10*LCD_WIDTH + 40], 3);
+  assert_eq!(screen[11*LCD_WIDTH], 3);
+}
+
+#[test]
+fn window_quirks() {
+  let rom = RomBuilder::new().code(&asm!["loop:", "JR loop"]).build();
+  let mut gb = Gameboy::boot_from_bytes(&rom).unwrap();
+  gb.step_until_vblank();
+
+  let ppu = gb.get_ppu();
+  ppu.write(0xFF47, 0b11_10_01_00);
+  // the window map is all tile 1, where each row has the color of its index % 4:
+  // the color shows the window line counter
+  for row in 0..8 {
+    let color = row % 4;
+    ppu.vram[16 + row*2] = if color & 1 != 0 { 0xFF } else { 0 };
+    ppu.vram[16 + row*2 + 1] = if color & 2 != 0 { 0xFF } else { 0 };
+  }
+  ppu.vram[0x1C00..0x2000].fill(1);
+
+  let to_line = |gb: &mut Gameboy, line: u8| {
+    let ppu = gb.get_ppu();
+    while ppu.read(0xFF44) != line { ppu.tick(); }
+  };
+  let pixel = |gb: &Gameboy, x: usize, y: usize| gb.get_screen().indexed[y*LCD_WIDTH + x];
+  const WND_ON: u8 = 0b1111_0001;
+  const WND_OFF: u8 = 0b1101_0001;
+
+  // the line counter doesn't move while the window is disabled
+  gb.get_ppu().write(0xFF40, WND_ON);
+  gb.get_ppu().write(0xFF4A, 0);
+  gb.get_ppu().write(0xFF4B, 7);
+  to_line(&mut gb, 10);
+  gb.get_ppu().write(0xFF40, WND_OFF);
+  to_line(&mut gb, 20);
+  gb.get_ppu().write(0xFF40, WND_ON);
+  to_line(&mut gb, 21);
+  assert_eq!(pixel(&gb, 0, 9), 1);
+  assert_eq!(pixel(&gb, 0, 15), 0);
+  assert_eq!(pixel(&gb, 0, 20), 10 % 4);
+
+  // moving WY below LY after they matched doesn't hide the window
+  to_line(&mut gb, 144);
+  gb.get_ppu().write(0xFF4A, 20);
+  to_line(&mut gb, 30);
+  gb.get_ppu().write(0xFF4A, 100);
+  to_line(&mut gb, 42);
+  assert_eq!(pixel(&gb, 0, 19), 0);
+  assert_eq!(pixel(&gb, 0, 41), (41 - 20) % 4);
+
+  // WX=166 shows the last pixel, then the whole next line
+  to_line(&mut gb, 144);
+  gb.get_ppu().write(0xFF4A, 0);
+  gb.get_ppu().write(0xFF4B, 166);
+  to_line(&mut gb, 3);
+  assert_eq!(pixel(&gb, 158, 0), 0);
+  assert_eq!(pixel(&gb, 159, 1), 1);
+  assert_eq!(pixel(&gb, 0, 2), 2);
+}
+
+#[cfg(feature = "dot-renderer")]
+#[test]
+fn window_enabled_mid_line() {
+  let rom = RomBuilder::new().code(&asm!["loop:", "JR loop"]).build();
+  let mut gb = Gameboy::boot_from_bytes(&rom).unwrap();
+  gb.step_until_vblank();
+
+  let ppu = gb.get_ppu();
+  ppu.write(0xFF47, 0b11_10_01_00);
+  ppu.vram[16..32].fill(0xFF);
+  ppu.vram[0x1C00..0x2000].fill(1);
+  ppu.write(0xFF4A, 0);
+  ppu.write(0xFF4B, 47);
+  ppu.write(0xFF40, 0b1101_0001);
+
+  while ppu.read(0xFF44) != 10 { ppu.tick(); }
+  while ppu.read(0xFF41) & 0b11 != 3 { ppu.tick(); }
+  for _ in 0..120 { ppu.tick(); }
+  // the output is past WX, the window doesn't start on this line
+  ppu.write(0xFF40, 0b1111_0001);
+  while ppu.read(0xFF44) != 12 { ppu.tick(); }
+
+  let screen = &gb.get_screen().indexed;
+  assert_eq!(screen[10*LCD_WIDTH + 100], 0);
+  assert_eq!(screen[11*LCD_WIDTH + 100], 3);
+}
+
+#[test]
+fn lcd_off_and_on() {
+  let rom = RomBuilder::new().code(&asm!["loop:", "JR loop"]).build();
+  let mut gb = Gameboy::boot_from_bytes(&rom).unwrap();
+  gb.step_until_vblank();
+
+  // the BG is all color 3
+  let ppu = gb.get_ppu();
+  ppu.write(0xFF40, 0b1001_0001);
+  ppu.write(0xFF47, 0b11_10_01_00);
+  ppu.vram[..16].fill(0xFF);
+  gb.step_until_vblank();
+  gb.step_until_vblank();
+  assert!(gb.get_screen().indexed.iter().all(|&shade| shade == 3));
+
+  // turned off, the screen is blank and LY stays 0, but frames keep coming
+  let ppu = gb.get_ppu();
+  ppu.write(0xFF40, 0b0001_0001);
+  let frames = ppu.frame_count();
+  for _ in 0..70224 { ppu.tick(); }
+  assert_eq!(ppu.frame_count(), frames + 1);
+  assert!(ppu.frame_ready.take().is_some());
+  assert_eq!(ppu.read(0xFF44), 0);
+  assert_eq!(ppu.read(0xFF41) & 0b11, 0);
+  assert!(gb.get_screen().indexed.iter().all(|&shade| shade == 0));
+
+  // turned on, the first line has no OAM scan and starts mode 3 4 dots early
+  let ppu = gb.get_ppu();
+  ppu.write(0xFF40, 0b1001_0001);
+  let mut dots = 0;
+  while ppu.read(0xFF41) & 0b11 == 0 {
+    ppu.tick();
+    dots += 1;
   }
-}
\ No newline at end of file
+  assert_eq!(ppu.read(0xFF41) & 0b11, 3);
+  assert_eq!(dots, 80 - 4);
+
+  // the first frame isn't shown
+  gb.step_until_vblank();
+  assert!(gb.get_screen().indexed.iter().all(|&shade| shade == 0));
+  gb.step_until_vblank();
+  assert!(gb.get_screen().indexed.iter().all(|&shade| shade == 3));
+}
+
+#[test]
+fn stat_write_quirk() {
+  let rom = RomBuilder::new().code(&asm!["loop:", "JR loop"]).build();
+  let mut gb = Gameboy::boot_from_bytes(&rom).unwrap();
+  gb.step_until_vblank();
+
+  // in vblank, with no STAT source enabled
+  gb.get_bus().set_intf(IFlags::empty());
+  gb.get_ppu().write(0xFF41, 0);
+  assert!(gb.get_bus().intf().contains(IFlags::lcd));
+
+  gb.set_dmg_quirks(false);
+  gb.get_ppu().tick();
+  gb.get_bus().set_intf(IFlags::empty());
+  gb.get_ppu().write(0xFF41, 0);
+  assert!(!gb.get_bus().intf().contains(IFlags::lcd));
+}
+
+#[test]
+fn ly_153_quirk() {
+  let rom = RomBuilder::new().code(&asm!["loop:", "JR loop"]).build();
+  let mut gb = Gameboy::boot_from_bytes(&rom).unwrap();
+  gb.step_until_vblank();
+
+  let ppu = gb.get_ppu();
+  ppu.write(0xFF45, 0);
+  while ppu.read(0xFF44) != 153 { ppu.tick(); }
+  let mut dots = 0;
+  while ppu.read(0xFF44) == 153 {
+    ppu.tick();
+    dots += 1;
+  }
+  assert_eq!(dots, 4);
+
+  // LY=LYC=0 already matches in vblank
+  assert_eq!(ppu.read(0xFF41) & 0b11, 1);
+  assert_ne!(ppu.read(0xFF41) & 0b100, 0);
+
+  // the rest of line 153, then line 0
+  let mut dots = 0;
+  while ppu.read(0xFF41) & 0b11 == 1 {
+    ppu.tick();
+    dots += 1;
+  }
+  assert_eq!(dots, 456 - 4);
+  assert_eq!(ppu.read(0xFF44), 0);
+  assert_eq!(ppu.read(0xFF41) & 0b11, 2);
+
+  // vblank lasts 10 lines
+  while ppu.read(0xFF44) != 144 { ppu.tick(); }
+  let mut dots = 0;
+  while ppu.read(0xFF41) & 0b11 == 1 {
+    ppu.tick();
+    dots += 1;
+  }
+  assert_eq!(dots, 4560);
+}
+
+#[test]
+fn scanline_callback() {
+  let rom = RomBuilder::new().code(&asm!["loop:", "JR loop"]).build();
+  let mut gb = Gameboy::boot_from_bytes(&rom).unwrap();
+  gb.step_until_vblank();
+
+  let lines = Arc::new(Mutex::new(Vec::new()));
+  let seen = lines.clone();
+  gb.on_scanline(move |ppu| seen.lock().unwrap().push((ppu.ly(), ppu.mode())));
+  gb.step_until_vblank();
+  assert_eq!(gb.get_ppu().mode(), PpuMode::Vblank);
+  assert_eq!(gb.get_ppu().ly(), 144);
+
+  // every visible line once, reported as soon as it's drawn
+  let expected: Vec<_> = (0..144).map(|ly| (ly, PpuMode::Hblank)).collect();
+  assert_eq!(*lines.lock().unwrap(), expected);
+}
+
+#[test]
+fn layer_toggles() {
+  let rom = RomBuilder::new().code(&asm!["loop:", "JR loop"]).build();
+  let mut gb = Gameboy::boot_from_bytes(&rom).unwrap();
+  gb.step_until_vblank();
+
+  let ppu = gb.get_ppu();
+  ppu.write(0xFF47, 0b11_10_01_00);
+  ppu.write(0xFF48, 0b11_10_01_00);
+  // both maps are tile 1, all color 3, with the window right of x=80
+  ppu.vram[16..32].fill(0xFF);
+  ppu.vram[0x1800..0x2000].fill(1);
+  ppu.write(0xFF4A, 0);
+  ppu.write(0xFF4B, 87);
+  // an object of tile 2, all color 1, in the top left corner
+  ppu.vram[32..48].copy_from_slice(&[0xFF, 0].repeat(8));
+  ppu.oam[..4].copy_from_slice(&[16, 8, 2, 0]);
+  ppu.write(0xFF40, 0b1111_0011);
+
+  let pixels = |gb: &mut Gameboy| {
+    gb.step_until_vblank();
+    let screen = gb.get_screen();
+    [0, 40, 100].map(|x| screen.indexed[x])
+  };
+
+  assert_eq!(pixels(&mut gb), [1, 3, 3]);
+  gb.get_ppu().set_layer_enabled(Layer::Objects, false);
+  assert_eq!(pixels(&mut gb), [3, 3, 3]);
+  gb.get_ppu().set_layer_enabled(Layer::Background, false);
+  assert_eq!(pixels(&mut gb), [0, 0, 3]);
+  gb.get_ppu().set_layer_enabled(Layer::Objects, true);
+  gb.get_ppu().set_layer_enabled(Layer::Window, false);
+  assert_eq!(pixels(&mut gb), [1, 0, 0]);
+  assert!(!gb.get_ppu().is_layer_enabled(Layer::Window));
+}
+
+#[test]
+fn obj_priority_modes() {
+  let setup = |rom: &[u8]| {
+    let mut gb = Gameboy::boot_from_bytes(rom).unwrap();
+    gb.step_until_vblank();
+    let ppu = gb.get_ppu();
+    ppu.write(0xFF48, 0b11_10_01_00);
+    // tile 1 is all color 1, tile 2 all color 2
+    ppu.vram[16..32].copy_from_slice(&[0xFF, 0].repeat(8));
+    ppu.vram[32..48].copy_from_slice(&[0, 0xFF].repeat(8));
+    // the object with the lower index is the one more on the right
+    ppu.oam[..8].copy_from_slice(&[16, 12, 1, 0, 16, 8, 2, 0]);
+    ppu.write(0xFF40, 0b1001_0011);
+    gb
+  };
+  let overlap = |gb: &mut Gameboy| {
+    gb.step_until_vblank();
+    gb.get_screen().indexed[5]
+  };
+
+  // on DMG the leftmost object is on top, and OPRI doesn't exist
+  let mut gb = setup(&RomBuilder::new().code(&asm!["loop:", "JR loop"]).build());
+  assert_eq!(gb.get_bus().read(0xFF6C), 0xFF);
+  gb.get_bus().write(0xFF6C, 0);
+  assert_eq!(overlap(&mut gb), 2);
+
+  // CGB games start with OAM index priority
+  let rom = RomBuilder::new().code(&asm!["loop:", "JR loop"]).at(0x143, &[0x80]).build();
+  let mut gb = setup(&rom);
+  assert_eq!(gb.get_bus().read(0xFF6C), 0xFE);
+  assert_eq!(overlap(&mut gb), 1);
+  gb.get_bus().write(0xFF6C, 1);
+  assert_eq!(gb.get_bus().read(0xFF6C), 0xFF);
+  assert_eq!(overlap(&mut gb), 2);
+}
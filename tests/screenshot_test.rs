@@ -0,0 +1,125 @@
+#[cfg(test)]
+mod screenshot_test {
+  use std::{fs, path::Path};
+
+  use tomboy_emulator::testing::{self, assemble, compare_screen, run_frames, RomBuilder, CODE_START};
+
+  const SCREENSHOTS: &str = "./tests/screenshots";
+
+  /// Test ROMs placed in `tests/roms`, with the frames to run. They aren't distributed with the sources.
+  const ROMS: &[(&str, usize)] = &[
+    ("dmg-acid2", 60),
+    ("sprite_priority", 60),
+  ];
+
+  fn check(name: &str, rom: &[u8], frames: usize) {
+    let gb = run_frames(rom, frames).unwrap();
+    let reference = Path::new(SCREENSHOTS).join(format!("{name}.pgm"));
+    if let Err(e) = compare_screen(gb.get_screen(), reference) {
+      panic!("{e}");
+    }
+  }
+
+  // Objects overlapping each other and the background:
+  // - at the top left, the object with the lower X is drawn over the other
+  // - below, at the same X, the first in OAM is drawn over the other
+  // - on the stripe, the object behind the background is hidden, the one next to it isn't
+  // - on the bottom line, only the first 10 of 12 objects are drawn
+  fn sprite_priority_rom() -> Vec<u8> {
+    let mut oam = vec![
+      [16 + 8, 8 + 20, 1, 0],
+      [16 + 12, 8 + 16, 3, 0],
+      [16 + 40, 8 + 40, 1, 0],
+      [16 + 44, 8 + 40, 3, 0],
+      [16 + 16, 8 + 60, 1, 0x80],
+      [16 + 16, 8 + 70, 1, 0],
+    ];
+    oam.extend((0..12).map(|i| [16 + 100, 8 + 10 * i, 3, 0]));
+    oam.resize(40, [0; 4]);
+
+    let table = oam.iter().flatten().map(|b| format!("DB {b}")).collect::<Vec<_>>();
+    let mut lines = vec![
+      "LD A, 0",
+      "LDH ($40), A",
+      "LD A, $E4",
+      "LDH ($47), A",
+      "LDH ($48), A",
+
+      // tile 1 is shade 3, tile 2 shade 1, tile 3 shade 2
+      "LD HL, $8010",
+      "LD B, 8",
+      "tiles:",
+      "LD A, $FF",
+      "LD (HL+), A",
+      "LD (HL+), A",
+      "DEC B",
+      "JR NZ, tiles",
+      "LD B, 8",
+      "tile2:",
+      "LD A, $FF",
+      "LD (HL+), A",
+      "XOR A",
+      "LD (HL+), A",
+      "DEC B",
+      "JR NZ, tile2",
+      "LD B, 8",
+      "tile3:",
+      "XOR A",
+      "LD (HL+), A",
+      "LD A, $FF",
+      "LD (HL+), A",
+      "DEC B",
+      "JR NZ, tile3",
+
+      // a stripe of tile 2 on the third row
+      "LD HL, $9840",
+      "LD B, 32",
+      "LD A, 2",
+      "stripe:",
+      "LD (HL+), A",
+      "DEC B",
+      "JR NZ, stripe",
+
+      "LD HL, oam",
+      "LD DE, $FE00",
+      "LD B, 160",
+      "copy:",
+      "LD A, (HL+)",
+      "LD (DE), A",
+      "INC DE",
+      "DEC B",
+      "JR NZ, copy",
+
+      "LD A, $93",
+      "LDH ($40), A",
+      "loop:",
+      "JR loop",
+      "oam:",
+    ];
+    lines.extend(table.iter().map(String::as_str));
+
+    RomBuilder::new().code(&assemble(CODE_START, &lines).unwrap()).build()
+  }
+
+  #[test]
+  fn sprite_priority() {
+    check("sprite_priority_scene", &sprite_priority_rom(), 3);
+  }
+
+  #[test]
+  fn ppu_scene() {
+    check("ppu_scene", &testing::ppu_bound_rom(), 30);
+  }
+
+  /// BLESS=1 cargo test --test screenshot_test, to record new references.
+  #[test]
+  fn test_roms() {
+    for (name, frames) in ROMS {
+      let Ok(rom) = fs::read(format!("./tests/roms/{name}.gb")) else {
+        println!("{name}.gb not found, skipping");
+        continue;
+      };
+      check(name, &rom, *frames);
+    }
+  }
+}
@@ -0,0 +1,174 @@
+#[cfg(test)]
+mod test_roms {
+  use std::{env, fs, path::{Path, PathBuf}, sync::{Arc, Mutex}};
+
+  use tomboy_emulator::{cart::LoadOptions, gb::Gameboy, testing::{assemble, RomBuilder, CODE_START}};
+
+  const CPU_FREQ: usize = 4194304;
+  /// Emulated time given to a ROM that isn't in [`TIMEOUTS`].
+  const DEFAULT_TIMEOUT_SECS: usize = 20;
+  /// ROMs known to need more time, by file name.
+  const TIMEOUTS: &[(&str, usize)] = &[
+    ("cpu_instrs.gb", 60),
+    ("instr_timing.gb", 5),
+    ("mem_timing.gb", 10),
+    ("halt_bug.gb", 10),
+  ];
+  /// Serial output is checked every this many T-cycles.
+  const CHECK_INTERVAL: usize = 70224;
+
+  const MOONEYE_PASS: [u8; 6] = [3, 5, 8, 13, 21, 34];
+  const LD_B_B: u8 = 0x40;
+
+  /// How a test ROM reports its result.
+  #[derive(Clone, Copy, Debug, PartialEq)]
+  enum Convention {
+    /// Blargg's: "Passed" or "Failed" over the link port.
+    Serial,
+    /// Mooneye's: `LD B, B` once done, with B C D E H L holding the fibonacci numbers on success.
+    Fibonacci,
+  }
+
+  #[derive(Debug, PartialEq)]
+  enum Outcome {
+    Passed,
+    Failed(String),
+    TimedOut(String),
+  }
+
+  fn run_rom(rom: &[u8], convention: Convention, timeout_secs: usize) -> Outcome {
+    let mut gb = match Gameboy::boot_with_options(rom, &LoadOptions::tolerant()) {
+      Ok(gb) => gb,
+      Err(e) => return Outcome::Failed(e.to_string()),
+    };
+
+    let serial = Arc::new(Mutex::new(String::new()));
+    let out = serial.clone();
+    gb.on_serial_byte(move |byte| out.lock().unwrap().push(byte as char));
+
+    let budget = timeout_secs * CPU_FREQ;
+    match convention {
+      Convention::Serial => {
+        while gb.total_tcycles() < budget {
+          gb.run_tcycles(CHECK_INTERVAL);
+          let serial = serial.lock().unwrap();
+          if serial.contains("Passed") { return Outcome::Passed; }
+          if serial.contains("Failed") { return Outcome::Failed(serial.clone()); }
+        }
+      }
+      Convention::Fibonacci => {
+        while gb.total_tcycles() < budget {
+          gb.step();
+          let cpu = gb.get_cpu();
+          if cpu.peek(cpu.pc) != LD_B_B { continue; }
+
+          let regs = [cpu.bc.hi(), cpu.bc.lo(), cpu.de.hi(), cpu.de.lo(), cpu.hl.hi(), cpu.hl.lo()];
+          return match regs == MOONEYE_PASS {
+            true => Outcome::Passed,
+            false => Outcome::Failed(format!("registers {regs:02X?}")),
+          };
+        }
+      }
+    }
+
+    let output = serial.lock().unwrap().clone();
+    Outcome::TimedOut(output)
+  }
+
+  /// The ROMs under `dir`, recursively, sorted.
+  fn find_roms(dir: &Path, roms: &mut Vec<PathBuf>) {
+    let Ok(entries) = fs::read_dir(dir) else { return };
+    for path in entries.map(|e| e.unwrap().path()) {
+      if path.is_dir() {
+        find_roms(&path, roms);
+      } else if path.extension().is_some_and(|ext| ext == "gb") {
+        roms.push(path);
+      }
+    }
+    roms.sort();
+  }
+
+  fn timeout(path: &Path) -> usize {
+    let name = path.file_name().unwrap().to_string_lossy();
+    TIMEOUTS.iter()
+      .find(|(rom, _)| *rom == name)
+      .map(|(_, secs)| *secs)
+      .unwrap_or(DEFAULT_TIMEOUT_SECS)
+  }
+
+  /// Runs the test ROM suites placed in `tests/roms/blargg` and `tests/roms/mooneye`,
+  /// or under the directory in `TOMBOY_TEST_ROMS`. The ROMs aren't distributed with the sources,
+  /// the test passes without them.
+  #[test]
+  fn suites() {
+    let root = env::var_os("TOMBOY_TEST_ROMS")
+      .map(PathBuf::from)
+      .unwrap_or_else(|| PathBuf::from("./tests/roms"));
+
+    let mut failures = Vec::new();
+    let mut ran = 0;
+    for (dir, convention) in [("blargg", Convention::Serial), ("mooneye", Convention::Fibonacci)] {
+      let mut roms = Vec::new();
+      find_roms(&root.join(dir), &mut roms);
+
+      for path in roms {
+        let outcome = run_rom(&fs::read(&path).unwrap(), convention, timeout(&path));
+        println!("{}: {outcome:?}", path.display());
+        if outcome != Outcome::Passed {
+          failures.push(path.display().to_string());
+        }
+        ran += 1;
+      }
+    }
+
+    if ran == 0 {
+      println!("No test ROMs found in {}, skipping", root.display());
+    }
+    assert!(failures.is_empty(), "{} of {ran} test ROMs failed: {failures:#?}", failures.len());
+  }
+
+  fn serial_rom(text: &str) -> Vec<u8> {
+    let db = text.bytes().map(|b| format!("DB {b}")).collect::<Vec<_>>();
+    let mut lines = vec![
+      "LD HL, text",
+      "next:",
+      "LD A, (HL+)",
+      "AND A",
+      "JR Z, done",
+      "LD ($FF01), A",
+      "LD A, $81",
+      "LD ($FF02), A",
+      "wait:",
+      "LD A, ($FF02)",
+      "BIT 7, A",
+      "JR NZ, wait",
+      "JR next",
+      "done:",
+      "JR done",
+      "text:",
+    ];
+    lines.extend(db.iter().map(String::as_str));
+    lines.push("DB 0");
+    RomBuilder::new().code(&assemble(CODE_START, &lines).unwrap()).build()
+  }
+
+  fn fibonacci_rom(b: u8) -> Vec<u8> {
+    let b = format!("LD B, {b}");
+    let lines = [&b, "LD C, 5", "LD D, 8", "LD E, 13", "LD H, 21", "LD L, 34", "LD B, B", "loop:", "JR loop"];
+    RomBuilder::new().code(&assemble(CODE_START, &lines).unwrap()).build()
+  }
+
+  #[test]
+  fn conventions() {
+    assert_eq!(run_rom(&serial_rom("cpu_instrs\n\nPassed\n"), Convention::Serial, 1), Outcome::Passed);
+    assert_eq!(
+      run_rom(&serial_rom("01:ok\n02:01\nFailed\n"), Convention::Serial, 1),
+      Outcome::Failed("01:ok\n02:01\nFailed\n".to_string()),
+    );
+    assert!(matches!(run_rom(&serial_rom("hang"), Convention::Serial, 1), Outcome::TimedOut(s) if s == "hang"));
+
+    assert_eq!(run_rom(&fibonacci_rom(3), Convention::Fibonacci, 1), Outcome::Passed);
+    assert!(matches!(run_rom(&fibonacci_rom(0x42), Convention::Fibonacci, 1), Outcome::Failed(_)));
+    assert!(matches!(run_rom(&serial_rom("x"), Convention::Fibonacci, 1), Outcome::TimedOut(_)));
+  }
+}